@@ -1,8 +1,7 @@
 #![feature(test)]
 
 extern crate test;
-use p92000::fcall;
-use std::borrow::Cow;
+use p92000l::fcall;
 use test::Bencher;
 
 #[bench]
@@ -13,13 +12,10 @@ fn encode_walk(b: &mut Bencher) {
         new_fid: 345,
         wnames: ["abc", "def", "hij"]
             .iter()
-            .map(|x| Cow::from(*x))
+            .map(|x| fcall::FcallStr::from(*x))
             .collect(),
     });
-    let tagged_fcall = fcall::TaggedFcall {
-        tag: 123,
-        fcall: fcall,
-    };
+    let tagged_fcall = fcall::TaggedFcall { tag: 123, fcall };
     b.iter(|| tagged_fcall.encode_to_buf(&mut buf).unwrap());
 }
 
@@ -31,13 +27,10 @@ fn decode_walk(b: &mut Bencher) {
         new_fid: 345,
         wnames: ["abc", "def", "hij"]
             .iter()
-            .map(|x| Cow::from(*x))
+            .map(|x| fcall::FcallStr::from(*x))
             .collect(),
     });
-    let tagged_fcall = fcall::TaggedFcall {
-        tag: 123,
-        fcall: fcall,
-    };
+    let tagged_fcall = fcall::TaggedFcall { tag: 123, fcall };
     tagged_fcall.encode_to_buf(&mut buf).unwrap();
     b.iter(|| fcall::TaggedFcall::decode(&buf).unwrap());
 }
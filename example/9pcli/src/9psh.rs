@@ -0,0 +1,119 @@
+use p9cli::{connect, connect_options, exit_code, Shell};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Editor, Helper};
+
+// Bad arguments exit as EINVAL would.
+const EXIT_USAGE: i32 = p92000l::errno::EINVAL as i32;
+
+// Completes from the shell, which is kept here so the editor can reach it
+// while reading a line.
+struct ShellHelper(Shell);
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(self.0.complete(&line[..pos]))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+// Help goes to stdout and exits 0, usage errors go to stderr.
+fn usage(program: &str, opts: getopts::Options, status: i32) -> ! {
+    let brief = format!(
+        "9psh - Explore a 9p2000.L export interactively.\n\n\
+        Usage: {} [options] ADDR\n\n\
+        ADDR is tcp:HOST:PORT or unix:PATH, type help once connected.",
+        program
+    );
+    if status == 0 {
+        print!("{}", opts.usage(&brief));
+    } else {
+        eprint!("{}", opts.usage(&brief));
+    }
+    std::process::exit(status)
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let program = args[0].clone();
+
+    let mut opts = getopts::Options::new();
+    connect_options(&mut opts);
+    opts.optflag("h", "help", "print this help menu");
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(err) => {
+            eprintln!("{}: {}", program, err);
+            std::process::exit(EXIT_USAGE)
+        }
+    };
+    if matches.opt_present("h") {
+        usage(&program, opts, 0);
+    }
+    if matches.free.len() != 1 {
+        usage(&program, opts, EXIT_USAGE);
+    }
+
+    let shell = match connect(&matches, &matches.free[0])
+        .and_then(|(client, root)| Shell::new(client, root))
+    {
+        Ok(shell) => shell,
+        Err(err) => {
+            eprintln!("{}: {}", program, err);
+            std::process::exit(exit_code(&err))
+        }
+    };
+    let mut rl = match Editor::new() {
+        Ok(rl) => rl,
+        Err(err) => {
+            eprintln!("{}: {}", program, err);
+            std::process::exit(1)
+        }
+    };
+    rl.set_helper(Some(ShellHelper(shell)));
+
+    let stdout = std::io::stdout();
+    loop {
+        let line = match rl.readline("9p> ") {
+            Ok(line) => line,
+            // Ctrl-C abandons the line, Ctrl-D leaves.
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => {
+                eprintln!("{}: {}", program, err);
+                std::process::exit(1)
+            }
+        };
+        if !line.trim().is_empty() {
+            let _ = rl.add_history_entry(line.as_str());
+        }
+        let shell = &mut rl.helper_mut().unwrap().0;
+        match shell.exec(&line, &mut stdout.lock()) {
+            Ok(true) => (),
+            Ok(false) => break,
+            Err(err) => {
+                eprintln!("{}: {}", program, err);
+                std::process::exit(exit_code(&err))
+            }
+        }
+    }
+}
@@ -0,0 +1,529 @@
+use crate::resolve;
+use p92000l::{
+    errno, ClientFid, DirEntry, GetattrMask, LOpenFlags, P9Error, P9ErrorKind, Rgetattr, SetAttr,
+    SetattrMask, Statfs, Time,
+};
+use serde_json::json;
+use std::io::{Read, Write};
+
+// Unlinkat flag for removing directories.
+const AT_REMOVEDIR: u32 = 0x200;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+
+/// Every subcommand with its usage.
+pub const COMMANDS: &[(&str, &str)] = &[
+    ("list", "list [PATH...]"),
+    ("stat", "stat PATH..."),
+    ("cat", "cat PATH..."),
+    ("put", "put [-m MODE] PATH"),
+    ("mkdir", "mkdir [-p] [-m MODE] PATH..."),
+    ("rm", "rm [-d] [-f] [-r] PATH..."),
+    ("chmod", "chmod MODE PATH..."),
+    (
+        "setfattr",
+        "setfattr {-n NAME [-v VALUE] | -x NAME} PATH...",
+    ),
+    ("statfs", "statfs [PATH]"),
+];
+
+fn usage_error(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, msg)
+}
+
+fn path_error(ecode: u32, names: &[String]) -> std::io::Error {
+    let mut err = P9Error::new(P9ErrorKind::RemoteErrno(ecode));
+    err.path = names.to_vec();
+    err.into()
+}
+
+fn parse_mode(mode: &str) -> Result<u32, std::io::Error> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(usage_error(format!("invalid mode {:?}", mode))),
+    }
+}
+
+fn parse_opts(
+    cmd: &str,
+    opts: &getopts::Options,
+    args: &[String],
+) -> Result<getopts::Matches, std::io::Error> {
+    opts.parse(args)
+        .map_err(|err| usage_error(format!("{}: {}", cmd, err)))
+}
+
+fn file_type(mode: u32) -> &'static str {
+    match mode & S_IFMT {
+        0o010000 => "fifo",
+        0o020000 => "char",
+        S_IFDIR => "dir",
+        0o060000 => "block",
+        0o100000 => "file",
+        0o120000 => "symlink",
+        0o140000 => "socket",
+        _ => "unknown",
+    }
+}
+
+// Directory entry types are the top bits of the mode.
+fn dirent_type(typ: u8) -> &'static str {
+    file_type((typ as u32) << 12)
+}
+
+fn time(t: &Time) -> String {
+    format!("{}.{:09}", t.sec, t.nsec)
+}
+
+fn stat_json(path: &str, attr: &Rgetattr) -> serde_json::Value {
+    let stat = &attr.stat;
+    json!({
+        "path": path,
+        "type": file_type(stat.mode),
+        "mode": stat.mode & 0o7777,
+        "uid": stat.uid,
+        "gid": stat.gid,
+        "nlink": stat.nlink,
+        "rdev": stat.rdev,
+        "size": stat.size,
+        "blksize": stat.blksize,
+        "blocks": stat.blocks,
+        "atime": stat.atime.sec,
+        "atime_nsec": stat.atime.nsec,
+        "mtime": stat.mtime.sec,
+        "mtime_nsec": stat.mtime.nsec,
+        "ctime": stat.ctime.sec,
+        "ctime_nsec": stat.ctime.nsec,
+        "qid": {
+            "type": attr.qid.typ.bits(),
+            "version": attr.qid.version,
+            "path": attr.qid.path,
+        },
+    })
+}
+
+fn statfs_json(statfs: &Statfs) -> serde_json::Value {
+    json!({
+        "type": statfs.typ,
+        "bsize": statfs.bsize,
+        "blocks": statfs.blocks,
+        "bfree": statfs.bfree,
+        "bavail": statfs.bavail,
+        "files": statfs.files,
+        "ffree": statfs.ffree,
+        "fsid": statfs.fsid,
+        "namelen": statfs.namelen,
+    })
+}
+
+// The value of an xattr, hex when prefixed with 0x as in setfattr(1).
+fn parse_xattr_value(value: &str) -> Result<Vec<u8>, std::io::Error> {
+    let hex = match value.strip_prefix("0x") {
+        Some(hex) => hex,
+        None => return Ok(value.as_bytes().to_vec()),
+    };
+    if hex.len() % 2 != 0 {
+        return Err(usage_error(format!("invalid hex value {:?}", value)));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|_| usage_error(format!("invalid hex value {:?}", value)))
+        })
+        .collect()
+}
+
+fn write_all(fid: &ClientFid, offset: u64, data: &[u8]) -> Result<(), std::io::Error> {
+    let mut n = 0;
+    while n < data.len() {
+        match fid.write(offset + n as u64, &data[n..])? {
+            0 => return Err(errno::io_error(errno::EIO)),
+            count => n += count,
+        }
+    }
+    Ok(())
+}
+
+/// An attached export and a working directory within it, running
+/// subcommands.
+pub struct Context {
+    pub root: ClientFid,
+    /// The walk names of the working directory.
+    pub cwd: Vec<String>,
+    /// Print results as JSON values, one per line.
+    pub json: bool,
+    /// The group files and directories are created with.
+    pub gid: u32,
+}
+
+impl Context {
+    pub fn new(root: ClientFid) -> Context {
+        Context {
+            root,
+            cwd: Vec::new(),
+            json: false,
+            gid: nix::unistd::getgid().as_raw(),
+        }
+    }
+
+    // A partial walk is reported as success with fewer qids.
+    fn walk_names(&self, names: &[String]) -> Result<ClientFid, std::io::Error> {
+        let (wqids, fid) = self.root.walk(&names.iter().collect::<Vec<_>>())?;
+        if wqids.len() != names.len() {
+            return Err(path_error(errno::ENOENT, names));
+        }
+        Ok(fid)
+    }
+
+    /// A fid for path, relative to the working directory.
+    pub fn walk(&self, path: &str) -> Result<ClientFid, std::io::Error> {
+        self.walk_names(&resolve(&self.cwd, path))
+    }
+
+    // A fid for the directory containing path, and the last name in path.
+    fn walk_parent(&self, path: &str) -> Result<(ClientFid, String), std::io::Error> {
+        let mut names = resolve(&self.cwd, path);
+        match names.pop() {
+            Some(name) => Ok((self.walk_names(&names)?, name)),
+            None => Err(path_error(errno::EINVAL, &[])),
+        }
+    }
+
+    /// Whether fid is a directory.
+    pub fn is_dir(fid: &ClientFid) -> Result<bool, std::io::Error> {
+        let attr = fid.getattr(GetattrMask::MODE)?;
+        Ok(attr.stat.mode & S_IFMT == S_IFDIR)
+    }
+
+    /// The entries of the directory fid, without "." and "..".
+    pub fn read_dir(fid: &ClientFid) -> Result<Vec<DirEntry<'static>>, std::io::Error> {
+        let (_, dir) = fid.walk::<&str>(&[])?;
+        dir.open(LOpenFlags::O_RDONLY)?;
+        let mut entries = dir.read_dir()?;
+        entries.retain(|e| e.name.as_bytes() != b"." && e.name.as_bytes() != b"..");
+        Ok(entries)
+    }
+
+    /// Run the subcommand args[0] with its arguments, reading any file
+    /// contents from input and printing results to out.
+    pub fn run(
+        &mut self,
+        args: &[String],
+        input: &mut dyn Read,
+        out: &mut dyn Write,
+    ) -> Result<(), std::io::Error> {
+        let (cmd, args) = match args.split_first() {
+            Some((cmd, args)) => (cmd.as_str(), args),
+            None => return Err(usage_error("missing command".to_string())),
+        };
+        match cmd {
+            "list" | "ls" => self.list(args, out),
+            "stat" => self.stat(args, out),
+            "cat" => self.cat(args, out),
+            "put" => self.put(args, input),
+            "mkdir" => self.mkdir(args),
+            "rm" => self.rm(args),
+            "chmod" => self.chmod(args),
+            "setfattr" => self.setfattr(args),
+            "statfs" => self.statfs(args, out),
+            _ => Err(usage_error(format!("unknown command {:?}", cmd))),
+        }
+    }
+
+    fn list(&self, args: &[String], out: &mut dyn Write) -> Result<(), std::io::Error> {
+        let default = [".".to_string()];
+        let paths = if args.is_empty() { &default[..] } else { args };
+        for (i, path) in paths.iter().enumerate() {
+            let fid = self.walk(path)?;
+            if !Context::is_dir(&fid)? {
+                if self.json {
+                    let attr = fid.getattr(GetattrMask::MODE | GetattrMask::INO)?;
+                    let entry = json!({
+                        "path": path,
+                        "name": resolve(&[], path).pop().unwrap_or_default(),
+                        "type": file_type(attr.stat.mode),
+                        "ino": attr.qid.path,
+                    });
+                    writeln!(out, "{}", entry)?;
+                } else {
+                    writeln!(out, "{}", path)?;
+                }
+                continue;
+            }
+            let entries = Context::read_dir(&fid)?;
+            if !self.json && paths.len() > 1 {
+                if i > 0 {
+                    writeln!(out)?;
+                }
+                writeln!(out, "{}:", path)?;
+            }
+            for entry in entries {
+                let name = entry.name.to_string();
+                if self.json {
+                    let entry = json!({
+                        "path": format!("{}/{}", path.trim_end_matches('/'), name),
+                        "name": name,
+                        "type": dirent_type(entry.typ),
+                        "ino": entry.qid.path,
+                    });
+                    writeln!(out, "{}", entry)?;
+                } else {
+                    writeln!(out, "{}", name)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn stat(&self, args: &[String], out: &mut dyn Write) -> Result<(), std::io::Error> {
+        if args.is_empty() {
+            return Err(usage_error("stat: missing path".to_string()));
+        }
+        for path in args {
+            let attr = self.walk(path)?.getattr(GetattrMask::ALL)?;
+            if self.json {
+                writeln!(out, "{}", stat_json(path, &attr))?;
+                continue;
+            }
+            let stat = &attr.stat;
+            writeln!(out, "path: {}", path)?;
+            writeln!(out, "type: {}", file_type(stat.mode))?;
+            writeln!(out, "mode: {:04o}", stat.mode & 0o7777)?;
+            writeln!(out, "uid: {}", stat.uid)?;
+            writeln!(out, "gid: {}", stat.gid)?;
+            writeln!(out, "nlink: {}", stat.nlink)?;
+            writeln!(out, "size: {}", stat.size)?;
+            writeln!(out, "blocks: {}", stat.blocks)?;
+            writeln!(out, "blksize: {}", stat.blksize)?;
+            writeln!(out, "atime: {}", time(&stat.atime))?;
+            writeln!(out, "mtime: {}", time(&stat.mtime))?;
+            writeln!(out, "ctime: {}", time(&stat.ctime))?;
+            writeln!(out, "qid: {}.{}", attr.qid.path, attr.qid.version)?;
+        }
+        Ok(())
+    }
+
+    fn cat(&self, args: &[String], out: &mut dyn Write) -> Result<(), std::io::Error> {
+        let mut buf = vec![0; 64 * 1024];
+        for path in args {
+            let fid = self.walk(path)?;
+            fid.open(LOpenFlags::O_RDONLY)?;
+            let mut offset = 0;
+            loop {
+                let n = fid.read(offset, &mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                out.write_all(&buf[..n])?;
+                offset += n as u64;
+            }
+        }
+        out.flush()
+    }
+
+    fn put(&self, args: &[String], input: &mut dyn Read) -> Result<(), std::io::Error> {
+        let mut opts = getopts::Options::new();
+        opts.optopt("m", "", "mode of a created file", "MODE");
+        let matches = parse_opts("put", &opts, args)?;
+        let mode = match matches.opt_str("m") {
+            Some(mode) => parse_mode(&mode)?,
+            None => 0o644,
+        };
+        let path = match &matches.free[..] {
+            [path] => path,
+            _ => return Err(usage_error("put: expected one path".to_string())),
+        };
+
+        let (dir, name) = self.walk_parent(path)?;
+        let flags = LOpenFlags::O_WRONLY | LOpenFlags::O_TRUNC;
+        match dir.walk(&[name.as_str()]) {
+            Ok((_, file)) => {
+                file.open(flags)?;
+                self.copy_in(&file, input)
+            }
+            Err(err) if errno::errno_from_io_error(&err) == errno::ENOENT => {
+                // Tlcreate turns the directory fid into one for the file.
+                dir.create(name.as_str(), flags, mode, self.gid)?;
+                self.copy_in(&dir, input)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn copy_in(&self, fid: &ClientFid, input: &mut dyn Read) -> Result<(), std::io::Error> {
+        let mut buf = vec![0; 64 * 1024];
+        let mut offset = 0;
+        loop {
+            let n = match input.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            write_all(fid, offset, &buf[..n])?;
+            offset += n as u64;
+        }
+        Ok(())
+    }
+
+    fn mkdir(&self, args: &[String]) -> Result<(), std::io::Error> {
+        let mut opts = getopts::Options::new();
+        opts.optflag("p", "", "make parents, no error if existing");
+        opts.optopt("m", "", "mode of created directories", "MODE");
+        let matches = parse_opts("mkdir", &opts, args)?;
+        let mode = match matches.opt_str("m") {
+            Some(mode) => parse_mode(&mode)?,
+            None => 0o755,
+        };
+        if matches.free.is_empty() {
+            return Err(usage_error("mkdir: missing path".to_string()));
+        }
+        let parents = matches.opt_present("p");
+        for path in &matches.free {
+            if !parents {
+                let (dir, name) = self.walk_parent(path)?;
+                dir.mkdir(name.as_str(), mode, self.gid)?;
+                continue;
+            }
+            let mut dir = self.walk("/")?;
+            for name in resolve(&self.cwd, path) {
+                match dir.mkdir(name.as_str(), mode, self.gid) {
+                    Ok(_) => (),
+                    Err(err) if errno::errno_from_io_error(&err) == errno::EEXIST => (),
+                    Err(err) => return Err(err),
+                }
+                dir = dir.walk(&[name.as_str()])?.1;
+            }
+        }
+        Ok(())
+    }
+
+    fn rm(&self, args: &[String]) -> Result<(), std::io::Error> {
+        let mut opts = getopts::Options::new();
+        opts.optflag("d", "", "remove empty directories");
+        opts.optflag("f", "", "ignore missing files");
+        opts.optflag("r", "", "remove directories and their contents");
+        let matches = parse_opts("rm", &opts, args)?;
+        if matches.free.is_empty() {
+            return Err(usage_error("rm: missing path".to_string()));
+        }
+        let dirs = matches.opt_present("d") || matches.opt_present("r");
+        for path in &matches.free {
+            let (dir, name) = self.walk_parent(path)?;
+            match self.remove(&dir, &name, dirs, matches.opt_present("r")) {
+                Err(err)
+                    if matches.opt_present("f")
+                        && errno::errno_from_io_error(&err) == errno::ENOENT => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(
+        &self,
+        dir: &ClientFid,
+        name: &str,
+        dirs: bool,
+        recursive: bool,
+    ) -> Result<(), std::io::Error> {
+        let (_, fid) = dir.walk(&[name])?;
+        if !Context::is_dir(&fid)? {
+            return dir.unlinkat(name, 0);
+        }
+        if !dirs {
+            return Err(path_error(errno::EISDIR, &[name.to_string()]));
+        }
+        if recursive {
+            for entry in Context::read_dir(&fid)? {
+                self.remove(&fid, &entry.name.to_string(), dirs, recursive)?;
+            }
+        }
+        drop(fid);
+        dir.unlinkat(name, AT_REMOVEDIR)
+    }
+
+    fn chmod(&self, args: &[String]) -> Result<(), std::io::Error> {
+        let (mode, paths) = match args.split_first() {
+            Some((mode, paths)) if !paths.is_empty() => (parse_mode(mode)?, paths),
+            _ => return Err(usage_error("chmod: expected a mode and paths".to_string())),
+        };
+        let zero = Time { sec: 0, nsec: 0 };
+        for path in paths {
+            self.walk(path)?.setattr(
+                SetattrMask::MODE,
+                SetAttr {
+                    mode,
+                    uid: 0,
+                    gid: 0,
+                    size: 0,
+                    atime: zero,
+                    mtime: zero,
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    fn setfattr(&self, args: &[String]) -> Result<(), std::io::Error> {
+        let mut opts = getopts::Options::new();
+        opts.optopt("n", "", "name of the attribute to set", "NAME");
+        opts.optopt("v", "", "value to set, hex if it starts with 0x", "VALUE");
+        opts.optopt("x", "", "name of the attribute to remove", "NAME");
+        let matches = parse_opts("setfattr", &opts, args)?;
+        let (name, value) = match (matches.opt_str("n"), matches.opt_str("x")) {
+            (Some(name), None) => {
+                let value = parse_xattr_value(&matches.opt_str("v").unwrap_or_default())?;
+                (name, Some(value))
+            }
+            (None, Some(name)) => (name, None),
+            _ => {
+                return Err(usage_error(
+                    "setfattr: expected one of -n or -x".to_string(),
+                ))
+            }
+        };
+        if matches.free.is_empty() {
+            return Err(usage_error("setfattr: missing path".to_string()));
+        }
+        for path in &matches.free {
+            let fid = self.walk(path)?;
+            match &value {
+                Some(value) => {
+                    fid.xattrcreate(name.as_str(), value.len() as u64, 0)?;
+                    write_all(&fid, 0, value)?;
+                }
+                // Creating an empty attribute with no flags removes it.
+                None => fid.xattrcreate(name.as_str(), 0, 0)?,
+            }
+            // The attribute is set by the clunk.
+            fid.clunk()?;
+        }
+        Ok(())
+    }
+
+    fn statfs(&self, args: &[String], out: &mut dyn Write) -> Result<(), std::io::Error> {
+        let path = match args {
+            [] => ".",
+            [path] => path.as_str(),
+            _ => return Err(usage_error("statfs: expected at most one path".to_string())),
+        };
+        let statfs = self.walk(path)?.statfs()?;
+        if self.json {
+            writeln!(out, "{}", statfs_json(&statfs))?;
+            return Ok(());
+        }
+        writeln!(out, "type: {:#x}", statfs.typ)?;
+        writeln!(out, "bsize: {}", statfs.bsize)?;
+        writeln!(out, "blocks: {}", statfs.blocks)?;
+        writeln!(out, "bfree: {}", statfs.bfree)?;
+        writeln!(out, "bavail: {}", statfs.bavail)?;
+        writeln!(out, "files: {}", statfs.files)?;
+        writeln!(out, "ffree: {}", statfs.ffree)?;
+        writeln!(out, "fsid: {:#x}", statfs.fsid)?;
+        writeln!(out, "namelen: {}", statfs.namelen)?;
+        Ok(())
+    }
+}
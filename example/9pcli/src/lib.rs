@@ -0,0 +1,100 @@
+pub mod commands;
+pub mod shell;
+
+pub use commands::{Context, COMMANDS};
+pub use shell::Shell;
+
+use p92000l::{errno, Client, ClientBuilder, ClientFid};
+
+/// The walk names reaching path from cwd, or from the attach point if path
+/// starts with a slash. ".." is resolved lexically, it never walks above
+/// the attach point.
+pub fn resolve(cwd: &[String], path: &str) -> Vec<String> {
+    let mut names = if path.starts_with('/') {
+        Vec::new()
+    } else {
+        cwd.to_vec()
+    };
+    for name in path.split('/') {
+        match name {
+            "" | "." => (),
+            ".." => {
+                names.pop();
+            }
+            name => names.push(name.to_string()),
+        }
+    }
+    names
+}
+
+/// The process exit status for a failure, its Linux errno.
+pub fn exit_code(err: &std::io::Error) -> i32 {
+    errno::errno_from_io_error(err).clamp(1, 255) as i32
+}
+
+/// Describe a failure as a single line of JSON.
+pub fn json_error(err: &std::io::Error) -> serde_json::Value {
+    let ecode = errno::errno_from_io_error(err);
+    serde_json::json!({
+        "error": err.to_string(),
+        "errno": ecode,
+        "name": errno::strerror(ecode),
+    })
+}
+
+/// Add the options describing how to connect and attach.
+pub fn connect_options(opts: &mut getopts::Options) {
+    opts.optopt(
+        "u",
+        "uname",
+        "Attach as this user (default $USER).",
+        "UNAME",
+    );
+    opts.optopt(
+        "a",
+        "aname",
+        "Attach to this tree (default empty).",
+        "ANAME",
+    );
+    opts.optopt(
+        "n",
+        "n-uname",
+        "Attach as this numeric user (default our uid).",
+        "UID",
+    );
+    opts.optopt(
+        "",
+        "msize",
+        "Negotiate at most this message size (default 131072).",
+        "BYTES",
+    );
+}
+
+fn parse_num(matches: &getopts::Matches, name: &str, default: u32) -> Result<u32, std::io::Error> {
+    match matches.opt_str(name) {
+        Some(v) => v.parse().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid --{} value {:?}", name, v),
+            )
+        }),
+        None => Ok(default),
+    }
+}
+
+/// Dial addr and attach as the connect_options in matches ask.
+pub fn connect(
+    matches: &getopts::Matches,
+    addr: &str,
+) -> Result<(Client, ClientFid), std::io::Error> {
+    let n_uname = parse_num(matches, "n-uname", nix::unistd::getuid().as_raw())?;
+    let msize = parse_num(matches, "msize", 128 * 1024)?;
+    let uname = matches
+        .opt_str("uname")
+        .or_else(|| std::env::var("USER").ok())
+        .unwrap_or_default();
+    let aname = matches.opt_str("aname").unwrap_or_default();
+    let client = ClientBuilder::new().msize(msize).dial(addr)?;
+    let (_, root) = client.attach(n_uname, uname.as_str(), aname.as_str())?;
+    Ok((client, root))
+}
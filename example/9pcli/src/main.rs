@@ -0,0 +1,75 @@
+use p9cli::{connect, connect_options, exit_code, json_error, Context, COMMANDS};
+
+// Bad arguments exit as EINVAL would.
+const EXIT_USAGE: i32 = p92000l::errno::EINVAL as i32;
+
+// Help goes to stdout and exits 0, usage errors go to stderr.
+fn usage(program: &str, opts: getopts::Options, status: i32) -> ! {
+    let mut brief = format!(
+        "9pcli - Run a command against a 9p2000.L export.\n\n\
+        Usage: {} [options] ADDR COMMAND [ARGS...]\n\n\
+        ADDR is tcp:HOST:PORT or unix:PATH. Failures exit with their\n\
+        errno, bad arguments with EINVAL.\n\nCommands:\n",
+        program
+    );
+    for (_, usage) in COMMANDS {
+        brief.push_str(&format!("    {}\n", usage));
+    }
+    if status == 0 {
+        print!("{}", opts.usage(&brief));
+    } else {
+        eprint!("{}", opts.usage(&brief));
+    }
+    std::process::exit(status)
+}
+
+fn fatal(program: &str, json: bool, err: std::io::Error) -> ! {
+    if json {
+        eprintln!("{}", json_error(&err));
+    } else {
+        eprintln!("{}: {}", program, err);
+    }
+    std::process::exit(exit_code(&err))
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let program = args[0].clone();
+
+    let mut opts = getopts::Options::new();
+    // Options after the command belong to it.
+    opts.parsing_style(getopts::ParsingStyle::StopAtFirstFree);
+    connect_options(&mut opts);
+    opts.optflag(
+        "j",
+        "json",
+        "Print results and errors as JSON, one value per line.",
+    );
+    opts.optflag("h", "help", "print this help menu");
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(err) => {
+            eprintln!("{}: {}", program, err);
+            std::process::exit(EXIT_USAGE)
+        }
+    };
+    if matches.opt_present("h") {
+        usage(&program, opts, 0);
+    }
+    if matches.free.len() < 2 {
+        usage(&program, opts, EXIT_USAGE);
+    }
+    let json = matches.opt_present("json");
+
+    let root = match connect(&matches, &matches.free[0]) {
+        Ok((_, root)) => root,
+        Err(err) => fatal(&program, json, err),
+    };
+    let mut ctx = Context::new(root);
+    ctx.json = json;
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
+    if let Err(err) = ctx.run(&matches.free[1..], &mut stdin.lock(), &mut stdout.lock()) {
+        fatal(&program, json, err);
+    }
+}
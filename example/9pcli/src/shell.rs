@@ -0,0 +1,490 @@
+use crate::commands::Context;
+use crate::resolve;
+use p92000l::{
+    errno, hook, Client, ClientFid, Fcall, GetattrMask, LOpenFlags, P9Error, P9ErrorKind, QidType,
+};
+use std::collections::BTreeMap;
+use std::io::Write;
+
+/// Commands the shell runs itself, with their usage.
+pub const SHELL_COMMANDS: &[(&str, &str)] = &[
+    ("cd", "cd [PATH]"),
+    ("pwd", "pwd"),
+    ("fids", "fids"),
+    ("json", "json"),
+    ("help", "help"),
+    ("exit", "exit"),
+];
+
+/// Raw T messages, with their arguments. Those named like a shell command
+/// are only available with the T prefix, any of them may be given it.
+pub const RAW_COMMANDS: &[(&str, &str)] = &[
+    ("attach", "attach FID UNAME ANAME [N_UNAME]"),
+    ("walk", "walk FID NEWFID [NAME...]"),
+    ("xattrwalk", "xattrwalk FID NEWFID NAME"),
+    ("lopen", "lopen FID FLAGS"),
+    ("lcreate", "lcreate FID NAME FLAGS MODE GID"),
+    ("read", "read FID OFFSET COUNT"),
+    ("write", "write FID OFFSET DATA..."),
+    ("readdir", "readdir FID OFFSET COUNT"),
+    ("getattr", "getattr FID [MASK]"),
+    ("readlink", "readlink FID"),
+    ("fsync", "fsync FID"),
+    ("Tstatfs", "Tstatfs FID"),
+    ("Tmkdir", "Tmkdir DFID NAME MODE GID"),
+    ("unlinkat", "unlinkat DFID NAME FLAGS"),
+    ("clunk", "clunk FID"),
+    ("remove", "remove FID"),
+    ("flush", "flush OLDTAG"),
+];
+
+// Path commands from 9pcli the shell runs, put is left out as it reads
+// stdin.
+const PATH_COMMANDS: &[&str] = &[
+    "list", "ls", "stat", "cat", "mkdir", "rm", "chmod", "setfattr", "statfs",
+];
+
+const OPEN_FLAGS: &[(&str, LOpenFlags)] = &[
+    ("O_RDONLY", LOpenFlags::O_RDONLY),
+    ("O_WRONLY", LOpenFlags::O_WRONLY),
+    ("O_RDWR", LOpenFlags::O_RDWR),
+    ("O_EXCL", LOpenFlags::O_EXCL),
+    ("O_TRUNC", LOpenFlags::O_TRUNC),
+];
+
+fn usage_error(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, msg)
+}
+
+fn arg<T: std::str::FromStr>(args: &[String], i: usize, what: &str) -> Result<T, std::io::Error> {
+    let v = args
+        .get(i)
+        .ok_or_else(|| usage_error(format!("missing {}", what)))?;
+    v.parse()
+        .map_err(|_| usage_error(format!("invalid {} {:?}", what, v)))
+}
+
+// Numbers in any base Rust reads, so modes can be given as 0o755.
+fn num(args: &[String], i: usize, what: &str) -> Result<u64, std::io::Error> {
+    let v = args
+        .get(i)
+        .ok_or_else(|| usage_error(format!("missing {}", what)))?;
+    let (digits, radix) = if let Some(hex) = v.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(oct) = v.strip_prefix("0o") {
+        (oct, 8)
+    } else {
+        (v.as_str(), 10)
+    };
+    u64::from_str_radix(digits, radix).map_err(|_| usage_error(format!("invalid {} {:?}", what, v)))
+}
+
+/// Flags such as O_RDWR|O_TRUNC, or a number.
+pub fn parse_open_flags(flags: &str) -> Result<LOpenFlags, std::io::Error> {
+    if let Ok(bits) = flags.parse() {
+        return Ok(LOpenFlags::from_bits_truncate(bits));
+    }
+    let mut result = LOpenFlags::empty();
+    for name in flags.split('|') {
+        match OPEN_FLAGS.iter().find(|(n, _)| *n == name) {
+            Some((_, flag)) => result |= *flag,
+            None => return Err(usage_error(format!("unknown open flag {:?}", name))),
+        }
+    }
+    Ok(result)
+}
+
+/// A reply as the shell prints it, file data is shown as text.
+pub fn describe(fcall: &Fcall) -> String {
+    match fcall {
+        Fcall::Rread(rread) => format!(
+            "Rread {{ count: {}, data: \"{}\" }}",
+            rread.data.len(),
+            rread.data.escape_ascii()
+        ),
+        Fcall::Rlerror(err) => format!(
+            "Rlerror {{ ecode: {} }} {}",
+            err.ecode,
+            errno::strerror(err.ecode)
+        ),
+        fcall => format!("{:?}", fcall),
+    }
+}
+
+/// An interactive session, running 9pcli commands relative to a working
+/// directory fid and raw T messages on fids the user numbers.
+pub struct Shell {
+    client: Client,
+    ctx: Context,
+    cwd: ClientFid,
+    // Fids made by raw messages, and the message that made them.
+    raw: BTreeMap<u32, String>,
+}
+
+impl Shell {
+    /// Start at root, the fid of an attach on client.
+    pub fn new(client: Client, root: ClientFid) -> Result<Shell, std::io::Error> {
+        let (_, cwd) = root.walk::<&str>(&[])?;
+        Ok(Shell {
+            client,
+            ctx: Context::new(root),
+            cwd,
+            raw: BTreeMap::new(),
+        })
+    }
+
+    /// The working directory, from the attach point.
+    pub fn pwd(&self) -> String {
+        format!("/{}", self.ctx.cwd.join("/"))
+    }
+
+    /// Run one line, false once the user asked to leave. Failures are
+    /// printed to out rather than returned.
+    pub fn exec(&mut self, line: &str, out: &mut dyn Write) -> Result<bool, std::io::Error> {
+        let args: Vec<String> = line.split_whitespace().map(String::from).collect();
+        match self.run(&args, out) {
+            Ok(more) => Ok(more),
+            Err(err) if self.ctx.json => {
+                writeln!(out, "{}", crate::json_error(&err))?;
+                Ok(true)
+            }
+            Err(err) => {
+                writeln!(out, "error: {}", err)?;
+                Ok(true)
+            }
+        }
+    }
+
+    fn run(&mut self, args: &[String], out: &mut dyn Write) -> Result<bool, std::io::Error> {
+        let (cmd, rest) = match args.split_first() {
+            Some((cmd, rest)) => (cmd.as_str(), rest),
+            None => return Ok(true),
+        };
+        match cmd {
+            "exit" | "quit" => return Ok(false),
+            "help" => self.help(out)?,
+            "cd" => self.cd(rest.first().map(String::as_str).unwrap_or("/"))?,
+            "pwd" => writeln!(out, "{} (fid {})", self.pwd(), self.cwd.id())?,
+            "fids" => self.fids(out)?,
+            "json" => {
+                self.ctx.json = !self.ctx.json;
+                writeln!(out, "json {}", if self.ctx.json { "on" } else { "off" })?;
+            }
+            "put" => {
+                return Err(usage_error(
+                    "put reads stdin, use lcreate and write".to_string(),
+                ))
+            }
+            cmd if PATH_COMMANDS.contains(&cmd) => {
+                self.ctx.run(args, &mut std::io::empty(), out)?;
+            }
+            cmd => self.raw_message(cmd, rest, out)?,
+        }
+        Ok(true)
+    }
+
+    fn help(&self, out: &mut dyn Write) -> Result<(), std::io::Error> {
+        writeln!(out, "Shell commands:")?;
+        for (_, usage) in SHELL_COMMANDS {
+            writeln!(out, "    {}", usage)?;
+        }
+        writeln!(out, "Path commands:")?;
+        for (name, usage) in crate::COMMANDS {
+            if PATH_COMMANDS.contains(name) {
+                writeln!(out, "    {}", usage)?;
+            }
+        }
+        writeln!(
+            out,
+            "Raw messages, FIDs other than walk sources must come from attach or walk:"
+        )?;
+        for (_, usage) in RAW_COMMANDS {
+            writeln!(out, "    {}", usage)?;
+        }
+        Ok(())
+    }
+
+    fn cd(&mut self, path: &str) -> Result<(), std::io::Error> {
+        let names = resolve(&self.ctx.cwd, path);
+        let fid = self.ctx.walk(path)?;
+        if !Context::is_dir(&fid)? {
+            let mut err = P9Error::new(P9ErrorKind::RemoteErrno(errno::ENOTDIR));
+            err.path = names;
+            return Err(err.into());
+        }
+        self.cwd = fid;
+        self.ctx.cwd = names;
+        Ok(())
+    }
+
+    fn fids(&self, out: &mut dyn Write) -> Result<(), std::io::Error> {
+        writeln!(out, "{}\troot", self.ctx.root.id())?;
+        writeln!(out, "{}\tcwd {}", self.cwd.id(), self.pwd())?;
+        for (fid, made_by) in &self.raw {
+            writeln!(out, "{}\t{}", fid, made_by)?;
+        }
+        Ok(())
+    }
+
+    // The fid of a raw message, which must be one of ours.
+    fn own_fid(&self, args: &[String], i: usize) -> Result<u32, std::io::Error> {
+        let fid = arg(args, i, "fid")?;
+        if !self.raw.contains_key(&fid) {
+            return Err(usage_error(format!(
+                "fid {} was not made by attach or walk",
+                fid
+            )));
+        }
+        Ok(fid)
+    }
+
+    fn raw_message(
+        &mut self,
+        cmd: &str,
+        args: &[String],
+        out: &mut dyn Write,
+    ) -> Result<(), std::io::Error> {
+        let name = match cmd.strip_prefix('T') {
+            Some(name) if !name.is_empty() => name,
+            _ => cmd,
+        };
+        let name_arg = |i: usize| -> Result<&String, std::io::Error> {
+            args.get(i)
+                .ok_or_else(|| usage_error("missing name".to_string()))
+        };
+        // The fid a message makes, if any.
+        let mut makes = None;
+        let fcall: Fcall = match name {
+            "attach" => {
+                let fid = arg(args, 0, "fid")?;
+                makes = Some(fid);
+                p92000l::Tattach {
+                    fid,
+                    afid: p92000l::NOFID,
+                    uname: name_arg(1)?.into(),
+                    aname: args.get(2).map(String::as_str).unwrap_or("").into(),
+                    n_uname: match args.get(3) {
+                        Some(_) => arg(args, 3, "n_uname")?,
+                        None => nix::unistd::getuid().as_raw(),
+                    },
+                }
+                .into()
+            }
+            "walk" => {
+                let (fid, new_fid) = (arg(args, 0, "fid")?, arg(args, 1, "newfid")?);
+                if new_fid != fid {
+                    makes = Some(new_fid);
+                }
+                p92000l::Twalk {
+                    fid,
+                    new_fid,
+                    wnames: args
+                        .get(2..)
+                        .unwrap_or(&[])
+                        .iter()
+                        .map(|n| n.into())
+                        .collect(),
+                }
+                .into()
+            }
+            "xattrwalk" => {
+                let new_fid = arg(args, 1, "newfid")?;
+                makes = Some(new_fid);
+                p92000l::Txattrwalk {
+                    fid: arg(args, 0, "fid")?,
+                    new_fid,
+                    name: name_arg(2)?.into(),
+                }
+                .into()
+            }
+            "lopen" => p92000l::Tlopen {
+                fid: self.own_fid(args, 0)?,
+                flags: parse_open_flags(name_arg(1)?)?,
+            }
+            .into(),
+            "lcreate" => p92000l::Tlcreate {
+                fid: self.own_fid(args, 0)?,
+                name: name_arg(1)?.into(),
+                flags: parse_open_flags(name_arg(2)?)?,
+                mode: num(args, 3, "mode")? as u32,
+                gid: arg(args, 4, "gid")?,
+            }
+            .into(),
+            "read" => p92000l::Tread {
+                fid: self.own_fid(args, 0)?,
+                offset: num(args, 1, "offset")?,
+                count: num(args, 2, "count")? as u32,
+            }
+            .into(),
+            "write" => p92000l::Twrite {
+                fid: self.own_fid(args, 0)?,
+                offset: num(args, 1, "offset")?,
+                data: args.get(2..).unwrap_or(&[]).join(" ").into_bytes().into(),
+            }
+            .into(),
+            "readdir" => p92000l::Treaddir {
+                fid: self.own_fid(args, 0)?,
+                offset: num(args, 1, "offset")?,
+                count: num(args, 2, "count")? as u32,
+            }
+            .into(),
+            "getattr" => p92000l::Tgetattr {
+                fid: self.own_fid(args, 0)?,
+                req_mask: match args.get(1) {
+                    Some(_) => GetattrMask::from_bits_truncate(num(args, 1, "mask")?),
+                    None => GetattrMask::ALL,
+                },
+            }
+            .into(),
+            "readlink" => p92000l::Treadlink {
+                fid: self.own_fid(args, 0)?,
+            }
+            .into(),
+            "fsync" => p92000l::Tfsync {
+                fid: self.own_fid(args, 0)?,
+            }
+            .into(),
+            "statfs" if cmd.starts_with('T') => p92000l::Tstatfs {
+                fid: self.own_fid(args, 0)?,
+            }
+            .into(),
+            "mkdir" if cmd.starts_with('T') => p92000l::Tmkdir {
+                dfid: self.own_fid(args, 0)?,
+                name: name_arg(1)?.into(),
+                mode: num(args, 2, "mode")? as u32,
+                gid: arg(args, 3, "gid")?,
+            }
+            .into(),
+            "unlinkat" => p92000l::Tunlinkat {
+                dfid: self.own_fid(args, 0)?,
+                name: name_arg(1)?.into(),
+                flags: num(args, 2, "flags")? as u32,
+            }
+            .into(),
+            "clunk" => p92000l::Tclunk {
+                fid: self.own_fid(args, 0)?,
+            }
+            .into(),
+            "remove" => p92000l::Tremove {
+                fid: self.own_fid(args, 0)?,
+            }
+            .into(),
+            "flush" => p92000l::Tflush {
+                oldtag: arg(args, 0, "oldtag")?,
+            }
+            .into(),
+            _ => return Err(usage_error(format!("unknown command {:?}", cmd))),
+        };
+
+        let reserved = match makes {
+            Some(fid) if !self.raw.contains_key(&fid) => {
+                if !self.client.reserve_fid(fid) {
+                    return Err(usage_error(format!("fid {} is in use by the shell", fid)));
+                }
+                Some(fid)
+            }
+            _ => None,
+        };
+        let fid = hook::request_fid(&fcall);
+        let frees = matches!(fcall, Fcall::Tclunk(_) | Fcall::Tremove(_));
+        let wnames = match &fcall {
+            Fcall::Twalk(twalk) => Some(twalk.wnames.len()),
+            _ => None,
+        };
+        let resp = self.client.raw_fcall(fcall);
+        // A short walk succeeds without making its new fid.
+        let made = match &resp {
+            Ok(Fcall::Rwalk(rwalk)) => Some(rwalk.wqids.len()) == wnames,
+            Ok(Fcall::Rlerror(_)) | Err(_) => false,
+            Ok(_) => true,
+        };
+        match makes {
+            Some(new_fid) if made => {
+                self.raw
+                    .insert(new_fid, format!("{} {}", name, args.join(" ")));
+            }
+            _ => {
+                if let Some(new_fid) = reserved {
+                    self.client.release_fid(new_fid);
+                }
+            }
+        }
+        // Clunks and removes free the fid whatever the reply.
+        if let (true, Some(fid)) = (frees, fid) {
+            self.raw.remove(&fid);
+            self.client.release_fid(fid);
+        }
+        writeln!(out, "{}", describe(&resp?))?;
+        Ok(())
+    }
+
+    /// Candidates for the word ending at the end of line, and where that
+    /// word starts.
+    pub fn complete(&self, line: &str) -> (usize, Vec<String>) {
+        let start = line.rfind(char::is_whitespace).map(|i| i + 1).unwrap_or(0);
+        let word = &line[start..];
+        let words: Vec<&str> = line[..start].split_whitespace().collect();
+        let cmd = match words.first() {
+            Some(cmd) => *cmd,
+            None => {
+                let names = SHELL_COMMANDS
+                    .iter()
+                    .chain(RAW_COMMANDS)
+                    .map(|(name, _)| *name)
+                    .chain(PATH_COMMANDS.iter().copied());
+                let mut matches: Vec<String> = names
+                    .filter(|name| name.starts_with(word))
+                    .map(String::from)
+                    .collect();
+                matches.sort();
+                matches.dedup();
+                return (start, matches);
+            }
+        };
+        if cmd == "cd" || PATH_COMMANDS.contains(&cmd) {
+            return (start, self.complete_path(word));
+        }
+        let flags_arg = match cmd.trim_start_matches('T') {
+            "lopen" => 1,
+            "lcreate" => 2,
+            _ => return (start, Vec::new()),
+        };
+        if words.len() != flags_arg + 1 {
+            return (start, Vec::new());
+        }
+        // Complete the last of several flags.
+        let flag_start = word.rfind('|').map(|i| i + 1).unwrap_or(0);
+        let candidates = OPEN_FLAGS
+            .iter()
+            .filter(|(name, _)| name.starts_with(&word[flag_start..]))
+            .map(|(name, _)| name.to_string())
+            .collect();
+        (start + flag_start, candidates)
+    }
+
+    fn complete_path(&self, word: &str) -> Vec<String> {
+        let (dir, prefix) = match word.rfind('/') {
+            Some(i) => (&word[..i + 1], &word[i + 1..]),
+            None => ("", word),
+        };
+        let fid = match self.ctx.walk(if dir.is_empty() { "." } else { dir }) {
+            Ok(fid) => fid,
+            Err(_) => return Vec::new(),
+        };
+        let entries = match Context::read_dir(&fid) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        let mut candidates: Vec<String> = entries
+            .iter()
+            .map(|e| (e.name.to_string(), e.qid.typ))
+            .filter(|(name, _)| name.starts_with(prefix))
+            .map(|(name, typ)| {
+                let slash = if typ.contains(QidType::DIR) { "/" } else { "" };
+                format!("{}{}{}", dir, name, slash)
+            })
+            .collect();
+        candidates.sort();
+        candidates
+    }
+}
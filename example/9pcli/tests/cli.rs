@@ -0,0 +1,137 @@
+use p92000l::*;
+use p9cli::{exit_code, resolve, Context, Shell};
+use p9memfs::MemFs;
+use std::os::unix::net::UnixStream;
+
+fn spawn_client() -> (Client, ClientFid) {
+    let (client_conn, server_conn) = UnixStream::pair().unwrap();
+    std::thread::spawn(move || {
+        let mut fs = MemFs::new();
+        serve_unix(server_conn, &mut fs, 8192);
+    });
+    let client =
+        Client::over_transport(client_conn.try_clone().unwrap(), client_conn, 8192).unwrap();
+    let (_, root) = client.attach(0, "root", "").unwrap();
+    (client, root)
+}
+
+fn spawn_context() -> Context {
+    Context::new(spawn_client().1)
+}
+
+// Run a command line, returning what it printed.
+fn run(ctx: &mut Context, line: &str, input: &[u8]) -> Result<String, std::io::Error> {
+    let args: Vec<String> = line.split_whitespace().map(String::from).collect();
+    let mut out = Vec::new();
+    ctx.run(&args, &mut &input[..], &mut out)?;
+    Ok(String::from_utf8(out).unwrap())
+}
+
+#[test]
+fn resolve_paths() {
+    let cwd = vec!["a".to_string(), "b".to_string()];
+    assert_eq!(resolve(&cwd, "c/./d"), vec!["a", "b", "c", "d"]);
+    assert_eq!(resolve(&cwd, "../c"), vec!["a", "c"]);
+    assert_eq!(resolve(&cwd, "/x//y/"), vec!["x", "y"]);
+    assert!(resolve(&cwd, "/../..").is_empty());
+}
+
+#[test]
+fn commands() {
+    let mut ctx = spawn_context();
+    run(&mut ctx, "mkdir -p d/e", b"").unwrap();
+    run(&mut ctx, "put d/e/f", b"hello").unwrap();
+    run(&mut ctx, "put -m 600 g", b"world").unwrap();
+    assert_eq!(run(&mut ctx, "cat d/e/f g", b"").unwrap(), "helloworld");
+    run(&mut ctx, "put g", b"!").unwrap();
+    assert_eq!(run(&mut ctx, "cat g", b"").unwrap(), "!");
+    assert_eq!(run(&mut ctx, "list", b"").unwrap(), "d\ng\n");
+
+    run(&mut ctx, "chmod 640 g", b"").unwrap();
+    let stat = run(&mut ctx, "stat g", b"").unwrap();
+    assert!(stat.contains("type: file\nmode: 0640\n"), "{}", stat);
+    assert!(stat.contains("size: 1\n"), "{}", stat);
+
+    ctx.json = true;
+    let stat: serde_json::Value =
+        serde_json::from_str(&run(&mut ctx, "stat /d", b"").unwrap()).unwrap();
+    assert_eq!(stat["type"], "dir");
+    assert_eq!(stat["mode"], 0o755);
+    let statfs: serde_json::Value =
+        serde_json::from_str(&run(&mut ctx, "statfs", b"").unwrap()).unwrap();
+    assert_eq!(statfs["bfree"], 50);
+
+    let err = run(&mut ctx, "rm d", b"").unwrap_err();
+    assert_eq!(exit_code(&err), errno::EISDIR as i32);
+    run(&mut ctx, "rm -r d g", b"").unwrap();
+    assert_eq!(run(&mut ctx, "list", b"").unwrap(), "");
+    run(&mut ctx, "rm -f g", b"").unwrap();
+}
+
+#[test]
+fn errors() {
+    let mut ctx = spawn_context();
+    let err = run(&mut ctx, "cat missing", b"").unwrap_err();
+    assert_eq!(exit_code(&err), errno::ENOENT as i32);
+    let err = run(&mut ctx, "stat missing/deeper", b"").unwrap_err();
+    assert_eq!(exit_code(&err), errno::ENOENT as i32);
+    run(&mut ctx, "mkdir d", b"").unwrap();
+    let err = run(&mut ctx, "mkdir d", b"").unwrap_err();
+    assert_eq!(exit_code(&err), errno::EEXIST as i32);
+
+    for line in ["bogus", "chmod 999 d", "rm", "mkdir -z d"] {
+        let err = run(&mut ctx, line, b"").unwrap_err();
+        assert_eq!(exit_code(&err), errno::EINVAL as i32, "{}", line);
+    }
+}
+
+fn exec(shell: &mut Shell, line: &str) -> String {
+    let mut out = Vec::new();
+    assert!(shell.exec(line, &mut out).unwrap());
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn shell() {
+    let (client, root) = spawn_client();
+    let mut shell = Shell::new(client, root).unwrap();
+    exec(&mut shell, "mkdir -p usr/lib");
+    exec(&mut shell, "cd usr");
+    assert!(exec(&mut shell, "pwd").starts_with("/usr (fid "));
+    assert_eq!(exec(&mut shell, "ls"), "lib\n");
+    assert!(exec(&mut shell, "cd nothing").starts_with("error: "));
+    exec(&mut shell, "cd ..");
+    assert_eq!(shell.pwd(), "/");
+
+    assert!(exec(&mut shell, "Tattach 1 root").starts_with("Rattach"));
+    assert!(exec(&mut shell, "walk 1 2 usr lib").starts_with("Rwalk"));
+    // A short walk leaves no fid behind.
+    assert!(exec(&mut shell, "walk 1 3 usr nothing").starts_with("Rwalk"));
+    assert!(exec(&mut shell, "lopen 3 O_RDONLY").starts_with("error: "));
+    assert!(exec(&mut shell, "lcreate 2 f O_RDWR|O_TRUNC 0o644 0").starts_with("Rlcreate"));
+    assert!(exec(&mut shell, "write 2 0 hello there").starts_with("Rwrite"));
+    assert_eq!(
+        exec(&mut shell, "read 2 0 100"),
+        "Rread { count: 11, data: \"hello there\" }\n"
+    );
+    assert!(exec(&mut shell, "Tstatfs 2").starts_with("Rstatfs"));
+    assert!(exec(&mut shell, "readlink 2").starts_with("Rlerror"));
+    let fids = exec(&mut shell, "fids");
+    assert!(fids.contains("\n1\tattach 1 root\n2\twalk 1 2 usr lib\n"));
+    assert!(exec(&mut shell, "clunk 2").starts_with("Rclunk"));
+    assert!(!exec(&mut shell, "fids").contains("\n2\t"));
+    let mut out = Vec::new();
+    assert!(!shell.exec("exit", &mut out).unwrap());
+
+    assert_eq!(shell.complete("wa").1, vec!["walk"]);
+    assert_eq!(shell.complete("cd u"), (3, vec!["usr/".to_string()]));
+    assert_eq!(
+        shell.complete("ls usr/l"),
+        (3, vec!["usr/lib/".to_string()])
+    );
+    assert_eq!(
+        shell.complete("lopen 2 O_RDWR|O_T"),
+        (15, vec!["O_TRUNC".to_string()])
+    );
+    assert!(shell.complete("lopen O_").1.is_empty());
+}
@@ -0,0 +1,767 @@
+//! An in memory 9p2000.L filesystem for the examples' tests.
+//!
+//! Every [`MemFs`] made with [`MemFs::share`] serves the same tree, so
+//! several connections, or a client reconnecting, see the same files.
+//! Locks never block, a conflicting Tlock is answered BLOCKED, and locks
+//! are released when the connection that took them is dropped.
+
+use p92000l::*;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+// Unlinkat flag for removing directories.
+const AT_REMOVEDIR: u32 = 0x200;
+
+// Txattrcreate flags.
+const XATTR_CREATE: u32 = 1;
+const XATTR_REPLACE: u32 = 2;
+
+static NEXT_CONN: AtomicU64 = AtomicU64::new(1);
+
+struct Node {
+    mode: u32,
+    // File contents or a symlink's target.
+    data: Vec<u8>,
+    children: BTreeMap<Vec<u8>, u64>,
+    xattrs: BTreeMap<Vec<u8>, Vec<u8>>,
+    nlink: u64,
+    rdev: u64,
+    version: u32,
+}
+
+impl Node {
+    fn new(mode: u32) -> Node {
+        Node {
+            mode,
+            data: Vec::new(),
+            children: BTreeMap::new(),
+            xattrs: BTreeMap::new(),
+            nlink: 1,
+            rdev: 0,
+            version: 0,
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+}
+
+// A byte range lock, end is inclusive and u64::MAX means the end of file.
+#[derive(Clone)]
+struct Held {
+    conn: u64,
+    node: u64,
+    typ: LockType,
+    start: u64,
+    end: u64,
+    proc_id: u32,
+    client_id: Vec<u8>,
+}
+
+impl Held {
+    fn overlaps(&self, node: u64, start: u64, end: u64) -> bool {
+        self.node == node && self.start <= end && start <= self.end
+    }
+
+    fn owned_by(&self, proc_id: u32, client_id: &[u8]) -> bool {
+        self.proc_id == proc_id && self.client_id == client_id
+    }
+}
+
+fn lock_end(start: u64, length: u64) -> u64 {
+    if length == 0 {
+        u64::MAX
+    } else {
+        start.saturating_add(length - 1)
+    }
+}
+
+fn lock_length(held: &Held) -> u64 {
+    if held.end == u64::MAX {
+        0
+    } else {
+        held.end - held.start + 1
+    }
+}
+
+// Node 1 is the root.
+struct Tree {
+    nodes: HashMap<u64, Node>,
+    locks: Vec<Held>,
+    next: u64,
+}
+
+impl Tree {
+    fn node(&self, path: u64) -> &Node {
+        &self.nodes[&path]
+    }
+
+    fn node_mut(&mut self, path: u64) -> &mut Node {
+        self.nodes.get_mut(&path).unwrap()
+    }
+
+    fn qid(&self, path: u64) -> Qid {
+        let node = self.node(path);
+        let typ = match node.mode & S_IFMT {
+            S_IFDIR => QidType::DIR,
+            S_IFLNK => QidType::SYMLINK,
+            _ => QidType::FILE,
+        };
+        Qid {
+            typ,
+            version: node.version,
+            path,
+        }
+    }
+
+    fn dir(&self, path: u64) -> Result<&Node, u32> {
+        let node = self.node(path);
+        if node.is_dir() {
+            Ok(node)
+        } else {
+            Err(errno::ENOTDIR)
+        }
+    }
+
+    fn add(&mut self, dir: u64, name: &[u8], node: Node) -> Result<u64, u32> {
+        if self.dir(dir)?.children.contains_key(name) {
+            return Err(errno::EEXIST);
+        }
+        let path = self.next;
+        self.next += 1;
+        self.nodes.insert(path, node);
+        self.node_mut(dir).children.insert(name.to_vec(), path);
+        Ok(path)
+    }
+
+    fn conflict(
+        &self,
+        node: u64,
+        typ: LockType,
+        start: u64,
+        end: u64,
+        proc_id: u32,
+        client_id: &[u8],
+    ) -> Option<&Held> {
+        self.locks.iter().find(|held| {
+            held.overlaps(node, start, end)
+                && !held.owned_by(proc_id, client_id)
+                && (held.typ == LockType::WRLOCK || typ == LockType::WRLOCK)
+        })
+    }
+}
+
+enum Xattr {
+    // A snapshot of an attribute value or the list of names.
+    Read(Vec<u8>),
+    // Written by the client, set when the fid is clunked.
+    Write {
+        name: Vec<u8>,
+        size: u64,
+        flags: u32,
+        data: Vec<u8>,
+    },
+}
+
+struct Fid {
+    node: u64,
+    xattr: Option<Xattr>,
+}
+
+/// One connection's view of an in memory tree.
+pub struct MemFs {
+    tree: Arc<Mutex<Tree>>,
+    conn: u64,
+    fids: HashMap<u32, Fid>,
+}
+
+fn reply<'a, R: Into<Fcall<'a>>>(resp: FcallResponse, result: Result<R, u32>) {
+    match result {
+        Ok(r) => resp.send(r),
+        Err(ecode) => resp.send(Rlerror { ecode }),
+    }
+}
+
+impl Default for MemFs {
+    fn default() -> MemFs {
+        MemFs::new()
+    }
+}
+
+impl MemFs {
+    /// A new tree holding an empty root directory.
+    pub fn new() -> MemFs {
+        let mut nodes = HashMap::new();
+        nodes.insert(1, Node::new(S_IFDIR | 0o755));
+        let tree = Tree {
+            nodes,
+            locks: Vec::new(),
+            next: 2,
+        };
+        MemFs::with_tree(Arc::new(Mutex::new(tree)))
+    }
+
+    fn with_tree(tree: Arc<Mutex<Tree>>) -> MemFs {
+        MemFs {
+            tree,
+            conn: NEXT_CONN.fetch_add(1, Ordering::SeqCst),
+            fids: HashMap::new(),
+        }
+    }
+
+    /// Another connection to the same tree, with no fids.
+    pub fn share(&self) -> MemFs {
+        MemFs::with_tree(self.tree.clone())
+    }
+
+    fn node(&self, fid: u32) -> Result<u64, u32> {
+        match self.fids.get(&fid) {
+            Some(Fid { xattr: None, node }) => Ok(*node),
+            Some(_) => Err(errno::EINVAL),
+            None => Err(errno::EBADF),
+        }
+    }
+
+    fn set_fid(&mut self, fid: u32, node: u64) {
+        self.fids.insert(fid, Fid { node, xattr: None });
+    }
+
+    fn walk_names(&self, fid: u32, wnames: &[FcallStr]) -> Result<(u64, Vec<Qid>), u32> {
+        let tree = self.tree.lock().unwrap();
+        let mut path = self.node(fid)?;
+        let mut wqids = Vec::new();
+        for name in wnames.iter() {
+            let child = match tree.dir(path) {
+                Ok(dir) => dir.children.get(name.as_bytes()).copied(),
+                Err(_) => None,
+            };
+            match child {
+                Some(child) => path = child,
+                None if wqids.is_empty() => return Err(errno::ENOENT),
+                None => break,
+            }
+            wqids.push(tree.qid(path));
+        }
+        Ok((path, wqids))
+    }
+
+    // Set or remove an attribute written through an xattr fid.
+    fn set_xattr(
+        &self,
+        node: u64,
+        name: Vec<u8>,
+        size: u64,
+        flags: u32,
+        data: Vec<u8>,
+    ) -> Result<(), u32> {
+        if data.len() as u64 != size {
+            return Err(errno::EINVAL);
+        }
+        let mut tree = self.tree.lock().unwrap();
+        let xattrs = &mut tree.node_mut(node).xattrs;
+        let exists = xattrs.contains_key(&name);
+        if size == 0 && flags == 0 {
+            return match xattrs.remove(&name) {
+                Some(_) => Ok(()),
+                None => Err(errno::ENODATA),
+            };
+        }
+        if flags & XATTR_CREATE != 0 && exists {
+            return Err(errno::EEXIST);
+        }
+        if flags & XATTR_REPLACE != 0 && !exists {
+            return Err(errno::ENODATA);
+        }
+        xattrs.insert(name, data);
+        Ok(())
+    }
+}
+
+impl Drop for MemFs {
+    fn drop(&mut self) {
+        if let Ok(mut tree) = self.tree.lock() {
+            tree.locks.retain(|held| held.conn != self.conn);
+        }
+    }
+}
+
+impl Filesystem for MemFs {
+    fn attach(&mut self, req: &Tattach, resp: FcallResponse) {
+        self.set_fid(req.fid, 1);
+        let qid = self.tree.lock().unwrap().qid(1);
+        resp.send(Rattach { qid })
+    }
+
+    fn walk(&mut self, req: &Twalk, resp: FcallResponse) {
+        let result = self.walk_names(req.fid, &req.wnames).map(|(path, wqids)| {
+            if wqids.len() == req.wnames.len() {
+                self.set_fid(req.new_fid, path);
+            }
+            Rwalk { wqids }
+        });
+        reply(resp, result)
+    }
+
+    fn getattr(&mut self, req: &Tgetattr, resp: FcallResponse) {
+        let result = self.node(req.fid).map(|path| {
+            let tree = self.tree.lock().unwrap();
+            let node = tree.node(path);
+            let time = Time { sec: 0, nsec: 0 };
+            Rgetattr {
+                valid: GetattrMask::BASIC,
+                qid: tree.qid(path),
+                stat: Stat {
+                    mode: node.mode,
+                    uid: 0,
+                    gid: 0,
+                    nlink: node.nlink,
+                    rdev: node.rdev,
+                    size: node.data.len() as u64,
+                    blksize: 4096,
+                    blocks: 0,
+                    atime: time,
+                    mtime: time,
+                    ctime: time,
+                    btime: time,
+                    gen: 0,
+                    data_version: node.version as u64,
+                },
+            }
+        });
+        reply(resp, result)
+    }
+
+    fn setattr(&mut self, req: &Tsetattr, resp: FcallResponse) {
+        let result = self.node(req.fid).map(|path| {
+            let mut tree = self.tree.lock().unwrap();
+            let node = tree.node_mut(path);
+            if req.valid.contains(SetattrMask::SIZE) {
+                node.data.resize(req.stat.size as usize, 0);
+                node.version += 1;
+            }
+            if req.valid.contains(SetattrMask::MODE) {
+                node.mode = (node.mode & S_IFMT) | (req.stat.mode & 0o7777);
+            }
+            Rsetattr {}
+        });
+        reply(resp, result)
+    }
+
+    fn lopen(&mut self, req: &Tlopen, resp: FcallResponse) {
+        let result = self.node(req.fid).map(|path| {
+            let mut tree = self.tree.lock().unwrap();
+            if req.flags.contains(LOpenFlags::O_TRUNC) {
+                let node = tree.node_mut(path);
+                node.data.clear();
+                node.version += 1;
+            }
+            Rlopen {
+                qid: tree.qid(path),
+                iounit: 0,
+            }
+        });
+        reply(resp, result)
+    }
+
+    fn lcreate(&mut self, req: &Tlcreate, resp: FcallResponse) {
+        let result = self.node(req.fid).and_then(|dir| {
+            let mut tree = self.tree.lock().unwrap();
+            let path = tree.add(dir, req.name.as_bytes(), Node::new(S_IFREG | req.mode))?;
+            Ok((path, tree.qid(path)))
+        });
+        let result = result.map(|(path, qid)| {
+            self.set_fid(req.fid, path);
+            Rlcreate { qid, iounit: 0 }
+        });
+        reply(resp, result)
+    }
+
+    fn mkdir(&mut self, req: &Tmkdir, resp: FcallResponse) {
+        let result = self.node(req.dfid).and_then(|dir| {
+            let mut tree = self.tree.lock().unwrap();
+            let path = tree.add(dir, req.name.as_bytes(), Node::new(S_IFDIR | req.mode))?;
+            Ok(Rmkdir {
+                qid: tree.qid(path),
+            })
+        });
+        reply(resp, result)
+    }
+
+    fn symlink(&mut self, req: &Tsymlink, resp: FcallResponse) {
+        let result = self.node(req.fid).and_then(|dir| {
+            let mut tree = self.tree.lock().unwrap();
+            let mut node = Node::new(S_IFLNK | 0o777);
+            node.data = req.symtgt.as_bytes().to_vec();
+            let path = tree.add(dir, req.name.as_bytes(), node)?;
+            Ok(Rsymlink {
+                qid: tree.qid(path),
+            })
+        });
+        reply(resp, result)
+    }
+
+    fn mknod(&mut self, req: &Tmknod, resp: FcallResponse) {
+        let result = self.node(req.dfid).and_then(|dir| {
+            let mut tree = self.tree.lock().unwrap();
+            let mut node = Node::new(req.mode);
+            node.rdev = ((req.major as u64) << 8) | req.minor as u64;
+            let path = tree.add(dir, req.name.as_bytes(), node)?;
+            Ok(Rmknod {
+                qid: tree.qid(path),
+            })
+        });
+        reply(resp, result)
+    }
+
+    fn readlink(&mut self, req: &Treadlink, resp: FcallResponse) {
+        let result = self.node(req.fid).and_then(|path| {
+            let tree = self.tree.lock().unwrap();
+            let node = tree.node(path);
+            if node.mode & S_IFMT != S_IFLNK {
+                return Err(errno::EINVAL);
+            }
+            Ok(Rreadlink {
+                target: FcallStr::Owned(node.data.clone()),
+            })
+        });
+        reply(resp, result)
+    }
+
+    fn link(&mut self, req: &Tlink, resp: FcallResponse) {
+        let result = self.node(req.dfid).and_then(|dir| {
+            let file = self.node(req.fid)?;
+            let mut tree = self.tree.lock().unwrap();
+            if tree.node(file).is_dir() {
+                return Err(errno::EPERM);
+            }
+            if tree.dir(dir)?.children.contains_key(req.name.as_bytes()) {
+                return Err(errno::EEXIST);
+            }
+            tree.node_mut(dir)
+                .children
+                .insert(req.name.as_bytes().to_vec(), file);
+            tree.node_mut(file).nlink += 1;
+            Ok(Rlink {})
+        });
+        reply(resp, result)
+    }
+
+    fn read(&mut self, req: &Tread, resp: FcallResponse) {
+        let tree = self.tree.lock().unwrap();
+        let data = match self.fids.get(&req.fid) {
+            Some(Fid {
+                xattr: Some(Xattr::Read(data)),
+                ..
+            }) => data,
+            Some(Fid { xattr: None, node }) => &tree.node(*node).data,
+            Some(_) => {
+                return resp.send(Rlerror {
+                    ecode: errno::EINVAL,
+                })
+            }
+            None => {
+                return resp.send(Rlerror {
+                    ecode: errno::EBADF,
+                })
+            }
+        };
+        let off = (req.offset as usize).min(data.len());
+        let end = (off + req.count as usize).min(data.len());
+        resp.send(Rread {
+            data: Cow::from(&data[off..end]),
+        })
+    }
+
+    fn write(&mut self, req: &Twrite, resp: FcallResponse) {
+        let off = req.offset as usize;
+        let result = match self.fids.get_mut(&req.fid) {
+            Some(Fid {
+                xattr: Some(Xattr::Write { data, .. }),
+                ..
+            }) => {
+                data.extend_from_slice(&req.data);
+                Ok(())
+            }
+            Some(Fid { xattr: None, node }) => {
+                let mut tree = self.tree.lock().unwrap();
+                let node = tree.node_mut(*node);
+                if node.data.len() < off + req.data.len() {
+                    node.data.resize(off + req.data.len(), 0);
+                }
+                node.data[off..off + req.data.len()].copy_from_slice(&req.data);
+                node.version += 1;
+                Ok(())
+            }
+            Some(_) => Err(errno::EINVAL),
+            None => Err(errno::EBADF),
+        };
+        let result = result.map(|_| Rwrite {
+            count: req.data.len() as u32,
+        });
+        reply(resp, result)
+    }
+
+    fn readdir(&mut self, req: &Treaddir, resp: FcallResponse) {
+        let result = self.node(req.fid).and_then(|path| {
+            let tree = self.tree.lock().unwrap();
+            let mut data = DirEntryData::new();
+            for (i, (name, child)) in tree.dir(path)?.children.iter().enumerate() {
+                if (i as u64) < req.offset {
+                    continue;
+                }
+                data.push(DirEntry {
+                    qid: tree.qid(*child),
+                    offset: i as u64 + 1,
+                    typ: 0,
+                    name: FcallStr::Owned(name.clone()),
+                });
+            }
+            Ok(Rreaddir { data })
+        });
+        reply(resp, result)
+    }
+
+    fn unlinkat(&mut self, req: &Tunlinkat, resp: FcallResponse) {
+        let result = self.node(req.dfid).and_then(|dir| {
+            let mut tree = self.tree.lock().unwrap();
+            let child = match tree.dir(dir)?.children.get(req.name.as_bytes()) {
+                Some(child) => *child,
+                None => return Err(errno::ENOENT),
+            };
+            let node = tree.node(child);
+            if node.is_dir() && req.flags & AT_REMOVEDIR == 0 {
+                return Err(errno::EISDIR);
+            }
+            if !node.is_dir() && req.flags & AT_REMOVEDIR != 0 {
+                return Err(errno::ENOTDIR);
+            }
+            if !node.children.is_empty() {
+                return Err(errno::ENOTEMPTY);
+            }
+            tree.node_mut(dir).children.remove(req.name.as_bytes());
+            tree.node_mut(child).nlink -= 1;
+            Ok(Runlinkat {})
+        });
+        reply(resp, result)
+    }
+
+    fn rename(&mut self, req: &Trename, resp: FcallResponse) {
+        // Fids don't know their parents, so look for the file's name in
+        // every directory.
+        let result = self.node(req.fid).and_then(|file| {
+            let dir = self.node(req.dfid)?;
+            let mut tree = self.tree.lock().unwrap();
+            tree.dir(dir)?;
+            let old = tree.nodes.iter().find_map(|(path, node)| {
+                node.children
+                    .iter()
+                    .find(|(_, child)| **child == file)
+                    .map(|(name, _)| (*path, name.clone()))
+            });
+            let (parent, name) = old.ok_or(errno::ENOENT)?;
+            tree.node_mut(parent).children.remove(&name);
+            tree.node_mut(dir)
+                .children
+                .insert(req.name.as_bytes().to_vec(), file);
+            Ok(Rrename {})
+        });
+        reply(resp, result)
+    }
+
+    fn renameat(&mut self, req: &Trenameat, resp: FcallResponse) {
+        let result = self.node(req.olddfid).and_then(|old| {
+            let new = self.node(req.newdfid)?;
+            let mut tree = self.tree.lock().unwrap();
+            tree.dir(new)?;
+            let child = tree
+                .node_mut(old)
+                .children
+                .remove(req.oldname.as_bytes())
+                .ok_or(errno::ENOENT)?;
+            tree.node_mut(new)
+                .children
+                .insert(req.newname.as_bytes().to_vec(), child);
+            Ok(Rrenameat {})
+        });
+        reply(resp, result)
+    }
+
+    fn xattrwalk(&mut self, req: &Txattrwalk, resp: FcallResponse) {
+        let result = self.node(req.fid).and_then(|path| {
+            let tree = self.tree.lock().unwrap();
+            let xattrs = &tree.node(path).xattrs;
+            if req.name.as_bytes().is_empty() {
+                let mut names = Vec::new();
+                for name in xattrs.keys() {
+                    names.extend_from_slice(name);
+                    names.push(0);
+                }
+                Ok(names)
+            } else {
+                xattrs
+                    .get(req.name.as_bytes())
+                    .cloned()
+                    .ok_or(errno::ENODATA)
+            }
+        });
+        let result = result.map(|data| {
+            let size = data.len() as u64;
+            let node = self.fids[&req.fid].node;
+            self.fids.insert(
+                req.new_fid,
+                Fid {
+                    node,
+                    xattr: Some(Xattr::Read(data)),
+                },
+            );
+            Rxattrwalk { size }
+        });
+        reply(resp, result)
+    }
+
+    fn xattrcreate(&mut self, req: &Txattrcreate, resp: FcallResponse) {
+        let result = self.node(req.fid).map(|node| {
+            self.fids.insert(
+                req.fid,
+                Fid {
+                    node,
+                    xattr: Some(Xattr::Write {
+                        name: req.name.as_bytes().to_vec(),
+                        size: req.attr_size,
+                        flags: req.flags,
+                        data: Vec::new(),
+                    }),
+                },
+            );
+            Rxattrcreate {}
+        });
+        reply(resp, result)
+    }
+
+    fn lock(&mut self, req: &Tlock, resp: FcallResponse) {
+        let result = self.node(req.fid).map(|node| {
+            let flock = &req.flock;
+            let (start, end) = (flock.start, lock_end(flock.start, flock.length));
+            let client_id = flock.client_id.as_bytes();
+            let mut tree = self.tree.lock().unwrap();
+            if flock.typ != LockType::UNLOCK
+                && tree
+                    .conflict(node, flock.typ, start, end, flock.proc_id, client_id)
+                    .is_some()
+            {
+                return Rlock {
+                    status: LockStatus::BLOCKED,
+                };
+            }
+            // Replace the owner's locks over the range, keeping the parts
+            // outside it.
+            let mut locks = Vec::new();
+            for held in tree.locks.drain(..) {
+                if !held.overlaps(node, start, end) || !held.owned_by(flock.proc_id, client_id) {
+                    locks.push(held);
+                    continue;
+                }
+                if held.start < start {
+                    locks.push(Held {
+                        end: start - 1,
+                        ..held.clone()
+                    });
+                }
+                if held.end > end {
+                    locks.push(Held {
+                        start: end + 1,
+                        ..held
+                    });
+                }
+            }
+            if flock.typ != LockType::UNLOCK {
+                locks.push(Held {
+                    conn: self.conn,
+                    node,
+                    typ: flock.typ,
+                    start,
+                    end,
+                    proc_id: flock.proc_id,
+                    client_id: client_id.to_vec(),
+                });
+            }
+            tree.locks = locks;
+            Rlock {
+                status: LockStatus::SUCCESS,
+            }
+        });
+        reply(resp, result)
+    }
+
+    fn getlock(&mut self, req: &Tgetlock, resp: FcallResponse) {
+        let result = self.node(req.fid).map(|node| {
+            let flock = &req.flock;
+            let tree = self.tree.lock().unwrap();
+            let end = lock_end(flock.start, flock.length);
+            let client_id = flock.client_id.as_bytes();
+            let flock =
+                match tree.conflict(node, flock.typ, flock.start, end, flock.proc_id, client_id) {
+                    Some(held) => Getlock {
+                        typ: held.typ,
+                        start: held.start,
+                        length: lock_length(held),
+                        proc_id: held.proc_id,
+                        client_id: FcallStr::Owned(held.client_id.clone()),
+                    },
+                    None => Getlock {
+                        typ: LockType::UNLOCK,
+                        ..flock.clone()
+                    },
+                };
+            Rgetlock { flock }
+        });
+        reply(resp, result)
+    }
+
+    fn fsync(&mut self, req: &Tfsync, resp: FcallResponse) {
+        reply(resp, self.node(req.fid).map(|_| Rfsync {}))
+    }
+
+    fn statfs(&mut self, req: &Tstatfs, resp: FcallResponse) {
+        let result = self.node(req.fid).map(|_| Rstatfs {
+            statfs: Statfs {
+                typ: 0x01021997,
+                bsize: 4096,
+                blocks: 100,
+                bfree: 50,
+                bavail: 50,
+                files: 10,
+                ffree: 5,
+                fsid: 0,
+                namelen: 255,
+            },
+        });
+        reply(resp, result)
+    }
+
+    fn clunk(&mut self, req: &Tclunk, resp: FcallResponse) {
+        let result = match self.fids.remove(&req.fid) {
+            Some(Fid {
+                node,
+                xattr:
+                    Some(Xattr::Write {
+                        name,
+                        size,
+                        flags,
+                        data,
+                    }),
+            }) => self.set_xattr(node, name, size, flags, data),
+            Some(_) => Ok(()),
+            None => Err(errno::EBADF),
+        };
+        reply(resp, result.map(|_| Rclunk {}))
+    }
+}
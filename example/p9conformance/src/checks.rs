@@ -0,0 +1,865 @@
+use crate::{
+    ensure, expect_errno, expect_error, unexpected, unsupported, Check, Failure, RawConn, Scratch,
+    Target,
+};
+use p92000l::{
+    errno, fcall, DirEntryData, Fcall, Flock, GetattrMask, Getlock, LOpenFlags, LockFlag,
+    LockStatus, LockType, MAXWELEM,
+};
+
+/// Every check, in the order they run.
+pub const CHECKS: &[Check] = &[
+    Check {
+        name: "version.negotiate",
+        description: "Tversion 9P2000.L is accepted with an msize no larger than asked for.",
+        run: version_negotiate,
+    },
+    Check {
+        name: "version.unknown",
+        description: "An unknown version is answered with \"unknown\".",
+        run: version_unknown,
+    },
+    Check {
+        name: "version.large_msize",
+        description: "A huge msize is cut down to one the server can use.",
+        run: version_large_msize,
+    },
+    Check {
+        name: "version.required",
+        description: "Messages before Tversion are refused.",
+        run: version_required,
+    },
+    Check {
+        name: "version.renegotiate",
+        description: "A second Tversion resets the session, clunking every fid.",
+        run: version_renegotiate,
+    },
+    Check {
+        name: "fid.in_use",
+        description: "Walks and attaches to a fid already in use fail.",
+        run: fid_in_use,
+    },
+    Check {
+        name: "fid.reuse_after_clunk",
+        description: "A clunked fid can be used again.",
+        run: fid_reuse_after_clunk,
+    },
+    Check {
+        name: "fid.unknown",
+        description: "Messages on fids never made fail.",
+        run: fid_unknown,
+    },
+    Check {
+        name: "fid.remove_clunks",
+        description: "Tremove clunks its fid, even when the remove fails.",
+        run: fid_remove_clunks,
+    },
+    Check {
+        name: "walk.zero",
+        description: "A walk of no names clones the fid.",
+        run: walk_zero,
+    },
+    Check {
+        name: "walk.maxwelem",
+        description: "A walk of MAXWELEM names succeeds.",
+        run: walk_maxwelem,
+    },
+    Check {
+        name: "walk.too_many",
+        description: "A walk of 17 names, more than Linux ever sends, fails.",
+        run: walk_too_many,
+    },
+    Check {
+        name: "walk.partial",
+        description: "A walk failing part way returns the qids walked and no new fid.",
+        run: walk_partial,
+    },
+    Check {
+        name: "walk.first_missing",
+        description: "A walk failing at its first name is an error.",
+        run: walk_first_missing,
+    },
+    Check {
+        name: "walk.in_place",
+        description: "A walk with newfid equal to fid moves the fid.",
+        run: walk_in_place,
+    },
+    Check {
+        name: "walk.dotdot_root",
+        description: "Walking .. from the attach point stays there.",
+        run: walk_dotdot_root,
+    },
+    Check {
+        name: "readdir.complete",
+        description: "Reading a directory in small pieces returns every entry once.",
+        run: readdir_complete,
+    },
+    Check {
+        name: "readdir.offset_stable",
+        description: "Reading again from an entry's offset continues after that entry.",
+        run: readdir_offset_stable,
+    },
+    Check {
+        name: "lcreate.open",
+        description: "Tlcreate leaves its fid open on the new file.",
+        run: lcreate_open,
+    },
+    Check {
+        name: "lcreate.excl",
+        description: "Tlcreate with O_EXCL of an existing name fails with EEXIST.",
+        run: lcreate_excl,
+    },
+    Check {
+        name: "lopen.access",
+        description: "Writes to O_RDONLY fids and reads from O_WRONLY fids fail.",
+        run: lopen_access,
+    },
+    Check {
+        name: "lopen.trunc",
+        description: "O_TRUNC empties the file.",
+        run: lopen_trunc,
+    },
+    Check {
+        name: "lopen.twice",
+        description: "Opening an open fid fails.",
+        run: lopen_twice,
+    },
+    Check {
+        name: "lopen.dir_write",
+        description: "Opening a directory for writing fails with EISDIR.",
+        run: lopen_dir_write,
+    },
+    Check {
+        name: "xattr.create",
+        description: "An attribute written through Txattrcreate reads back.",
+        run: xattr_create,
+    },
+    Check {
+        name: "xattr.list",
+        description: "Txattrwalk of an empty name lists attribute names.",
+        run: xattr_list,
+    },
+    Check {
+        name: "xattr.missing",
+        description: "Txattrwalk of a missing attribute fails with ENODATA.",
+        run: xattr_missing,
+    },
+    Check {
+        name: "lock.exclusive",
+        description: "A write lock blocks other clients until it is released.",
+        run: lock_exclusive,
+    },
+    Check {
+        name: "lock.shared",
+        description: "Read locks from different clients coexist.",
+        run: lock_shared,
+    },
+    Check {
+        name: "lock.getlock_free",
+        description: "Tgetlock of an unlocked range returns F_UNLCK.",
+        run: lock_getlock_free,
+    },
+    Check {
+        name: "flush.unknown_tag",
+        description: "Flushing a tag not in use is answered with Rflush.",
+        run: flush_unknown_tag,
+    },
+    Check {
+        name: "flush.race",
+        description: "A flushed request is answered before its Rflush or not at all.",
+        run: flush_race,
+    },
+];
+
+// Fids of the attach point and scratch directory on raw connections.
+const ROOT: u32 = 1;
+const DIR: u32 = 2;
+
+fn twalk<'a>(fid: u32, new_fid: u32, names: &[&'a str]) -> Fcall<'a> {
+    fcall::Twalk {
+        fid,
+        new_fid,
+        wnames: names.iter().map(|n| (*n).into()).collect(),
+    }
+    .into()
+}
+
+fn tlopen(fid: u32, flags: LOpenFlags) -> Fcall<'static> {
+    fcall::Tlopen { fid, flags }.into()
+}
+
+fn tlcreate(fid: u32, name: &str, flags: LOpenFlags) -> Fcall<'_> {
+    fcall::Tlcreate {
+        fid,
+        name: name.into(),
+        flags,
+        mode: 0o644,
+        gid: nix::unistd::getgid().as_raw(),
+    }
+    .into()
+}
+
+fn tread(fid: u32, offset: u64, count: u32) -> Fcall<'static> {
+    fcall::Tread { fid, offset, count }.into()
+}
+
+fn twrite(fid: u32, offset: u64, data: &[u8]) -> Fcall<'_> {
+    fcall::Twrite {
+        fid,
+        offset,
+        data: data.into(),
+    }
+    .into()
+}
+
+fn tclunk(fid: u32) -> Fcall<'static> {
+    fcall::Tclunk { fid }.into()
+}
+
+fn tgetattr(fid: u32) -> Fcall<'static> {
+    fcall::Tgetattr {
+        fid,
+        req_mask: GetattrMask::BASIC,
+    }
+    .into()
+}
+
+// A scratch directory for the check, and a raw connection with the attach
+// point as ROOT and the scratch directory as DIR.
+fn scratch_conn(target: &Target, check: &str) -> Result<(Scratch, RawConn), Failure> {
+    let scratch = Scratch::new(target, check)?;
+    let (mut conn, _) = target.raw_attached(ROOT)?;
+    conn.walk(ROOT, DIR, &[scratch.name.as_str()])?;
+    Ok((scratch, conn))
+}
+
+// Create and open name in DIR as fid.
+fn create(conn: &mut RawConn, fid: u32, name: &str, data: &[u8]) -> Result<(), Failure> {
+    conn.walk(DIR, fid, &[])?;
+    conn.ok(tlcreate(fid, name, LOpenFlags::O_RDWR))?;
+    if !data.is_empty() {
+        conn.ok(twrite(fid, 0, data))?;
+    }
+    Ok(())
+}
+
+fn version_negotiate(target: &Target) -> Result<(), Failure> {
+    let mut conn = target.raw()?;
+    let rversion = conn.version(target.msize, "9P2000.L")?;
+    ensure!(
+        rversion.version.as_bytes() == b"9P2000.L",
+        "version {:?} was offered for 9P2000.L",
+        rversion.version.to_string()
+    );
+    ensure!(
+        rversion.msize <= target.msize && rversion.msize > 0,
+        "msize {} was offered for {}",
+        rversion.msize,
+        target.msize
+    );
+    Ok(())
+}
+
+fn version_unknown(target: &Target) -> Result<(), Failure> {
+    let mut conn = target.raw()?;
+    let rversion = conn.version(target.msize, "9P2000.p9conformance")?;
+    ensure!(
+        rversion.version.as_bytes() == b"unknown",
+        "version {:?} was offered for 9P2000.p9conformance",
+        rversion.version.to_string()
+    );
+    Ok(())
+}
+
+fn version_large_msize(target: &Target) -> Result<(), Failure> {
+    let mut conn = target.raw()?;
+    let rversion = conn.version(u32::MAX, "9P2000.L")?;
+    ensure!(
+        rversion.msize >= 4096,
+        "msize {} was offered, too small to use",
+        rversion.msize
+    );
+    conn.attach(target, ROOT)?;
+    conn.ok(tgetattr(ROOT))?;
+    Ok(())
+}
+
+fn version_required(target: &Target) -> Result<(), Failure> {
+    let mut conn = target.raw()?;
+    let attach = fcall::Tattach {
+        fid: ROOT,
+        afid: fcall::NOFID,
+        uname: target.uname.as_str().into(),
+        aname: target.aname.as_str().into(),
+        n_uname: target.n_uname,
+    };
+    conn.send(0, attach.into())?;
+    // Hanging up is a refusal too.
+    match conn.recv() {
+        Ok(resp) => {
+            expect_error("Tattach before Tversion", &resp.fcall)?;
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+            return Err(Failure::Fail(
+                "Tattach before Tversion was not answered".to_string(),
+            ))
+        }
+        Err(_) => (),
+    }
+    Ok(())
+}
+
+fn version_renegotiate(target: &Target) -> Result<(), Failure> {
+    let (mut conn, _) = target.raw_attached(ROOT)?;
+    conn.walk(ROOT, 3, &[])?;
+    conn.version(target.msize, "9P2000.L")?;
+    ensure!(
+        !conn.fid_exists(ROOT)? && !conn.fid_exists(3)?,
+        "fids survived a second Tversion"
+    );
+    conn.attach(target, ROOT)?;
+    Ok(())
+}
+
+fn fid_in_use(target: &Target) -> Result<(), Failure> {
+    let (mut conn, _) = target.raw_attached(ROOT)?;
+    conn.walk(ROOT, 3, &[])?;
+    let resp = conn.call(twalk(ROOT, 3, &[]))?;
+    expect_error("walk to a fid in use", &resp)?;
+    let resp = conn.call(
+        fcall::Tattach {
+            fid: 3,
+            afid: fcall::NOFID,
+            uname: target.uname.as_str().into(),
+            aname: target.aname.as_str().into(),
+            n_uname: target.n_uname,
+        }
+        .into(),
+    )?;
+    expect_error("attach to a fid in use", &resp)?;
+    Ok(())
+}
+
+fn fid_reuse_after_clunk(target: &Target) -> Result<(), Failure> {
+    let (mut conn, _) = target.raw_attached(ROOT)?;
+    conn.walk(ROOT, 3, &[])?;
+    conn.ok(tclunk(3))?;
+    ensure!(!conn.fid_exists(3)?, "fid survived Tclunk");
+    conn.walk(ROOT, 3, &[])?;
+    Ok(())
+}
+
+fn fid_unknown(target: &Target) -> Result<(), Failure> {
+    let (mut conn, _) = target.raw_attached(ROOT)?;
+    let resp = conn.call(tgetattr(99))?;
+    expect_error("getattr of an unknown fid", &resp)?;
+    let resp = conn.call(tclunk(99))?;
+    expect_error("clunk of an unknown fid", &resp)?;
+    let resp = conn.call(twalk(99, 3, &[]))?;
+    expect_error("walk from an unknown fid", &resp)?;
+    Ok(())
+}
+
+fn fid_remove_clunks(target: &Target) -> Result<(), Failure> {
+    let (_scratch, mut conn) = scratch_conn(target, "fid.remove_clunks")?;
+    create(&mut conn, 3, "file", b"")?;
+    conn.ok(fcall::Tremove { fid: 3 }.into())?;
+    ensure!(!conn.fid_exists(3)?, "fid survived Tremove");
+    let resp = conn.call(twalk(DIR, 3, &["file"]))?;
+    expect_error("walk to a removed file", &resp)?;
+
+    conn.ok(fcall::Tmkdir {
+        dfid: DIR,
+        name: "dir".into(),
+        mode: 0o755,
+        gid: nix::unistd::getgid().as_raw(),
+    }
+    .into())?;
+    conn.walk(DIR, 3, &["dir"])?;
+    conn.ok(tlcreate(3, "file", LOpenFlags::O_RDWR))?;
+    conn.ok(tclunk(3))?;
+    conn.walk(DIR, 4, &["dir"])?;
+    let resp = conn.call(fcall::Tremove { fid: 4 }.into())?;
+    expect_error("remove of a non-empty directory", &resp)?;
+    ensure!(!conn.fid_exists(4)?, "fid survived a failed Tremove");
+    Ok(())
+}
+
+fn walk_zero(target: &Target) -> Result<(), Failure> {
+    let (mut conn, qid) = target.raw_attached(ROOT)?;
+    let wqids = conn.walk(ROOT, 3, &[])?;
+    ensure!(wqids.is_empty(), "a walk of no names returned qids");
+    match conn.ok(tgetattr(3))? {
+        Fcall::Rgetattr(rgetattr) => {
+            ensure!(
+                rgetattr.qid == qid,
+                "the clone has qid {:?} rather than {:?}",
+                rgetattr.qid,
+                qid
+            );
+        }
+        resp => return Err(unexpected(&resp)),
+    }
+    conn.walk(ROOT, ROOT, &[])?;
+    Ok(())
+}
+
+// A chain of n directories in DIR, named d0/d1/...
+fn make_chain(conn: &mut RawConn, n: usize) -> Result<Vec<String>, Failure> {
+    let names: Vec<String> = (0..n).map(|i| format!("d{}", i)).collect();
+    conn.walk(DIR, 3, &[])?;
+    for name in &names {
+        conn.ok(fcall::Tmkdir {
+            dfid: 3,
+            name: name.as_str().into(),
+            mode: 0o755,
+            gid: nix::unistd::getgid().as_raw(),
+        }
+        .into())?;
+        conn.walk(3, 3, &[name.as_str()])?;
+    }
+    conn.ok(tclunk(3))?;
+    Ok(names)
+}
+
+fn walk_maxwelem(target: &Target) -> Result<(), Failure> {
+    let (_scratch, mut conn) = scratch_conn(target, "walk.maxwelem")?;
+    let names = make_chain(&mut conn, MAXWELEM)?;
+    let names: Vec<&str> = names.iter().map(String::as_str).collect();
+    conn.walk(DIR, 3, &names)?;
+    Ok(())
+}
+
+fn walk_too_many(target: &Target) -> Result<(), Failure> {
+    let (_scratch, mut conn) = scratch_conn(target, "walk.too_many")?;
+    // Every name exists, so only the count can fail the walk.
+    let names = make_chain(&mut conn, 17)?;
+    let names: Vec<&str> = names.iter().map(String::as_str).collect();
+    let resp = conn.call(twalk(DIR, 3, &names))?;
+    expect_error("walk of 17 names", &resp)?;
+    ensure!(!conn.fid_exists(3)?, "the failed walk made its new fid");
+    Ok(())
+}
+
+fn walk_partial(target: &Target) -> Result<(), Failure> {
+    let (_scratch, mut conn) = scratch_conn(target, "walk.partial")?;
+    make_chain(&mut conn, 1)?;
+    let resp = conn.call(twalk(DIR, 3, &["d0", "missing", "d1"]))?;
+    match resp {
+        Fcall::Rwalk(rwalk) => ensure!(
+            rwalk.wqids.len() == 1,
+            "partial walk returned {} qids rather than 1",
+            rwalk.wqids.len()
+        ),
+        Fcall::Rlerror(err) => {
+            return Err(Failure::Fail(format!(
+                "partial walk failed with {} rather than returning Rwalk",
+                errno::strerror(err.ecode)
+            )))
+        }
+        resp => return Err(unexpected(&resp)),
+    }
+    ensure!(!conn.fid_exists(3)?, "a partial walk made its new fid");
+    Ok(())
+}
+
+fn walk_first_missing(target: &Target) -> Result<(), Failure> {
+    let (_scratch, mut conn) = scratch_conn(target, "walk.first_missing")?;
+    let resp = conn.call(twalk(DIR, 3, &["missing", "more"]))?;
+    expect_errno("walk to a missing name", &resp, errno::ENOENT)?;
+    ensure!(!conn.fid_exists(3)?, "the failed walk made its new fid");
+    Ok(())
+}
+
+fn walk_in_place(target: &Target) -> Result<(), Failure> {
+    let (_scratch, mut conn) = scratch_conn(target, "walk.in_place")?;
+    make_chain(&mut conn, 2)?;
+    conn.walk(DIR, 3, &[])?;
+    let wqids = conn.walk(3, 3, &["d0", "d1"])?;
+    match conn.ok(tgetattr(3))? {
+        Fcall::Rgetattr(rgetattr) => ensure!(
+            Some(&rgetattr.qid) == wqids.last(),
+            "the fid did not move to the end of the walk"
+        ),
+        resp => return Err(unexpected(&resp)),
+    }
+    Ok(())
+}
+
+fn walk_dotdot_root(target: &Target) -> Result<(), Failure> {
+    let (mut conn, qid) = target.raw_attached(ROOT)?;
+    let wqids = conn.walk(ROOT, 3, &[".."])?;
+    ensure!(
+        wqids[0] == qid,
+        "walking .. from the attach point reached {:?}",
+        wqids[0]
+    );
+    Ok(())
+}
+
+const READDIR_FILES: usize = 50;
+
+// Every entry of the directory fid, reading count bytes at a time, with the
+// offset each was returned with.
+fn read_dir(conn: &mut RawConn, fid: u32, offset: u64) -> Result<Vec<(String, u64)>, Failure> {
+    let mut entries = Vec::new();
+    let mut offset = offset;
+    loop {
+        let resp = conn.ok(fcall::Treaddir {
+            fid,
+            offset,
+            count: 128,
+        }
+        .into())?;
+        let data: DirEntryData = match resp {
+            Fcall::Rreaddir(rreaddir) => rreaddir.data,
+            resp => return Err(unexpected(&resp)),
+        };
+        if data.data().is_empty() {
+            return Ok(entries);
+        }
+        ensure!(
+            entries.len() < READDIR_FILES * 4,
+            "readdir never reached the end"
+        );
+        for entry in data.data() {
+            entries.push((entry.name.to_string(), entry.offset));
+            offset = entry.offset;
+        }
+    }
+}
+
+// A directory with READDIR_FILES files, open as fid 3.
+fn readdir_setup(conn: &mut RawConn) -> Result<(), Failure> {
+    for i in 0..READDIR_FILES {
+        create(conn, 4, &format!("file{}", i), b"")?;
+        conn.ok(tclunk(4))?;
+    }
+    conn.walk(DIR, 3, &[])?;
+    conn.ok(tlopen(3, LOpenFlags::O_RDONLY))?;
+    Ok(())
+}
+
+fn readdir_complete(target: &Target) -> Result<(), Failure> {
+    let (_scratch, mut conn) = scratch_conn(target, "readdir.complete")?;
+    readdir_setup(&mut conn)?;
+    let mut names: Vec<String> = read_dir(&mut conn, 3, 0)?
+        .into_iter()
+        .map(|(name, _)| name)
+        .filter(|name| name != "." && name != "..")
+        .collect();
+    names.sort();
+    let mut expected: Vec<String> = (0..READDIR_FILES).map(|i| format!("file{}", i)).collect();
+    expected.sort();
+    ensure!(
+        names == expected,
+        "listed {} entries for {} files",
+        names.len(),
+        READDIR_FILES
+    );
+    Ok(())
+}
+
+fn readdir_offset_stable(target: &Target) -> Result<(), Failure> {
+    let (_scratch, mut conn) = scratch_conn(target, "readdir.offset_stable")?;
+    readdir_setup(&mut conn)?;
+    let entries = read_dir(&mut conn, 3, 0)?;
+    for i in [0, entries.len() / 3, entries.len() / 2, entries.len() - 2] {
+        let rest = read_dir(&mut conn, 3, entries[i].1)?;
+        ensure!(
+            rest[..] == entries[i + 1..],
+            "reading from the offset of entry {} returned {} entries, not {}",
+            i,
+            rest.len(),
+            entries.len() - i - 1
+        );
+    }
+    let again = read_dir(&mut conn, 3, 0)?;
+    ensure!(again == entries, "a second listing differs from the first");
+    Ok(())
+}
+
+fn lcreate_open(target: &Target) -> Result<(), Failure> {
+    let (_scratch, mut conn) = scratch_conn(target, "lcreate.open")?;
+    conn.walk(DIR, 3, &[])?;
+    match conn.ok(tlcreate(3, "file", LOpenFlags::O_RDWR))? {
+        Fcall::Rlcreate(rlcreate) => ensure!(
+            !rlcreate.qid.typ.contains(fcall::QidType::DIR),
+            "the new file has a directory qid"
+        ),
+        resp => return Err(unexpected(&resp)),
+    }
+    conn.ok(twrite(3, 0, b"hello"))?;
+    match conn.ok(tread(3, 0, 100))? {
+        Fcall::Rread(rread) => ensure!(
+            rread.data.as_ref() == b"hello",
+            "read back {:?} after writing \"hello\"",
+            rread.data.escape_ascii().to_string()
+        ),
+        resp => return Err(unexpected(&resp)),
+    }
+    Ok(())
+}
+
+fn lcreate_excl(target: &Target) -> Result<(), Failure> {
+    let (_scratch, mut conn) = scratch_conn(target, "lcreate.excl")?;
+    create(&mut conn, 3, "file", b"")?;
+    conn.walk(DIR, 4, &[])?;
+    let resp = conn.call(tlcreate(4, "file", LOpenFlags::O_RDWR | LOpenFlags::O_EXCL))?;
+    expect_errno("exclusive create of an existing file", &resp, errno::EEXIST)?;
+    Ok(())
+}
+
+fn lopen_access(target: &Target) -> Result<(), Failure> {
+    let (_scratch, mut conn) = scratch_conn(target, "lopen.access")?;
+    create(&mut conn, 3, "file", b"data")?;
+    conn.walk(DIR, 4, &["file"])?;
+    conn.ok(tlopen(4, LOpenFlags::O_RDONLY))?;
+    let resp = conn.call(twrite(4, 0, b"x"))?;
+    expect_error("write to an O_RDONLY fid", &resp)?;
+    conn.walk(DIR, 5, &["file"])?;
+    conn.ok(tlopen(5, LOpenFlags::O_WRONLY))?;
+    let resp = conn.call(tread(5, 0, 100))?;
+    expect_error("read from an O_WRONLY fid", &resp)?;
+    Ok(())
+}
+
+fn lopen_trunc(target: &Target) -> Result<(), Failure> {
+    let (_scratch, mut conn) = scratch_conn(target, "lopen.trunc")?;
+    create(&mut conn, 3, "file", b"data")?;
+    conn.walk(DIR, 4, &["file"])?;
+    conn.ok(tlopen(4, LOpenFlags::O_WRONLY | LOpenFlags::O_TRUNC))?;
+    match conn.ok(tgetattr(4))? {
+        Fcall::Rgetattr(rgetattr) => ensure!(
+            rgetattr.stat.size == 0,
+            "size is {} after O_TRUNC",
+            rgetattr.stat.size
+        ),
+        resp => return Err(unexpected(&resp)),
+    }
+    Ok(())
+}
+
+fn lopen_twice(target: &Target) -> Result<(), Failure> {
+    let (_scratch, mut conn) = scratch_conn(target, "lopen.twice")?;
+    create(&mut conn, 3, "file", b"")?;
+    let resp = conn.call(tlopen(3, LOpenFlags::O_RDONLY))?;
+    expect_error("open of an open fid", &resp)?;
+    Ok(())
+}
+
+fn lopen_dir_write(target: &Target) -> Result<(), Failure> {
+    let (_scratch, mut conn) = scratch_conn(target, "lopen.dir_write")?;
+    conn.walk(DIR, 3, &[])?;
+    let resp = conn.call(tlopen(3, LOpenFlags::O_WRONLY))?;
+    expect_errno("open of a directory for writing", &resp, errno::EISDIR)?;
+    Ok(())
+}
+
+const XATTR: &str = "user.p9conformance";
+
+// A scratch file with XATTR set to "value".
+fn xattr_setup(target: &Target, check: &str) -> Result<Scratch, Failure> {
+    let scratch = Scratch::new(target, check)?;
+    scratch.create("file", b"")?.clunk()?;
+    let (_, fid) = scratch.dir.walk(&["file"])?;
+    fid.xattrcreate(XATTR, 5, 0).map_err(unsupported)?;
+    fid.write(0, b"value")?;
+    fid.clunk().map_err(unsupported)?;
+    Ok(scratch)
+}
+
+fn xattr_create(target: &Target) -> Result<(), Failure> {
+    let scratch = xattr_setup(target, "xattr.create")?;
+    let (_, fid) = scratch.dir.walk(&["file"])?;
+    let (size, xattr) = fid.xattrwalk(XATTR)?;
+    ensure!(size == 5, "attribute size is {} rather than 5", size);
+    let mut buf = [0; 16];
+    let n = xattr.read(0, &mut buf)?;
+    ensure!(
+        &buf[..n] == b"value",
+        "attribute reads back as {:?}",
+        buf[..n].escape_ascii().to_string()
+    );
+    Ok(())
+}
+
+fn xattr_list(target: &Target) -> Result<(), Failure> {
+    let scratch = xattr_setup(target, "xattr.list")?;
+    let (_, fid) = scratch.dir.walk(&["file"])?;
+    let (size, list) = fid.xattrwalk("")?;
+    let mut buf = vec![0; size as usize];
+    let n = list.read(0, &mut buf)?;
+    ensure!(n == buf.len(), "read {} of {} bytes of names", n, size);
+    let found = buf.split(|b| *b == 0).any(|name| name == XATTR.as_bytes());
+    ensure!(found, "{} is not listed", XATTR);
+    Ok(())
+}
+
+fn xattr_missing(target: &Target) -> Result<(), Failure> {
+    let (_scratch, mut conn) = scratch_conn(target, "xattr.missing")?;
+    create(&mut conn, 3, "file", b"")?;
+    conn.walk(DIR, 4, &["file"])?;
+    let resp = conn.call(
+        fcall::Txattrwalk {
+            fid: 4,
+            new_fid: 5,
+            name: "user.p9conformance.missing".into(),
+        }
+        .into(),
+    )?;
+    if let Fcall::Rlerror(err) = &resp {
+        if err.ecode == errno::EOPNOTSUPP {
+            return Err(Failure::Skip("xattrs are not supported".to_string()));
+        }
+    }
+    expect_errno("xattrwalk of a missing name", &resp, errno::ENODATA)?;
+    Ok(())
+}
+
+fn flock(typ: LockType, client_id: &str) -> Flock<'_> {
+    Flock {
+        typ,
+        flags: LockFlag::empty(),
+        start: 0,
+        length: 0,
+        proc_id: std::process::id(),
+        client_id: client_id.into(),
+    }
+}
+
+// A scratch file opened for writing on two connections.
+fn lock_setup(
+    target: &Target,
+    check: &str,
+) -> Result<(Scratch, p92000l::ClientFid, p92000l::ClientFid), Failure> {
+    let scratch = Scratch::new(target, check)?;
+    let a = scratch.create("file", b"data")?;
+    let (_client, root) = target.client()?;
+    let (_, b) = root.walk(&[scratch.name.as_str(), "file"])?;
+    b.open(LOpenFlags::O_RDWR)?;
+    Ok((scratch, a, b))
+}
+
+fn lock_exclusive(target: &Target) -> Result<(), Failure> {
+    let (_scratch, a, b) = lock_setup(target, "lock.exclusive")?;
+    let status = a
+        .lock(flock(LockType::WRLOCK, "p9conformance-a"))
+        .map_err(unsupported)?;
+    ensure!(status == LockStatus::SUCCESS, "first lock was {:?}", status);
+    let held = b.getlock(Getlock {
+        typ: LockType::WRLOCK,
+        start: 0,
+        length: 0,
+        proc_id: std::process::id(),
+        client_id: "p9conformance-b".into(),
+    })?;
+    ensure!(
+        held.typ != LockType::UNLOCK,
+        "getlock did not report the conflicting lock"
+    );
+    let status = b.lock(flock(LockType::WRLOCK, "p9conformance-b"))?;
+    ensure!(
+        status == LockStatus::BLOCKED,
+        "a conflicting lock was {:?}",
+        status
+    );
+    a.lock(flock(LockType::UNLOCK, "p9conformance-a"))?;
+    let status = b.lock(flock(LockType::WRLOCK, "p9conformance-b"))?;
+    ensure!(
+        status == LockStatus::SUCCESS,
+        "lock after unlock was {:?}",
+        status
+    );
+    Ok(())
+}
+
+fn lock_shared(target: &Target) -> Result<(), Failure> {
+    let (_scratch, a, b) = lock_setup(target, "lock.shared")?;
+    let status = a
+        .lock(flock(LockType::RDLOCK, "p9conformance-a"))
+        .map_err(unsupported)?;
+    ensure!(status == LockStatus::SUCCESS, "first lock was {:?}", status);
+    let status = b.lock(flock(LockType::RDLOCK, "p9conformance-b"))?;
+    ensure!(
+        status == LockStatus::SUCCESS,
+        "a second read lock was {:?}",
+        status
+    );
+    Ok(())
+}
+
+fn lock_getlock_free(target: &Target) -> Result<(), Failure> {
+    let (_scratch, a, _b) = lock_setup(target, "lock.getlock_free")?;
+    let held = a
+        .getlock(Getlock {
+            typ: LockType::WRLOCK,
+            start: 0,
+            length: 0,
+            proc_id: std::process::id(),
+            client_id: "p9conformance-a".into(),
+        })
+        .map_err(unsupported)?;
+    ensure!(
+        held.typ == LockType::UNLOCK,
+        "getlock of an unlocked file returned {:?}",
+        held.typ
+    );
+    Ok(())
+}
+
+fn flush_unknown_tag(target: &Target) -> Result<(), Failure> {
+    let (mut conn, _) = target.raw_attached(ROOT)?;
+    match conn.call(fcall::Tflush { oldtag: 1000 }.into())? {
+        Fcall::Rflush(_) => Ok(()),
+        Fcall::Rlerror(err) => Err(Failure::Fail(format!(
+            "Tflush failed with {}, it must be answered with Rflush",
+            errno::strerror(err.ecode)
+        ))),
+        resp => Err(unexpected(&resp)),
+    }
+}
+
+const FLUSH_ROUNDS: u16 = 100;
+
+fn flush_race(target: &Target) -> Result<(), Failure> {
+    let (_scratch, mut conn) = scratch_conn(target, "flush.race")?;
+    create(&mut conn, 3, "file", &[7; 4096])?;
+    for round in 0..FLUSH_ROUNDS {
+        // New tags every round, so a reply to an earlier flushed read
+        // can't pass for one of this round.
+        let (rtag, ftag) = (10 + 2 * round, 11 + 2 * round);
+        conn.send(rtag, tread(3, 0, 4096))?;
+        conn.send(ftag, fcall::Tflush { oldtag: rtag }.into())?;
+        let mut flushed = false;
+        while !flushed {
+            let resp = conn.recv()?;
+            match (resp.tag, &resp.fcall) {
+                (tag, _) if tag == rtag => (),
+                (tag, Fcall::Rflush(_)) if tag == ftag => flushed = true,
+                (tag, _) if (10..rtag).contains(&tag) => {
+                    return Err(Failure::Fail(format!(
+                        "tag {} was answered after its Rflush",
+                        tag
+                    )))
+                }
+                (tag, resp) => {
+                    return Err(Failure::Fail(format!(
+                        "unexpected {:?} with tag {} while flushing",
+                        p92000l::FcallType::from(resp),
+                        tag
+                    )))
+                }
+            }
+        }
+    }
+    // A reply to a flushed read would arrive before this.
+    conn.send(3, tgetattr(3))?;
+    let resp = conn.recv()?;
+    ensure!(
+        resp.tag == 3,
+        "tag {} was answered after its Rflush",
+        resp.tag
+    );
+    Ok(())
+}
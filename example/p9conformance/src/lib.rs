@@ -0,0 +1,467 @@
+pub mod checks;
+
+pub use checks::CHECKS;
+
+use p92000l::{
+    errno, fcall, Client, ClientBuilder, ClientFid, Fcall, FcallType, GetattrMask, Qid,
+    ReadTransport, TaggedFcall, WriteTransport,
+};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+// Unlinkat flag for removing directories.
+const AT_REMOVEDIR: u32 = 0x200;
+
+// The two halves of a connection.
+type Conn = (Box<dyn ReadTransport>, Box<dyn WriteTransport>);
+
+/// The server under test and how to attach to it.
+#[derive(Clone, Debug)]
+pub struct Target {
+    /// tcp:HOST:PORT or unix:PATH.
+    pub addr: String,
+    pub uname: String,
+    pub aname: String,
+    pub n_uname: u32,
+    /// The msize to ask for.
+    pub msize: u32,
+    /// How long to wait for any one reply, and for a whole check.
+    pub timeout: Duration,
+}
+
+impl Target {
+    pub fn new(addr: &str) -> Target {
+        Target {
+            addr: addr.to_string(),
+            uname: std::env::var("USER").unwrap_or_default(),
+            aname: String::new(),
+            n_uname: nix::unistd::getuid().as_raw(),
+            msize: 128 * 1024,
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    fn dial(&self) -> Result<Conn, std::io::Error> {
+        if let Some(addr) = self.addr.strip_prefix("tcp:") {
+            let conn = TcpStream::connect(addr)?;
+            return Ok((Box::new(conn.try_clone()?), Box::new(conn)));
+        }
+        if let Some(path) = self.addr.strip_prefix("unix:") {
+            let conn = UnixStream::connect(path)?;
+            return Ok((Box::new(conn.try_clone()?), Box::new(conn)));
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("unsupported 9p address {:?}", self.addr),
+        ))
+    }
+
+    /// A new connection, attached.
+    pub fn client(&self) -> Result<(Client, ClientFid), std::io::Error> {
+        let (r, w) = self.dial()?;
+        let client = ClientBuilder::new()
+            .msize(self.msize)
+            .handshake_timeout(self.timeout)
+            .over_transport(r, w)?;
+        let (_, root) = client.attach(self.n_uname, self.uname.as_str(), self.aname.as_str())?;
+        Ok((client, root))
+    }
+
+    /// A new connection for sending messages by hand, before Tversion.
+    pub fn raw(&self) -> Result<RawConn, std::io::Error> {
+        let (mut r, w) = self.dial()?;
+        r.set_read_timeout(Some(self.timeout))?;
+        Ok(RawConn {
+            r,
+            w,
+            rbuf: Vec::with_capacity(self.msize as usize),
+            wbuf: Vec::with_capacity(self.msize as usize),
+            next_tag: 0,
+        })
+    }
+
+    /// A raw connection after Tversion, with fid attached.
+    pub fn raw_attached(&self, fid: u32) -> Result<(RawConn, Qid), Failure> {
+        let mut conn = self.raw()?;
+        conn.version(self.msize, "9P2000.L")?;
+        let qid = conn.attach(self, fid)?;
+        Ok((conn, qid))
+    }
+}
+
+/// Why a check did not pass.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// The server broke the protocol or returned the wrong result.
+    Fail(String),
+    /// The server does not support what the check needs.
+    Skip(String),
+}
+
+impl From<std::io::Error> for Failure {
+    fn from(err: std::io::Error) -> Failure {
+        Failure::Fail(err.to_string())
+    }
+}
+
+/// Fail the check unless cond holds.
+#[macro_export]
+macro_rules! ensure {
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            return Err($crate::Failure::Fail(format!($($arg)+)));
+        }
+    };
+}
+
+/// Skip rather than fail on errors meaning the server lacks a feature.
+pub fn unsupported(err: std::io::Error) -> Failure {
+    match errno::errno_from_io_error(&err) {
+        errno::EOPNOTSUPP | errno::ENOSYS => Failure::Skip(err.to_string()),
+        _ => Failure::from(err),
+    }
+}
+
+/// The errno of a reply that should have been an Rlerror.
+pub fn expect_error(what: &str, resp: &Fcall) -> Result<u32, Failure> {
+    match resp {
+        Fcall::Rlerror(err) => Ok(err.ecode),
+        resp => Err(Failure::Fail(format!(
+            "{} succeeded with {:?}, expected an error",
+            what,
+            FcallType::from(resp)
+        ))),
+    }
+}
+
+/// Fail unless resp is an Rlerror with ecode.
+pub fn expect_errno(what: &str, resp: &Fcall, ecode: u32) -> Result<(), Failure> {
+    let got = expect_error(what, resp)?;
+    ensure!(
+        got == ecode,
+        "{} failed with {}, expected {}",
+        what,
+        errno::strerror(got),
+        errno::strerror(ecode)
+    );
+    Ok(())
+}
+
+/// A connection speaking 9p message by message, with no checks on what
+/// is sent.
+pub struct RawConn {
+    r: Box<dyn ReadTransport>,
+    w: Box<dyn WriteTransport>,
+    rbuf: Vec<u8>,
+    wbuf: Vec<u8>,
+    next_tag: u16,
+}
+
+impl RawConn {
+    pub fn send(&mut self, tag: u16, fcall: Fcall<'_>) -> Result<(), std::io::Error> {
+        p92000l::write(&mut self.w, &mut self.wbuf, &TaggedFcall { tag, fcall })
+    }
+
+    pub fn recv(&mut self) -> Result<TaggedFcall<'static>, std::io::Error> {
+        Ok(p92000l::read(&mut self.r, &mut self.rbuf)?.clone_static())
+    }
+
+    /// Send fcall and wait for its reply, which must be the next message.
+    pub fn call(&mut self, fcall: Fcall<'_>) -> Result<Fcall<'static>, Failure> {
+        let tag = self.next_tag;
+        self.next_tag = (self.next_tag + 1) % fcall::NOTAG;
+        let op = FcallType::from(&fcall);
+        self.send(tag, fcall)?;
+        let resp = self.recv()?;
+        ensure!(
+            resp.tag == tag,
+            "{:?} was answered with tag {} rather than {}",
+            op,
+            resp.tag,
+            tag
+        );
+        Ok(resp.fcall)
+    }
+
+    /// Like call, failing on Rlerror.
+    pub fn ok(&mut self, fcall: Fcall<'_>) -> Result<Fcall<'static>, Failure> {
+        let op = FcallType::from(&fcall);
+        match self.call(fcall)? {
+            Fcall::Rlerror(err) => Err(Failure::Fail(format!(
+                "{:?} failed: {}",
+                op,
+                errno::strerror(err.ecode)
+            ))),
+            resp => Ok(resp),
+        }
+    }
+
+    pub fn version(
+        &mut self,
+        msize: u32,
+        version: &str,
+    ) -> Result<fcall::Rversion<'static>, Failure> {
+        self.send(
+            fcall::NOTAG,
+            fcall::Tversion {
+                msize,
+                version: version.into(),
+            }
+            .into(),
+        )?;
+        match self.recv()? {
+            TaggedFcall {
+                tag: fcall::NOTAG,
+                fcall: Fcall::Rversion(rversion),
+            } => Ok(rversion),
+            resp => Err(Failure::Fail(format!(
+                "Tversion was answered with {:?} tag {}",
+                FcallType::from(&resp.fcall),
+                resp.tag
+            ))),
+        }
+    }
+
+    pub fn attach(&mut self, target: &Target, fid: u32) -> Result<Qid, Failure> {
+        let resp = self.ok(fcall::Tattach {
+            fid,
+            afid: fcall::NOFID,
+            uname: target.uname.as_str().into(),
+            aname: target.aname.as_str().into(),
+            n_uname: target.n_uname,
+        }
+        .into())?;
+        match resp {
+            Fcall::Rattach(rattach) => Ok(rattach.qid),
+            resp => Err(unexpected(&resp)),
+        }
+    }
+
+    /// Walk all of names, failing on a partial walk.
+    pub fn walk(&mut self, fid: u32, new_fid: u32, names: &[&str]) -> Result<Vec<Qid>, Failure> {
+        let resp = self.ok(fcall::Twalk {
+            fid,
+            new_fid,
+            wnames: names.iter().map(|n| (*n).into()).collect(),
+        }
+        .into())?;
+        match resp {
+            Fcall::Rwalk(rwalk) if rwalk.wqids.len() == names.len() => Ok(rwalk.wqids),
+            Fcall::Rwalk(rwalk) => Err(Failure::Fail(format!(
+                "walk of {} names stopped after {}",
+                names.len(),
+                rwalk.wqids.len()
+            ))),
+            resp => Err(unexpected(&resp)),
+        }
+    }
+
+    /// Whether the server still knows fid.
+    pub fn fid_exists(&mut self, fid: u32) -> Result<bool, Failure> {
+        let resp = self.call(
+            fcall::Tgetattr {
+                fid,
+                req_mask: GetattrMask::MODE,
+            }
+            .into(),
+        )?;
+        Ok(!matches!(resp, Fcall::Rlerror(_)))
+    }
+}
+
+/// Fail with a reply of the wrong type.
+pub fn unexpected(resp: &Fcall) -> Failure {
+    Failure::Fail(format!("unexpected reply {:?}", FcallType::from(resp)))
+}
+
+/// A directory of its own for a check to work in, removed when dropped.
+pub struct Scratch {
+    pub client: Client,
+    pub root: ClientFid,
+    pub dir: ClientFid,
+    /// The directory's name in the attach point.
+    pub name: String,
+}
+
+impl Scratch {
+    pub fn new(target: &Target, check: &str) -> Result<Scratch, Failure> {
+        let (client, root) = target.client()?;
+        let name = format!("p9conformance.{}.{}", std::process::id(), check);
+        // Left behind by a run that was killed.
+        let _ = remove_all(&root, &name);
+        root.mkdir(name.as_str(), 0o755, nix::unistd::getgid().as_raw())
+            .map_err(|err| Failure::Skip(format!("cannot create a scratch directory: {}", err)))?;
+        let (_, dir) = root.walk(&[name.as_str()])?;
+        Ok(Scratch {
+            client,
+            root,
+            dir,
+            name,
+        })
+    }
+
+    /// Create and open a file in the scratch directory.
+    pub fn create(&self, name: &str, data: &[u8]) -> Result<ClientFid, std::io::Error> {
+        let (_, fid) = self.dir.walk::<&str>(&[])?;
+        fid.create(
+            name,
+            fcall::LOpenFlags::O_RDWR,
+            0o644,
+            nix::unistd::getgid().as_raw(),
+        )?;
+        if !data.is_empty() {
+            fid.write(0, data)?;
+        }
+        Ok(fid)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = remove_all(&self.root, &self.name);
+    }
+}
+
+fn remove_all(dir: &ClientFid, name: &str) -> Result<(), std::io::Error> {
+    let (_, fid) = dir.walk(&[name])?;
+    if fid.getattr(GetattrMask::MODE)?.stat.mode & 0o170000 != 0o040000 {
+        return dir.unlinkat(name, 0);
+    }
+    let (_, list) = fid.walk::<&str>(&[])?;
+    list.open(fcall::LOpenFlags::O_RDONLY)?;
+    for entry in list.read_dir()? {
+        let child = entry.name.to_string();
+        if child != "." && child != ".." {
+            remove_all(&fid, &child)?;
+        }
+    }
+    dir.unlinkat(name, AT_REMOVEDIR)
+}
+
+/// A check and what it verifies.
+pub struct Check {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub run: fn(&Target) -> Result<(), Failure>,
+}
+
+/// The result of one check.
+#[derive(Clone, Debug)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub result: Result<(), Failure>,
+    pub elapsed: Duration,
+}
+
+/// The results of a run.
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub results: Vec<CheckResult>,
+}
+
+impl Report {
+    pub fn passed(&self) -> usize {
+        self.results.iter().filter(|r| r.result.is_ok()).count()
+    }
+
+    pub fn failed(&self) -> usize {
+        let failed = |r: &&CheckResult| matches!(r.result, Err(Failure::Fail(_)));
+        self.results.iter().filter(failed).count()
+    }
+
+    pub fn skipped(&self) -> usize {
+        let skipped = |r: &&CheckResult| matches!(r.result, Err(Failure::Skip(_)));
+        self.results.iter().filter(skipped).count()
+    }
+
+    /// The result of the check called name.
+    pub fn result(&self, name: &str) -> Option<&Result<(), Failure>> {
+        self.results
+            .iter()
+            .find(|r| r.name == name)
+            .map(|r| &r.result)
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let results: Vec<serde_json::Value> = self
+            .results
+            .iter()
+            .map(|r| {
+                let (status, message) = match &r.result {
+                    Ok(()) => ("pass", None),
+                    Err(Failure::Fail(msg)) => ("fail", Some(msg)),
+                    Err(Failure::Skip(msg)) => ("skip", Some(msg)),
+                };
+                serde_json::json!({
+                    "name": r.name,
+                    "status": status,
+                    "message": message,
+                    "seconds": r.elapsed.as_secs_f64(),
+                })
+            })
+            .collect();
+        serde_json::json!({
+            "passed": self.passed(),
+            "failed": self.failed(),
+            "skipped": self.skipped(),
+            "results": results,
+        })
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for r in &self.results {
+            match &r.result {
+                Ok(()) => writeln!(f, "PASS {}", r.name)?,
+                Err(Failure::Fail(msg)) => writeln!(f, "FAIL {}: {}", r.name, msg)?,
+                Err(Failure::Skip(msg)) => writeln!(f, "SKIP {}: {}", r.name, msg)?,
+            }
+        }
+        write!(
+            f,
+            "{} passed, {} failed, {} skipped",
+            self.passed(),
+            self.failed(),
+            self.skipped()
+        )
+    }
+}
+
+/// Run one check, failing it if it takes longer than the target's timeout.
+/// A check that hangs is left running on its own thread.
+pub fn run_check(target: &Target, check: &Check) -> CheckResult {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let target2 = target.clone();
+    let run = check.run;
+    let start = Instant::now();
+    std::thread::spawn(move || {
+        let _ = tx.send(run(&target2));
+    });
+    let result = match rx.recv_timeout(target.timeout) {
+        Ok(result) => result,
+        Err(std::sync::mpsc::RecvTimeoutError::Timeout) => Err(Failure::Fail(format!(
+            "timed out after {:?}",
+            target.timeout
+        ))),
+        Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+            Err(Failure::Fail("check panicked".to_string()))
+        }
+    };
+    CheckResult {
+        name: check.name,
+        result,
+        elapsed: start.elapsed(),
+    }
+}
+
+/// Run checks in order.
+pub fn run(target: &Target, checks: &[&Check]) -> Report {
+    Report {
+        results: checks
+            .iter()
+            .map(|check| run_check(target, check))
+            .collect(),
+    }
+}
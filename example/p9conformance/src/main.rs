@@ -0,0 +1,120 @@
+use p9conformance::{run, Check, Target, CHECKS};
+use std::time::Duration;
+
+fn usage(program: &str, opts: getopts::Options) -> ! {
+    let brief = format!(
+        "p9conformance - Check a 9p2000.L server against the protocol.\n\n\
+        Usage: {} [options] ADDR\n\n\
+        ADDR is tcp:HOST:PORT or unix:PATH. Checks work in a scratch\n\
+        directory they create in the attach point. Exits 1 if any check fails.",
+        program
+    );
+    print!("{}", opts.usage(&brief));
+    std::process::exit(2)
+}
+
+fn die(program: &str, msg: String) -> ! {
+    eprintln!("{}: {}", program, msg);
+    std::process::exit(2)
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let program = args[0].clone();
+
+    let mut opts = getopts::Options::new();
+    opts.optopt(
+        "u",
+        "uname",
+        "Attach as this user (default $USER).",
+        "UNAME",
+    );
+    opts.optopt(
+        "a",
+        "aname",
+        "Attach to this tree (default empty).",
+        "ANAME",
+    );
+    opts.optopt(
+        "n",
+        "n-uname",
+        "Attach as this numeric user (default our uid).",
+        "UID",
+    );
+    opts.optopt(
+        "",
+        "msize",
+        "Negotiate at most this message size (default 131072).",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "timeout",
+        "Fail checks taking longer than this (default 10).",
+        "SECONDS",
+    );
+    opts.optmulti(
+        "r",
+        "run",
+        "Only run checks whose names start with PREFIX, may be repeated.",
+        "PREFIX",
+    );
+    opts.optflag("l", "list", "List the checks and exit.");
+    opts.optflag("j", "json", "Print the report as JSON.");
+    opts.optflag("h", "help", "print this help menu");
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(err) => die(&program, err.to_string()),
+    };
+    if matches.opt_present("h") {
+        usage(&program, opts);
+    }
+
+    let prefixes = matches.opt_strs("run");
+    let checks: Vec<&Check> = CHECKS
+        .iter()
+        .filter(|c| prefixes.is_empty() || prefixes.iter().any(|p| c.name.starts_with(p)))
+        .collect();
+    if matches.opt_present("list") {
+        for check in checks {
+            println!("{}\t{}", check.name, check.description);
+        }
+        return;
+    }
+    if matches.free.len() != 1 {
+        usage(&program, opts);
+    }
+
+    let mut target = Target::new(&matches.free[0]);
+    let num = |name: &str| -> Option<u32> {
+        matches.opt_str(name).map(|v| match v.parse() {
+            Ok(v) => v,
+            Err(_) => die(&program, format!("invalid --{} value {:?}", name, v)),
+        })
+    };
+    if let Some(uname) = matches.opt_str("uname") {
+        target.uname = uname;
+    }
+    if let Some(aname) = matches.opt_str("aname") {
+        target.aname = aname;
+    }
+    if let Some(n_uname) = num("n-uname") {
+        target.n_uname = n_uname;
+    }
+    if let Some(msize) = num("msize") {
+        target.msize = msize;
+    }
+    if let Some(timeout) = num("timeout") {
+        target.timeout = Duration::from_secs(timeout as u64);
+    }
+
+    let report = run(&target, &checks);
+    if matches.opt_present("json") {
+        println!("{}", report.to_json());
+    } else {
+        println!("{}", report);
+    }
+    if report.failed() != 0 {
+        std::process::exit(1)
+    }
+}
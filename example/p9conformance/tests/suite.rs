@@ -0,0 +1,498 @@
+use p92000l::*;
+use p9conformance::{run, Check, Failure, Target, CHECKS};
+use std::collections::{BTreeMap, HashMap};
+use std::os::unix::net::UnixListener;
+use std::sync::{Arc, Mutex};
+
+struct Node {
+    dir: bool,
+    parent: u64,
+    data: Vec<u8>,
+    children: BTreeMap<Vec<u8>, u64>,
+    xattrs: BTreeMap<Vec<u8>, Vec<u8>>,
+    // Whole file locks, by client id.
+    locks: Vec<(Vec<u8>, LockType)>,
+}
+
+// An in memory tree shared by every connection, node 1 is the root.
+struct Tree {
+    nodes: HashMap<u64, Node>,
+    next: u64,
+}
+
+enum Fid {
+    Node {
+        path: u64,
+        open: Option<LOpenFlags>,
+    },
+    XattrRead(Vec<u8>),
+    XattrWrite {
+        path: u64,
+        name: Vec<u8>,
+        data: Vec<u8>,
+    },
+}
+
+struct MemFs {
+    tree: Arc<Mutex<Tree>>,
+    fids: HashMap<u32, Fid>,
+    // Answer partial walks with an error, as some servers wrongly do.
+    partial_walk_errors: bool,
+}
+
+fn err(ecode: u32) -> Rlerror {
+    Rlerror { ecode }
+}
+
+impl Tree {
+    fn new() -> Tree {
+        let mut nodes = HashMap::new();
+        nodes.insert(1, Tree::node(true, 1));
+        Tree { nodes, next: 2 }
+    }
+
+    fn node(dir: bool, parent: u64) -> Node {
+        Node {
+            dir,
+            parent,
+            data: Vec::new(),
+            children: BTreeMap::new(),
+            xattrs: BTreeMap::new(),
+            locks: Vec::new(),
+        }
+    }
+
+    fn qid(&self, path: u64) -> Qid {
+        let typ = if self.nodes[&path].dir {
+            QidType::DIR
+        } else {
+            QidType::FILE
+        };
+        Qid {
+            typ,
+            version: 0,
+            path,
+        }
+    }
+
+    fn add(&mut self, dir: u64, name: &[u8], is_dir: bool) -> Result<u64, Rlerror> {
+        if self.nodes[&dir].children.contains_key(name) {
+            return Err(err(errno::EEXIST));
+        }
+        let path = self.next;
+        self.next += 1;
+        self.nodes.insert(path, Tree::node(is_dir, dir));
+        let parent = self.nodes.get_mut(&dir).unwrap();
+        parent.children.insert(name.to_vec(), path);
+        Ok(path)
+    }
+
+    fn remove(&mut self, dir: u64, name: &[u8]) -> Result<(), Rlerror> {
+        let child = match self.nodes[&dir].children.get(name) {
+            Some(child) => *child,
+            None => return Err(err(errno::ENOENT)),
+        };
+        if !self.nodes[&child].children.is_empty() {
+            return Err(err(errno::ENOTEMPTY));
+        }
+        self.nodes.get_mut(&dir).unwrap().children.remove(name);
+        Ok(())
+    }
+
+    // The lock held by another client that conflicts with typ.
+    fn conflict(&self, path: u64, typ: LockType, client: &[u8]) -> Option<&(Vec<u8>, LockType)> {
+        self.nodes[&path].locks.iter().find(|(holder, held)| {
+            holder != client && (typ == LockType::WRLOCK || *held == LockType::WRLOCK)
+        })
+    }
+}
+
+impl MemFs {
+    fn node(&self, fid: u32) -> Result<(u64, Option<LOpenFlags>), Rlerror> {
+        match self.fids.get(&fid) {
+            Some(Fid::Node { path, open }) => Ok((*path, *open)),
+            Some(_) => Err(err(errno::EINVAL)),
+            None => Err(err(errno::EBADF)),
+        }
+    }
+
+    fn new_fid(&self, fid: u32) -> Result<(), Rlerror> {
+        match self.fids.contains_key(&fid) {
+            true => Err(err(errno::EBADF)),
+            false => Ok(()),
+        }
+    }
+}
+
+macro_rules! tri {
+    ($resp:expr, $e:expr) => {
+        match $e {
+            Ok(v) => v,
+            Err(e) => return $resp.send(e),
+        }
+    };
+}
+
+impl Filesystem for MemFs {
+    fn attach(&mut self, req: &Tattach, resp: FcallResponse) {
+        tri!(resp, self.new_fid(req.fid));
+        self.fids.insert(
+            req.fid,
+            Fid::Node {
+                path: 1,
+                open: None,
+            },
+        );
+        resp.send(Rattach {
+            qid: self.tree.lock().unwrap().qid(1),
+        })
+    }
+
+    fn walk(&mut self, req: &Twalk, resp: FcallResponse) {
+        let (mut path, _) = tri!(resp, self.node(req.fid));
+        if req.new_fid != req.fid {
+            tri!(resp, self.new_fid(req.new_fid));
+        }
+        if req.wnames.len() > 16 {
+            return resp.send(err(errno::EINVAL));
+        }
+        let tree = self.tree.lock().unwrap();
+        let mut wqids = Vec::new();
+        for name in req.wnames.iter() {
+            let next = match name.as_bytes() {
+                b".." => Some(tree.nodes[&path].parent),
+                name => tree.nodes[&path].children.get(name).copied(),
+            };
+            match next {
+                Some(next) => path = next,
+                None if wqids.is_empty() || self.partial_walk_errors => {
+                    return resp.send(err(errno::ENOENT))
+                }
+                None => return resp.send(Rwalk { wqids }),
+            }
+            wqids.push(tree.qid(path));
+        }
+        self.fids
+            .insert(req.new_fid, Fid::Node { path, open: None });
+        resp.send(Rwalk { wqids })
+    }
+
+    fn getattr(&mut self, req: &Tgetattr, resp: FcallResponse) {
+        let (path, _) = tri!(resp, self.node(req.fid));
+        let tree = self.tree.lock().unwrap();
+        let node = &tree.nodes[&path];
+        let time = Time { sec: 0, nsec: 0 };
+        resp.send(Rgetattr {
+            valid: GetattrMask::BASIC,
+            qid: tree.qid(path),
+            stat: Stat {
+                mode: if node.dir { 0o40755 } else { 0o100644 },
+                uid: 0,
+                gid: 0,
+                nlink: 1,
+                rdev: 0,
+                size: node.data.len() as u64,
+                blksize: 4096,
+                blocks: 0,
+                atime: time,
+                mtime: time,
+                ctime: time,
+                btime: time,
+                gen: 0,
+                data_version: 0,
+            },
+        })
+    }
+
+    fn lopen(&mut self, req: &Tlopen, resp: FcallResponse) {
+        let (path, open) = tri!(resp, self.node(req.fid));
+        if open.is_some() {
+            return resp.send(err(errno::EINVAL));
+        }
+        let mut tree = self.tree.lock().unwrap();
+        let node = tree.nodes.get_mut(&path).unwrap();
+        if node.dir && req.flags.bits() & 3 != 0 {
+            return resp.send(err(errno::EISDIR));
+        }
+        if req.flags.contains(LOpenFlags::O_TRUNC) {
+            node.data.clear();
+        }
+        let open = Some(req.flags);
+        self.fids.insert(req.fid, Fid::Node { path, open });
+        resp.send(Rlopen {
+            qid: tree.qid(path),
+            iounit: 0,
+        })
+    }
+
+    fn lcreate(&mut self, req: &Tlcreate, resp: FcallResponse) {
+        let (dir, _) = tri!(resp, self.node(req.fid));
+        let mut tree = self.tree.lock().unwrap();
+        let path = match tree.nodes[&dir].children.get(req.name.as_bytes()) {
+            Some(_) if req.flags.contains(LOpenFlags::O_EXCL) => {
+                return resp.send(err(errno::EEXIST))
+            }
+            Some(path) => *path,
+            None => tri!(resp, tree.add(dir, req.name.as_bytes(), false)),
+        };
+        let open = Some(req.flags);
+        self.fids.insert(req.fid, Fid::Node { path, open });
+        resp.send(Rlcreate {
+            qid: tree.qid(path),
+            iounit: 0,
+        })
+    }
+
+    fn mkdir(&mut self, req: &Tmkdir, resp: FcallResponse) {
+        let (dir, _) = tri!(resp, self.node(req.dfid));
+        let mut tree = self.tree.lock().unwrap();
+        let path = tri!(resp, tree.add(dir, req.name.as_bytes(), true));
+        resp.send(Rmkdir {
+            qid: tree.qid(path),
+        })
+    }
+
+    fn read(&mut self, req: &Tread, resp: FcallResponse) {
+        let tree = self.tree.lock().unwrap();
+        let data = match self.fids.get(&req.fid) {
+            Some(Fid::XattrRead(data)) => data,
+            Some(Fid::Node {
+                path,
+                open: Some(flags),
+            }) if flags.bits() & 3 != 1 => &tree.nodes[path].data,
+            _ => return resp.send(err(errno::EBADF)),
+        };
+        let off = (req.offset as usize).min(data.len());
+        let end = (off + req.count as usize).min(data.len());
+        resp.send(Rread {
+            data: std::borrow::Cow::from(&data[off..end]),
+        })
+    }
+
+    fn write(&mut self, req: &Twrite, resp: FcallResponse) {
+        let mut tree = self.tree.lock().unwrap();
+        let data = match self.fids.get_mut(&req.fid) {
+            Some(Fid::XattrWrite { data, .. }) => data,
+            Some(Fid::Node {
+                path,
+                open: Some(flags),
+            }) if flags.bits() & 3 != 0 => &mut tree.nodes.get_mut(path).unwrap().data,
+            _ => return resp.send(err(errno::EBADF)),
+        };
+        let off = req.offset as usize;
+        if data.len() < off + req.data.len() {
+            data.resize(off + req.data.len(), 0);
+        }
+        data[off..off + req.data.len()].copy_from_slice(&req.data);
+        resp.send(Rwrite {
+            count: req.data.len() as u32,
+        })
+    }
+
+    fn readdir(&mut self, req: &Treaddir, resp: FcallResponse) {
+        let (path, open) = tri!(resp, self.node(req.fid));
+        if open.is_none() {
+            return resp.send(err(errno::EBADF));
+        }
+        let tree = self.tree.lock().unwrap();
+        let node = &tree.nodes[&path];
+        let entries = [(b".".to_vec(), path), (b"..".to_vec(), node.parent)];
+        let children = node.children.iter().map(|(n, p)| (n.clone(), *p));
+        let mut data = DirEntryData::new();
+        for (i, (name, child)) in entries.into_iter().chain(children).enumerate() {
+            if (i as u64) < req.offset {
+                continue;
+            }
+            let entry = DirEntry {
+                qid: tree.qid(child),
+                offset: i as u64 + 1,
+                typ: 0,
+                name: FcallStr::Owned(name),
+            };
+            if data.size() + entry.size() > req.count as u64 {
+                break;
+            }
+            data.push(entry);
+        }
+        resp.send(Rreaddir { data })
+    }
+
+    fn unlinkat(&mut self, req: &Tunlinkat, resp: FcallResponse) {
+        let (dir, _) = tri!(resp, self.node(req.dfid));
+        let mut tree = self.tree.lock().unwrap();
+        tri!(resp, tree.remove(dir, req.name.as_bytes()));
+        resp.send(Runlinkat {})
+    }
+
+    fn remove(&mut self, req: &Tremove, resp: FcallResponse) {
+        let fid = self.node(req.fid);
+        self.fids.remove(&req.fid);
+        let (path, _) = tri!(resp, fid);
+        let mut tree = self.tree.lock().unwrap();
+        let parent = tree.nodes[&path].parent;
+        let name = tree.nodes[&parent]
+            .children
+            .iter()
+            .find(|(_, p)| **p == path)
+            .map(|(n, _)| n.clone());
+        match name {
+            Some(name) => tri!(resp, tree.remove(parent, &name)),
+            None => return resp.send(err(errno::EBUSY)),
+        }
+        resp.send(Rremove {})
+    }
+
+    fn xattrwalk(&mut self, req: &Txattrwalk, resp: FcallResponse) {
+        let (path, _) = tri!(resp, self.node(req.fid));
+        tri!(resp, self.new_fid(req.new_fid));
+        let tree = self.tree.lock().unwrap();
+        let xattrs = &tree.nodes[&path].xattrs;
+        let data = if req.name.as_bytes().is_empty() {
+            let mut list = Vec::new();
+            for name in xattrs.keys() {
+                list.extend_from_slice(name);
+                list.push(0);
+            }
+            list
+        } else {
+            match xattrs.get(req.name.as_bytes()) {
+                Some(value) => value.clone(),
+                None => return resp.send(err(errno::ENODATA)),
+            }
+        };
+        let size = data.len() as u64;
+        self.fids.insert(req.new_fid, Fid::XattrRead(data));
+        resp.send(Rxattrwalk { size })
+    }
+
+    fn xattrcreate(&mut self, req: &Txattrcreate, resp: FcallResponse) {
+        let (path, _) = tri!(resp, self.node(req.fid));
+        let fid = Fid::XattrWrite {
+            path,
+            name: req.name.as_bytes().to_vec(),
+            data: Vec::new(),
+        };
+        self.fids.insert(req.fid, fid);
+        resp.send(Rxattrcreate {})
+    }
+
+    fn lock(&mut self, req: &Tlock, resp: FcallResponse) {
+        let (path, _) = tri!(resp, self.node(req.fid));
+        let mut tree = self.tree.lock().unwrap();
+        let client = req.flock.client_id.as_bytes();
+        if req.flock.typ != LockType::UNLOCK && tree.conflict(path, req.flock.typ, client).is_some()
+        {
+            return resp.send(Rlock {
+                status: LockStatus::BLOCKED,
+            });
+        }
+        let locks = &mut tree.nodes.get_mut(&path).unwrap().locks;
+        locks.retain(|(holder, _)| holder != client);
+        if req.flock.typ != LockType::UNLOCK {
+            locks.push((client.to_vec(), req.flock.typ));
+        }
+        resp.send(Rlock {
+            status: LockStatus::SUCCESS,
+        })
+    }
+
+    fn getlock(&mut self, req: &Tgetlock, resp: FcallResponse) {
+        let (path, _) = tri!(resp, self.node(req.fid));
+        let tree = self.tree.lock().unwrap();
+        let client = req.flock.client_id.as_bytes();
+        let (typ, client_id) = match tree.conflict(path, req.flock.typ, client) {
+            Some((holder, typ)) => (*typ, holder.clone()),
+            None => (LockType::UNLOCK, client.to_vec()),
+        };
+        let flock = Getlock {
+            typ,
+            start: req.flock.start,
+            length: req.flock.length,
+            proc_id: req.flock.proc_id,
+            client_id: FcallStr::Owned(client_id),
+        };
+        resp.send(Rgetlock { flock })
+    }
+
+    fn flush(&mut self, _req: &Tflush, resp: FcallResponse) {
+        // Requests are answered in order, so any flushed one already was.
+        resp.send(Rflush {})
+    }
+
+    fn clunk(&mut self, req: &Tclunk, resp: FcallResponse) {
+        match self.fids.remove(&req.fid) {
+            Some(Fid::XattrWrite { path, name, data }) => {
+                let mut tree = self.tree.lock().unwrap();
+                let xattrs = &mut tree.nodes.get_mut(&path).unwrap().xattrs;
+                xattrs.insert(name, data);
+            }
+            Some(_) => (),
+            None => return resp.send(err(errno::EBADF)),
+        }
+        resp.send(Rclunk {})
+    }
+}
+
+// Serve a fresh tree on a unix socket, returning its address.
+fn spawn_server(name: &str, partial_walk_errors: bool) -> String {
+    let path = std::env::temp_dir().join(format!(
+        "p9conformance-test.{}.{}.sock",
+        std::process::id(),
+        name
+    ));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    let tree = Arc::new(Mutex::new(Tree::new()));
+    std::thread::spawn(move || {
+        for conn in listener.incoming() {
+            let mut fs = MemFs {
+                tree: tree.clone(),
+                fids: HashMap::new(),
+                partial_walk_errors,
+            };
+            std::thread::spawn(move || serve_unix(conn.unwrap(), &mut fs, 8192));
+        }
+    });
+    format!("unix:{}", path.display())
+}
+
+fn target(addr: &str) -> Target {
+    let mut target = Target::new(addr);
+    target.uname = "root".to_string();
+    target
+}
+
+#[test]
+fn conforming_server() {
+    let target = target(&spawn_server("conforming", false));
+    let checks: Vec<&Check> = CHECKS.iter().collect();
+    let report = run(&target, &checks);
+    for r in &report.results {
+        match r.name {
+            // serve_transport hangs up on a second Tversion.
+            "version.renegotiate" => assert!(r.result.is_err()),
+            _ => assert_eq!(r.result, Ok(()), "{}", r.name),
+        }
+    }
+    assert_eq!(report.failed(), 1);
+    let json = report.to_json();
+    assert_eq!(json["passed"], CHECKS.len() - 1);
+    assert_eq!(json["results"][0]["name"], "version.negotiate");
+    assert!(report.to_string().ends_with("passed, 1 failed, 0 skipped"));
+}
+
+#[test]
+fn broken_server() {
+    let target = target(&spawn_server("broken", true));
+    let checks: Vec<&Check> = CHECKS
+        .iter()
+        .filter(|c| c.name.starts_with("walk."))
+        .collect();
+    let report = run(&target, &checks);
+    match report.result("walk.partial") {
+        Some(Err(Failure::Fail(msg))) => assert!(msg.contains("rather than returning Rwalk")),
+        result => panic!("walk.partial: {:?}", result),
+    }
+    assert_eq!(report.failed(), 1);
+    assert_eq!(report.results.len(), checks.len());
+}
@@ -0,0 +1,186 @@
+// The subset of the FUSE kernel protocol (linux/fuse.h) p9fuse speaks.
+//
+// Messages use host byte order and are laid out as the C structs, so they
+// are encoded field by field rather than transmuted.
+
+pub const KERNEL_VERSION: u32 = 7;
+pub const KERNEL_MINOR_VERSION: u32 = 31;
+/// The kernel refuses reads into smaller buffers.
+pub const MIN_READ_BUFFER: usize = 8192;
+
+pub const FUSE_LOOKUP: u32 = 1;
+pub const FUSE_FORGET: u32 = 2;
+pub const FUSE_GETATTR: u32 = 3;
+pub const FUSE_SETATTR: u32 = 4;
+pub const FUSE_READLINK: u32 = 5;
+pub const FUSE_SYMLINK: u32 = 6;
+pub const FUSE_MKNOD: u32 = 8;
+pub const FUSE_MKDIR: u32 = 9;
+pub const FUSE_UNLINK: u32 = 10;
+pub const FUSE_RMDIR: u32 = 11;
+pub const FUSE_RENAME: u32 = 12;
+pub const FUSE_LINK: u32 = 13;
+pub const FUSE_OPEN: u32 = 14;
+pub const FUSE_READ: u32 = 15;
+pub const FUSE_WRITE: u32 = 16;
+pub const FUSE_STATFS: u32 = 17;
+pub const FUSE_RELEASE: u32 = 18;
+pub const FUSE_FSYNC: u32 = 20;
+pub const FUSE_SETXATTR: u32 = 21;
+pub const FUSE_GETXATTR: u32 = 22;
+pub const FUSE_LISTXATTR: u32 = 23;
+pub const FUSE_REMOVEXATTR: u32 = 24;
+pub const FUSE_FLUSH: u32 = 25;
+pub const FUSE_INIT: u32 = 26;
+pub const FUSE_OPENDIR: u32 = 27;
+pub const FUSE_READDIR: u32 = 28;
+pub const FUSE_RELEASEDIR: u32 = 29;
+pub const FUSE_FSYNCDIR: u32 = 30;
+pub const FUSE_GETLK: u32 = 31;
+pub const FUSE_SETLK: u32 = 32;
+pub const FUSE_SETLKW: u32 = 33;
+pub const FUSE_CREATE: u32 = 35;
+pub const FUSE_INTERRUPT: u32 = 36;
+pub const FUSE_DESTROY: u32 = 38;
+pub const FUSE_BATCH_FORGET: u32 = 42;
+
+// Init flags.
+pub const FUSE_ASYNC_READ: u32 = 1 << 0;
+pub const FUSE_POSIX_LOCKS: u32 = 1 << 1;
+pub const FUSE_ATOMIC_O_TRUNC: u32 = 1 << 3;
+pub const FUSE_BIG_WRITES: u32 = 1 << 5;
+
+// Setattr valid bits.
+pub const FATTR_MODE: u32 = 1 << 0;
+pub const FATTR_UID: u32 = 1 << 1;
+pub const FATTR_GID: u32 = 1 << 2;
+pub const FATTR_SIZE: u32 = 1 << 3;
+pub const FATTR_ATIME: u32 = 1 << 4;
+pub const FATTR_MTIME: u32 = 1 << 5;
+pub const FATTR_FH: u32 = 1 << 6;
+pub const FATTR_ATIME_NOW: u32 = 1 << 7;
+pub const FATTR_MTIME_NOW: u32 = 1 << 8;
+
+// Lock flags.
+pub const FUSE_LK_FLOCK: u32 = 1 << 0;
+
+pub const IN_HEADER_SIZE: usize = 40;
+pub const OUT_HEADER_SIZE: usize = 16;
+pub const DIRENT_SIZE: usize = 24;
+
+/// fuse_in_header
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InHeader {
+    pub len: u32,
+    pub opcode: u32,
+    pub unique: u64,
+    pub nodeid: u64,
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: u32,
+}
+
+/// Decodes the fields of a request in order.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf }
+    }
+
+    pub fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < n {
+            return None;
+        }
+        let (b, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Some(b)
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_ne_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_ne_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// A nul terminated name, without the nul.
+    pub fn name(&mut self) -> Option<&'a [u8]> {
+        let end = self.buf.iter().position(|b| *b == 0)?;
+        let name = &self.buf[..end];
+        self.buf = &self.buf[end + 1..];
+        Some(name)
+    }
+
+    /// Everything left.
+    pub fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.buf)
+    }
+
+    pub fn header(&mut self) -> Option<InHeader> {
+        let header = InHeader {
+            len: self.u32()?,
+            opcode: self.u32()?,
+            unique: self.u64()?,
+            nodeid: self.u64()?,
+            uid: self.u32()?,
+            gid: self.u32()?,
+            pid: self.u32()?,
+        };
+        self.u32()?;
+        Some(header)
+    }
+}
+
+/// Encodes the fields of a reply in order.
+#[derive(Default)]
+pub struct Writer {
+    pub buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Writer {
+        Writer::default()
+    }
+
+    pub fn u16(&mut self, v: u16) -> &mut Writer {
+        self.buf.extend_from_slice(&v.to_ne_bytes());
+        self
+    }
+
+    pub fn u32(&mut self, v: u32) -> &mut Writer {
+        self.buf.extend_from_slice(&v.to_ne_bytes());
+        self
+    }
+
+    pub fn u64(&mut self, v: u64) -> &mut Writer {
+        self.buf.extend_from_slice(&v.to_ne_bytes());
+        self
+    }
+
+    pub fn bytes(&mut self, b: &[u8]) -> &mut Writer {
+        self.buf.extend_from_slice(b);
+        self
+    }
+
+    /// Pad with zeros to a multiple of 8 bytes.
+    pub fn align(&mut self) -> &mut Writer {
+        while !self.buf.len().is_multiple_of(8) {
+            self.buf.push(0);
+        }
+        self
+    }
+}
+
+/// Prefix a reply body with fuse_out_header, error is a negated errno.
+pub fn reply(unique: u64, error: i32, body: &[u8]) -> Vec<u8> {
+    let mut w = Writer::new();
+    w.u32((OUT_HEADER_SIZE + body.len()) as u32)
+        .u32(error as u32)
+        .u64(unique)
+        .bytes(body);
+    w.buf
+}
@@ -0,0 +1,573 @@
+use nix::libc;
+use p92000l::{
+    errno, Client, ClientFid, DirEntry, Flock, GetattrMask, Getlock, LOpenFlags, LockFlag,
+    LockStatus, LockType, Qid, Rgetattr, SetAttr, SetattrMask, Statfs,
+};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// The FUSE node id of the mount's root.
+pub const ROOT_ID: u64 = 1;
+
+// Sent as the client id of every lock request.
+const LOCK_CLIENT_ID: &str = "p9fuse";
+
+// How often a waiting lock asks the server again.
+const LOCK_POLL: Duration = Duration::from_millis(100);
+
+// Interrupts kept for requests not seen yet.
+const EARLY_INTERRUPTS: usize = 64;
+
+// Unlinkat flag for removing directories.
+const AT_REMOVEDIR: u32 = 0x200;
+
+/// Failures are host errnos, ready to hand back to the kernel.
+pub type Result<T> = std::result::Result<T, i32>;
+
+fn host_errno(err: std::io::Error) -> i32 {
+    errno::host_errno(errno::errno_from_io_error(&err)).unwrap_or(libc::EIO)
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FuseOptions {
+    /// How long the kernel may cache name lookups.
+    pub entry_timeout: Duration,
+    /// How long the kernel may cache file attributes.
+    pub attr_timeout: Duration,
+}
+
+impl Default for FuseOptions {
+    fn default() -> FuseOptions {
+        FuseOptions {
+            entry_timeout: Duration::from_secs(1),
+            attr_timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+/// The answer to a lookup or anything creating a name.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub ino: u64,
+    pub attr: Rgetattr,
+}
+
+/// A byte range lock, end is inclusive as in FUSE.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Lock {
+    /// F_RDLCK, F_WRLCK or F_UNLCK.
+    pub typ: u32,
+    pub start: u64,
+    pub end: u64,
+    pub pid: u32,
+}
+
+/// The answer to an xattr read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Xattr {
+    /// Only the length, when asked for with a size of zero.
+    Len(u64),
+    Data(Vec<u8>),
+}
+
+// FUSE ranges end at OFFSET_MAX, 9p lengths of zero mean the rest of the
+// file.
+fn lock_length(lock: &Lock) -> u64 {
+    if lock.end >= i64::MAX as u64 {
+        0
+    } else {
+        lock.end.saturating_sub(lock.start) + 1
+    }
+}
+
+fn lock_end(start: u64, length: u64) -> u64 {
+    if length == 0 {
+        i64::MAX as u64
+    } else {
+        start + length - 1
+    }
+}
+
+fn lock_type(typ: u32) -> Result<LockType> {
+    match typ as i32 {
+        libc::F_RDLCK => Ok(LockType::RDLOCK),
+        libc::F_WRLCK => Ok(LockType::WRLOCK),
+        libc::F_UNLCK => Ok(LockType::UNLOCK),
+        _ => Err(libc::EINVAL),
+    }
+}
+
+struct Inode {
+    fid: Arc<ClientFid>,
+    path: u64,
+    lookups: u64,
+}
+
+// Node ids handed to the kernel, each holding a fid walked to the file. A
+// file reached by several names shares one node, found by its Qid.path.
+struct Inodes {
+    nodes: HashMap<u64, Inode>,
+    by_path: HashMap<u64, u64>,
+    next: u64,
+}
+
+struct Handles {
+    open: HashMap<u64, Arc<ClientFid>>,
+    next: u64,
+}
+
+// FUSE_SETLKW requests waiting for a lock, by their unique.
+#[derive(Default)]
+struct Waiting {
+    interrupted: HashMap<u64, bool>,
+    // An interrupt can be read before the request it names.
+    early: VecDeque<u64>,
+}
+
+/// Serves FUSE requests from a 9p export, one fid per node the kernel
+/// knows and one per open file.
+pub struct P9Fuse {
+    client: Client,
+    opts: FuseOptions,
+    inodes: Mutex<Inodes>,
+    handles: Mutex<Handles>,
+    waiting: Mutex<Waiting>,
+    woken: Condvar,
+}
+
+impl P9Fuse {
+    /// Serve the tree under root, an attached or walked fid.
+    pub fn new(client: Client, root: ClientFid, opts: FuseOptions) -> Result<P9Fuse> {
+        let qid = root.getattr(GetattrMask::INO).map_err(host_errno)?.qid;
+        let mut nodes = HashMap::new();
+        nodes.insert(
+            ROOT_ID,
+            Inode {
+                fid: Arc::new(root),
+                path: qid.path,
+                // Never forgotten.
+                lookups: u64::MAX,
+            },
+        );
+        let mut by_path = HashMap::new();
+        by_path.insert(qid.path, ROOT_ID);
+        Ok(P9Fuse {
+            client,
+            opts,
+            inodes: Mutex::new(Inodes {
+                nodes,
+                by_path,
+                next: ROOT_ID + 1,
+            }),
+            handles: Mutex::new(Handles {
+                open: HashMap::new(),
+                next: 1,
+            }),
+            waiting: Mutex::new(Waiting::default()),
+            woken: Condvar::new(),
+        })
+    }
+
+    pub fn options(&self) -> &FuseOptions {
+        &self.opts
+    }
+
+    /// The largest read or write the export handles in one request.
+    pub fn iounit(&self) -> u32 {
+        self.client.iounit()
+    }
+
+    fn fid(&self, ino: u64) -> Result<Arc<ClientFid>> {
+        let inodes = self.inodes.lock().unwrap();
+        match inodes.nodes.get(&ino) {
+            Some(inode) => Ok(inode.fid.clone()),
+            None => Err(libc::ESTALE),
+        }
+    }
+
+    fn handle(&self, fh: u64) -> Result<Arc<ClientFid>> {
+        match self.handles.lock().unwrap().open.get(&fh) {
+            Some(fid) => Ok(fid.clone()),
+            None => Err(libc::EBADF),
+        }
+    }
+
+    fn add_handle(&self, fid: ClientFid) -> u64 {
+        let mut handles = self.handles.lock().unwrap();
+        let fh = handles.next;
+        handles.next += 1;
+        handles.open.insert(fh, Arc::new(fid));
+        fh
+    }
+
+    // A fresh fid for the same file as ino, for opening.
+    fn clone_fid(&self, ino: u64) -> Result<ClientFid> {
+        let fid = self.fid(ino)?;
+        let (_, fid) = fid.walk::<&str>(&[]).map_err(host_errno)?;
+        Ok(fid)
+    }
+
+    // Record a lookup of a file reached by fid, reusing the node of a file
+    // already known.
+    fn remember(&self, qid: Qid, fid: ClientFid) -> u64 {
+        let mut inodes = self.inodes.lock().unwrap();
+        if let Some(ino) = inodes.by_path.get(&qid.path).copied() {
+            let inode = inodes.nodes.get_mut(&ino).unwrap();
+            inode.lookups = inode.lookups.saturating_add(1);
+            // Drop the duplicate fid after unlocking.
+            drop(inodes);
+            drop(fid);
+            return ino;
+        }
+        let ino = inodes.next;
+        inodes.next += 1;
+        inodes.by_path.insert(qid.path, ino);
+        inodes.nodes.insert(
+            ino,
+            Inode {
+                fid: Arc::new(fid),
+                path: qid.path,
+                lookups: 1,
+            },
+        );
+        ino
+    }
+
+    pub fn lookup(&self, parent: u64, name: &[u8]) -> Result<Entry> {
+        let dir = self.fid(parent)?;
+        let (_, fid) = dir.walk(&[name]).map_err(host_errno)?;
+        let attr = fid.getattr(GetattrMask::ALL).map_err(host_errno)?;
+        let ino = self.remember(attr.qid, fid);
+        Ok(Entry { ino, attr })
+    }
+
+    /// The kernel dropped n references to a node.
+    pub fn forget(&self, ino: u64, n: u64) {
+        let mut inodes = self.inodes.lock().unwrap();
+        let gone = match inodes.nodes.get_mut(&ino) {
+            Some(inode) if ino != ROOT_ID => {
+                inode.lookups = inode.lookups.saturating_sub(n);
+                inode.lookups == 0
+            }
+            _ => false,
+        };
+        if gone {
+            let inode = inodes.nodes.remove(&ino).unwrap();
+            inodes.by_path.remove(&inode.path);
+            // Clunks once any request using the fid finishes.
+            drop(inodes);
+            drop(inode);
+        }
+    }
+
+    /// Node ids currently known, including the root.
+    pub fn nodes(&self) -> usize {
+        self.inodes.lock().unwrap().nodes.len()
+    }
+
+    pub fn getattr(&self, ino: u64) -> Result<Rgetattr> {
+        self.fid(ino)?.getattr(GetattrMask::ALL).map_err(host_errno)
+    }
+
+    /// Change attributes through the open file fh if given, as ftruncate
+    /// needs, otherwise through the node.
+    pub fn setattr(
+        &self,
+        ino: u64,
+        fh: Option<u64>,
+        valid: SetattrMask,
+        stat: SetAttr,
+    ) -> Result<Rgetattr> {
+        let fid = match fh {
+            Some(fh) => self.handle(fh)?,
+            None => self.fid(ino)?,
+        };
+        if !valid.is_empty() {
+            fid.setattr(valid, stat).map_err(host_errno)?;
+        }
+        fid.getattr(GetattrMask::ALL).map_err(host_errno)
+    }
+
+    pub fn readlink(&self, ino: u64) -> Result<Vec<u8>> {
+        let target = self.fid(ino)?.readlink().map_err(host_errno)?;
+        Ok(target.as_bytes().to_vec())
+    }
+
+    pub fn mknod(&self, parent: u64, name: &[u8], mode: u32, rdev: u32, gid: u32) -> Result<Entry> {
+        let dir = self.fid(parent)?;
+        let (major, minor) = (libc::major(rdev as _), libc::minor(rdev as _));
+        dir.mknod(name, mode, major, minor, gid)
+            .map_err(host_errno)?;
+        self.lookup(parent, name)
+    }
+
+    pub fn mkdir(&self, parent: u64, name: &[u8], mode: u32, gid: u32) -> Result<Entry> {
+        let dir = self.fid(parent)?;
+        dir.mkdir(name, mode, gid).map_err(host_errno)?;
+        self.lookup(parent, name)
+    }
+
+    pub fn symlink(&self, parent: u64, name: &[u8], target: &[u8], gid: u32) -> Result<Entry> {
+        let dir = self.fid(parent)?;
+        dir.symlink(name, target, gid).map_err(host_errno)?;
+        self.lookup(parent, name)
+    }
+
+    pub fn link(&self, ino: u64, new_parent: u64, new_name: &[u8]) -> Result<Entry> {
+        let fid = self.fid(ino)?;
+        let dir = self.fid(new_parent)?;
+        fid.link(&dir, new_name).map_err(host_errno)?;
+        self.lookup(new_parent, new_name)
+    }
+
+    pub fn unlink(&self, parent: u64, name: &[u8]) -> Result<()> {
+        self.fid(parent)?.unlinkat(name, 0).map_err(host_errno)
+    }
+
+    pub fn rmdir(&self, parent: u64, name: &[u8]) -> Result<()> {
+        self.fid(parent)?
+            .unlinkat(name, AT_REMOVEDIR)
+            .map_err(host_errno)
+    }
+
+    pub fn rename(&self, parent: u64, name: &[u8], new_parent: u64, new_name: &[u8]) -> Result<()> {
+        let dir = self.fid(parent)?;
+        let new_dir = self.fid(new_parent)?;
+        dir.renameat(name, &new_dir, new_name).map_err(host_errno)
+    }
+
+    /// Open a file or directory with Linux open flags, returning a handle.
+    pub fn open(&self, ino: u64, flags: u32) -> Result<u64> {
+        let fid = self.clone_fid(ino)?;
+        fid.open(LOpenFlags::from_bits_truncate(flags))
+            .map_err(host_errno)?;
+        Ok(self.add_handle(fid))
+    }
+
+    pub fn create(
+        &self,
+        parent: u64,
+        name: &[u8],
+        flags: u32,
+        mode: u32,
+        gid: u32,
+    ) -> Result<(Entry, u64)> {
+        // Tlcreate turns the directory fid into one for the open file.
+        let fid = self.clone_fid(parent)?;
+        fid.create(name, LOpenFlags::from_bits_truncate(flags), mode, gid)
+            .map_err(host_errno)?;
+        let fh = self.add_handle(fid);
+        match self.lookup(parent, name) {
+            Ok(entry) => Ok((entry, fh)),
+            Err(err) => {
+                self.release(fh);
+                Err(err)
+            }
+        }
+    }
+
+    /// Read up to size bytes, short only at the end of the file.
+    pub fn read(&self, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>> {
+        let fid = self.handle(fh)?;
+        let mut buf = vec![0; size as usize];
+        let mut n = 0;
+        while n < buf.len() {
+            match fid.read(offset + n as u64, &mut buf[n..]) {
+                Ok(0) => break,
+                Ok(count) => n += count,
+                Err(err) if n == 0 => return Err(host_errno(err)),
+                Err(_) => break,
+            }
+        }
+        buf.truncate(n);
+        Ok(buf)
+    }
+
+    pub fn write(&self, fh: u64, offset: u64, data: &[u8]) -> Result<u32> {
+        let fid = self.handle(fh)?;
+        let mut n = 0;
+        while n < data.len() {
+            match fid.write(offset + n as u64, &data[n..]) {
+                Ok(0) => break,
+                Ok(count) => n += count,
+                Err(err) if n == 0 => return Err(host_errno(err)),
+                Err(_) => break,
+            }
+        }
+        Ok(n as u32)
+    }
+
+    pub fn fsync(&self, fh: u64) -> Result<()> {
+        self.handle(fh)?.fsync().map_err(host_errno)
+    }
+
+    /// Close a handle from open or create.
+    pub fn release(&self, fh: u64) {
+        let fid = self.handles.lock().unwrap().open.remove(&fh);
+        drop(fid);
+    }
+
+    /// Directory entries after offset, as returned by the server.
+    pub fn readdir(&self, fh: u64, offset: u64) -> Result<Vec<DirEntry<'static>>> {
+        self.handle(fh)?.read_dir1(offset).map_err(host_errno)
+    }
+
+    pub fn statfs(&self, ino: u64) -> Result<Statfs> {
+        self.fid(ino)?.statfs().map_err(host_errno)
+    }
+
+    // Read all of an xattr fid of length len into at most size bytes, a
+    // size of zero asks for just the length.
+    fn read_xattr(fid: ClientFid, len: u64, size: u32) -> Result<Xattr> {
+        if size == 0 {
+            return Ok(Xattr::Len(len));
+        }
+        if len > size as u64 {
+            return Err(libc::ERANGE);
+        }
+        let mut buf = vec![0; len as usize];
+        let mut n = 0;
+        while n < buf.len() {
+            match fid.read(n as u64, &mut buf[n..]).map_err(host_errno)? {
+                0 => break,
+                count => n += count,
+            }
+        }
+        buf.truncate(n);
+        Ok(Xattr::Data(buf))
+    }
+
+    /// An xattr value of at most size bytes, ERANGE if it is bigger.
+    pub fn getxattr(&self, ino: u64, name: &[u8], size: u32) -> Result<Xattr> {
+        let (len, fid) = self.fid(ino)?.xattrwalk(name).map_err(host_errno)?;
+        P9Fuse::read_xattr(fid, len, size)
+    }
+
+    /// Attribute names, each followed by a nul, like getxattr.
+    pub fn listxattr(&self, ino: u64, size: u32) -> Result<Xattr> {
+        let (len, fid) = self.fid(ino)?.xattrwalk("").map_err(host_errno)?;
+        P9Fuse::read_xattr(fid, len, size)
+    }
+
+    pub fn setxattr(&self, ino: u64, name: &[u8], value: &[u8], flags: u32) -> Result<()> {
+        let fid = self.clone_fid(ino)?;
+        fid.xattrcreate(name, value.len() as u64, flags)
+            .map_err(host_errno)?;
+        let mut n = 0;
+        while n < value.len() {
+            match fid.write(n as u64, &value[n..]).map_err(host_errno)? {
+                0 => return Err(libc::EIO),
+                count => n += count,
+            }
+        }
+        // The attribute is set by the clunk.
+        fid.clunk().map_err(host_errno)
+    }
+
+    pub fn removexattr(&self, ino: u64, name: &[u8]) -> Result<()> {
+        let fid = self.clone_fid(ino)?;
+        fid.xattrcreate(name, 0, 0).map_err(host_errno)?;
+        fid.clunk().map_err(host_errno)
+    }
+
+    /// The first lock conflicting with lock, or lock with typ F_UNLCK.
+    pub fn getlk(&self, fh: u64, lock: Lock) -> Result<Lock> {
+        let fid = self.handle(fh)?;
+        let got = fid
+            .getlock(Getlock {
+                typ: lock_type(lock.typ)?,
+                start: lock.start,
+                length: lock_length(&lock),
+                proc_id: lock.pid,
+                client_id: LOCK_CLIENT_ID.into(),
+            })
+            .map_err(host_errno)?;
+        let typ = if got.typ == LockType::WRLOCK {
+            libc::F_WRLCK
+        } else if got.typ == LockType::RDLOCK {
+            libc::F_RDLCK
+        } else {
+            libc::F_UNLCK
+        };
+        Ok(Lock {
+            typ: typ as u32,
+            start: got.start,
+            end: lock_end(got.start, got.length),
+            pid: got.proc_id,
+        })
+    }
+
+    /// Take or release a lock. Given the unique of a FUSE_SETLKW request,
+    /// wait for conflicting locks until it is interrupted.
+    pub fn setlk(&self, fh: u64, lock: Lock, unique: Option<u64>) -> Result<()> {
+        let fid = self.handle(fh)?;
+        let flock = Flock {
+            typ: lock_type(lock.typ)?,
+            flags: if unique.is_some() {
+                LockFlag::BLOCK
+            } else {
+                LockFlag::empty()
+            },
+            start: lock.start,
+            length: lock_length(&lock),
+            proc_id: lock.pid,
+            client_id: LOCK_CLIENT_ID.into(),
+        };
+        if let Some(unique) = unique {
+            let mut waiting = self.waiting.lock().unwrap();
+            let early = match waiting.early.iter().position(|&u| u == unique) {
+                Some(i) => waiting.early.remove(i).is_some(),
+                None => false,
+            };
+            waiting.interrupted.insert(unique, early);
+        }
+        let result = self.poll_lock(&fid, flock, unique);
+        if let Some(unique) = unique {
+            self.waiting.lock().unwrap().interrupted.remove(&unique);
+        }
+        result
+    }
+
+    fn poll_lock(&self, fid: &ClientFid, flock: Flock, unique: Option<u64>) -> Result<()> {
+        loop {
+            let status = fid.lock(flock.clone()).map_err(host_errno)?;
+            if status == LockStatus::SUCCESS {
+                return Ok(());
+            }
+            if status == LockStatus::ERROR {
+                return Err(libc::ENOLCK);
+            }
+            let unique = match unique {
+                Some(unique) => unique,
+                None => return Err(libc::EAGAIN),
+            };
+            // Servers answer blocked rather than waiting, as v9fs does poll.
+            let waiting = self.waiting.lock().unwrap();
+            let (waiting, _) = self
+                .woken
+                .wait_timeout_while(waiting, LOCK_POLL, |waiting| !waiting.interrupted[&unique])
+                .unwrap();
+            if waiting.interrupted[&unique] {
+                return Err(libc::EINTR);
+            }
+        }
+    }
+
+    /// Stop the FUSE_SETLKW request unique waiting, it returns EINTR.
+    pub fn interrupt(&self, unique: u64) {
+        let mut waiting = self.waiting.lock().unwrap();
+        match waiting.interrupted.get_mut(&unique) {
+            Some(interrupted) => *interrupted = true,
+            None => {
+                // Most likely a request that finishes on its own.
+                if waiting.early.len() == EARLY_INTERRUPTS {
+                    waiting.early.pop_front();
+                }
+                waiting.early.push_back(unique);
+            }
+        }
+        drop(waiting);
+        self.woken.notify_all();
+    }
+}
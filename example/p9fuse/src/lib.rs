@@ -0,0 +1,6 @@
+pub mod abi;
+pub mod fs;
+pub mod session;
+
+pub use fs::{Entry, FuseOptions, Lock, P9Fuse, Xattr, ROOT_ID};
+pub use session::{handle, mount, serve, unmount};
@@ -0,0 +1,155 @@
+use log::{error, info};
+use p92000l::ClientBuilder;
+use p9fuse::{FuseOptions, P9Fuse};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+fn usage(program: &str, opts: getopts::Options) {
+    let brief = format!(
+        "p9fuse - Mount a 9p2000.L export with FUSE.\n\n\
+        Usage: {} [options] ADDR MOUNTPOINT\n\n\
+        ADDR is tcp:HOST:PORT or unix:PATH, unmount MOUNTPOINT to exit.",
+        program
+    );
+    print!("{}", opts.usage(&brief));
+    std::process::exit(1);
+}
+
+fn fatal(program: &str, msg: impl std::fmt::Display) -> ! {
+    eprintln!("{}: {}", program, msg);
+    std::process::exit(1)
+}
+
+fn parse_opt<T: std::str::FromStr>(
+    program: &str,
+    matches: &getopts::Matches,
+    name: &str,
+    default: T,
+) -> T {
+    match matches.opt_str(name) {
+        Some(v) => match v.parse() {
+            Ok(v) => v,
+            Err(_) => fatal(program, format!("invalid --{} value {:?}", name, v)),
+        },
+        None => default,
+    }
+}
+
+fn main() {
+    let mut log_builder = env_logger::Builder::new();
+    log_builder.filter_level(log::LevelFilter::Info);
+    log_builder.parse_env("P9FUSE_LOG");
+    log_builder.init();
+
+    let args: Vec<String> = std::env::args().collect();
+    let program = args[0].clone();
+
+    let mut opts = getopts::Options::new();
+    opts.optopt(
+        "u",
+        "uname",
+        "Attach as this user (default $USER).",
+        "UNAME",
+    );
+    opts.optopt(
+        "a",
+        "aname",
+        "Attach to this tree (default empty).",
+        "ANAME",
+    );
+    opts.optopt(
+        "n",
+        "n-uname",
+        "Attach as this numeric user (default our uid).",
+        "UID",
+    );
+    opts.optopt(
+        "",
+        "msize",
+        "Negotiate at most this message size (default 131072).",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "entry-timeout",
+        "Let the kernel cache name lookups for this many seconds (default 1).",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "attr-timeout",
+        "Let the kernel cache file attributes for this many seconds (default 1).",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "threads",
+        "Serve this many kernel requests at once (default 4).",
+        "N",
+    );
+    opts.optflag("", "allow-other", "Let other users access the mount.");
+    opts.optflag("h", "help", "print this help menu");
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(err) => fatal(&program, err),
+    };
+
+    if matches.opt_present("h") || matches.free.len() != 2 {
+        usage(&program, opts);
+    }
+
+    let addr = &matches.free[0];
+    let mountpoint = Path::new(&matches.free[1]);
+    let uname = matches
+        .opt_str("uname")
+        .or_else(|| std::env::var("USER").ok())
+        .unwrap_or_default();
+    let aname = matches.opt_str("aname").unwrap_or_default();
+    let n_uname = parse_opt(
+        &program,
+        &matches,
+        "n-uname",
+        nix::unistd::getuid().as_raw(),
+    );
+    let msize = parse_opt(&program, &matches, "msize", 128 * 1024);
+    let fuse_opts = FuseOptions {
+        entry_timeout: Duration::from_secs_f64(parse_opt(&program, &matches, "entry-timeout", 1.0)),
+        attr_timeout: Duration::from_secs_f64(parse_opt(&program, &matches, "attr-timeout", 1.0)),
+    };
+    let threads = parse_opt(&program, &matches, "threads", 4);
+
+    let client = match ClientBuilder::new().msize(msize).dial(addr) {
+        Ok(client) => client,
+        Err(err) => fatal(&program, format!("unable to connect to {}: {}", addr, err)),
+    };
+    let (_, root) = match client.attach(n_uname, uname.as_str(), aname.as_str()) {
+        Ok(attached) => attached,
+        Err(err) => fatal(&program, format!("attach failed: {}", err)),
+    };
+    let fs = match P9Fuse::new(client, root, fuse_opts) {
+        Ok(fs) => Arc::new(fs),
+        Err(errno) => fatal(&program, std::io::Error::from_raw_os_error(errno)),
+    };
+
+    let mount_opts = if matches.opt_present("allow-other") {
+        "allow_other"
+    } else {
+        ""
+    };
+    let dev = match p9fuse::mount(mountpoint, mount_opts) {
+        Ok(dev) => dev,
+        Err(err) => fatal(
+            &program,
+            format!("unable to mount {}: {}", mountpoint.display(), err),
+        ),
+    };
+    info!("serving {} on {}", addr, mountpoint.display());
+
+    if let Err(err) = p9fuse::serve(fs, dev, threads) {
+        error!("fuse session failed: {}", err);
+        let _ = p9fuse::unmount(mountpoint);
+        std::process::exit(1);
+    }
+    info!("unmounted {}", mountpoint.display());
+}
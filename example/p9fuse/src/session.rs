@@ -0,0 +1,468 @@
+use crate::abi::*;
+use crate::fs::{Entry, Lock, P9Fuse, Result, Xattr};
+use log::{debug, warn};
+use nix::libc;
+use p92000l::{Rgetattr, SetAttr, SetattrMask, Time};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+fn nix_error(err: nix::Error) -> std::io::Error {
+    match err {
+        nix::Error::Sys(errno) => std::io::Error::from_raw_os_error(errno as i32),
+        err => std::io::Error::other(err),
+    }
+}
+
+/// Mount a FUSE filesystem at mountpoint, returning the /dev/fuse
+/// connection to serve it on. Needs CAP_SYS_ADMIN, options are extra
+/// comma separated mount options such as allow_other.
+pub fn mount(mountpoint: &Path, options: &str) -> std::io::Result<File> {
+    let dev = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/fuse")?;
+    let rootmode = std::fs::metadata(mountpoint)?.mode() & libc::S_IFMT;
+    let mut data = format!(
+        "fd={},rootmode={:o},user_id={},group_id={},default_permissions",
+        dev.as_raw_fd(),
+        rootmode,
+        nix::unistd::getuid(),
+        nix::unistd::getgid()
+    );
+    if !options.is_empty() {
+        data.push(',');
+        data.push_str(options);
+    }
+    nix::mount::mount(
+        Some("p9fuse"),
+        mountpoint,
+        Some("fuse.p9fuse"),
+        nix::mount::MsFlags::MS_NOSUID | nix::mount::MsFlags::MS_NODEV,
+        Some(data.as_str()),
+    )
+    .map_err(nix_error)?;
+    Ok(dev)
+}
+
+pub fn unmount(mountpoint: &Path) -> std::io::Result<()> {
+    nix::mount::umount2(mountpoint, nix::mount::MntFlags::MNT_DETACH).map_err(nix_error)
+}
+
+/// Serve requests from dev on threads threads until the filesystem is
+/// unmounted.
+pub fn serve(fs: Arc<P9Fuse>, dev: File, threads: usize) -> std::io::Result<()> {
+    let bufsize = (fs.iounit() as usize + MIN_READ_BUFFER).max(MIN_READ_BUFFER);
+    let mut workers = Vec::new();
+    for _ in 0..threads.max(1) {
+        let fs = fs.clone();
+        let dev = dev.try_clone()?;
+        workers.push(std::thread::spawn(move || serve_one(&fs, dev, bufsize)));
+    }
+    let mut result = Ok(());
+    for worker in workers {
+        let worker_result = worker.join().unwrap();
+        if result.is_ok() {
+            result = worker_result;
+        }
+    }
+    result
+}
+
+fn serve_one(fs: &P9Fuse, mut dev: File, bufsize: usize) -> std::io::Result<()> {
+    let mut buf = vec![0; bufsize];
+    loop {
+        let n = match dev.read(&mut buf) {
+            Ok(n) => n,
+            Err(err) => match err.raw_os_error() {
+                // The request was interrupted before we read it.
+                Some(libc::ENOENT) | Some(libc::EINTR) | Some(libc::EAGAIN) => continue,
+                Some(libc::ENODEV) => return Ok(()),
+                _ => return Err(err),
+            },
+        };
+        let destroy = Reader::new(&buf[..n])
+            .header()
+            .map(|header| header.opcode == FUSE_DESTROY)
+            .unwrap_or(false);
+        if let Some(reply) = handle(fs, &buf[..n]) {
+            match dev.write(&reply) {
+                Ok(_) => (),
+                // The request was interrupted while we answered it.
+                Err(err) if err.raw_os_error() == Some(libc::ENOENT) => (),
+                Err(err) => return Err(err),
+            }
+        }
+        if destroy {
+            return Ok(());
+        }
+    }
+}
+
+fn split_duration(d: Duration) -> (u64, u32) {
+    (d.as_secs(), d.subsec_nanos())
+}
+
+// The kernel's 32 bit device number encoding.
+fn encode_dev(rdev: u64) -> u32 {
+    let (major, minor) = (libc::major(rdev) as u32, libc::minor(rdev) as u32);
+    (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)
+}
+
+fn attr(w: &mut Writer, attr: &Rgetattr) {
+    let stat = &attr.stat;
+    w.u64(attr.qid.path)
+        .u64(stat.size)
+        .u64(stat.blocks)
+        .u64(stat.atime.sec)
+        .u64(stat.mtime.sec)
+        .u64(stat.ctime.sec)
+        .u32(stat.atime.nsec as u32)
+        .u32(stat.mtime.nsec as u32)
+        .u32(stat.ctime.nsec as u32)
+        .u32(stat.mode)
+        .u32(stat.nlink as u32)
+        .u32(stat.uid)
+        .u32(stat.gid)
+        .u32(encode_dev(stat.rdev))
+        .u32(stat.blksize as u32)
+        .u32(0);
+}
+
+fn entry(fs: &P9Fuse, w: &mut Writer, entry: &Entry) {
+    let (entry_secs, entry_nsecs) = split_duration(fs.options().entry_timeout);
+    let (attr_secs, attr_nsecs) = split_duration(fs.options().attr_timeout);
+    w.u64(entry.ino)
+        .u64(entry.attr.stat.gen)
+        .u64(entry_secs)
+        .u64(attr_secs)
+        .u32(entry_nsecs)
+        .u32(attr_nsecs);
+    attr(w, &entry.attr);
+}
+
+fn attr_out(fs: &P9Fuse, w: &mut Writer, a: &Rgetattr) {
+    let (secs, nsecs) = split_duration(fs.options().attr_timeout);
+    w.u64(secs).u32(nsecs).u32(0);
+    attr(w, a);
+}
+
+fn open_out(w: &mut Writer, fh: u64) {
+    w.u64(fh).u32(0).u32(0);
+}
+
+fn lock_in(r: &mut Reader) -> Option<(u64, Lock, u32)> {
+    let fh = r.u64()?;
+    let _owner = r.u64()?;
+    let lock = Lock {
+        start: r.u64()?,
+        end: r.u64()?,
+        typ: r.u32()?,
+        pid: r.u32()?,
+    };
+    let flags = r.u32()?;
+    Some((fh, lock, flags))
+}
+
+fn setattr_in(r: &mut Reader) -> Option<(Option<u64>, SetattrMask, SetAttr)> {
+    let valid = r.u32()?;
+    r.u32()?;
+    let fh = r.u64()?;
+    let size = r.u64()?;
+    let _lock_owner = r.u64()?;
+    let atime = r.u64()?;
+    let mtime = r.u64()?;
+    let _ctime = r.u64()?;
+    let atimensec = r.u32()?;
+    let mtimensec = r.u32()?;
+    let _ctimensec = r.u32()?;
+    let mode = r.u32()?;
+    r.u32()?;
+    let uid = r.u32()?;
+    let gid = r.u32()?;
+
+    let mut mask = SetattrMask::empty();
+    for (fattr, bit) in [
+        (FATTR_MODE, SetattrMask::MODE),
+        (FATTR_UID, SetattrMask::UID),
+        (FATTR_GID, SetattrMask::GID),
+        (FATTR_SIZE, SetattrMask::SIZE),
+        (FATTR_ATIME, SetattrMask::ATIME | SetattrMask::ATIME_SET),
+        (FATTR_MTIME, SetattrMask::MTIME | SetattrMask::MTIME_SET),
+        // Without the SET bit the server uses its own clock.
+        (FATTR_ATIME_NOW, SetattrMask::ATIME),
+        (FATTR_MTIME_NOW, SetattrMask::MTIME),
+    ] {
+        if valid & fattr != 0 {
+            mask |= bit;
+        }
+    }
+    if valid & FATTR_ATIME_NOW != 0 {
+        mask.remove(SetattrMask::ATIME_SET);
+    }
+    if valid & FATTR_MTIME_NOW != 0 {
+        mask.remove(SetattrMask::MTIME_SET);
+    }
+    let fh = if valid & FATTR_FH != 0 {
+        Some(fh)
+    } else {
+        None
+    };
+    let stat = SetAttr {
+        mode,
+        uid,
+        gid,
+        size,
+        atime: Time {
+            sec: atime,
+            nsec: atimensec as u64,
+        },
+        mtime: Time {
+            sec: mtime,
+            nsec: mtimensec as u64,
+        },
+    };
+    Some((fh, mask, stat))
+}
+
+// Answer an xattr read, with fuse_getxattr_out when only the length was
+// asked for.
+fn xattr_reply(w: &mut Writer, xattr: Xattr) {
+    match xattr {
+        Xattr::Len(len) => {
+            w.u32(len.min(u32::MAX as u64) as u32).u32(0);
+        }
+        Xattr::Data(data) => {
+            w.bytes(&data);
+        }
+    }
+}
+
+fn init(fs: &P9Fuse, r: &mut Reader, w: &mut Writer) -> Result<()> {
+    let major = r.u32().ok_or(libc::EIO)?;
+    let minor = r.u32().ok_or(libc::EIO)?;
+    let max_readahead = r.u32().ok_or(libc::EIO)?;
+    let flags = r.u32().ok_or(libc::EIO)?;
+    if major != KERNEL_VERSION {
+        warn!("unsupported fuse kernel protocol {}.{}", major, minor);
+        return Err(libc::EPROTO);
+    }
+    let minor = minor.min(KERNEL_MINOR_VERSION);
+    w.u32(KERNEL_VERSION)
+        .u32(minor)
+        .u32(max_readahead)
+        .u32(flags & (FUSE_ASYNC_READ | FUSE_POSIX_LOCKS | FUSE_ATOMIC_O_TRUNC | FUSE_BIG_WRITES))
+        // max_background and congestion_threshold.
+        .u16(16)
+        .u16(12)
+        .u32(fs.iounit())
+        // time_gran, max_pages, map_alignment and flags2.
+        .u32(1)
+        .u16(0)
+        .u16(0)
+        .u32(0);
+    for _ in 0..7 {
+        w.u32(0);
+    }
+    // Older kernels expect the 7.22 reply size.
+    if minor < 23 {
+        w.buf.truncate(24);
+    }
+    Ok(())
+}
+
+fn dispatch(fs: &P9Fuse, header: &InHeader, r: &mut Reader, w: &mut Writer) -> Result<()> {
+    let ino = header.nodeid;
+    let bad = libc::EIO;
+    match header.opcode {
+        FUSE_INIT => init(fs, r, w)?,
+        FUSE_DESTROY | FUSE_FLUSH => (),
+        FUSE_LOOKUP => {
+            let name = r.name().ok_or(bad)?;
+            entry(fs, w, &fs.lookup(ino, name)?);
+        }
+        FUSE_GETATTR => attr_out(fs, w, &fs.getattr(ino)?),
+        FUSE_SETATTR => {
+            let (fh, mask, stat) = setattr_in(r).ok_or(bad)?;
+            attr_out(fs, w, &fs.setattr(ino, fh, mask, stat)?);
+        }
+        FUSE_READLINK => {
+            w.bytes(&fs.readlink(ino)?);
+        }
+        FUSE_SYMLINK => {
+            let name = r.name().ok_or(bad)?;
+            let target = r.name().ok_or(bad)?;
+            entry(fs, w, &fs.symlink(ino, name, target, header.gid)?);
+        }
+        FUSE_MKNOD => {
+            let mode = r.u32().ok_or(bad)?;
+            let rdev = r.u32().ok_or(bad)?;
+            r.bytes(8).ok_or(bad)?;
+            let name = r.name().ok_or(bad)?;
+            entry(fs, w, &fs.mknod(ino, name, mode, rdev, header.gid)?);
+        }
+        FUSE_MKDIR => {
+            let mode = r.u32().ok_or(bad)?;
+            r.u32().ok_or(bad)?;
+            let name = r.name().ok_or(bad)?;
+            entry(fs, w, &fs.mkdir(ino, name, mode, header.gid)?);
+        }
+        FUSE_UNLINK => fs.unlink(ino, r.name().ok_or(bad)?)?,
+        FUSE_RMDIR => fs.rmdir(ino, r.name().ok_or(bad)?)?,
+        FUSE_RENAME => {
+            let new_parent = r.u64().ok_or(bad)?;
+            let name = r.name().ok_or(bad)?;
+            let new_name = r.name().ok_or(bad)?;
+            fs.rename(ino, name, new_parent, new_name)?;
+        }
+        FUSE_LINK => {
+            let old = r.u64().ok_or(bad)?;
+            let name = r.name().ok_or(bad)?;
+            entry(fs, w, &fs.link(old, ino, name)?);
+        }
+        FUSE_OPEN | FUSE_OPENDIR => {
+            let flags = r.u32().ok_or(bad)?;
+            let flags = if header.opcode == FUSE_OPENDIR {
+                libc::O_RDONLY as u32
+            } else {
+                flags
+            };
+            open_out(w, fs.open(ino, flags)?);
+        }
+        FUSE_CREATE => {
+            let flags = r.u32().ok_or(bad)?;
+            let mode = r.u32().ok_or(bad)?;
+            r.bytes(8).ok_or(bad)?;
+            let name = r.name().ok_or(bad)?;
+            let (e, fh) = fs.create(ino, name, flags, mode, header.gid)?;
+            entry(fs, w, &e);
+            open_out(w, fh);
+        }
+        FUSE_READ => {
+            let fh = r.u64().ok_or(bad)?;
+            let offset = r.u64().ok_or(bad)?;
+            let size = r.u32().ok_or(bad)?;
+            w.bytes(&fs.read(fh, offset, size)?);
+        }
+        FUSE_WRITE => {
+            let fh = r.u64().ok_or(bad)?;
+            let offset = r.u64().ok_or(bad)?;
+            let size = r.u32().ok_or(bad)?;
+            r.bytes(20).ok_or(bad)?;
+            let data = r.bytes(size as usize).ok_or(bad)?;
+            w.u32(fs.write(fh, offset, data)?).u32(0);
+        }
+        FUSE_RELEASE | FUSE_RELEASEDIR => fs.release(r.u64().ok_or(bad)?),
+        FUSE_FSYNC | FUSE_FSYNCDIR => fs.fsync(r.u64().ok_or(bad)?)?,
+        FUSE_READDIR => {
+            let fh = r.u64().ok_or(bad)?;
+            let offset = r.u64().ok_or(bad)?;
+            let size = r.u32().ok_or(bad)? as usize;
+            for dirent in fs.readdir(fh, offset)? {
+                let name = dirent.name.as_bytes();
+                let len = (DIRENT_SIZE + name.len()).div_ceil(8) * 8;
+                if w.buf.len() + len > size {
+                    break;
+                }
+                w.u64(dirent.qid.path)
+                    .u64(dirent.offset)
+                    .u32(name.len() as u32)
+                    .u32(dirent.typ as u32)
+                    .bytes(name)
+                    .align();
+            }
+        }
+        FUSE_STATFS => {
+            let statfs = fs.statfs(ino)?;
+            w.u64(statfs.blocks)
+                .u64(statfs.bfree)
+                .u64(statfs.bavail)
+                .u64(statfs.files)
+                .u64(statfs.ffree)
+                .u32(statfs.bsize)
+                .u32(statfs.namelen)
+                .u32(statfs.bsize)
+                .u32(0);
+            for _ in 0..6 {
+                w.u32(0);
+            }
+        }
+        FUSE_SETXATTR => {
+            let size = r.u32().ok_or(bad)?;
+            let flags = r.u32().ok_or(bad)?;
+            let name = r.name().ok_or(bad)?;
+            let value = r.bytes(size as usize).ok_or(bad)?;
+            fs.setxattr(ino, name, value, flags)?;
+        }
+        FUSE_GETXATTR => {
+            let size = r.u32().ok_or(bad)?;
+            r.u32().ok_or(bad)?;
+            let name = r.name().ok_or(bad)?;
+            xattr_reply(w, fs.getxattr(ino, name, size)?);
+        }
+        FUSE_LISTXATTR => {
+            let size = r.u32().ok_or(bad)?;
+            xattr_reply(w, fs.listxattr(ino, size)?);
+        }
+        FUSE_REMOVEXATTR => fs.removexattr(ino, r.name().ok_or(bad)?)?,
+        FUSE_GETLK => {
+            let (fh, lock, _) = lock_in(r).ok_or(bad)?;
+            let lock = fs.getlk(fh, lock)?;
+            w.u64(lock.start).u64(lock.end).u32(lock.typ).u32(lock.pid);
+        }
+        FUSE_SETLK | FUSE_SETLKW => {
+            let (fh, lock, flags) = lock_in(r).ok_or(bad)?;
+            // flock(2) locks stay local to the kernel.
+            if flags & FUSE_LK_FLOCK != 0 {
+                return Err(libc::ENOSYS);
+            }
+            let unique = Some(header.unique).filter(|_| header.opcode == FUSE_SETLKW);
+            fs.setlk(fh, lock, unique)?;
+        }
+        _ => return Err(libc::ENOSYS),
+    }
+    Ok(())
+}
+
+/// Answer one request read from /dev/fuse, None for requests that take no
+/// reply.
+pub fn handle(fs: &P9Fuse, req: &[u8]) -> Option<Vec<u8>> {
+    let mut r = Reader::new(req);
+    let header = r.header()?;
+    match header.opcode {
+        FUSE_FORGET => {
+            fs.forget(header.nodeid, r.u64()?);
+            return None;
+        }
+        FUSE_BATCH_FORGET => {
+            let count = r.u32()?;
+            r.u32()?;
+            for _ in 0..count {
+                let ino = r.u64()?;
+                let n = r.u64()?;
+                fs.forget(ino, n);
+            }
+            return None;
+        }
+        FUSE_INTERRUPT => {
+            fs.interrupt(r.u64()?);
+            return None;
+        }
+        _ => (),
+    }
+    let mut w = Writer::new();
+    match dispatch(fs, &header, &mut r, &mut w) {
+        Ok(()) => Some(reply(header.unique, 0, &w.buf)),
+        Err(errno) => {
+            debug!(
+                "opcode {} on node {}: errno {}",
+                header.opcode, header.nodeid, errno
+            );
+            Some(reply(header.unique, -errno, &[]))
+        }
+    }
+}
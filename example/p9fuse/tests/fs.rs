@@ -0,0 +1,229 @@
+use nix::libc;
+use p92000l::*;
+use p9fuse::abi::*;
+use p9fuse::{handle, FuseOptions, Lock, P9Fuse, Xattr, ROOT_ID};
+use p9memfs::MemFs;
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+fn spawn_fuse() -> P9Fuse {
+    let (client_conn, server_conn) = UnixStream::pair().unwrap();
+    std::thread::spawn(move || {
+        let mut fs = MemFs::new();
+        serve_unix(server_conn, &mut fs, 8192);
+    });
+    let client =
+        Client::over_transport(client_conn.try_clone().unwrap(), client_conn, 8192).unwrap();
+    let (_, root) = client.attach(0, "root", "").unwrap();
+    P9Fuse::new(client, root, FuseOptions::default()).unwrap()
+}
+
+#[test]
+fn create_write_read() {
+    let fs = spawn_fuse();
+    let (entry, fh) = fs
+        .create(ROOT_ID, b"f", libc::O_RDWR as u32, 0o644, 0)
+        .unwrap();
+    assert_eq!(entry.attr.stat.mode, 0o100644);
+    assert_eq!(fs.write(fh, 0, b"hello world").unwrap(), 11);
+    assert_eq!(fs.read(fh, 6, 100).unwrap(), b"world");
+    fs.release(fh);
+
+    // Lookups of a known file share its node.
+    let again = fs.lookup(ROOT_ID, b"f").unwrap();
+    assert_eq!(again.ino, entry.ino);
+    assert_eq!(again.attr.stat.size, 11);
+    assert_eq!(fs.nodes(), 2);
+    fs.forget(entry.ino, 2);
+    assert_eq!(fs.nodes(), 1);
+    assert_eq!(fs.getattr(entry.ino).unwrap_err(), libc::ESTALE);
+
+    let entry = fs.lookup(ROOT_ID, b"f").unwrap();
+    let attr = fs
+        .setattr(
+            entry.ino,
+            None,
+            SetattrMask::SIZE,
+            SetAttr {
+                mode: 0,
+                uid: 0,
+                gid: 0,
+                size: 5,
+                atime: Time { sec: 0, nsec: 0 },
+                mtime: Time { sec: 0, nsec: 0 },
+            },
+        )
+        .unwrap();
+    assert_eq!(attr.stat.size, 5);
+}
+
+#[test]
+fn directories() {
+    let fs = spawn_fuse();
+    let dir = fs.mkdir(ROOT_ID, b"d", 0o755, 0).unwrap();
+    let (_, fh) = fs.create(dir.ino, b"a", 0, 0o600, 0).unwrap();
+    fs.release(fh);
+    fs.rename(dir.ino, b"a", ROOT_ID, b"b").unwrap();
+
+    let fh = fs.open(ROOT_ID, libc::O_RDONLY as u32).unwrap();
+    let names: Vec<_> = fs
+        .readdir(fh, 0)
+        .unwrap()
+        .into_iter()
+        .map(|e| e.name.as_bytes().to_vec())
+        .collect();
+    assert_eq!(names, vec![b"b".to_vec(), b"d".to_vec()]);
+    fs.release(fh);
+
+    assert_eq!(fs.lookup(dir.ino, b"a").unwrap_err(), libc::ENOENT);
+    assert_eq!(fs.unlink(ROOT_ID, b"d").unwrap_err(), libc::EISDIR);
+    fs.rmdir(ROOT_ID, b"d").unwrap();
+    fs.unlink(ROOT_ID, b"b").unwrap();
+    assert_eq!(fs.unlink(ROOT_ID, b"b").unwrap_err(), libc::ENOENT);
+}
+
+fn lock(typ: i32, start: u64, end: u64, pid: u32) -> Lock {
+    Lock {
+        typ: typ as u32,
+        start,
+        end,
+        pid,
+    }
+}
+
+#[test]
+fn locks() {
+    let fs = Arc::new(spawn_fuse());
+    let (entry, fh) = fs.create(ROOT_ID, b"f", 0, 0o644, 0).unwrap();
+    let fh2 = fs.open(entry.ino, libc::O_RDWR as u32).unwrap();
+    let whole = i64::MAX as u64;
+
+    // To the end of the file and back.
+    fs.setlk(fh, lock(libc::F_WRLCK, 10, whole, 1), None)
+        .unwrap();
+    let got = fs.getlk(fh2, lock(libc::F_RDLCK, 0, 99, 2)).unwrap();
+    assert_eq!(got, lock(libc::F_WRLCK, 10, whole, 1));
+    assert_eq!(
+        fs.setlk(fh2, lock(libc::F_RDLCK, 0, 99, 2), None),
+        Err(libc::EAGAIN)
+    );
+
+    // Ranges keep their length.
+    fs.setlk(fh, lock(libc::F_UNLCK, 30, whole, 1), None)
+        .unwrap();
+    let got = fs.getlk(fh2, lock(libc::F_RDLCK, 0, whole, 2)).unwrap();
+    assert_eq!(got, lock(libc::F_WRLCK, 10, 29, 1));
+    let got = fs.getlk(fh2, lock(libc::F_RDLCK, 30, 39, 2)).unwrap();
+    assert_eq!(got.typ, libc::F_UNLCK as u32);
+
+    // A waiting lock is taken once the conflict goes away.
+    let waiter = {
+        let fs = fs.clone();
+        std::thread::spawn(move || fs.setlk(fh2, lock(libc::F_RDLCK, 0, 19, 2), Some(7)))
+    };
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(!waiter.is_finished());
+    fs.setlk(fh, lock(libc::F_UNLCK, 0, whole, 1), None)
+        .unwrap();
+    assert_eq!(waiter.join().unwrap(), Ok(()));
+
+    // Or given up when interrupted, even by an interrupt read first.
+    fs.interrupt(8);
+    assert_eq!(
+        fs.setlk(fh, lock(libc::F_WRLCK, 0, 9, 1), Some(8)),
+        Err(libc::EINTR)
+    );
+    let waiter = {
+        let fs = fs.clone();
+        std::thread::spawn(move || fs.setlk(fh, lock(libc::F_WRLCK, 0, 9, 1), Some(9)))
+    };
+    std::thread::sleep(Duration::from_millis(50));
+    fs.interrupt(9);
+    assert_eq!(waiter.join().unwrap(), Err(libc::EINTR));
+    fs.release(fh);
+    fs.release(fh2);
+}
+
+#[test]
+fn xattrs_and_statfs() {
+    let fs = spawn_fuse();
+    let dir = fs.mkdir(ROOT_ID, b"d", 0o755, 0).unwrap();
+    fs.setxattr(dir.ino, b"user.a", b"one", 0).unwrap();
+    fs.setxattr(dir.ino, b"user.b", b"two", 0).unwrap();
+    let data = |value: &[u8]| Ok(Xattr::Data(value.to_vec()));
+    assert_eq!(fs.getxattr(dir.ino, b"user.a", 64), data(b"one"));
+    assert_eq!(fs.getxattr(dir.ino, b"user.b", 3), data(b"two"));
+    // A size of zero asks for the length, too small a size is ERANGE.
+    assert_eq!(fs.getxattr(dir.ino, b"user.a", 0), Ok(Xattr::Len(3)));
+    assert_eq!(fs.getxattr(dir.ino, b"user.a", 2), Err(libc::ERANGE));
+    assert_eq!(fs.listxattr(dir.ino, 64), data(b"user.a\0user.b\0"));
+    assert_eq!(fs.listxattr(dir.ino, 0), Ok(Xattr::Len(14)));
+    assert_eq!(fs.listxattr(dir.ino, 13), Err(libc::ERANGE));
+    fs.removexattr(dir.ino, b"user.a").unwrap();
+    assert_eq!(fs.getxattr(dir.ino, b"user.a", 64), Err(libc::ENODATA));
+    assert_eq!(fs.listxattr(dir.ino, 64), data(b"user.b\0"));
+
+    let statfs = fs.statfs(ROOT_ID).unwrap();
+    assert_eq!(statfs.bsize, 4096);
+    assert_eq!(statfs.bfree, 50);
+}
+
+fn request(opcode: u32, unique: u64, nodeid: u64, body: &[u8]) -> Vec<u8> {
+    let mut w = Writer::new();
+    w.u32((IN_HEADER_SIZE + body.len()) as u32)
+        .u32(opcode)
+        .u64(unique)
+        .u64(nodeid)
+        .u32(0)
+        .u32(0)
+        .u32(0)
+        .u32(0)
+        .bytes(body);
+    w.buf
+}
+
+#[test]
+fn session_messages() {
+    let fs = spawn_fuse();
+
+    let mut init = Writer::new();
+    init.u32(KERNEL_VERSION)
+        .u32(KERNEL_MINOR_VERSION)
+        .u32(65536)
+        .u32(FUSE_ASYNC_READ | FUSE_POSIX_LOCKS | 1 << 20);
+    let reply = handle(&fs, &request(FUSE_INIT, 1, 0, &init.buf)).unwrap();
+    let mut r = Reader::new(&reply);
+    assert_eq!(r.u32(), Some(OUT_HEADER_SIZE as u32 + 64));
+    assert_eq!(r.u32(), Some(0));
+    assert_eq!(r.u64(), Some(1));
+    assert_eq!(r.u32(), Some(KERNEL_VERSION));
+    assert_eq!(r.u32(), Some(KERNEL_MINOR_VERSION));
+    assert_eq!(r.u32(), Some(65536));
+    assert_eq!(r.u32(), Some(FUSE_ASYNC_READ | FUSE_POSIX_LOCKS));
+    r.u32();
+    assert_eq!(r.u32(), Some(fs.iounit()));
+
+    fs.mkdir(ROOT_ID, b"d", 0o755, 0).unwrap();
+    let reply = handle(&fs, &request(FUSE_LOOKUP, 2, ROOT_ID, b"d\0")).unwrap();
+    let mut r = Reader::new(&reply);
+    assert_eq!(r.u32(), Some(OUT_HEADER_SIZE as u32 + 40 + 88));
+    assert_eq!(r.u32(), Some(0));
+    assert_eq!(r.u64(), Some(2));
+    let ino = r.u64().unwrap();
+    assert_ne!(ino, ROOT_ID);
+
+    let reply = handle(&fs, &request(FUSE_LOOKUP, 3, ROOT_ID, b"missing\0")).unwrap();
+    let mut r = Reader::new(&reply);
+    assert_eq!(r.u32(), Some(OUT_HEADER_SIZE as u32));
+    assert_eq!(r.u32(), Some(-libc::ENOENT as u32));
+
+    // FUSE_ACCESS is left to default_permissions.
+    let reply = handle(&fs, &request(34, 4, ROOT_ID, &[0; 8])).unwrap();
+    assert_eq!(Reader::new(&reply[4..]).u32(), Some(-libc::ENOSYS as u32));
+
+    // Forgets take no reply, mkdir and the lookup each counted once.
+    assert!(handle(&fs, &request(FUSE_FORGET, 5, ino, &2u64.to_ne_bytes())).is_none());
+    assert!(handle(&fs, &request(FUSE_INTERRUPT, 6, 0, &2u64.to_ne_bytes())).is_none());
+    assert_eq!(fs.nodes(), 1);
+}
@@ -0,0 +1,247 @@
+use crate::fids::FidTracker;
+use crate::socket::{Socket, SocketAddr};
+use log::{info, warn};
+use p92000l::{Action, Fcall, Interceptor, ProxyContext, TaggedFcall};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How a backend is picked for a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Balance {
+    /// The first healthy backend in the order they were given.
+    Failover,
+    /// Rotate through the healthy backends.
+    RoundRobin,
+    /// The healthy backend with the fewest requests in flight.
+    LeastOutstanding,
+}
+
+impl std::str::FromStr for Balance {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Balance, String> {
+        match s {
+            "failover" => Ok(Balance::Failover),
+            "round-robin" => Ok(Balance::RoundRobin),
+            "least-outstanding" => Ok(Balance::LeastOutstanding),
+            _ => Err(format!("unknown balance policy '{}'", s)),
+        }
+    }
+}
+
+struct Backend {
+    addr: String,
+    healthy: AtomicBool,
+    outstanding: AtomicUsize,
+}
+
+/// The servers we proxy to, shared by all connections.
+pub struct Backends {
+    backends: Vec<Backend>,
+    balance: Balance,
+    next: AtomicUsize,
+}
+
+fn resolve(addr: &str) -> Result<SocketAddr, std::io::Error> {
+    SocketAddr::resolve(addr).map_err(|err| {
+        std::io::Error::other(format!(
+            "unable to resolve {} to an ip address: {}",
+            addr, err
+        ))
+    })
+}
+
+/// Connect to a backend, giving up after timeout.
+pub fn dial(addr: &str, timeout: Duration) -> Result<Socket, std::io::Error> {
+    Socket::connect_timeout(&resolve(addr)?, timeout)
+}
+
+/// Check a server answers Tversion, and Tattach if an attach is given,
+/// all within timeout.
+pub fn probe(
+    addr: &str,
+    attach: Option<(&str, &str)>,
+    timeout: Duration,
+) -> Result<(), std::io::Error> {
+    let mut conn = Socket::connect_timeout(&resolve(addr)?, timeout)?;
+    p92000l::ReadTransport::set_read_timeout(&mut conn, Some(timeout))?;
+    let client = p92000l::Client::over_transport(conn.try_clone()?, conn, 8192)?;
+    if let Some((uname, aname)) = attach {
+        client.attach(p92000l::NONUNAME, uname, aname)?;
+    }
+    Ok(())
+}
+
+impl Backends {
+    pub fn new(addrs: Vec<String>, balance: Balance) -> Backends {
+        Backends {
+            backends: addrs
+                .into_iter()
+                .map(|addr| Backend {
+                    addr,
+                    healthy: AtomicBool::new(true),
+                    outstanding: AtomicUsize::new(0),
+                })
+                .collect(),
+            balance,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn addr(&self, idx: usize) -> &str {
+        &self.backends[idx].addr
+    }
+
+    /// Backends to try connecting to, best first. Unhealthy backends come
+    /// last so there is something to try when all checks are failing.
+    pub fn candidates(&self) -> Vec<usize> {
+        let (mut healthy, unhealthy): (Vec<usize>, Vec<usize>) = (0..self.backends.len())
+            .partition(|idx| self.backends[*idx].healthy.load(Ordering::SeqCst));
+        match self.balance {
+            Balance::Failover => (),
+            Balance::RoundRobin => {
+                if !healthy.is_empty() {
+                    let n = self.next.fetch_add(1, Ordering::SeqCst) % healthy.len();
+                    healthy.rotate_left(n);
+                }
+            }
+            Balance::LeastOutstanding => {
+                healthy.sort_by_key(|idx| self.backends[*idx].outstanding.load(Ordering::SeqCst))
+            }
+        }
+        healthy.extend(unhealthy);
+        healthy
+    }
+
+    pub fn set_healthy(&self, idx: usize, healthy: bool) {
+        let backend = &self.backends[idx];
+        if backend.healthy.swap(healthy, Ordering::SeqCst) != healthy {
+            if healthy {
+                info!("backend {} is healthy", backend.addr);
+            } else {
+                warn!("backend {} is unhealthy", backend.addr);
+            }
+        }
+    }
+
+    pub fn add_outstanding(&self, idx: usize, n: usize) {
+        self.backends[idx]
+            .outstanding
+            .fetch_add(n, Ordering::SeqCst);
+    }
+
+    /// Requests sent to the backend that are still waiting for a response.
+    pub fn outstanding(&self, idx: usize) -> usize {
+        self.backends[idx].outstanding.load(Ordering::SeqCst)
+    }
+
+    pub fn sub_outstanding(&self, idx: usize, n: usize) {
+        self.backends[idx]
+            .outstanding
+            .fetch_sub(n, Ordering::SeqCst);
+    }
+
+    /// Probe every backend at once, updating their health. A backend that
+    /// doesn't answer within timeout is unhealthy.
+    pub fn check_health(&self, timeout: Duration, attach: Option<(&str, &str)>) {
+        std::thread::scope(|s| {
+            for idx in 0..self.backends.len() {
+                s.spawn(move || {
+                    let result = probe(self.addr(idx), attach, timeout);
+                    if let Err(err) = &result {
+                        warn!("health check of {} failed: {}", self.addr(idx), err);
+                    }
+                    self.set_healthy(idx, result.is_ok());
+                });
+            }
+        });
+    }
+
+    /// Probe every backend forever, updating their health.
+    pub fn health_check_loop(&self, interval: Duration, attach: Option<(&str, &str)>) {
+        loop {
+            self.check_health(interval, attach);
+            std::thread::sleep(interval);
+        }
+    }
+}
+
+/// A client connection's interceptor, tracks fids and counts the requests
+/// outstanding on the backend it is connected to.
+pub struct Session {
+    tracker: FidTracker,
+    backends: Arc<Backends>,
+    current: Mutex<Option<usize>>,
+    counted: Mutex<HashSet<u16>>,
+}
+
+impl Session {
+    pub fn new(backends: Arc<Backends>) -> Session {
+        Session {
+            tracker: FidTracker::new(),
+            backends,
+            current: Mutex::new(None),
+            counted: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn tracker(&self) -> &FidTracker {
+        &self.tracker
+    }
+
+    pub fn current(&self) -> Option<usize> {
+        *self.current.lock().unwrap()
+    }
+
+    /// Move to another backend, requests still in flight will never be
+    /// answered by the old one.
+    pub fn switch(&self, idx: Option<usize>) {
+        let mut current = self.current.lock().unwrap();
+        let mut counted = self.counted.lock().unwrap();
+        if let Some(old) = *current {
+            self.backends.sub_outstanding(old, counted.len());
+        }
+        counted.clear();
+        *current = idx;
+    }
+
+    fn uncount(&self, tag: u16) {
+        let current = self.current.lock().unwrap();
+        if let Some(idx) = *current {
+            if self.counted.lock().unwrap().remove(&tag) {
+                self.backends.sub_outstanding(idx, 1);
+            }
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.switch(None);
+    }
+}
+
+impl Interceptor for Session {
+    fn request(&self, ctx: &ProxyContext, req: &TaggedFcall) -> Action {
+        let action = self.tracker.request(ctx, req);
+        if let Action::Forward | Action::Rewrite(_) = action {
+            let current = self.current.lock().unwrap();
+            if let Some(idx) = *current {
+                if self.counted.lock().unwrap().insert(req.tag) {
+                    self.backends.add_outstanding(idx, 1);
+                }
+            }
+        }
+        action
+    }
+
+    fn response(&self, ctx: &ProxyContext, req: &Fcall, resp: &TaggedFcall) -> Action {
+        if let Fcall::Tflush(tflush) = req {
+            self.uncount(tflush.oldtag);
+        }
+        self.uncount(resp.tag);
+        self.tracker.response(ctx, req, resp)
+    }
+}
@@ -0,0 +1,131 @@
+use crate::backends::{dial, Backends, Session};
+use crate::socket::Socket;
+use log::{debug, error, info};
+use p92000l::{Proxy, ReadTransport, RetryOptions};
+use std::sync::Arc;
+use std::time;
+
+type SessionProxy = Proxy<Arc<Session>>;
+
+// How long a backend has to accept a connection, answer Tversion and
+// restore the client's fids before we move on to the next one.
+const CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+fn connect(
+    proxy: &mut SessionProxy,
+    backends: &Backends,
+    idx: usize,
+) -> Result<(), std::io::Error> {
+    let server_conn = dial(backends.addr(idx), CONNECT_TIMEOUT)?;
+    let mut timeouts = server_conn.try_clone()?;
+    timeouts.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+    proxy.connect(server_conn.try_clone()?, server_conn)?;
+    let session = proxy.interceptor().clone();
+    session.switch(Some(idx));
+    // Restablish the client's fids.
+    session.tracker().restore(proxy.context())?;
+    // Once running, a quiet backend is not a failed one.
+    timeouts.set_read_timeout(None)
+}
+
+// Try every backend, best first. Only fails with InvalidData if no backend
+// speaks a protocol we can use.
+fn connect_any(proxy: &mut SessionProxy, backends: &Backends) -> Result<(), std::io::Error> {
+    let mut incompatible = 0;
+    let mut last_err = None;
+    let candidates = backends.candidates();
+    for idx in candidates.iter().copied() {
+        info!("connecting to {}", backends.addr(idx));
+        match connect(proxy, backends, idx) {
+            Ok(()) => {
+                backends.set_healthy(idx, true);
+                info!("connected to {}", backends.addr(idx));
+                return Ok(());
+            }
+            Err(err) => {
+                error!("connection to {} failed: {}", backends.addr(idx), err);
+                proxy.interceptor().switch(None);
+                backends.set_healthy(idx, false);
+                if err.kind() == std::io::ErrorKind::InvalidData {
+                    incompatible += 1;
+                }
+                last_err = Some(err);
+            }
+        }
+    }
+    match last_err {
+        Some(err) if incompatible == candidates.len() => Err(err),
+        _ => Err(std::io::Error::other("no backend available")),
+    }
+}
+
+fn initial_connect(proxy: &mut SessionProxy, backends: &Backends) -> Result<(), std::io::Error> {
+    if let Err(err) = proxy.accept_version() {
+        error!("rejecting connection: {}", err);
+        return Err(err);
+    }
+
+    loop {
+        match connect_any(proxy, backends) {
+            Ok(()) => return Ok(()),
+            // No server is speaking a protocol we understand.
+            Err(err) if err.kind() == std::io::ErrorKind::InvalidData => return Err(err),
+            Err(_) => (),
+        }
+        std::thread::sleep(std::time::Duration::from_millis(1000));
+        error!("retrying initial connection");
+    }
+}
+
+fn reconnect(proxy: &mut SessionProxy, backends: &Backends) -> Result<(), std::io::Error> {
+    if let Some(idx) = proxy.interceptor().current() {
+        error!("lost connection to {}", backends.addr(idx));
+        backends.set_healthy(idx, false);
+    }
+    proxy.interceptor().switch(None);
+
+    // Answer remaining in flight calls expecting a response with an error,
+    // idempotent ones are held and sent again once we reconnect.
+    debug!(
+        "cancelling or holding inflight tags {:?}",
+        proxy.context().inflight_tags()
+    );
+    proxy.fail_inflight(p92000l::EIO)?;
+
+    let mut first_attempt = true;
+    loop {
+        if first_attempt {
+            first_attempt = false;
+        } else {
+            // While we are disconnected, reply to requests that can't be held with EIO.
+            proxy.reject_until(
+                time::Instant::now() + time::Duration::from_millis(1000),
+                p92000l::EIO,
+            )?;
+        }
+
+        match connect_any(proxy, backends) {
+            Ok(()) => return Ok(()),
+            // Every server has changed it's parameters, we must abort.
+            Err(err) if err.kind() == std::io::ErrorKind::InvalidData => return Err(err),
+            Err(_) => continue,
+        }
+    }
+}
+
+/// Proxy one client connection until it closes, reconnecting or failing
+/// over whenever the backend is lost.
+pub fn handle_connection(
+    client_conn: Socket,
+    backends: Arc<Backends>,
+    retry: Option<RetryOptions>,
+) -> Result<(), std::io::Error> {
+    let session = Arc::new(Session::new(backends.clone()));
+    let mut proxy = Proxy::new(client_conn.try_clone()?, client_conn, session);
+    proxy.set_retry(retry);
+    initial_connect(&mut proxy, &backends)?;
+    loop {
+        proxy.run()?;
+        reconnect(&mut proxy, &backends)?;
+    }
+}
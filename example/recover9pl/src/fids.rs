@@ -0,0 +1,397 @@
+use log::{debug, info};
+use p92000l::{
+    request_fids, Action, Fcall, FcallStr, Flock, Interceptor, LOpenFlags, LockFlag, LockStatus,
+    LockType, ProxyContext, TaggedFcall, Tattach,
+};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+#[derive(Clone, Debug)]
+enum Xattr {
+    // Reading the named attribute of the walked file, or the list of names
+    // when the name is empty.
+    Read(FcallStr<'static>),
+    // A partially written attribute, these can't be restored.
+    Write,
+}
+
+#[derive(Clone, Debug)]
+struct FidState {
+    attach: Tattach<'static>,
+    // Names walked from the attach point.
+    path: Vec<FcallStr<'static>>,
+    open: Option<LOpenFlags>,
+    xattr: Option<Xattr>,
+    // Successful Tlock requests, in the order they were made.
+    locks: Vec<Flock<'static>>,
+}
+
+impl FidState {
+    fn walked(&self, names: &[FcallStr]) -> FidState {
+        let mut path = self.path.clone();
+        path.extend(names.iter().map(|name| name.clone_static()));
+        FidState {
+            attach: self.attach.clone(),
+            path,
+            open: None,
+            xattr: None,
+            locks: Vec::new(),
+        }
+    }
+}
+
+fn same_root(a: &Tattach, b: &Tattach) -> bool {
+    a.afid == b.afid
+        && a.n_uname == b.n_uname
+        && a.uname.as_bytes() == b.uname.as_bytes()
+        && a.aname.as_bytes() == b.aname.as_bytes()
+}
+
+// The attach a file was reached from and the names walked from there.
+type FilePath = (Tattach<'static>, Vec<FcallStr<'static>>);
+
+#[derive(Default)]
+struct FidTable {
+    fids: HashMap<u32, FidState>,
+    // Fids that could not be restored after a reconnect, the client
+    // gets EIO for them until it clunks them.
+    lost: HashSet<u32>,
+}
+
+impl FidTable {
+    fn path(&self, fid: u32) -> Option<FilePath> {
+        self.fids
+            .get(&fid)
+            .map(|s| (s.attach.clone(), s.path.clone()))
+    }
+
+    fn child_path(&self, dfid: u32, name: &FcallStr) -> Option<FilePath> {
+        self.path(dfid).map(|(attach, mut path)| {
+            path.push(name.clone_static());
+            (attach, path)
+        })
+    }
+
+    // Move every fid at or below old to the same place below new.
+    fn renamed(&mut self, old: Option<FilePath>, new: Option<FilePath>) {
+        let ((attach, old), (new_attach, new)) = match (old, new) {
+            (Some(old), Some(new)) => (old, new),
+            _ => return,
+        };
+        if !same_root(&attach, &new_attach) || old.is_empty() {
+            return;
+        }
+        for state in self.fids.values_mut() {
+            if same_root(&state.attach, &attach) && state.path.starts_with(&old) {
+                let rest = state.path.split_off(old.len());
+                state.path = new.clone();
+                state.path.extend(rest);
+            }
+        }
+    }
+
+    fn update(&mut self, req: &Fcall, resp: &Fcall) {
+        match (req, resp) {
+            (Fcall::Tattach(tattach), Fcall::Rattach(_)) => {
+                self.fids.insert(
+                    tattach.fid,
+                    FidState {
+                        attach: tattach.clone_static(),
+                        path: Vec::new(),
+                        open: None,
+                        xattr: None,
+                        locks: Vec::new(),
+                    },
+                );
+            }
+            (Fcall::Twalk(twalk), Fcall::Rwalk(rwalk))
+                if rwalk.wqids.len() == twalk.wnames.len() =>
+            {
+                if let Some(state) = self.fids.get(&twalk.fid) {
+                    let state = state.walked(&twalk.wnames);
+                    self.fids.insert(twalk.new_fid, state);
+                }
+            }
+            (Fcall::Tlopen(tlopen), Fcall::Rlopen(_)) => {
+                if let Some(state) = self.fids.get_mut(&tlopen.fid) {
+                    state.open = Some(tlopen.flags);
+                }
+            }
+            (Fcall::Tlcreate(tlcreate), Fcall::Rlcreate(_)) => {
+                if let Some(state) = self.fids.get_mut(&tlcreate.fid) {
+                    state.path.push(tlcreate.name.clone_static());
+                    state.open = Some(tlcreate.flags);
+                }
+            }
+            (Fcall::Txattrwalk(txattrwalk), Fcall::Rxattrwalk(_)) => {
+                if let Some(state) = self.fids.get(&txattrwalk.fid) {
+                    let mut state = state.walked(&[]);
+                    state.xattr = Some(Xattr::Read(txattrwalk.name.clone_static()));
+                    self.fids.insert(txattrwalk.new_fid, state);
+                }
+            }
+            (Fcall::Txattrcreate(txattrcreate), Fcall::Rxattrcreate(_)) => {
+                if let Some(state) = self.fids.get_mut(&txattrcreate.fid) {
+                    state.xattr = Some(Xattr::Write);
+                }
+            }
+            (Fcall::Tlock(tlock), Fcall::Rlock(rlock)) if rlock.status == LockStatus::SUCCESS => {
+                if let Some(state) = self.fids.get_mut(&tlock.fid) {
+                    let flock = &tlock.flock;
+                    if flock.typ == LockType::UNLOCK && flock.start == 0 && flock.length == 0 {
+                        state.locks.clear();
+                    } else {
+                        state.locks.push(tlock.clone_static().flock);
+                    }
+                }
+            }
+            (Fcall::Trename(trename), Fcall::Rrename(_)) => {
+                let old = self.path(trename.fid);
+                let new = self.child_path(trename.dfid, &trename.name);
+                self.renamed(old, new);
+            }
+            (Fcall::Trenameat(trenameat), Fcall::Rrenameat(_)) => {
+                let old = self.child_path(trenameat.olddfid, &trenameat.oldname);
+                let new = self.child_path(trenameat.newdfid, &trenameat.newname);
+                self.renamed(old, new);
+            }
+            // Clunk and remove forget the fid even on error.
+            (Fcall::Tclunk(p92000l::Tclunk { fid }), _)
+            | (Fcall::Tremove(p92000l::Tremove { fid }), _) => {
+                self.fids.remove(fid);
+                self.lost.remove(fid);
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Tracks the state of every client fid so it can be rebuilt after
+/// reconnecting to the server.
+#[derive(Default)]
+pub struct FidTracker {
+    table: Mutex<FidTable>,
+}
+
+fn succeeded(resp: &Fcall) -> bool {
+    !matches!(resp, Fcall::Rlerror(_))
+}
+
+// Keeps track of fids the restore process uses for itself.
+struct Scratch {
+    roots: Vec<(Tattach<'static>, u32)>,
+    next_fid: u32,
+}
+
+impl Scratch {
+    fn alloc(&mut self, in_use: &HashMap<u32, FidState>) -> u32 {
+        loop {
+            self.next_fid -= 1;
+            if !in_use.contains_key(&self.next_fid) {
+                return self.next_fid;
+            }
+        }
+    }
+
+    fn root(
+        &mut self,
+        ctx: &ProxyContext,
+        in_use: &HashMap<u32, FidState>,
+        attach: &Tattach<'static>,
+    ) -> Result<Option<u32>, std::io::Error> {
+        if let Some((_, fid)) = self.roots.iter().find(|(a, _)| same_root(a, attach)) {
+            return Ok(Some(*fid));
+        }
+        let fid = self.alloc(in_use);
+        let resp = ctx.call(Fcall::Tattach(Tattach {
+            fid,
+            ..attach.clone()
+        }))?;
+        if !matches!(resp, Fcall::Rattach(_)) {
+            return Ok(None);
+        }
+        self.roots.push((attach.clone(), fid));
+        Ok(Some(fid))
+    }
+
+    fn clunk_all(&mut self, ctx: &ProxyContext) -> Result<(), std::io::Error> {
+        for (_, fid) in self.roots.drain(..) {
+            ctx.call(Fcall::Tclunk(p92000l::Tclunk { fid }))?;
+        }
+        Ok(())
+    }
+}
+
+fn walk(
+    ctx: &ProxyContext,
+    from: u32,
+    to: u32,
+    path: &[FcallStr<'static>],
+) -> Result<bool, std::io::Error> {
+    let mut chunks: Vec<&[FcallStr<'static>]> = path.chunks(p92000l::MAXWELEM).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+    for (i, wnames) in chunks.into_iter().enumerate() {
+        let resp = ctx.call(Fcall::Twalk(p92000l::Twalk {
+            fid: if i == 0 { from } else { to },
+            new_fid: to,
+            wnames: wnames.to_vec(),
+        }))?;
+        match resp {
+            Fcall::Rwalk(rwalk) if rwalk.wqids.len() == wnames.len() => (),
+            _ => {
+                if i != 0 {
+                    ctx.call(Fcall::Tclunk(p92000l::Tclunk { fid: to }))?;
+                }
+                return Ok(false);
+            }
+        }
+    }
+    Ok(true)
+}
+
+// Rebuild a single fid, returns false if it could not be restored.
+fn restore_fid(
+    ctx: &ProxyContext,
+    scratch: &mut Scratch,
+    in_use: &HashMap<u32, FidState>,
+    fid: u32,
+    state: &FidState,
+) -> Result<bool, std::io::Error> {
+    match &state.xattr {
+        Some(Xattr::Write) => return Ok(false),
+        Some(Xattr::Read(name)) => {
+            let root = match scratch.root(ctx, in_use, &state.attach)? {
+                Some(root) => root,
+                None => return Ok(false),
+            };
+            let file = scratch.alloc(in_use);
+            if !walk(ctx, root, file, &state.path)? {
+                return Ok(false);
+            }
+            let resp = ctx.call(Fcall::Txattrwalk(p92000l::Txattrwalk {
+                fid: file,
+                new_fid: fid,
+                name: name.clone(),
+            }))?;
+            ctx.call(Fcall::Tclunk(p92000l::Tclunk { fid: file }))?;
+            return Ok(matches!(resp, Fcall::Rxattrwalk(_)));
+        }
+        None => (),
+    }
+
+    if state.path.is_empty() {
+        let resp = ctx.call(Fcall::Tattach(Tattach {
+            fid,
+            ..state.attach.clone()
+        }))?;
+        if !matches!(resp, Fcall::Rattach(_)) {
+            return Ok(false);
+        }
+    } else {
+        let root = match scratch.root(ctx, in_use, &state.attach)? {
+            Some(root) => root,
+            None => return Ok(false),
+        };
+        if !walk(ctx, root, fid, &state.path)? {
+            return Ok(false);
+        }
+    }
+
+    let mut ok = true;
+    if let Some(flags) = state.open {
+        // Reopening must not modify the file.
+        let flags = flags - (LOpenFlags::O_TRUNC | LOpenFlags::O_EXCL);
+        ok = succeeded(&ctx.call(Fcall::Tlopen(p92000l::Tlopen { fid, flags }))?);
+    }
+    for flock in state.locks.iter() {
+        if !ok {
+            break;
+        }
+        let resp = ctx.call(Fcall::Tlock(p92000l::Tlock {
+            fid,
+            flock: Flock {
+                // Someone else may hold the lock now, don't wait for them.
+                flags: flock.flags - LockFlag::BLOCK,
+                ..flock.clone()
+            },
+        }))?;
+        ok = matches!(resp, Fcall::Rlock(rlock) if rlock.status == LockStatus::SUCCESS);
+    }
+    if !ok {
+        ctx.call(Fcall::Tclunk(p92000l::Tclunk { fid }))?;
+    }
+    Ok(ok)
+}
+
+impl FidTracker {
+    pub fn new() -> FidTracker {
+        FidTracker::default()
+    }
+
+    /// Rebuild every tracked fid on a freshly connected server. An error
+    /// means the connection failed and the restore should be retried.
+    pub fn restore(&self, ctx: &ProxyContext) -> Result<(), std::io::Error> {
+        let mut table = self.table.lock().unwrap();
+        let mut fids: Vec<(u32, FidState)> = table
+            .fids
+            .iter()
+            .map(|(fid, state)| (*fid, state.clone()))
+            .collect();
+        fids.sort_by_key(|(fid, _)| *fid);
+
+        let mut scratch = Scratch {
+            roots: Vec::new(),
+            next_fid: p92000l::NOFID,
+        };
+        let mut lost = Vec::new();
+        for (fid, state) in fids.iter() {
+            if restore_fid(ctx, &mut scratch, &table.fids, *fid, state)? {
+                debug!("restored fid {}", fid);
+            } else {
+                lost.push(*fid);
+            }
+        }
+        scratch.clunk_all(ctx)?;
+
+        if !lost.is_empty() {
+            info!("unable to restore {} fids: {:?}", lost.len(), lost);
+        }
+        for fid in lost {
+            table.fids.remove(&fid);
+            table.lost.insert(fid);
+        }
+        Ok(())
+    }
+}
+
+impl Interceptor for FidTracker {
+    fn request(&self, _ctx: &ProxyContext, req: &TaggedFcall) -> Action {
+        let mut table = self.table.lock().unwrap();
+        if table.lost.is_empty() {
+            return Action::Forward;
+        }
+        match &req.fcall {
+            Fcall::Tclunk(p92000l::Tclunk { fid }) if table.lost.remove(fid) => {
+                Action::Reply(Fcall::Rclunk(p92000l::Rclunk {}))
+            }
+            Fcall::Tremove(p92000l::Tremove { fid }) if table.lost.remove(fid) => {
+                Action::Reject(p92000l::EIO)
+            }
+            fcall
+                if request_fids(fcall)
+                    .iter()
+                    .any(|fid| table.lost.contains(fid)) =>
+            {
+                Action::Reject(p92000l::EIO)
+            }
+            _ => Action::Forward,
+        }
+    }
+
+    fn response(&self, _ctx: &ProxyContext, req: &Fcall, resp: &TaggedFcall) -> Action {
+        self.table.lock().unwrap().update(req, &resp.fcall);
+        Action::Forward
+    }
+}
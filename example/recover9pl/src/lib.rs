@@ -0,0 +1,4 @@
+pub mod backends;
+pub mod connection;
+pub mod fids;
+pub mod socket;
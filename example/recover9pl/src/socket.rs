@@ -154,6 +154,16 @@ impl Socket {
         }
     }
 
+    /// Connect, giving up on tcp connections after timeout. Unix sockets
+    /// connect or fail straight away.
+    pub fn connect_timeout(s: &SocketAddr, timeout: Duration) -> io::Result<Socket> {
+        match s {
+            SocketAddr::Inet(s) => net::TcpStream::connect_timeout(s, timeout).map(Socket::Inet),
+            #[cfg(unix)]
+            SocketAddr::Unix(s) => unix::UnixStream::connect(s).map(Socket::Unix),
+        }
+    }
+
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
         match self {
             Socket::Inet(s) => s.local_addr().map(SocketAddr::Inet),
@@ -0,0 +1,308 @@
+use p92000l::*;
+use p9memfs::MemFs;
+use recover9pl::backends::{Backends, Balance};
+use recover9pl::connection::handle_connection;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+// Serves a shared MemFs tree over tcp and can drop every connection to it.
+// Tstatfs is never answered, to leave requests outstanding.
+struct Backend {
+    addr: String,
+    conns: Arc<Mutex<Vec<(TcpStream, JoinHandle<()>)>>>,
+    down: Arc<AtomicBool>,
+}
+
+impl Backend {
+    fn start(fs: &MemFs) -> Backend {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let conns = Arc::new(Mutex::new(Vec::new()));
+        let accepted = conns.clone();
+        let down = Arc::new(AtomicBool::new(false));
+        let refuse = down.clone();
+        let fs = fs.share();
+        std::thread::spawn(move || {
+            for conn in listener.incoming() {
+                let conn = conn.unwrap();
+                if refuse.load(Ordering::SeqCst) {
+                    continue;
+                }
+                let kill = conn.try_clone().unwrap();
+                let mut fs = FaultFs::new(fs.share(), 0)
+                    .rule(FaultRule::new(Fault::Drop).op(FcallType::Tstatfs));
+                let handle = std::thread::spawn(move || serve(conn, &mut fs, 8192));
+                accepted.lock().unwrap().push((kill, handle));
+            }
+        });
+        Backend { addr, conns, down }
+    }
+
+    // A backend whose tree holds a file f containing data.
+    fn replica(data: &[u8]) -> Backend {
+        let backend = Backend::start(&MemFs::new());
+        let (_client, root) = backend.attach();
+        create(&root, "f", data);
+        backend
+    }
+
+    // Drop every connection and close new ones straight away.
+    fn stop(&self) {
+        self.down.store(true, Ordering::SeqCst);
+        self.kill();
+    }
+
+    fn restart(&self) {
+        self.down.store(false, Ordering::SeqCst);
+    }
+
+    // Drop every connection and wait for the server side to go away, so
+    // the locks they held are released.
+    fn kill(&self) {
+        for (conn, handle) in self.conns.lock().unwrap().drain(..) {
+            let _ = conn.shutdown(Shutdown::Both);
+            handle.join().unwrap();
+        }
+    }
+
+    // A client talking to the backend directly.
+    fn attach(&self) -> (Client, ClientFid) {
+        let conn = TcpStream::connect(&self.addr).unwrap();
+        let client = Client::over_transport(conn.try_clone().unwrap(), conn, 8192).unwrap();
+        let (_, root) = client.attach(0, "direct", "").unwrap();
+        (client, root)
+    }
+}
+
+fn backends(backends: &[&Backend], balance: Balance) -> Arc<Backends> {
+    let addrs = backends.iter().map(|b| b.addr.clone()).collect();
+    Arc::new(Backends::new(addrs, balance))
+}
+
+// A client connected through the proxy.
+fn proxy(backends: &Arc<Backends>) -> (Client, ClientFid) {
+    let backends = backends.clone();
+    let (conn, proxy_conn) = UnixStream::pair().unwrap();
+    std::thread::spawn(move || {
+        handle_connection(proxy_conn.into(), backends, Some(RetryOptions::default()))
+    });
+    let client = Client::over_transport(conn.try_clone().unwrap(), conn, 8192).unwrap();
+    let (_, root) = client.attach(0, "proxied", "").unwrap();
+    (client, root)
+}
+
+// Wait for the proxy to reconnect, getattr is held until the fids are restored.
+fn reconnected(root: &ClientFid) {
+    root.getattr(GetattrMask::BASIC).unwrap();
+}
+
+fn create(dir: &ClientFid, name: &str, data: &[u8]) {
+    let (_, fid) = dir.walk::<&str>(&[]).unwrap();
+    fid.create(name, LOpenFlags::O_RDWR, 0o644, 0).unwrap();
+    fid.write(0, data).unwrap();
+}
+
+fn read(fid: &ClientFid) -> std::io::Result<Vec<u8>> {
+    let mut buf = [0; 64];
+    let n = fid.read(0, &mut buf)?;
+    Ok(buf[..n].to_vec())
+}
+
+fn errno(result: std::io::Result<impl Sized>) -> u32 {
+    errno::errno_from_io_error(&result.err().unwrap())
+}
+
+#[test]
+fn renamed_fids_are_restored() {
+    let backend = Backend::start(&MemFs::new());
+    let (_client, root) = proxy(&backends(&[&backend], Balance::Failover));
+    root.mkdir("d", 0o755, 0).unwrap();
+    let (_, dir) = root.walk(&["d"]).unwrap();
+    create(&dir, "f", b"hello");
+    let (_, file) = root.walk(&["d", "f"]).unwrap();
+    file.open(LOpenFlags::O_RDONLY).unwrap();
+
+    // d/f becomes g with Trenameat, then d/h with Trename.
+    dir.renameat("f", &root, "g").unwrap();
+    let (_, g) = root.walk(&["g"]).unwrap();
+    g.rename(&dir, "h").unwrap();
+
+    backend.kill();
+    reconnected(&root);
+    assert_eq!(read(&file).unwrap(), b"hello");
+    let qid = file.getattr(GetattrMask::BASIC).unwrap().qid;
+    assert_eq!(g.getattr(GetattrMask::BASIC).unwrap().qid, qid);
+    let entries = dir.walk::<&str>(&[]).unwrap().1;
+    entries.open(LOpenFlags::O_RDONLY).unwrap();
+    assert_eq!(entries.read_dir().unwrap()[0].name.as_bytes(), b"h");
+}
+
+#[test]
+fn locks_are_replayed() {
+    let backend = Backend::start(&MemFs::new());
+    let (_client, root) = proxy(&backends(&[&backend], Balance::Failover));
+    create(&root, "f", b"data");
+    let (_, file) = root.walk(&["f"]).unwrap();
+    file.open(LOpenFlags::O_RDWR).unwrap();
+    let flock = |typ| Flock {
+        typ,
+        flags: LockFlag::BLOCK,
+        start: 10,
+        length: 0,
+        proc_id: 1,
+        client_id: "proxied".into(),
+    };
+    assert_eq!(
+        file.lock(flock(LockType::WRLOCK)).unwrap(),
+        LockStatus::SUCCESS
+    );
+
+    backend.kill();
+    reconnected(&root);
+    let (_direct, other) = backend.attach();
+    let (_, other) = other.walk(&["f"]).unwrap();
+    let probe = Getlock {
+        typ: LockType::RDLOCK,
+        start: 0,
+        length: 0,
+        proc_id: 2,
+        client_id: "direct".into(),
+    };
+    let held = other.getlock(probe.clone()).unwrap();
+    assert_eq!(held.typ, LockType::WRLOCK);
+    assert_eq!((held.start, held.length, held.proc_id), (10, 0, 1));
+
+    // The replayed lock belongs to the restored fid.
+    assert_eq!(
+        file.lock(flock(LockType::UNLOCK)).unwrap(),
+        LockStatus::SUCCESS
+    );
+    assert_eq!(other.getlock(probe).unwrap().typ, LockType::UNLOCK);
+}
+
+#[test]
+fn xattr_fids_are_restored() {
+    let backend = Backend::start(&MemFs::new());
+    let (_client, root) = proxy(&backends(&[&backend], Balance::Failover));
+    create(&root, "f", b"");
+    let (_, file) = root.walk(&["f"]).unwrap();
+    let (_, set) = file.walk::<&str>(&[]).unwrap();
+    set.xattrcreate("user.a", 3, 0).unwrap();
+    set.write(0, b"one").unwrap();
+    set.clunk().unwrap();
+
+    let (size, value) = file.xattrwalk("user.a").unwrap();
+    assert_eq!(size, 3);
+    let (_, list) = file.xattrwalk("").unwrap();
+    // Half written attributes can't be restored.
+    let (_, half) = file.walk::<&str>(&[]).unwrap();
+    half.xattrcreate("user.b", 3, 0).unwrap();
+    half.write(0, b"t").unwrap();
+
+    backend.kill();
+    reconnected(&root);
+    assert_eq!(read(&value).unwrap(), b"one");
+    assert_eq!(read(&list).unwrap(), b"user.a\0");
+    assert_eq!(errno(half.write(1, b"wo")), errno::EIO);
+    half.clunk().unwrap();
+    assert_eq!(errno(file.xattrwalk("user.b")), errno::ENODATA);
+}
+
+#[test]
+fn lost_fids_answer_eio() {
+    let backend = Backend::start(&MemFs::new());
+    let (_client, root) = proxy(&backends(&[&backend], Balance::Failover));
+    create(&root, "f", b"data");
+    create(&root, "kept", b"kept");
+    let (_, lost) = root.walk(&["f"]).unwrap();
+    lost.open(LOpenFlags::O_RDONLY).unwrap();
+    let (_, kept) = root.walk(&["kept"]).unwrap();
+    kept.open(LOpenFlags::O_RDONLY).unwrap();
+
+    // Renamed behind the proxy's back, so the walk can't be replayed.
+    let (_direct, other) = backend.attach();
+    other.renameat("f", &other, "moved").unwrap();
+
+    backend.kill();
+    reconnected(&root);
+    assert_eq!(errno(read(&lost)), errno::EIO);
+    assert_eq!(errno(lost.getattr(GetattrMask::BASIC)), errno::EIO);
+    assert_eq!(read(&kept).unwrap(), b"kept");
+    // Clunk is answered by the proxy and forgets the fid.
+    lost.clunk().unwrap();
+    let (_, again) = root.walk(&["moved"]).unwrap();
+    again.open(LOpenFlags::O_RDONLY).unwrap();
+    assert_eq!(read(&again).unwrap(), b"data");
+}
+
+// The contents of f on the backend the connection uses.
+fn served_by(root: &ClientFid) -> Vec<u8> {
+    let (_, f) = root.walk(&["f"]).unwrap();
+    f.open(LOpenFlags::O_RDONLY).unwrap();
+    read(&f).unwrap()
+}
+
+#[test]
+fn failover() {
+    let (a, b) = (Backend::replica(b"a"), Backend::replica(b"b"));
+    let backends = backends(&[&a, &b], Balance::Failover);
+    let (_client, root) = proxy(&backends);
+    let (_, f) = root.walk(&["f"]).unwrap();
+    f.open(LOpenFlags::O_RDONLY).unwrap();
+    assert_eq!(read(&f).unwrap(), b"a");
+    assert_eq!(served_by(&proxy(&backends).1), b"a");
+
+    // The open fid moves to the next backend.
+    a.stop();
+    reconnected(&root);
+    assert_eq!(read(&f).unwrap(), b"b");
+    assert_eq!(served_by(&proxy(&backends).1), b"b");
+
+    // Health checks bring the first backend back.
+    a.restart();
+    backends.check_health(Duration::from_secs(1), None);
+    assert_eq!(served_by(&proxy(&backends).1), b"a");
+}
+
+#[test]
+fn health_checks() {
+    let (a, b) = (Backend::replica(b"a"), Backend::replica(b"b"));
+    let backends = backends(&[&a, &b], Balance::Failover);
+    a.stop();
+    backends.check_health(Duration::from_secs(1), Some(("probe", "")));
+    assert_eq!(backends.candidates(), [1, 0]);
+    assert_eq!(served_by(&proxy(&backends).1), b"b");
+}
+
+#[test]
+fn round_robin() {
+    let (a, b) = (Backend::replica(b"a"), Backend::replica(b"b"));
+    let backends = backends(&[&a, &b], Balance::RoundRobin);
+    let served: Vec<Vec<u8>> = (0..4).map(|_| served_by(&proxy(&backends).1)).collect();
+    assert_eq!(served, [b"a", b"b", b"a", b"b"]);
+
+    // Unhealthy backends are left out of the rotation.
+    b.stop();
+    backends.check_health(Duration::from_secs(1), None);
+    let served: Vec<Vec<u8>> = (0..2).map(|_| served_by(&proxy(&backends).1)).collect();
+    assert_eq!(served, [b"a", b"a"]);
+}
+
+#[test]
+fn least_outstanding() {
+    let (a, b) = (Backend::replica(b"a"), Backend::replica(b"b"));
+    let backends = backends(&[&a, &b], Balance::LeastOutstanding);
+    let (_busy_client, busy) = proxy(&backends);
+    assert_eq!(served_by(&busy), b"a");
+    // The backend never answers statfs, leaving it outstanding.
+    std::thread::spawn(move || busy.statfs());
+    while backends.outstanding(0) == 0 {
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(served_by(&proxy(&backends).1), b"b");
+    assert_eq!(served_by(&proxy(&backends).1), b"b");
+}
@@ -0,0 +1,836 @@
+use super::errno;
+use super::fcall;
+use super::fcall::{Fcall, FcallStr, LOpenFlags, Qid, QidType, TaggedFcall};
+use super::hook::request_fids;
+use super::proxy::{Action, Interceptor, ProxyContext};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Rough bookkeeping overheads used for the memory budget.
+const NODE_COST: usize = 128;
+const ATTR_COST: usize = std::mem::size_of::<fcall::Rgetattr>();
+const ENTRY_COST: usize = 32;
+
+/// Limits for a Cache.
+#[derive(Clone, Debug)]
+pub struct CacheOptions {
+    /// Roughly how many bytes of attributes, names and file data to keep.
+    pub budget: usize,
+    /// How long cached entries are trusted before asking the server again,
+    /// a changed qid version or data version invalidates them sooner.
+    pub max_age: Duration,
+}
+
+impl Default for CacheOptions {
+    fn default() -> CacheOptions {
+        CacheOptions {
+            budget: 64 * 1024 * 1024,
+            max_age: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Requests answered from the cache.
+    pub hits: u64,
+    /// Cacheable requests sent to the server.
+    pub misses: u64,
+    /// Bytes counted against the budget.
+    pub bytes: usize,
+    /// Files with something cached.
+    pub files: usize,
+}
+
+// Everything cached about one file, keyed by Qid.path.
+struct Node {
+    version: u32,
+    data_version: Option<u64>,
+    // Changes whenever the node is invalidated, responses to requests sent
+    // before that are not cached.
+    gen: u64,
+    since: Instant,
+    tick: u64,
+    cost: usize,
+    // The mask the attributes were requested with.
+    attr: Option<(fcall::GetattrMask, fcall::Rgetattr)>,
+    children: HashMap<Vec<u8>, Qid>,
+    listings: HashMap<(u64, u32), fcall::DirEntryData<'static>>,
+    // Non overlapping runs of file data by offset.
+    extents: BTreeMap<u64, Vec<u8>>,
+    eof: Option<u64>,
+}
+
+impl Node {
+    fn clear(&mut self) {
+        self.attr = None;
+        self.children.clear();
+        self.listings.clear();
+        self.extents.clear();
+        self.eof = None;
+        self.since = Instant::now();
+    }
+
+    fn cost(&self) -> usize {
+        NODE_COST
+            + self.attr.as_ref().map(|_| ATTR_COST).unwrap_or(0)
+            + self
+                .children
+                .keys()
+                .map(|name| name.len() + ENTRY_COST)
+                .sum::<usize>()
+            + self
+                .listings
+                .values()
+                .flat_map(|listing| listing.data.iter())
+                .map(|entry| entry.name.len() + ENTRY_COST)
+                .sum::<usize>()
+            + self.extents.values().map(|data| data.len()).sum::<usize>()
+    }
+
+    fn read(&self, offset: u64, count: u32) -> Option<Vec<u8>> {
+        let mut end = offset + count as u64;
+        if let Some(eof) = self.eof {
+            if offset >= eof {
+                return Some(Vec::new());
+            }
+            end = end.min(eof);
+        }
+        let (start, data) = self.extents.range(..=offset).next_back()?;
+        if start + data.len() as u64 >= end {
+            Some(data[(offset - start) as usize..(end - start) as usize].to_vec())
+        } else {
+            None
+        }
+    }
+
+    // Newer data replaces anything it overlaps.
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        let mut start = offset;
+        let mut buf = data.to_vec();
+        if let Some((&s, d)) = self.extents.range(..=offset).next_back() {
+            if s + d.len() as u64 >= offset {
+                let mut merged = d[..(offset - s) as usize].to_vec();
+                merged.extend_from_slice(&buf);
+                if s + d.len() as u64 > offset + buf.len() as u64 {
+                    merged.extend_from_slice(&d[(offset - s) as usize + buf.len()..]);
+                }
+                self.extents.remove(&s);
+                start = s;
+                buf = merged;
+            }
+        }
+        while let Some((&s, d)) = self.extents.range(start..).next() {
+            let end = start + buf.len() as u64;
+            if s > end {
+                break;
+            }
+            if s + d.len() as u64 > end {
+                buf.extend_from_slice(&d[(end - s) as usize..]);
+            }
+            self.extents.remove(&s);
+        }
+        self.extents.insert(start, buf);
+    }
+}
+
+#[derive(Clone)]
+enum FidKind {
+    Real {
+        open: Option<LOpenFlags>,
+    },
+    // A walk answered from the cache, the server has not seen the fid yet.
+    Lazy {
+        root: u32,
+        wnames: Vec<FcallStr<'static>>,
+    },
+    // A lazy walk that failed when it was finally sent.
+    Stale(u32),
+}
+
+#[derive(Clone)]
+struct FidInfo {
+    qid: Qid,
+    kind: FidKind,
+}
+
+// A request that may fill the cache, and the generations it was sent at.
+struct Pending {
+    path: u64,
+    gen: u64,
+    ns_gen: u64,
+}
+
+struct State {
+    nodes: HashMap<u64, Node>,
+    lru: BTreeMap<u64, u64>,
+    tick: u64,
+    next_gen: u64,
+    // Changes whenever a name may have been removed from any directory.
+    ns_gen: u64,
+    used: usize,
+    fids: HashMap<u32, FidInfo>,
+    pending: HashMap<u16, Pending>,
+    // Tags answered without the server.
+    replied: HashSet<u16>,
+    hits: u64,
+    misses: u64,
+}
+
+// Walks needed to make a lazy fid real.
+struct LazyWalk {
+    fid: u32,
+    root: u32,
+    wnames: Vec<FcallStr<'static>>,
+}
+
+fn readable(flags: LOpenFlags) -> bool {
+    flags.bits() & 3 != LOpenFlags::O_WRONLY.bits()
+}
+
+impl State {
+    fn gen(&mut self) -> u64 {
+        self.next_gen += 1;
+        self.next_gen
+    }
+
+    fn touch(&mut self, path: u64) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(node) = self.nodes.get_mut(&path) {
+            self.lru.remove(&node.tick);
+            node.tick = tick;
+            self.lru.insert(tick, path);
+        }
+    }
+
+    fn recost(&mut self, path: u64) {
+        if let Some(node) = self.nodes.get_mut(&path) {
+            let cost = node.cost();
+            self.used = self.used - node.cost + cost;
+            node.cost = cost;
+        }
+    }
+
+    fn evict(&mut self, budget: usize) {
+        while self.used > budget {
+            let path = match self.lru.iter().next() {
+                Some((_, path)) => *path,
+                None => break,
+            };
+            self.remove_node(path);
+        }
+    }
+
+    fn remove_node(&mut self, path: u64) {
+        if let Some(node) = self.nodes.remove(&path) {
+            self.lru.remove(&node.tick);
+            self.used -= node.cost;
+        }
+    }
+
+    fn ensure_node(&mut self, qid: &Qid) -> u64 {
+        if !self.nodes.contains_key(&qid.path) {
+            let gen = self.gen();
+            self.nodes.insert(
+                qid.path,
+                Node {
+                    version: qid.version,
+                    data_version: None,
+                    gen,
+                    since: Instant::now(),
+                    tick: 0,
+                    cost: 0,
+                    attr: None,
+                    children: HashMap::new(),
+                    listings: HashMap::new(),
+                    extents: BTreeMap::new(),
+                    eof: None,
+                },
+            );
+            self.recost(qid.path);
+        }
+        self.touch(qid.path);
+        self.nodes[&qid.path].gen
+    }
+
+    // A node whose contents are young enough to use.
+    fn fresh_node(&mut self, path: u64, max_age: Duration) -> Option<&mut Node> {
+        let node = self.nodes.get_mut(&path)?;
+        if node.since.elapsed() > max_age {
+            node.clear();
+        }
+        self.recost(path);
+        self.touch(path);
+        self.nodes.get_mut(&path)
+    }
+
+    fn invalidate(&mut self, path: u64) {
+        let gen = self.gen();
+        if let Some(node) = self.nodes.get_mut(&path) {
+            node.clear();
+            node.gen = gen;
+            self.recost(path);
+        }
+    }
+
+    fn invalidate_fid(&mut self, fid: u32) {
+        if let Some(path) = self.fids.get(&fid).map(|info| info.qid.path) {
+            self.invalidate(path);
+        }
+    }
+
+    fn invalidate_child(&mut self, dfid: u32, name: &FcallStr) {
+        let child = self
+            .fids
+            .get(&dfid)
+            .and_then(|info| self.nodes.get(&info.qid.path))
+            .and_then(|node| node.children.get(name.as_bytes()))
+            .map(|qid| qid.path);
+        if let Some(path) = child {
+            self.invalidate(path);
+        }
+    }
+
+    // Forget every name, used when we can't tell which directory changed.
+    fn forget_names(&mut self) {
+        let paths: Vec<u64> = self.nodes.keys().copied().collect();
+        for path in paths {
+            let node = self.nodes.get_mut(&path).unwrap();
+            node.children.clear();
+            node.listings.clear();
+            self.recost(path);
+        }
+    }
+
+    // Invalidate whatever a request may change, before it is sent and again
+    // once it is answered.
+    fn mutated(&mut self, fcall: &Fcall) {
+        match fcall {
+            Fcall::Twrite(fcall::Twrite { fid, .. })
+            | Fcall::Tsetattr(fcall::Tsetattr { fid, .. })
+            | Fcall::Tlcreate(fcall::Tlcreate { fid, .. })
+            | Fcall::Tsymlink(fcall::Tsymlink { fid, .. })
+            | Fcall::Tmknod(fcall::Tmknod { dfid: fid, .. })
+            | Fcall::Tmkdir(fcall::Tmkdir { dfid: fid, .. }) => self.invalidate_fid(*fid),
+            Fcall::Tlopen(tlopen) if tlopen.flags.contains(LOpenFlags::O_TRUNC) => {
+                self.invalidate_fid(tlopen.fid)
+            }
+            Fcall::Tlink(tlink) => {
+                self.invalidate_fid(tlink.dfid);
+                self.invalidate_fid(tlink.fid);
+            }
+            Fcall::Tunlinkat(tunlinkat) => {
+                self.ns_gen += 1;
+                self.invalidate_child(tunlinkat.dfid, &tunlinkat.name);
+                self.invalidate_fid(tunlinkat.dfid);
+            }
+            Fcall::Trenameat(trenameat) => {
+                self.ns_gen += 1;
+                self.invalidate_child(trenameat.olddfid, &trenameat.oldname);
+                self.invalidate_child(trenameat.newdfid, &trenameat.newname);
+                self.invalidate_fid(trenameat.olddfid);
+                self.invalidate_fid(trenameat.newdfid);
+            }
+            // The old parent directory is unknown.
+            Fcall::Trename(trename) => {
+                self.ns_gen += 1;
+                self.invalidate_child(trename.dfid, &trename.name);
+                self.invalidate_fid(trename.fid);
+                self.invalidate_fid(trename.dfid);
+                self.forget_names();
+            }
+            Fcall::Tremove(tremove) => {
+                self.ns_gen += 1;
+                self.invalidate_fid(tremove.fid);
+                self.forget_names();
+            }
+            _ => (),
+        }
+    }
+
+    // Note a qid from the server, a new version invalidates the file.
+    fn observe(&mut self, qid: &Qid) {
+        let stale = match self.nodes.get(&qid.path) {
+            Some(node) => node.version != qid.version,
+            None => false,
+        };
+        if stale {
+            self.invalidate(qid.path);
+            self.nodes.get_mut(&qid.path).unwrap().version = qid.version;
+        }
+    }
+
+    fn observe_attr(&mut self, rgetattr: &fcall::Rgetattr) {
+        self.observe(&rgetattr.qid);
+        if !rgetattr.valid.contains(fcall::GetattrMask::DATA_VERSION) {
+            return;
+        }
+        let data_version = Some(rgetattr.stat.data_version);
+        let stale = match self.nodes.get(&rgetattr.qid.path) {
+            Some(node) => node.data_version.is_some() && node.data_version != data_version,
+            None => false,
+        };
+        if stale {
+            self.invalidate(rgetattr.qid.path);
+        }
+        if let Some(node) = self.nodes.get_mut(&rgetattr.qid.path) {
+            node.data_version = data_version;
+        }
+    }
+
+    fn is_fresh(&self, pending: &Pending, names: bool) -> bool {
+        match self.nodes.get(&pending.path) {
+            Some(node) => node.gen == pending.gen && (!names || pending.ns_gen == self.ns_gen),
+            None => false,
+        }
+    }
+
+    // The qids of a walk, if every name is cached.
+    fn walk_qids(&mut self, from: Qid, wnames: &[FcallStr], max_age: Duration) -> Option<Vec<Qid>> {
+        let mut qid = from;
+        let mut wqids = Vec::with_capacity(wnames.len());
+        for name in wnames {
+            if !qid.typ.contains(QidType::DIR) {
+                return None;
+            }
+            qid = *self
+                .fresh_node(qid.path, max_age)?
+                .children
+                .get(name.as_bytes())?;
+            wqids.push(qid);
+        }
+        Some(wqids)
+    }
+
+    // Answer a request without the server if we can.
+    fn answer(&mut self, req: &Fcall, max_age: Duration) -> Option<Action> {
+        for fid in request_fids(req) {
+            match self.fids.get(&fid).map(|info| &info.kind) {
+                Some(FidKind::Stale(ecode)) => {
+                    let ecode = *ecode;
+                    return Some(match req {
+                        Fcall::Tclunk(_) => {
+                            self.fids.remove(&fid);
+                            Action::Reply(fcall::Rclunk {}.into())
+                        }
+                        Fcall::Tremove(_) => {
+                            self.fids.remove(&fid);
+                            Action::Reject(ecode)
+                        }
+                        _ => Action::Reject(ecode),
+                    });
+                }
+                Some(FidKind::Lazy { .. }) if matches!(req, Fcall::Tclunk(_)) => {
+                    self.fids.remove(&fid);
+                    return Some(Action::Reply(fcall::Rclunk {}.into()));
+                }
+                _ => (),
+            }
+        }
+
+        match req {
+            Fcall::Tgetattr(tgetattr) => {
+                let info = self.fids.get(&tgetattr.fid)?.clone();
+                let node = self.fresh_node(info.qid.path, max_age)?;
+                match &node.attr {
+                    Some((mask, rgetattr)) if mask.contains(tgetattr.req_mask) => {
+                        Some(Action::Reply(rgetattr.clone().into()))
+                    }
+                    _ => None,
+                }
+            }
+            Fcall::Twalk(twalk) => {
+                if twalk.fid == twalk.new_fid
+                    || twalk.wnames.len() > fcall::MAXWELEM
+                    || self.fids.contains_key(&twalk.new_fid)
+                {
+                    return None;
+                }
+                let info = self.fids.get(&twalk.fid)?.clone();
+                let (root, mut wnames) = match info.kind {
+                    FidKind::Real { open: None } => (twalk.fid, Vec::new()),
+                    FidKind::Lazy { root, wnames } => (root, wnames),
+                    _ => return None,
+                };
+                let wqids = self.walk_qids(info.qid, &twalk.wnames, max_age)?;
+                wnames.extend(twalk.wnames.iter().map(|name| name.clone_static()));
+                self.fids.insert(
+                    twalk.new_fid,
+                    FidInfo {
+                        qid: wqids.last().copied().unwrap_or(info.qid),
+                        kind: FidKind::Lazy { root, wnames },
+                    },
+                );
+                Some(Action::Reply(fcall::Rwalk { wqids }.into()))
+            }
+            Fcall::Tread(tread) => {
+                let info = self.fids.get(&tread.fid)?.clone();
+                match info.kind {
+                    FidKind::Real { open: Some(flags) } if readable(flags) => (),
+                    _ => return None,
+                }
+                let data = self
+                    .fresh_node(info.qid.path, max_age)?
+                    .read(tread.offset, tread.count)?;
+                Some(Action::Reply(
+                    fcall::Rread {
+                        data: std::borrow::Cow::from(data),
+                    }
+                    .into(),
+                ))
+            }
+            Fcall::Treaddir(treaddir) => {
+                let info = self.fids.get(&treaddir.fid)?.clone();
+                match info.kind {
+                    FidKind::Real { open: Some(_) } => (),
+                    _ => return None,
+                }
+                let data = self
+                    .fresh_node(info.qid.path, max_age)?
+                    .listings
+                    .get(&(treaddir.offset, treaddir.count))?
+                    .clone();
+                Some(Action::Reply(fcall::Rreaddir { data }.into()))
+            }
+            _ => None,
+        }
+    }
+
+    // Lazy fids the server must know about before the request is sent.
+    fn lazy_walks(&self, req: &Fcall) -> Vec<LazyWalk> {
+        let mut fids: Vec<u32> = request_fids(req)
+            .into_iter()
+            .filter(|fid| {
+                matches!(
+                    self.fids.get(fid),
+                    Some(FidInfo {
+                        kind: FidKind::Lazy { .. },
+                        ..
+                    })
+                )
+            })
+            .collect();
+
+        // Requests that stop a fid being walked from.
+        let root = match req {
+            Fcall::Tclunk(fcall::Tclunk { fid })
+            | Fcall::Tremove(fcall::Tremove { fid })
+            | Fcall::Tlopen(fcall::Tlopen { fid, .. })
+            | Fcall::Tlcreate(fcall::Tlcreate { fid, .. }) => Some(*fid),
+            Fcall::Twalk(twalk) if twalk.fid == twalk.new_fid => Some(twalk.fid),
+            _ => None,
+        };
+        if let Some(root) = root {
+            fids.extend(self.fids.iter().filter_map(|(fid, info)| match &info.kind {
+                FidKind::Lazy { root: r, .. } if *r == root => Some(*fid),
+                _ => None,
+            }));
+        }
+
+        fids.sort_unstable();
+        fids.dedup();
+        fids.into_iter()
+            .filter_map(|fid| match &self.fids[&fid].kind {
+                FidKind::Lazy { root, wnames } => Some(LazyWalk {
+                    fid,
+                    root: *root,
+                    wnames: wnames.clone(),
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Track requests that will fill the cache when answered.
+    fn sent(&mut self, tag: u16, req: &Fcall) {
+        let fid = match req {
+            Fcall::Tgetattr(fcall::Tgetattr { fid, .. })
+            | Fcall::Twalk(fcall::Twalk { fid, .. })
+            | Fcall::Tread(fcall::Tread { fid, .. })
+            | Fcall::Treaddir(fcall::Treaddir { fid, .. }) => *fid,
+            _ => return,
+        };
+        let qid = match self.fids.get(&fid) {
+            Some(FidInfo {
+                qid,
+                kind: FidKind::Real { .. },
+            }) => *qid,
+            _ => return,
+        };
+        self.misses += 1;
+        let gen = self.ensure_node(&qid);
+        self.pending.insert(
+            tag,
+            Pending {
+                path: qid.path,
+                gen,
+                ns_gen: self.ns_gen,
+            },
+        );
+    }
+
+    fn received(&mut self, req: &Fcall, resp: &TaggedFcall) {
+        let pending = self.pending.remove(&resp.tag);
+        self.mutated(req);
+
+        match (req, &resp.fcall) {
+            (Fcall::Tattach(tattach), Fcall::Rattach(rattach)) => {
+                self.observe(&rattach.qid);
+                self.fids.insert(
+                    tattach.fid,
+                    FidInfo {
+                        qid: rattach.qid,
+                        kind: FidKind::Real { open: None },
+                    },
+                );
+            }
+            (Fcall::Twalk(twalk), Fcall::Rwalk(rwalk)) => {
+                for qid in rwalk.wqids.iter() {
+                    self.observe(qid);
+                }
+                let from = self.fids.get(&twalk.fid).map(|info| info.qid);
+                if let (Some(pending), Some(from)) = (pending, from) {
+                    if self.is_fresh(&pending, true) {
+                        let mut dir = from;
+                        for (name, qid) in twalk.wnames.iter().zip(rwalk.wqids.iter()) {
+                            self.ensure_node(&dir);
+                            let node = self.nodes.get_mut(&dir.path).unwrap();
+                            node.children.insert(name.as_bytes().to_vec(), *qid);
+                            self.recost(dir.path);
+                            dir = *qid;
+                        }
+                    }
+                }
+                if rwalk.wqids.len() == twalk.wnames.len() {
+                    if let Some(qid) = rwalk.wqids.last().copied().or(from) {
+                        self.fids.insert(
+                            twalk.new_fid,
+                            FidInfo {
+                                qid,
+                                kind: FidKind::Real { open: None },
+                            },
+                        );
+                    }
+                }
+            }
+            (Fcall::Tlopen(tlopen), Fcall::Rlopen(rlopen)) => {
+                self.observe(&rlopen.qid);
+                self.fids.insert(
+                    tlopen.fid,
+                    FidInfo {
+                        qid: rlopen.qid,
+                        kind: FidKind::Real {
+                            open: Some(tlopen.flags),
+                        },
+                    },
+                );
+            }
+            (Fcall::Tlcreate(tlcreate), Fcall::Rlcreate(rlcreate)) => {
+                self.observe(&rlcreate.qid);
+                self.fids.insert(
+                    tlcreate.fid,
+                    FidInfo {
+                        qid: rlcreate.qid,
+                        kind: FidKind::Real {
+                            open: Some(tlcreate.flags),
+                        },
+                    },
+                );
+            }
+            (Fcall::Tgetattr(tgetattr), Fcall::Rgetattr(rgetattr)) => {
+                let fresh = matches!(&pending, Some(pending) if self.is_fresh(pending, false));
+                self.observe_attr(rgetattr);
+                if let (true, Some(pending)) = (fresh, pending) {
+                    if let Some(node) = self.nodes.get_mut(&pending.path) {
+                        node.attr = Some((tgetattr.req_mask, rgetattr.clone()));
+                        self.recost(pending.path);
+                    }
+                }
+            }
+            (Fcall::Tread(tread), Fcall::Rread(rread)) => {
+                if let Some(pending) = pending.filter(|pending| self.is_fresh(pending, false)) {
+                    let node = self.nodes.get_mut(&pending.path).unwrap();
+                    node.write(tread.offset, &rread.data);
+                    // Servers may answer short anywhere in a file, only an
+                    // empty read or the size we last saw marks its end.
+                    let end = tread.offset + rread.data.len() as u64;
+                    let size = node
+                        .attr
+                        .as_ref()
+                        .filter(|(_, attr)| attr.valid.contains(fcall::GetattrMask::SIZE))
+                        .map(|(_, attr)| attr.stat.size);
+                    if rread.data.is_empty() || size == Some(end) {
+                        node.eof = Some(end);
+                    }
+                    self.recost(pending.path);
+                }
+            }
+            (Fcall::Treaddir(treaddir), Fcall::Rreaddir(rreaddir)) => {
+                if let Some(pending) = pending.filter(|pending| self.is_fresh(pending, true)) {
+                    let node = self.nodes.get_mut(&pending.path).unwrap();
+                    node.listings.insert(
+                        (treaddir.offset, treaddir.count),
+                        rreaddir.clone_static().data,
+                    );
+                    self.recost(pending.path);
+                }
+            }
+            (Fcall::Tclunk(fcall::Tclunk { fid }), _)
+            | (Fcall::Tremove(fcall::Tremove { fid }), _) => {
+                self.fids.remove(fid);
+            }
+            (Fcall::Tflush(tflush), _) => {
+                self.pending.remove(&tflush.oldtag);
+            }
+            _ => (),
+        }
+    }
+}
+
+// Make a lazy fid real by walking it from its root.
+fn materialize(ctx: &ProxyContext, walk: &LazyWalk) -> Result<(), u32> {
+    let mut chunks: Vec<&[FcallStr]> = walk.wnames.chunks(fcall::MAXWELEM).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+    let mut from = walk.root;
+    for chunk in chunks {
+        let resp = ctx.call(
+            fcall::Twalk {
+                fid: from,
+                new_fid: walk.fid,
+                wnames: chunk.to_vec(),
+            }
+            .into(),
+        );
+        let result = match resp {
+            Ok(Fcall::Rwalk(rwalk)) if rwalk.wqids.len() == chunk.len() => Ok(()),
+            Ok(Fcall::Rwalk(_)) => Err(errno::ENOENT),
+            Ok(Fcall::Rlerror(rlerror)) => Err(rlerror.ecode),
+            _ => Err(errno::EIO),
+        };
+        if let Err(ecode) = result {
+            if from == walk.fid {
+                let _ = ctx.call(fcall::Tclunk { fid: walk.fid }.into());
+            }
+            return Err(ecode);
+        }
+        from = walk.fid;
+    }
+    Ok(())
+}
+
+/// A proxy Interceptor that answers repeated reads from memory.
+///
+/// Attributes, walked names, directory listings and read data are cached
+/// by Qid.path. Entries are dropped when the server reports a different
+/// qid version or data version for the file, when they are older than
+/// CacheOptions::max_age, or when a request through the proxy changes the
+/// file. Writes and other changes always go to the server.
+///
+/// Walks answered from the cache create their fid on the server only once
+/// it is used for something the cache can't answer.
+pub struct Cache {
+    options: CacheOptions,
+    state: Mutex<State>,
+}
+
+impl Cache {
+    pub fn new(options: CacheOptions) -> Cache {
+        Cache {
+            options,
+            state: Mutex::new(State {
+                nodes: HashMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+                next_gen: 0,
+                ns_gen: 0,
+                used: 0,
+                fids: HashMap::new(),
+                pending: HashMap::new(),
+                replied: HashSet::new(),
+                hits: 0,
+                misses: 0,
+            }),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let state = self.state.lock().unwrap();
+        CacheStats {
+            hits: state.hits,
+            misses: state.misses,
+            bytes: state.used,
+            files: state.nodes.len(),
+        }
+    }
+
+    /// Drop everything cached, fids are still tracked.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        let paths: Vec<u64> = state.nodes.keys().copied().collect();
+        for path in paths {
+            state.remove_node(path);
+        }
+    }
+}
+
+impl Default for Cache {
+    fn default() -> Cache {
+        Cache::new(CacheOptions::default())
+    }
+}
+
+impl Interceptor for Cache {
+    fn request(&self, ctx: &ProxyContext, req: &TaggedFcall) -> Action {
+        let walks = {
+            let mut state = self.state.lock().unwrap();
+            state.pending.remove(&req.tag);
+            state.replied.remove(&req.tag);
+            if let Some(action) = state.answer(&req.fcall, self.options.max_age) {
+                if let Action::Reply(_) = action {
+                    state.hits += 1;
+                }
+                state.replied.insert(req.tag);
+                return action;
+            }
+            state.lazy_walks(&req.fcall)
+        };
+
+        if !walks.is_empty() {
+            // No lock held, the response reader needs it while we wait.
+            let results: Vec<(u32, Result<(), u32>)> = walks
+                .iter()
+                .map(|walk| (walk.fid, materialize(ctx, walk)))
+                .collect();
+            let mut state = self.state.lock().unwrap();
+            for (fid, result) in results {
+                let info = state.fids.get_mut(&fid).unwrap();
+                info.kind = match result {
+                    Ok(()) => FidKind::Real { open: None },
+                    Err(ecode) => FidKind::Stale(ecode),
+                };
+            }
+            drop(state);
+            // Failed walks are answered now.
+            return self.request(ctx, req);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.mutated(&req.fcall);
+        state.sent(req.tag, &req.fcall);
+        Action::Forward
+    }
+
+    fn response(&self, _ctx: &ProxyContext, req: &Fcall, resp: &TaggedFcall) -> Action {
+        let mut state = self.state.lock().unwrap();
+        if state.replied.remove(&resp.tag) {
+            return Action::Forward;
+        }
+        state.received(req, resp);
+        state.evict(self.options.budget);
+        Action::Forward
+    }
+}
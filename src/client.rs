@@ -1,7 +1,11 @@
+use super::error::{P9Error, P9ErrorKind};
 use super::fcall;
-use super::fcall::{Fcall, FcallStr, TaggedFcall};
+use super::fcall::{Fcall, FcallStr, FcallType, TaggedFcall};
+use super::hook;
+use super::hook::{FcallHook, HookPoint};
+use super::metrics::Metrics;
 use super::transport;
-use super::transport::{ReadTransport, WriteTransport};
+use super::transport::{frame_len, ReadTransport, WriteTransport};
 use crossbeam_channel as channel;
 use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
@@ -9,23 +13,40 @@ use std::net::TcpStream;
 use std::ops::DerefMut;
 #[cfg(unix)]
 use std::os::unix::net::UnixStream;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
 
 fn err_other(msg: &str) -> std::io::Error {
-    std::io::Error::new(std::io::ErrorKind::Other, msg)
+    std::io::Error::other(msg)
 }
 
-fn err_io() -> std::io::Error {
-    err_other("io error")
+// A request made by Client::fcall, for building the errors it fails with.
+struct Request<'p> {
+    op: FcallType,
+    fid: Option<u32>,
+    path: &'p [FcallStr<'p>],
 }
 
-fn err_io_result<R, E>(_e: E) -> Result<R, std::io::Error> {
-    Err(err_io())
-}
+impl Request<'_> {
+    fn error(&self, kind: P9ErrorKind) -> std::io::Error {
+        P9Error {
+            kind,
+            op: Some(self.op),
+            fid: self.fid,
+            path: self
+                .path
+                .iter()
+                .map(|name| String::from_utf8_lossy(name.as_bytes()).into_owned())
+                .collect(),
+        }
+        .into()
+    }
 
-fn err_unexpected_response() -> std::io::Error {
-    err_other("unexpected response from server")
+    // The response was not the one the request called for.
+    fn unexpected(&self, resp: &Fcall) -> std::io::Error {
+        self.error(P9ErrorKind::Protocol(FcallType::from(resp)))
+    }
 }
 
 struct FidsetInner {
@@ -67,11 +88,19 @@ impl Fidset {
             }
         }
     }
+
+    fn reserve(&self, id: u32) -> bool {
+        id != fcall::NOFID && self.inner.lock().unwrap().set.insert(id)
+    }
+
+    fn release(&self, id: u32) {
+        self.inner.lock().unwrap().set.remove(&id);
+    }
 }
 
 struct InflightFcallsInner {
     disconnected: bool,
-    map: HashMap<u16, channel::Sender<Fcall<'static>>>,
+    map: HashMap<u16, channel::Sender<(Fcall<'static>, usize)>>,
     next_tag: u16,
 }
 
@@ -101,7 +130,8 @@ impl InflightFcalls {
         cvar.notify_all();
     }
 
-    fn add(&self, respond_to: channel::Sender<Fcall<'static>>) -> Result<u16, std::io::Error> {
+    // Returns None once disconnected.
+    fn add(&self, respond_to: channel::Sender<(Fcall<'static>, usize)>) -> Option<u16> {
         let (ref inner, ref cvar) = self.inner_and_cvar.as_ref();
         let mut inner = inner.lock().unwrap();
 
@@ -111,7 +141,7 @@ impl InflightFcalls {
         }
 
         if inner.disconnected {
-            return Err(err_other("disconnected"));
+            return None;
         };
 
         loop {
@@ -123,12 +153,12 @@ impl InflightFcalls {
             if !inner.map.contains_key(&inner.next_tag) {
                 let tag = inner.next_tag;
                 inner.map.insert(tag, respond_to);
-                return Ok(tag);
+                return Some(tag);
             }
         }
     }
 
-    fn remove(&self, tag: u16) -> Option<channel::Sender<Fcall<'static>>> {
+    fn remove(&self, tag: u16) -> Option<channel::Sender<(Fcall<'static>, usize)>> {
         let (ref inner, ref cvar) = self.inner_and_cvar.as_ref();
         let mut inner = inner.lock().unwrap();
         cvar.notify_one();
@@ -143,12 +173,15 @@ struct ClientWriteState {
 
 struct ClientState {
     msize: u32,
+    version: String,
     fids: Fidset,
     fcalls: InflightFcalls,
     // Threads use a shared buffer and connection guarded by a mutex,
     // this slightly odd design lets us avoid copying when writing.
     write_state: Mutex<ClientWriteState>,
     read_worker_handle: Option<std::thread::JoinHandle<()>>,
+    metrics: Option<Arc<Metrics>>,
+    hook: RwLock<Option<Arc<dyn FcallHook>>>,
 }
 
 impl Drop for ClientState {
@@ -162,6 +195,117 @@ impl Drop for ClientState {
     }
 }
 
+/// The smallest msize a Client accepts.
+pub const MIN_MSIZE: u32 = 4096 + fcall::READDIRHDRSZ;
+
+/// Options for connecting a Client.
+#[derive(Clone, Debug)]
+pub struct ClientBuilder {
+    msize: u32,
+    min_msize: u32,
+    handshake_timeout: Option<Duration>,
+    dialects: Vec<String>,
+    thread_name: Option<String>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl Default for ClientBuilder {
+    fn default() -> ClientBuilder {
+        ClientBuilder {
+            msize: 128 * 1024,
+            min_msize: MIN_MSIZE,
+            handshake_timeout: None,
+            dialects: vec!["9P2000.L".to_string()],
+            thread_name: None,
+            metrics: None,
+        }
+    }
+}
+
+impl ClientBuilder {
+    pub fn new() -> ClientBuilder {
+        ClientBuilder::default()
+    }
+
+    /// The msize to ask for, the server may offer less.
+    pub fn msize(mut self, msize: u32) -> ClientBuilder {
+        self.msize = msize;
+        self
+    }
+
+    /// Fail to connect if the server offers an msize below this, must be at
+    /// least MIN_MSIZE.
+    pub fn min_msize(mut self, min_msize: u32) -> ClientBuilder {
+        self.min_msize = min_msize;
+        self
+    }
+
+    /// Fail to connect if the server takes longer than this to answer
+    /// Tversion.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> ClientBuilder {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// The protocol versions to offer in order of preference, the server
+    /// must still speak 9P2000.L messages. Defaults to just "9P2000.L".
+    pub fn dialects<S: Into<String>, I: IntoIterator<Item = S>>(
+        mut self,
+        dialects: I,
+    ) -> ClientBuilder {
+        self.dialects = dialects.into_iter().map(|s| s.into()).collect();
+        self
+    }
+
+    /// The name of the thread reading responses.
+    pub fn thread_name<S: Into<String>>(mut self, name: S) -> ClientBuilder {
+        self.thread_name = Some(name.into());
+        self
+    }
+
+    /// Record every request in metrics, off by default.
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> ClientBuilder {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Connect to an address of the form tcp:HOST:PORT or unix:PATH.
+    pub fn dial(self, addr: &str) -> Result<Client, std::io::Error> {
+        if let Some(addr) = addr.strip_prefix("tcp:") {
+            return self.over_tcp_stream(TcpStream::connect(addr)?);
+        }
+        #[cfg(unix)]
+        if let Some(path) = addr.strip_prefix("unix:") {
+            return self.over_unix_stream(UnixStream::connect(path)?);
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("unsupported 9p address {:?}", addr),
+        ))
+    }
+
+    pub fn over_tcp_stream(self, conn: TcpStream) -> Result<Client, std::io::Error> {
+        let r = conn.try_clone()?;
+        let w = conn;
+        self.over_transport(r, w)
+    }
+
+    #[cfg(unix)]
+    pub fn over_unix_stream(self, conn: UnixStream) -> Result<Client, std::io::Error> {
+        let r = conn.try_clone()?;
+        let w = conn;
+        self.over_transport(r, w)
+    }
+
+    pub fn over_transport<R: ReadTransport + 'static, W: WriteTransport + 'static>(
+        self,
+        r: R,
+        w: W,
+    ) -> Result<Client, std::io::Error> {
+        Client::_over_transport(self, Box::new(r), Box::new(w))
+    }
+}
+
 #[derive(Clone)]
 pub struct Client {
     state: Arc<ClientState>,
@@ -181,79 +325,163 @@ impl Client {
         Client::over_transport(r, w, bufsize)
     }
 
+    /// Connect with default options, bufsize is clamped to at least
+    /// MIN_MSIZE, see ClientBuilder for more control.
     pub fn over_transport<R: ReadTransport + 'static, W: WriteTransport + 'static>(
         r: R,
         w: W,
         bufsize: usize,
     ) -> Result<Client, std::io::Error> {
-        let r: Box<dyn ReadTransport> = std::boxed::Box::new(r);
-        let w: Box<dyn WriteTransport> = std::boxed::Box::new(w);
-
-        Client::_over_transport(r, w, bufsize)
+        let msize = bufsize.max(MIN_MSIZE as usize).min(u32::MAX as usize) as u32;
+        ClientBuilder::new().msize(msize).over_transport(r, w)
     }
 
     fn _over_transport(
+        opts: ClientBuilder,
         mut r: Box<dyn ReadTransport>,
         mut w: Box<dyn WriteTransport>,
-        bufsize: usize,
     ) -> Result<Client, std::io::Error> {
-        const MIN_MSIZE: u32 = 4096 + fcall::READDIRHDRSZ;
-        let mut bufsize = bufsize.max(MIN_MSIZE as usize).min(u32::MAX as usize);
-        let mut wbuf = Vec::with_capacity(bufsize);
-        let mut rbuf = Vec::with_capacity(bufsize);
-
-        transport::write(
-            &mut w,
-            &mut wbuf,
-            &TaggedFcall {
-                tag: fcall::NOTAG,
-                fcall: Fcall::Tversion(fcall::Tversion {
-                    msize: bufsize.min(u32::MAX as usize) as u32,
-                    version: "9P2000.L".into(),
-                }),
-            },
-        )?;
-
-        match transport::read(&mut r, &mut rbuf)? {
-            TaggedFcall {
-                tag: fcall::NOTAG,
-                fcall: Fcall::Rversion(fcall::Rversion { msize, version }),
-            } => {
-                if version.as_bytes() != "9P2000.L".as_bytes() {
-                    return Err(err_other("protocol negotiation failed"));
+        if opts.msize < opts.min_msize || opts.min_msize < MIN_MSIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "msize {} and minimum {} must be at least {}",
+                    opts.msize, opts.min_msize, MIN_MSIZE
+                ),
+            ));
+        }
+        let mut wbuf = Vec::with_capacity(opts.msize as usize);
+        let mut rbuf = Vec::with_capacity(opts.msize as usize);
+        let deadline = opts.handshake_timeout.map(|t| Instant::now() + t);
+        let handshake_error = |kind| -> std::io::Error {
+            let mut err = P9Error::new(kind);
+            err.op = Some(FcallType::Tversion);
+            err.into()
+        };
+
+        // Offer each dialect until the server accepts one.
+        let mut negotiated = None;
+        for dialect in opts.dialects.iter() {
+            transport::write(
+                &mut w,
+                &mut wbuf,
+                &TaggedFcall {
+                    tag: fcall::NOTAG,
+                    fcall: Fcall::Tversion(fcall::Tversion {
+                        msize: opts.msize,
+                        version: dialect.as_str().into(),
+                    }),
+                },
+            )?;
+
+            match deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    match transport::read_to_buf_timeout(&mut r, &mut rbuf, timeout) {
+                        Err(err) if err.kind() == std::io::ErrorKind::TimedOut => {
+                            return Err(handshake_error(P9ErrorKind::Timeout))
+                        }
+                        result => result?,
+                    }
+                }
+                None => transport::read_to_buf(&mut r, &mut rbuf)?,
+            }
+            match TaggedFcall::decode(&rbuf[..])? {
+                TaggedFcall {
+                    tag: fcall::NOTAG,
+                    fcall: Fcall::Rversion(fcall::Rversion { msize, version }),
+                } => {
+                    if version.as_bytes() == b"unknown" {
+                        continue;
+                    }
+                    let version = String::from_utf8_lossy(version.as_bytes()).into_owned();
+                    if !opts.dialects.contains(&version) {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("server offered unrequested 9p version {:?}", version),
+                        ));
+                    }
+                    negotiated = Some((msize.min(opts.msize), version));
+                    break;
+                }
+                resp => {
+                    let typ = FcallType::from(&resp.fcall);
+                    return Err(handshake_error(P9ErrorKind::Protocol(typ)));
                 }
-                bufsize = bufsize.min(msize as usize);
             }
-            _ => return Err(err_unexpected_response()),
         }
 
-        wbuf.truncate(bufsize);
-        rbuf.truncate(bufsize);
+        let (msize, version) = match negotiated {
+            Some(negotiated) => negotiated,
+            None => return Err(err_other("protocol negotiation failed")),
+        };
+        if msize < opts.min_msize {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "server msize {} is below the minimum {}",
+                    msize, opts.min_msize
+                ),
+            ));
+        }
+
+        wbuf.truncate(msize as usize);
+        rbuf.truncate(msize as usize);
 
         let fcalls = InflightFcalls::new();
 
         let worker_fcalls = fcalls.clone();
-        let read_worker_handle = thread::spawn(move || {
+        let mut worker = thread::Builder::new();
+        if let Some(name) = opts.thread_name {
+            worker = worker.name(name);
+        }
+        let read_worker_handle = worker.spawn(move || {
             Client::read_worker(r, rbuf, worker_fcalls);
-        });
+        })?;
 
         Ok(Client {
             state: Arc::new(ClientState {
-                msize: bufsize.try_into().unwrap(),
+                msize,
+                version,
                 fids: Fidset::new(),
                 write_state: Mutex::new(ClientWriteState { w, buf: wbuf }),
                 read_worker_handle: Some(read_worker_handle),
                 fcalls,
+                metrics: opts.metrics,
+                hook: RwLock::new(None),
             }),
         })
     }
 
+    /// The negotiated maximum message size.
+    pub fn msize(&self) -> u32 {
+        self.state.msize
+    }
+
+    /// The negotiated protocol version.
+    pub fn version(&self) -> &str {
+        &self.state.version
+    }
+
+    /// The most data a single read or write transfers.
+    pub fn iounit(&self) -> u32 {
+        self.state.msize - fcall::IOHDRSZ
+    }
+
+    /// The most directory entry bytes a single readdir returns.
+    pub fn readdir_size(&self) -> u32 {
+        self.state.msize - fcall::READDIRHDRSZ
+    }
+
     fn read_worker(mut r: Box<dyn ReadTransport>, mut rbuf: Vec<u8>, fcalls: InflightFcalls) {
         loop {
-            match transport::read(&mut r, &mut rbuf) {
+            match transport::read_to_buf(&mut r, &mut rbuf)
+                .and_then(|_| TaggedFcall::decode(&rbuf[..]))
+            {
                 Ok(response) => {
                     if let Some(resp) = fcalls.remove(response.tag) {
-                        resp.send(response.fcall.clone_static()).unwrap();
+                        resp.send((response.fcall.clone_static(), rbuf.len()))
+                            .unwrap();
                     }
                 }
                 Err(_) => {
@@ -275,17 +503,133 @@ impl Client {
         }
     }
 
-    fn fcall<'a>(&self, fcall: Fcall<'a>) -> Result<Fcall<'static>, std::io::Error> {
+    // Make a request, failing with a P9Error unless the response is the
+    // matching R message. Path is the names the request was given, for
+    // errors, and the Request returned describes any later failure too.
+    fn fcall<'a, 'p>(
+        &self,
+        fcall: Fcall<'a>,
+        path: &'p [FcallStr<'p>],
+    ) -> Result<(Fcall<'static>, Request<'p>), std::io::Error> {
+        let req = Request {
+            op: FcallType::from(&fcall),
+            fid: hook::request_fid(&fcall),
+            path,
+        };
+        let resp = self.roundtrip(fcall, req.fid, &|kind| req.error(kind))?;
+        match resp {
+            Fcall::Rlerror(err) => Err(req.error(P9ErrorKind::RemoteErrno(err.ecode))),
+            // Every R message type follows its T message.
+            resp if FcallType::from(&resp) as u8 == req.op as u8 + 1 => Ok((resp, req)),
+            resp => Err(req.unexpected(&resp)),
+        }
+    }
+
+    // Send a request and wait for its response, whatever it is. Error
+    // describes transport failures.
+    fn roundtrip(
+        &self,
+        fcall: Fcall<'_>,
+        fid: Option<u32>,
+        error: &dyn Fn(P9ErrorKind) -> std::io::Error,
+    ) -> Result<Fcall<'static>, std::io::Error> {
         let (tx, rx) = channel::bounded(1);
         let mut write_state_guard = self.state.write_state.lock().unwrap();
         let write_state = write_state_guard.deref_mut();
         let w = &mut write_state.w;
         let buf = &mut write_state.buf;
         // Will block until a tag is free.
-        let tag = self.state.fcalls.add(tx)?;
-        transport::write(w, buf, &TaggedFcall { tag, fcall })?;
+        let tag = match self.state.fcalls.add(tx) {
+            Some(tag) => tag,
+            None => return Err(error(P9ErrorKind::Disconnected)),
+        };
+        let req = TaggedFcall { tag, fcall };
+        if let Err(err) = transport::write(w, buf, &req) {
+            self.state.fcalls.remove(tag);
+            return Err(match err.kind() {
+                std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => {
+                    error(P9ErrorKind::Timeout)
+                }
+                std::io::ErrorKind::BrokenPipe
+                | std::io::ErrorKind::ConnectionReset
+                | std::io::ErrorKind::ConnectionAborted
+                | std::io::ErrorKind::NotConnected
+                | std::io::ErrorKind::UnexpectedEof => error(P9ErrorKind::Disconnected),
+                _ => err,
+            });
+        }
+        let started = self
+            .state
+            .metrics
+            .as_ref()
+            .map(|metrics| (metrics, metrics.start(&req.fcall, frame_len(buf))));
         drop(write_state_guard);
-        rx.recv().or_else(err_io_result)
+
+        let hook = self.state.hook.read().unwrap().clone();
+        if let Some(hook) = &hook {
+            hook::emit(
+                &**hook,
+                HookPoint::ClientRequest,
+                tag,
+                fid,
+                &req.fcall,
+                None,
+            );
+        }
+        let since = Instant::now();
+
+        let resp = match rx.recv() {
+            Ok((resp, bytes)) => {
+                if let Some((metrics, started)) = started {
+                    metrics.finish(started, &resp, bytes);
+                }
+                if let Some(hook) = &hook {
+                    let elapsed = Some(since.elapsed());
+                    hook::emit(&**hook, HookPoint::ClientResponse, tag, fid, &resp, elapsed);
+                }
+                resp
+            }
+            Err(_) => {
+                if let Some((metrics, started)) = started {
+                    metrics.cancel(started);
+                }
+                return Err(error(P9ErrorKind::Disconnected));
+            }
+        };
+        Ok(resp)
+    }
+
+    /// Send any T message as given and return the server's response,
+    /// Rlerror included, for debugging servers. Fids a message creates must
+    /// first be reserved with reserve_fid so the client never reuses them.
+    pub fn raw_fcall(&self, fcall: Fcall<'_>) -> Result<Fcall<'static>, std::io::Error> {
+        let req = Request {
+            op: FcallType::from(&fcall),
+            fid: hook::request_fid(&fcall),
+            path: &[],
+        };
+        self.roundtrip(fcall, req.fid, &|kind| req.error(kind))
+    }
+
+    /// Keep the client from allocating fid id, false if it is already in
+    /// use.
+    pub fn reserve_fid(&self, id: u32) -> bool {
+        self.state.fids.reserve(id)
+    }
+
+    /// Let the client allocate a fid reserved with reserve_fid again.
+    pub fn release_fid(&self, id: u32) {
+        self.state.fids.release(id)
+    }
+
+    /// The metrics given to ClientBuilder::metrics, if any.
+    pub fn metrics(&self) -> Option<Arc<Metrics>> {
+        self.state.metrics.clone()
+    }
+
+    /// Call hook for every request and response on this connection.
+    pub fn set_hook(&self, hook: Option<Arc<dyn FcallHook>>) {
+        *self.state.hook.write().unwrap() = hook;
     }
 
     fn _attach(
@@ -295,19 +639,21 @@ impl Client {
         aname: FcallStr,
     ) -> Result<(fcall::Qid, ClientFid), std::io::Error> {
         let mut fid = self.fresh_fid()?;
-        match self.fcall(Fcall::Tattach(fcall::Tattach {
-            afid: fcall::NOFID,
-            fid: fid.id,
-            n_uname,
-            uname,
-            aname,
-        }))? {
-            Fcall::Rattach(fcall::Rattach { qid }) => {
+        match self.fcall(
+            Fcall::Tattach(fcall::Tattach {
+                afid: fcall::NOFID,
+                fid: fid.id,
+                n_uname,
+                uname,
+                aname: aname.clone(),
+            }),
+            &[aname],
+        )? {
+            (Fcall::Rattach(fcall::Rattach { qid }), _) => {
                 fid.needs_clunk = true;
                 Ok((qid, fid))
             }
-            Fcall::Rlerror(err) => Err(err.into_io_error()),
-            _ => Err(err_unexpected_response()),
+            (resp, req) => Err(req.unexpected(&resp)),
         }
     }
 
@@ -328,22 +674,29 @@ pub struct ClientFid {
 }
 
 impl ClientFid {
+    /// The fid number sent to the server.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
     fn _walk1(&self, wnames: &[FcallStr]) -> Result<(Vec<fcall::Qid>, ClientFid), std::io::Error> {
         if wnames.len() > fcall::MAXWELEM {
             return Err(err_other("walk has too many wnames"));
         }
         let mut new_fid = self.client.fresh_fid()?;
-        match self.client.fcall(Fcall::Twalk(fcall::Twalk {
-            fid: self.id,
-            new_fid: new_fid.id,
-            wnames: wnames.to_vec(),
-        }))? {
-            Fcall::Rwalk(fcall::Rwalk { wqids }) => {
+        match self.client.fcall(
+            Fcall::Twalk(fcall::Twalk {
+                fid: self.id,
+                new_fid: new_fid.id,
+                wnames: wnames.to_vec(),
+            }),
+            wnames,
+        )? {
+            (Fcall::Rwalk(fcall::Rwalk { wqids }), _) => {
                 new_fid.needs_clunk = true;
                 Ok((wqids, new_fid))
             }
-            Fcall::Rlerror(err) => Err(err.into_io_error()),
-            _ => Err(err_unexpected_response()),
+            (resp, req) => Err(req.unexpected(&resp)),
         }
     }
 
@@ -372,13 +725,15 @@ impl ClientFid {
     }
 
     pub fn open(&self, flags: fcall::LOpenFlags) -> Result<fcall::Qid, std::io::Error> {
-        match self.client.fcall(Fcall::Tlopen(fcall::Tlopen {
-            fid: self.id,
-            flags,
-        }))? {
-            Fcall::Rlopen(fcall::Rlopen { qid, .. }) => Ok(qid),
-            Fcall::Rlerror(err) => Err(err.into_io_error()),
-            _ => Err(err_unexpected_response()),
+        match self.client.fcall(
+            Fcall::Tlopen(fcall::Tlopen {
+                fid: self.id,
+                flags,
+            }),
+            &[],
+        )? {
+            (Fcall::Rlopen(fcall::Rlopen { qid, .. }), _) => Ok(qid),
+            (resp, req) => Err(req.unexpected(&resp)),
         }
     }
 
@@ -389,16 +744,18 @@ impl ClientFid {
         mode: u32,
         gid: u32,
     ) -> Result<fcall::Qid, std::io::Error> {
-        match self.client.fcall(Fcall::Tlcreate(fcall::Tlcreate {
-            fid: self.id,
-            flags,
-            mode,
-            gid,
-            name: name,
-        }))? {
-            Fcall::Rlcreate(fcall::Rlcreate { qid, .. }) => Ok(qid),
-            Fcall::Rlerror(err) => Err(err.into_io_error()),
-            _ => Err(err_unexpected_response()),
+        match self.client.fcall(
+            Fcall::Tlcreate(fcall::Tlcreate {
+                fid: self.id,
+                flags,
+                mode,
+                gid,
+                name: name.clone(),
+            }),
+            &[name],
+        )? {
+            (Fcall::Rlcreate(fcall::Rlcreate { qid, .. }), _) => Ok(qid),
+            (resp, req) => Err(req.unexpected(&resp)),
         }
     }
 
@@ -413,15 +770,17 @@ impl ClientFid {
     }
 
     pub fn read_dir1(&self, offset: u64) -> Result<Vec<fcall::DirEntry<'static>>, std::io::Error> {
-        let count: u32 = self.client.state.msize as u32 - fcall::READDIRHDRSZ;
-        match self.client.fcall(Fcall::Treaddir(fcall::Treaddir {
-            fid: self.id,
-            offset,
-            count,
-        }))? {
-            Fcall::Rreaddir(fcall::Rreaddir { data }) => Ok(data.data),
-            Fcall::Rlerror(err) => Err(err.into_io_error()),
-            _ => Err(err_unexpected_response()),
+        let count = self.client.readdir_size();
+        match self.client.fcall(
+            Fcall::Treaddir(fcall::Treaddir {
+                fid: self.id,
+                offset,
+                count,
+            }),
+            &[],
+        )? {
+            (Fcall::Rreaddir(fcall::Rreaddir { data }), _) => Ok(data.data),
+            (resp, req) => Err(req.unexpected(&resp)),
         }
     }
 
@@ -440,51 +799,53 @@ impl ClientFid {
     }
 
     pub fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, std::io::Error> {
-        let count = buf
-            .len()
-            .min((self.client.state.msize - fcall::IOHDRSZ) as usize) as u32;
-        match self.client.fcall(Fcall::Tread(fcall::Tread {
-            fid: self.id,
-            offset,
-            count,
-        }))? {
-            Fcall::Rread(fcall::Rread { data }) => {
+        let count = buf.len().min(self.client.iounit() as usize) as u32;
+        match self.client.fcall(
+            Fcall::Tread(fcall::Tread {
+                fid: self.id,
+                offset,
+                count,
+            }),
+            &[],
+        )? {
+            (Fcall::Rread(fcall::Rread { data }), _) => {
                 if !data.is_empty() {
                     let dest = &mut buf[..data.len()];
                     dest.copy_from_slice(&data);
                 }
                 Ok(data.len())
             }
-            Fcall::Rlerror(err) => Err(err.into_io_error()),
-            _ => Err(err_unexpected_response()),
+            (resp, req) => Err(req.unexpected(&resp)),
         }
     }
 
     pub fn write(&self, offset: u64, buf: &[u8]) -> Result<usize, std::io::Error> {
-        let count = buf
-            .len()
-            .min((self.client.state.msize - fcall::IOHDRSZ) as usize);
-        match self.client.fcall(Fcall::Twrite(fcall::Twrite {
-            fid: self.id,
-            offset,
-            data: Cow::from(&buf[..count]),
-        }))? {
-            Fcall::Rwrite(fcall::Rwrite { count }) => Ok(count as usize),
-            Fcall::Rlerror(err) => Err(err.into_io_error()),
-            _ => Err(err_unexpected_response()),
+        let count = buf.len().min(self.client.iounit() as usize);
+        match self.client.fcall(
+            Fcall::Twrite(fcall::Twrite {
+                fid: self.id,
+                offset,
+                data: Cow::from(&buf[..count]),
+            }),
+            &[],
+        )? {
+            (Fcall::Rwrite(fcall::Rwrite { count }), _) => Ok(count as usize),
+            (resp, req) => Err(req.unexpected(&resp)),
         }
     }
 
     fn _mkdir(&self, name: FcallStr, mode: u32, gid: u32) -> Result<fcall::Qid, std::io::Error> {
-        match self.client.fcall(Fcall::Tmkdir(fcall::Tmkdir {
-            dfid: self.id,
-            name: name,
-            mode,
-            gid,
-        }))? {
-            Fcall::Rmkdir(fcall::Rmkdir { qid }) => Ok(qid),
-            Fcall::Rlerror(err) => Err(err.into_io_error()),
-            _ => Err(err_unexpected_response()),
+        match self.client.fcall(
+            Fcall::Tmkdir(fcall::Tmkdir {
+                dfid: self.id,
+                name: name.clone(),
+                mode,
+                gid,
+            }),
+            &[name],
+        )? {
+            (Fcall::Rmkdir(fcall::Rmkdir { qid }), _) => Ok(qid),
+            (resp, req) => Err(req.unexpected(&resp)),
         }
     }
 
@@ -499,14 +860,16 @@ impl ClientFid {
 
     // XXX make flags into a bitflag set?
     fn _unlinkat(&self, name: FcallStr, flags: u32) -> Result<(), std::io::Error> {
-        match self.client.fcall(Fcall::Tunlinkat(fcall::Tunlinkat {
-            dfid: self.id,
-            name: name,
-            flags,
-        }))? {
-            Fcall::Runlinkat(fcall::Runlinkat { .. }) => Ok(()),
-            Fcall::Rlerror(err) => Err(err.into_io_error()),
-            _ => Err(err_unexpected_response()),
+        match self.client.fcall(
+            Fcall::Tunlinkat(fcall::Tunlinkat {
+                dfid: self.id,
+                name: name.clone(),
+                flags,
+            }),
+            &[name],
+        )? {
+            (Fcall::Runlinkat(fcall::Runlinkat { .. }), _) => Ok(()),
+            (resp, req) => Err(req.unexpected(&resp)),
         }
     }
 
@@ -519,14 +882,16 @@ impl ClientFid {
     }
 
     fn _rename(&self, dir_fid: &ClientFid, name: FcallStr) -> Result<(), std::io::Error> {
-        match self.client.fcall(Fcall::Trename(fcall::Trename {
-            fid: self.id,
-            dfid: dir_fid.id,
-            name: name,
-        }))? {
-            Fcall::Rrename(fcall::Rrename { .. }) => Ok(()),
-            Fcall::Rlerror(err) => Err(err.into_io_error()),
-            _ => Err(err_unexpected_response()),
+        match self.client.fcall(
+            Fcall::Trename(fcall::Trename {
+                fid: self.id,
+                dfid: dir_fid.id,
+                name: name.clone(),
+            }),
+            &[name],
+        )? {
+            (Fcall::Rrename(fcall::Rrename { .. }), _) => Ok(()),
+            (resp, req) => Err(req.unexpected(&resp)),
         }
     }
 
@@ -544,15 +909,17 @@ impl ClientFid {
         new_dir_fid: &ClientFid,
         new_name: FcallStr,
     ) -> Result<(), std::io::Error> {
-        match self.client.fcall(Fcall::Trenameat(fcall::Trenameat {
-            olddfid: self.id,
-            newdfid: new_dir_fid.id,
-            oldname: old_name,
-            newname: new_name,
-        }))? {
-            Fcall::Rrenameat(fcall::Rrenameat { .. }) => Ok(()),
-            Fcall::Rlerror(err) => Err(err.into_io_error()),
-            _ => Err(err_unexpected_response()),
+        match self.client.fcall(
+            Fcall::Trenameat(fcall::Trenameat {
+                olddfid: self.id,
+                newdfid: new_dir_fid.id,
+                oldname: old_name.clone(),
+                newname: new_name.clone(),
+            }),
+            &[old_name, new_name],
+        )? {
+            (Fcall::Rrenameat(fcall::Rrenameat { .. }), _) => Ok(()),
+            (resp, req) => Err(req.unexpected(&resp)),
         }
     }
 
@@ -566,13 +933,15 @@ impl ClientFid {
     }
 
     pub fn getattr(&self, mask: fcall::GetattrMask) -> Result<fcall::Rgetattr, std::io::Error> {
-        match self.client.fcall(Fcall::Tgetattr(fcall::Tgetattr {
-            fid: self.id,
-            req_mask: mask,
-        }))? {
-            Fcall::Rgetattr(resp) => Ok(resp),
-            Fcall::Rlerror(err) => Err(err.into_io_error()),
-            _ => Err(err_unexpected_response()),
+        match self.client.fcall(
+            Fcall::Tgetattr(fcall::Tgetattr {
+                fid: self.id,
+                req_mask: mask,
+            }),
+            &[],
+        )? {
+            (Fcall::Rgetattr(resp), _) => Ok(resp),
+            (resp, req) => Err(req.unexpected(&resp)),
         }
     }
 
@@ -581,39 +950,220 @@ impl ClientFid {
         valid: fcall::SetattrMask,
         stat: fcall::SetAttr,
     ) -> Result<(), std::io::Error> {
-        match self.client.fcall(Fcall::Tsetattr(fcall::Tsetattr {
-            fid: self.id,
-            valid,
-            stat,
-        }))? {
-            Fcall::Rsetattr(fcall::Rsetattr { .. }) => Ok(()),
-            Fcall::Rlerror(err) => Err(err.into_io_error()),
-            _ => Err(err_unexpected_response()),
+        match self.client.fcall(
+            Fcall::Tsetattr(fcall::Tsetattr {
+                fid: self.id,
+                valid,
+                stat,
+            }),
+            &[],
+        )? {
+            (Fcall::Rsetattr(fcall::Rsetattr { .. }), _) => Ok(()),
+            (resp, req) => Err(req.unexpected(&resp)),
         }
     }
 
     pub fn fsync(&self) -> Result<(), std::io::Error> {
         match self
             .client
-            .fcall(Fcall::Tfsync(fcall::Tfsync { fid: self.id }))?
+            .fcall(Fcall::Tfsync(fcall::Tfsync { fid: self.id }), &[])?
         {
-            Fcall::Rfsync { .. } => Ok(()),
-            Fcall::Rlerror(err) => Err(err.into_io_error()),
-            _ => Err(err_unexpected_response()),
+            (Fcall::Rfsync { .. }, _) => Ok(()),
+            (resp, req) => Err(req.unexpected(&resp)),
+        }
+    }
+
+    pub fn lock(&self, flock: fcall::Flock) -> Result<fcall::LockStatus, std::io::Error> {
+        match self.client.fcall(
+            Fcall::Tlock(fcall::Tlock {
+                fid: self.id,
+                flock,
+            }),
+            &[],
+        )? {
+            (Fcall::Rlock(fcall::Rlock { status }), _) => Ok(status),
+            (resp, req) => Err(req.unexpected(&resp)),
+        }
+    }
+
+    pub fn getlock(
+        &self,
+        flock: fcall::Getlock,
+    ) -> Result<fcall::Getlock<'static>, std::io::Error> {
+        match self.client.fcall(
+            Fcall::Tgetlock(fcall::Tgetlock {
+                fid: self.id,
+                flock,
+            }),
+            &[],
+        )? {
+            (Fcall::Rgetlock(fcall::Rgetlock { flock }), _) => Ok(flock),
+            (resp, req) => Err(req.unexpected(&resp)),
+        }
+    }
+
+    pub fn statfs(&self) -> Result<fcall::Statfs, std::io::Error> {
+        match self
+            .client
+            .fcall(Fcall::Tstatfs(fcall::Tstatfs { fid: self.id }), &[])?
+        {
+            (Fcall::Rstatfs(fcall::Rstatfs { statfs }), _) => Ok(statfs),
+            (resp, req) => Err(req.unexpected(&resp)),
+        }
+    }
+
+    pub fn readlink(&self) -> Result<FcallStr<'static>, std::io::Error> {
+        match self
+            .client
+            .fcall(Fcall::Treadlink(fcall::Treadlink { fid: self.id }), &[])?
+        {
+            (Fcall::Rreadlink(fcall::Rreadlink { target }), _) => Ok(target),
+            (resp, req) => Err(req.unexpected(&resp)),
+        }
+    }
+
+    fn _symlink(
+        &self,
+        name: FcallStr,
+        target: FcallStr,
+        gid: u32,
+    ) -> Result<fcall::Qid, std::io::Error> {
+        match self.client.fcall(
+            Fcall::Tsymlink(fcall::Tsymlink {
+                fid: self.id,
+                name: name.clone(),
+                symtgt: target,
+                gid,
+            }),
+            &[name],
+        )? {
+            (Fcall::Rsymlink(fcall::Rsymlink { qid }), _) => Ok(qid),
+            (resp, req) => Err(req.unexpected(&resp)),
         }
     }
 
-    pub fn lock(&mut self, flock: fcall::Flock) -> Result<fcall::LockStatus, std::io::Error> {
-        match self.client.fcall(Fcall::Tlock(fcall::Tlock {
-            fid: self.id,
-            flock,
-        }))? {
-            Fcall::Rlock(fcall::Rlock { status }) => Ok(status),
-            Fcall::Rlerror(err) => Err(err.into_io_error()),
-            _ => Err(err_unexpected_response()),
+    /// Create a symlink called name in this directory pointing at target.
+    pub fn symlink<'a, 'b, S1: 'a + Into<FcallStr<'a>>, S2: 'b + Into<FcallStr<'b>>>(
+        &self,
+        name: S1,
+        target: S2,
+        gid: u32,
+    ) -> Result<fcall::Qid, std::io::Error> {
+        self._symlink(name.into(), target.into(), gid)
+    }
+
+    fn _mknod(
+        &self,
+        name: FcallStr,
+        mode: u32,
+        major: u32,
+        minor: u32,
+        gid: u32,
+    ) -> Result<fcall::Qid, std::io::Error> {
+        match self.client.fcall(
+            Fcall::Tmknod(fcall::Tmknod {
+                dfid: self.id,
+                name: name.clone(),
+                mode,
+                major,
+                minor,
+                gid,
+            }),
+            &[name],
+        )? {
+            (Fcall::Rmknod(fcall::Rmknod { qid }), _) => Ok(qid),
+            (resp, req) => Err(req.unexpected(&resp)),
         }
     }
 
+    pub fn mknod<'a, S: 'a + Into<FcallStr<'a>>>(
+        &self,
+        name: S,
+        mode: u32,
+        major: u32,
+        minor: u32,
+        gid: u32,
+    ) -> Result<fcall::Qid, std::io::Error> {
+        self._mknod(name.into(), mode, major, minor, gid)
+    }
+
+    fn _link(&self, dir_fid: &ClientFid, name: FcallStr) -> Result<(), std::io::Error> {
+        match self.client.fcall(
+            Fcall::Tlink(fcall::Tlink {
+                dfid: dir_fid.id,
+                fid: self.id,
+                name: name.clone(),
+            }),
+            &[name],
+        )? {
+            (Fcall::Rlink(fcall::Rlink { .. }), _) => Ok(()),
+            (resp, req) => Err(req.unexpected(&resp)),
+        }
+    }
+
+    /// Create a hard link to this file called name in dir_fid.
+    pub fn link<'a, S: 'a + Into<FcallStr<'a>>>(
+        &self,
+        dir_fid: &ClientFid,
+        name: S,
+    ) -> Result<(), std::io::Error> {
+        self._link(dir_fid, name.into())
+    }
+
+    fn _xattrwalk(&self, name: FcallStr) -> Result<(u64, ClientFid), std::io::Error> {
+        let mut new_fid = self.client.fresh_fid()?;
+        match self.client.fcall(
+            Fcall::Txattrwalk(fcall::Txattrwalk {
+                fid: self.id,
+                new_fid: new_fid.id,
+                name: name.clone(),
+            }),
+            &[name],
+        )? {
+            (Fcall::Rxattrwalk(fcall::Rxattrwalk { size }), _) => {
+                new_fid.needs_clunk = true;
+                Ok((size, new_fid))
+            }
+            (resp, req) => Err(req.unexpected(&resp)),
+        }
+    }
+
+    /// Returns the size of the named extended attribute and a fid to read it
+    /// from, an empty name lists the attribute names instead.
+    pub fn xattrwalk<'a, S: 'a + Into<FcallStr<'a>>>(
+        &self,
+        name: S,
+    ) -> Result<(u64, ClientFid), std::io::Error> {
+        self._xattrwalk(name.into())
+    }
+
+    fn _xattrcreate(&self, name: FcallStr, size: u64, flags: u32) -> Result<(), std::io::Error> {
+        match self.client.fcall(
+            Fcall::Txattrcreate(fcall::Txattrcreate {
+                fid: self.id,
+                name: name.clone(),
+                attr_size: size,
+                flags,
+            }),
+            &[name],
+        )? {
+            (Fcall::Rxattrcreate(fcall::Rxattrcreate { .. }), _) => Ok(()),
+            (resp, req) => Err(req.unexpected(&resp)),
+        }
+    }
+
+    /// Turn this fid into one for writing size bytes of the named extended
+    /// attribute, which is set when the fid is clunked. A size of zero
+    /// removes the attribute.
+    pub fn xattrcreate<'a, S: 'a + Into<FcallStr<'a>>>(
+        &self,
+        name: S,
+        size: u64,
+        flags: u32,
+    ) -> Result<(), std::io::Error> {
+        self._xattrcreate(name.into(), size, flags)
+    }
+
     fn _clunk(&mut self) -> Result<(), std::io::Error> {
         if !self.needs_clunk {
             return Ok(());
@@ -621,11 +1171,10 @@ impl ClientFid {
         self.needs_clunk = false;
         match self
             .client
-            .fcall(Fcall::Tclunk(fcall::Tclunk { fid: self.id }))?
+            .fcall(Fcall::Tclunk(fcall::Tclunk { fid: self.id }), &[])?
         {
-            Fcall::Rclunk { .. } => Ok(()),
-            Fcall::Rlerror(err) => Err(err.into_io_error()),
-            _ => Err(err_unexpected_response()),
+            (Fcall::Rclunk { .. }, _) => Ok(()),
+            (resp, req) => Err(req.unexpected(&resp)),
         }
     }
 
@@ -637,11 +1186,10 @@ impl ClientFid {
         self.needs_clunk = false;
         match self
             .client
-            .fcall(Fcall::Tremove(fcall::Tremove { fid: self.id }))?
+            .fcall(Fcall::Tremove(fcall::Tremove { fid: self.id }), &[])?
         {
-            Fcall::Rremove { .. } => Ok(()),
-            Fcall::Rlerror(err) => Err(err.into_io_error()),
-            _ => Err(err_unexpected_response()),
+            (Fcall::Rremove { .. }, _) => Ok(()),
+            (resp, req) => Err(req.unexpected(&resp)),
         }
     }
 }
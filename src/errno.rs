@@ -1,6 +1,6 @@
 // Linux error constants for use from other platforms.
-
-// XXX prune this error list down, we don't need it all.
+//
+// 9P2000.L always carries Linux errno values, whatever the host.
 pub const EPERM: u32 = 1;
 pub const ENOENT: u32 = 2;
 pub const ESRCH: u32 = 3;
@@ -64,7 +64,21 @@ pub const EXFULL: u32 = 54;
 pub const ENOANO: u32 = 55;
 pub const EBADRQC: u32 = 56;
 pub const EBADSLT: u32 = 57;
+pub const EBFONT: u32 = 59;
+pub const ENOSTR: u32 = 60;
+pub const ENODATA: u32 = 61;
+pub const ETIME: u32 = 62;
+pub const ENOSR: u32 = 63;
+pub const ENONET: u32 = 64;
+pub const ENOPKG: u32 = 65;
+pub const EREMOTE: u32 = 66;
+pub const ENOLINK: u32 = 67;
+pub const EADV: u32 = 68;
+pub const ESRMNT: u32 = 69;
+pub const ECOMM: u32 = 70;
+pub const EPROTO: u32 = 71;
 pub const EMULTIHOP: u32 = 72;
+pub const EDOTDOT: u32 = 73;
 pub const EOVERFLOW: u32 = 75;
 pub const ENOTUNIQ: u32 = 76;
 pub const EBADFD: u32 = 77;
@@ -184,7 +198,21 @@ pub fn strerror(ecode: u32) -> &'static str {
         ENOANO => "ENOANO",
         EBADRQC => "EBADRQC",
         EBADSLT => "EBADSLT",
+        EBFONT => "EBFONT",
+        ENOSTR => "ENOSTR",
+        ENODATA => "ENODATA",
+        ETIME => "ETIME",
+        ENOSR => "ENOSR",
+        ENONET => "ENONET",
+        ENOPKG => "ENOPKG",
+        EREMOTE => "EREMOTE",
+        ENOLINK => "ENOLINK",
+        EADV => "EADV",
+        ESRMNT => "ESRMNT",
+        ECOMM => "ECOMM",
+        EPROTO => "EPROTO",
         EMULTIHOP => "EMULTIHOP",
+        EDOTDOT => "EDOTDOT",
         EOVERFLOW => "EOVERFLOW",
         ENOTUNIQ => "ENOTUNIQ",
         EBADFD => "EBADFD",
@@ -243,3 +271,229 @@ pub fn strerror(ecode: u32) -> &'static str {
         _ => "unknown error",
     }
 }
+
+/// A Linux errno with no equivalent on this host, carried as the payload of
+/// an io::Error so it survives the trip through std::io.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Errno(pub u32);
+
+impl std::fmt::Display for Errno {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} (errno {})", strerror(self.0), self.0)
+    }
+}
+
+impl std::error::Error for Errno {}
+
+// Linux errnos and their host equivalents, used off Linux where the numbers
+// differ.
+const HOST_ERRNOS: &[(u32, i32)] = {
+    use nix::libc;
+    &[
+        (EPERM, libc::EPERM),
+        (ENOENT, libc::ENOENT),
+        (ESRCH, libc::ESRCH),
+        (EINTR, libc::EINTR),
+        (EIO, libc::EIO),
+        (ENXIO, libc::ENXIO),
+        (E2BIG, libc::E2BIG),
+        (ENOEXEC, libc::ENOEXEC),
+        (EBADF, libc::EBADF),
+        (ECHILD, libc::ECHILD),
+        (EAGAIN, libc::EAGAIN),
+        (ENOMEM, libc::ENOMEM),
+        (EACCES, libc::EACCES),
+        (EFAULT, libc::EFAULT),
+        (EBUSY, libc::EBUSY),
+        (EEXIST, libc::EEXIST),
+        (EXDEV, libc::EXDEV),
+        (ENODEV, libc::ENODEV),
+        (ENOTDIR, libc::ENOTDIR),
+        (EISDIR, libc::EISDIR),
+        (EINVAL, libc::EINVAL),
+        (ENFILE, libc::ENFILE),
+        (EMFILE, libc::EMFILE),
+        (ENOTTY, libc::ENOTTY),
+        (ETXTBSY, libc::ETXTBSY),
+        (EFBIG, libc::EFBIG),
+        (ENOSPC, libc::ENOSPC),
+        (ESPIPE, libc::ESPIPE),
+        (EROFS, libc::EROFS),
+        (EMLINK, libc::EMLINK),
+        (EPIPE, libc::EPIPE),
+        (EDOM, libc::EDOM),
+        (ERANGE, libc::ERANGE),
+        (EDEADLK, libc::EDEADLK),
+        (ENAMETOOLONG, libc::ENAMETOOLONG),
+        (ENOLCK, libc::ENOLCK),
+        (ENOSYS, libc::ENOSYS),
+        (ENOTEMPTY, libc::ENOTEMPTY),
+        (ELOOP, libc::ELOOP),
+        (ENOMSG, libc::ENOMSG),
+        (EIDRM, libc::EIDRM),
+        (EPROTO, libc::EPROTO),
+        (EOVERFLOW, libc::EOVERFLOW),
+        (EILSEQ, libc::EILSEQ),
+        (ENOTSOCK, libc::ENOTSOCK),
+        (EDESTADDRREQ, libc::EDESTADDRREQ),
+        (EMSGSIZE, libc::EMSGSIZE),
+        (EPROTOTYPE, libc::EPROTOTYPE),
+        (ENOPROTOOPT, libc::ENOPROTOOPT),
+        (EPROTONOSUPPORT, libc::EPROTONOSUPPORT),
+        (EOPNOTSUPP, libc::EOPNOTSUPP),
+        (EAFNOSUPPORT, libc::EAFNOSUPPORT),
+        (EADDRINUSE, libc::EADDRINUSE),
+        (EADDRNOTAVAIL, libc::EADDRNOTAVAIL),
+        (ENETDOWN, libc::ENETDOWN),
+        (ENETUNREACH, libc::ENETUNREACH),
+        (ENETRESET, libc::ENETRESET),
+        (ECONNABORTED, libc::ECONNABORTED),
+        (ECONNRESET, libc::ECONNRESET),
+        (ENOBUFS, libc::ENOBUFS),
+        (EISCONN, libc::EISCONN),
+        (ENOTCONN, libc::ENOTCONN),
+        (ETIMEDOUT, libc::ETIMEDOUT),
+        (ECONNREFUSED, libc::ECONNREFUSED),
+        (EHOSTUNREACH, libc::EHOSTUNREACH),
+        (EALREADY, libc::EALREADY),
+        (EINPROGRESS, libc::EINPROGRESS),
+        (ESTALE, libc::ESTALE),
+        (EDQUOT, libc::EDQUOT),
+        (ECANCELED, libc::ECANCELED),
+    ]
+};
+
+/// The host errno for a Linux errno, if the host has one.
+pub fn host_errno(ecode: u32) -> Option<i32> {
+    if cfg!(target_os = "linux") {
+        return i32::try_from(ecode).ok();
+    }
+    HOST_ERRNOS
+        .iter()
+        .find(|(linux, _)| *linux == ecode)
+        .map(|(_, host)| *host)
+}
+
+/// The Linux errno for a host errno, if Linux has one.
+pub fn errno_from_host(raw: i32) -> Option<u32> {
+    if cfg!(target_os = "linux") {
+        return u32::try_from(raw).ok();
+    }
+    HOST_ERRNOS
+        .iter()
+        .find(|(_, host)| *host == raw)
+        .map(|(linux, _)| *linux)
+}
+
+/// The io::ErrorKind for a Linux errno.
+pub fn io_error_kind(ecode: u32) -> std::io::ErrorKind {
+    use std::io::ErrorKind::*;
+
+    match ecode {
+        E2BIG => ArgumentListTooLong,
+        EACCES | EPERM => PermissionDenied,
+        EADDRINUSE => AddrInUse,
+        EADDRNOTAVAIL => AddrNotAvailable,
+        EAGAIN => WouldBlock,
+        EBUSY => ResourceBusy,
+        ECONNABORTED => ConnectionAborted,
+        ECONNREFUSED => ConnectionRefused,
+        ECONNRESET => ConnectionReset,
+        EDEADLK => Deadlock,
+        EDQUOT => QuotaExceeded,
+        EEXIST => AlreadyExists,
+        EFBIG => FileTooLarge,
+        EHOSTUNREACH => HostUnreachable,
+        EINTR => Interrupted,
+        EINVAL => InvalidInput,
+        EISDIR => IsADirectory,
+        EMLINK => TooManyLinks,
+        ENAMETOOLONG => InvalidFilename,
+        ENETDOWN => NetworkDown,
+        ENETUNREACH => NetworkUnreachable,
+        ENOENT => NotFound,
+        ENOMEM => OutOfMemory,
+        ENOSPC => StorageFull,
+        ENOSYS | EOPNOTSUPP => Unsupported,
+        ENOTCONN => NotConnected,
+        ENOTDIR => NotADirectory,
+        ENOTEMPTY => DirectoryNotEmpty,
+        EPIPE => BrokenPipe,
+        EROFS => ReadOnlyFilesystem,
+        ESPIPE => NotSeekable,
+        ESTALE => StaleNetworkFileHandle,
+        ETIMEDOUT => TimedOut,
+        ETXTBSY => ExecutableFileBusy,
+        EXDEV => CrossesDevices,
+        _ => Other,
+    }
+}
+
+/// The Linux errno best describing an io::ErrorKind.
+pub fn errno_from_kind(kind: std::io::ErrorKind) -> u32 {
+    use std::io::ErrorKind::*;
+
+    match kind {
+        NotFound => ENOENT,
+        PermissionDenied => EACCES,
+        ConnectionRefused => ECONNREFUSED,
+        ConnectionReset => ECONNRESET,
+        HostUnreachable => EHOSTUNREACH,
+        NetworkUnreachable => ENETUNREACH,
+        ConnectionAborted => ECONNABORTED,
+        NotConnected => ENOTCONN,
+        AddrInUse => EADDRINUSE,
+        AddrNotAvailable => EADDRNOTAVAIL,
+        NetworkDown => ENETDOWN,
+        BrokenPipe => EPIPE,
+        AlreadyExists => EEXIST,
+        WouldBlock => EAGAIN,
+        NotADirectory => ENOTDIR,
+        IsADirectory => EISDIR,
+        DirectoryNotEmpty => ENOTEMPTY,
+        ReadOnlyFilesystem => EROFS,
+        StaleNetworkFileHandle => ESTALE,
+        InvalidInput => EINVAL,
+        InvalidData => EINVAL,
+        TimedOut => ETIMEDOUT,
+        WriteZero => EAGAIN,
+        StorageFull => ENOSPC,
+        NotSeekable => ESPIPE,
+        QuotaExceeded => EDQUOT,
+        FileTooLarge => EFBIG,
+        ResourceBusy => EBUSY,
+        ExecutableFileBusy => ETXTBSY,
+        Deadlock => EDEADLK,
+        CrossesDevices => EXDEV,
+        TooManyLinks => EMLINK,
+        InvalidFilename => ENAMETOOLONG,
+        ArgumentListTooLong => E2BIG,
+        Interrupted => EINTR,
+        Unsupported => EOPNOTSUPP,
+        OutOfMemory => ENOMEM,
+        _ => EIO,
+    }
+}
+
+/// The Linux errno for an io::Error, preferring the os error, Errno or
+/// P9Error it carries over its kind.
+pub fn errno_from_io_error(err: &std::io::Error) -> u32 {
+    if let Some(ecode) = err.raw_os_error().and_then(errno_from_host) {
+        return ecode;
+    }
+    if let Some(Errno(ecode)) = err.get_ref().and_then(|e| e.downcast_ref::<Errno>()) {
+        return *ecode;
+    }
+    if let Some(err) = super::error::P9Error::downcast(err) {
+        return err.errno();
+    }
+    errno_from_kind(err.kind())
+}
+
+/// An io::Error for a Linux errno, errno_from_io_error gets the errno back.
+pub fn io_error(ecode: u32) -> std::io::Error {
+    match host_errno(ecode) {
+        Some(raw) => std::io::Error::from_raw_os_error(raw),
+        None => std::io::Error::new(io_error_kind(ecode), Errno(ecode)),
+    }
+}
@@ -0,0 +1,98 @@
+use super::errno;
+use super::fcall::FcallType;
+
+/// Why a Client request failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum P9ErrorKind {
+    /// The server answered with Rlerror and this errno.
+    RemoteErrno(u32),
+    /// The server answered with a message of the wrong type.
+    Protocol(FcallType),
+    /// The connection was lost before a response arrived.
+    Disconnected,
+    /// The transport timed out.
+    Timeout,
+}
+
+/// A failed Client request, along with what the request was.
+///
+/// Client methods return these inside an io::Error, use P9Error::downcast or
+/// get_ref to get it back.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct P9Error {
+    pub kind: P9ErrorKind,
+    /// The request that failed, if one was made.
+    pub op: Option<FcallType>,
+    /// The fid the request operated on.
+    pub fid: Option<u32>,
+    /// The names the request was given, the wnames of a walk or the name
+    /// being created, removed or renamed.
+    pub path: Vec<String>,
+}
+
+impl P9Error {
+    pub fn new(kind: P9ErrorKind) -> P9Error {
+        P9Error {
+            kind,
+            op: None,
+            fid: None,
+            path: Vec::new(),
+        }
+    }
+
+    /// The P9Error inside an io::Error returned by a Client.
+    pub fn downcast(err: &std::io::Error) -> Option<&P9Error> {
+        err.get_ref().and_then(|err| err.downcast_ref::<P9Error>())
+    }
+
+    /// The Linux errno best describing the error, for passing it on in an
+    /// Rlerror.
+    pub fn errno(&self) -> u32 {
+        match self.kind {
+            P9ErrorKind::RemoteErrno(ecode) => ecode,
+            P9ErrorKind::Protocol(_) => errno::EPROTO,
+            P9ErrorKind::Disconnected => errno::EIO,
+            P9ErrorKind::Timeout => errno::ETIMEDOUT,
+        }
+    }
+
+    pub fn io_error_kind(&self) -> std::io::ErrorKind {
+        match self.kind {
+            P9ErrorKind::RemoteErrno(ecode) => errno::io_error_kind(ecode),
+            P9ErrorKind::Protocol(_) => std::io::ErrorKind::InvalidData,
+            P9ErrorKind::Disconnected => std::io::ErrorKind::NotConnected,
+            P9ErrorKind::Timeout => std::io::ErrorKind::TimedOut,
+        }
+    }
+}
+
+impl std::fmt::Display for P9Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(op) = self.op {
+            write!(f, "{}", op)?;
+            if let Some(fid) = self.fid {
+                write!(f, " fid={}", fid)?;
+            }
+            if !self.path.is_empty() {
+                write!(f, " {:?}", self.path.join("/"))?;
+            }
+            write!(f, ": ")?;
+        }
+        match self.kind {
+            P9ErrorKind::RemoteErrno(ecode) => {
+                write!(f, "{} (errno {})", errno::strerror(ecode), ecode)
+            }
+            P9ErrorKind::Protocol(typ) => write!(f, "unexpected {} from server", typ),
+            P9ErrorKind::Disconnected => write!(f, "disconnected"),
+            P9ErrorKind::Timeout => write!(f, "timed out"),
+        }
+    }
+}
+
+impl std::error::Error for P9Error {}
+
+impl From<P9Error> for std::io::Error {
+    fn from(err: P9Error) -> std::io::Error {
+        std::io::Error::new(err.io_error_kind(), err)
+    }
+}
@@ -0,0 +1,251 @@
+use super::fcall::*;
+use super::rng::Rng;
+use super::server::{FcallResponse, Filesystem};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// What to do to a request instead of, or as well as, handling it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// Answer with Rlerror and this errno without calling the filesystem.
+    Errno(u32),
+    /// Wait this long before handling the request. Filesystems answering
+    /// from the serving thread stall every request behind it.
+    Delay(Duration),
+    /// Handle the request but never send the response.
+    Drop,
+    /// Send the response twice.
+    Duplicate,
+    /// Shut the connection instead of handling the request.
+    Kill,
+}
+
+/// Which requests get a fault.
+#[derive(Clone, Debug)]
+pub struct FaultRule {
+    fault: Fault,
+    op: Option<FcallType>,
+    fid: Option<u32>,
+    probability: f64,
+    times: Option<u64>,
+}
+
+impl FaultRule {
+    /// Inject fault into every request, until narrowed by the other
+    /// methods.
+    pub fn new(fault: Fault) -> FaultRule {
+        FaultRule {
+            fault,
+            op: None,
+            fid: None,
+            probability: 1.0,
+            times: None,
+        }
+    }
+
+    /// Only requests of this type, such as FcallType::Tread.
+    pub fn op(mut self, op: FcallType) -> FaultRule {
+        self.op = Some(op);
+        self
+    }
+
+    /// Only requests on this fid, see hook::request_fid.
+    pub fn fid(mut self, fid: u32) -> FaultRule {
+        self.fid = Some(fid);
+        self
+    }
+
+    /// Only this fraction of matching requests, from 0 to 1.
+    pub fn probability(mut self, probability: f64) -> FaultRule {
+        self.probability = probability;
+        self
+    }
+
+    /// At most this many times, after which the rule is removed.
+    pub fn times(mut self, times: u64) -> FaultRule {
+        self.times = Some(times);
+        self
+    }
+
+    fn matches(&self, op: FcallType, fid: Option<u32>) -> bool {
+        self.op.is_none_or(|o| o == op) && self.fid.is_none_or(|f| Some(f) == fid)
+    }
+}
+
+struct FaultState {
+    rules: Vec<FaultRule>,
+    rng: Rng,
+    injected: u64,
+}
+
+/// Changes the faults a FaultFs injects, from any thread.
+#[derive(Clone)]
+pub struct FaultControl {
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl FaultControl {
+    /// Add a rule, rules are tried in the order added.
+    pub fn add(&self, rule: FaultRule) {
+        self.state.lock().unwrap().rules.push(rule);
+    }
+
+    /// Remove every rule.
+    pub fn clear(&self) {
+        self.state.lock().unwrap().rules.clear();
+    }
+
+    /// How many faults have been injected.
+    pub fn injected(&self) -> u64 {
+        self.state.lock().unwrap().injected
+    }
+
+    // The faults for a request: any delays, then at most one other fault.
+    fn choose(&self, op: FcallType, fid: Option<u32>) -> Vec<Fault> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let mut faults = Vec::new();
+        let mut i = 0;
+        while i < state.rules.len() {
+            let rule = &mut state.rules[i];
+            if !rule.matches(op, fid) || state.rng.next_f64() >= rule.probability {
+                i += 1;
+                continue;
+            }
+            faults.push(rule.fault.clone());
+            state.injected += 1;
+            let delay = matches!(rule.fault, Fault::Delay(_));
+            match &mut rule.times {
+                Some(1) => {
+                    state.rules.remove(i);
+                }
+                Some(times) => {
+                    *times -= 1;
+                    i += 1;
+                }
+                None => i += 1,
+            }
+            if !delay {
+                break;
+            }
+        }
+        faults
+    }
+}
+
+/// A filesystem wrapper injecting faults into the requests of another,
+/// for testing how clients cope. Faults are chosen with a seeded random
+/// number generator, so the same seed and requests give the same faults.
+pub struct FaultFs<F: Filesystem> {
+    fs: F,
+    control: FaultControl,
+}
+
+impl<F: Filesystem> FaultFs<F> {
+    pub fn new(fs: F, seed: u64) -> FaultFs<F> {
+        FaultFs {
+            fs,
+            control: FaultControl {
+                state: Arc::new(Mutex::new(FaultState {
+                    rules: Vec::new(),
+                    rng: Rng::new(seed),
+                    injected: 0,
+                })),
+            },
+        }
+    }
+
+    /// Add a rule, see FaultControl::add.
+    pub fn rule(self, rule: FaultRule) -> FaultFs<F> {
+        self.control.add(rule);
+        self
+    }
+
+    /// A handle for changing faults while the filesystem is being served.
+    pub fn control(&self) -> FaultControl {
+        self.control.clone()
+    }
+
+    pub fn inner(&self) -> &F {
+        &self.fs
+    }
+
+    pub fn inner_mut(&mut self) -> &mut F {
+        &mut self.fs
+    }
+
+    pub fn into_inner(self) -> F {
+        self.fs
+    }
+
+    // The response to pass on, or None if a fault answered the request.
+    fn inject(
+        &self,
+        op: FcallType,
+        fid: Option<u32>,
+        mut resp: FcallResponse,
+    ) -> Option<FcallResponse> {
+        for fault in self.control.choose(op, fid) {
+            match fault {
+                Fault::Errno(ecode) => {
+                    resp.send(Rlerror { ecode });
+                    return None;
+                }
+                Fault::Delay(delay) => std::thread::sleep(delay),
+                Fault::Drop => resp.set_copies(0),
+                Fault::Duplicate => resp.set_copies(2),
+                Fault::Kill => {
+                    resp.kill();
+                    return None;
+                }
+            }
+        }
+        Some(resp)
+    }
+}
+
+macro_rules! fault_ops {
+    ($($method:ident($req:ty, $op:ident, $fid:ident);)*) => {
+        impl<F: Filesystem> Filesystem for FaultFs<F> {
+            $(
+                fn $method(&mut self, req: &$req, resp: FcallResponse) {
+                    if let Some(resp) = self.inject(FcallType::$op, fault_ops!(@fid req $fid), resp) {
+                        self.fs.$method(req, resp)
+                    }
+                }
+            )*
+        }
+    };
+    (@fid $req:ident none) => { None };
+    (@fid $req:ident $fid:ident) => { Some($req.$fid) };
+}
+
+fault_ops! {
+    statfs(Tstatfs, Tstatfs, fid);
+    lopen(Tlopen, Tlopen, fid);
+    lcreate(Tlcreate, Tlcreate, fid);
+    symlink(Tsymlink, Tsymlink, fid);
+    mknod(Tmknod, Tmknod, dfid);
+    rename(Trename, Trename, fid);
+    readlink(Treadlink, Treadlink, fid);
+    getattr(Tgetattr, Tgetattr, fid);
+    setattr(Tsetattr, Tsetattr, fid);
+    xattrwalk(Txattrwalk, Txattrwalk, fid);
+    xattrcreate(Txattrcreate, Txattrcreate, fid);
+    readdir(Treaddir, Treaddir, fid);
+    fsync(Tfsync, Tfsync, fid);
+    lock(Tlock, Tlock, fid);
+    getlock(Tgetlock, Tgetlock, fid);
+    link(Tlink, Tlink, fid);
+    mkdir(Tmkdir, Tmkdir, dfid);
+    renameat(Trenameat, Trenameat, olddfid);
+    unlinkat(Tunlinkat, Tunlinkat, dfid);
+    auth(Tauth, Tauth, afid);
+    attach(Tattach, Tattach, fid);
+    flush(Tflush, Tflush, none);
+    walk(Twalk, Twalk, fid);
+    read(Tread, Tread, fid);
+    write(Twrite, Twrite, fid);
+    clunk(Tclunk, Tclunk, fid);
+    remove(Tremove, Tremove, fid);
+}
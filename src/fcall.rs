@@ -1,4 +1,6 @@
 use bitflags::bitflags;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use std::convert::TryInto;
 use std::fs;
@@ -180,7 +182,7 @@ impl From<nix::sys::statvfs::Statvfs> for Statfs {
             bavail: buf.blocks_available(),
             files: buf.files(),
             ffree: buf.files_free(),
-            fsid: buf.filesystem_id() as u64,
+            fsid: buf.filesystem_id(),
             namelen: buf.name_max() as u32,
         }
     }
@@ -200,9 +202,9 @@ impl<'a> From<&'a fs::Metadata> for Stat {
             gid: attr.gid(),
             nlink: attr.nlink(),
             rdev: attr.rdev(),
-            size: attr.size() as u64,
-            blksize: attr.blksize() as u64,
-            blocks: attr.blocks() as u64,
+            size: attr.size(),
+            blksize: attr.blksize(),
+            blocks: attr.blocks(),
             atime: Time {
                 sec: attr.atime() as u64,
                 nsec: attr.atime_nsec() as u64,
@@ -261,7 +263,104 @@ impl<'a> std::fmt::Display for FcallStr<'a> {
     }
 }
 
-#[derive(Clone, Debug)]
+// Owned and borrowed strings compare by their contents.
+impl<'a, 'b> PartialEq<FcallStr<'b>> for FcallStr<'a> {
+    fn eq(&self, other: &FcallStr<'b>) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl<'a> Eq for FcallStr<'a> {}
+
+impl<'a> std::hash::Hash for FcallStr<'a> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.as_bytes().hash(state)
+    }
+}
+
+// Strings are serialized as text when they are valid utf8 so
+// that json output stays readable, and as raw bytes otherwise.
+#[cfg(feature = "serde")]
+impl<'a> Serialize for FcallStr<'a> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(self.as_bytes()) {
+            Ok(s) => serializer.serialize_str(s),
+            Err(_) => serializer.serialize_bytes(self.as_bytes()),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, 'a> Deserialize<'de> for FcallStr<'a> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FcallStrVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for FcallStrVisitor {
+            type Value = Vec<u8>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "a string or byte array")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Vec<u8>, E> {
+                Ok(v.as_bytes().to_vec())
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+                Ok(v.to_vec())
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<Vec<u8>, A::Error> {
+                let mut v = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(b) = seq.next_element()? {
+                    v.push(b);
+                }
+                Ok(v)
+            }
+        }
+
+        Ok(FcallStr::Owned(
+            deserializer.deserialize_any(FcallStrVisitor)?,
+        ))
+    }
+}
+
+// Flag sets are serialized as their raw bits.
+#[cfg(feature = "serde")]
+macro_rules! serde_bitflags {
+    ($($t:ty: $bits:ty),*) => {
+        $(
+            impl Serialize for $t {
+                fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                    self.bits().serialize(serializer)
+                }
+            }
+
+            impl<'de> Deserialize<'de> for $t {
+                fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                    Ok(<$t>::from_bits_truncate(<$bits>::deserialize(deserializer)?))
+                }
+            }
+        )*
+    };
+}
+
+#[cfg(feature = "serde")]
+serde_bitflags!(
+    LOpenFlags: u32,
+    LockType: u8,
+    LockFlag: u32,
+    LockStatus: u8,
+    QidType: u8,
+    GetattrMask: u64,
+    SetattrMask: u32
+);
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct DirEntryData<'a> {
     pub data: Vec<DirEntry<'a>>,
 }
@@ -273,11 +372,11 @@ impl<'a> DirEntryData<'a> {
     pub fn with(v: Vec<DirEntry<'a>>) -> DirEntryData<'a> {
         DirEntryData { data: v }
     }
-    pub fn data(&self) -> &[DirEntry] {
+    pub fn data(&self) -> &[DirEntry<'_>] {
         &self.data
     }
     pub fn size(&self) -> u64 {
-        self.data.iter().fold(0, |a, e| a + e.size()) as u64
+        self.data.iter().fold(0, |a, e| a + e.size())
     }
     pub fn push(&mut self, entry: DirEntry<'a>) {
         self.data.push(entry);
@@ -290,7 +389,8 @@ impl<'b> Default for DirEntryData<'b> {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum FcallType {
     // 9P2000.L
     Rlerror = 7,
@@ -365,6 +465,11 @@ pub enum FcallType {
 }
 
 impl FcallType {
+    /// Requests (T-messages) have even type numbers, responses odd ones.
+    pub fn is_request(&self) -> bool {
+        (*self as u8) & 1 == 0
+    }
+
     pub fn from_u8(v: u8) -> Option<FcallType> {
         match v {
             // 9P2000.L
@@ -443,13 +548,15 @@ impl FcallType {
     }
 }
 
-#[derive(Clone, Debug, Copy)]
+#[derive(Clone, Debug, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Qid {
     pub typ: QidType,
     pub version: u32,
     pub path: u64,
 }
-#[derive(Clone, Debug, Copy)]
+#[derive(Clone, Debug, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Statfs {
     pub typ: u32,
     pub bsize: u32,
@@ -461,12 +568,14 @@ pub struct Statfs {
     pub fsid: u64,
     pub namelen: u32,
 }
-#[derive(Clone, Debug, Copy)]
+#[derive(Clone, Debug, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Time {
     pub sec: u64,
     pub nsec: u64,
 }
-#[derive(Clone, Debug, Copy)]
+#[derive(Clone, Debug, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Stat {
     pub mode: u32,
     pub uid: u32,
@@ -483,7 +592,8 @@ pub struct Stat {
     pub gen: u64,
     pub data_version: u64,
 }
-#[derive(Clone, Debug, Copy)]
+#[derive(Clone, Debug, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct SetAttr {
     pub mode: u32,
     pub uid: u32,
@@ -492,7 +602,8 @@ pub struct SetAttr {
     pub atime: Time,
     pub mtime: Time,
 }
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct DirEntry<'a> {
     pub qid: Qid,
     pub offset: u64,
@@ -506,7 +617,8 @@ impl<'a> DirEntry<'a> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Flock<'a> {
     pub typ: LockType,
     pub flags: LockFlag,
@@ -516,7 +628,8 @@ pub struct Flock<'a> {
     pub client_id: FcallStr<'a>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Getlock<'a> {
     pub typ: LockType,
     pub start: u64,
@@ -525,66 +638,29 @@ pub struct Getlock<'a> {
     pub client_id: FcallStr<'a>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rlerror {
     pub ecode: u32,
 }
 
 impl Rlerror {
+    /// An io::Error carrying this errno, see errno::errno_from_io_error.
     pub fn into_io_error(self) -> std::io::Error {
-        use super::errno;
-        use std::io::Error;
-        use std::io::ErrorKind::*;
-
-        match self.ecode {
-            errno::ENOENT => Error::from(NotFound),
-            errno::EPERM => Error::from(PermissionDenied),
-            errno::ECONNREFUSED => Error::from(ConnectionRefused),
-            errno::ECONNRESET => Error::from(ConnectionReset),
-            errno::ECONNABORTED => Error::from(ConnectionAborted),
-            errno::ENOTCONN => Error::from(NotConnected),
-            errno::EADDRINUSE => Error::from(AddrInUse),
-            errno::EADDRNOTAVAIL => Error::from(AddrNotAvailable),
-            errno::EPIPE => Error::from(BrokenPipe),
-            errno::EALREADY => Error::from(AlreadyExists),
-            errno::EINVAL => Error::from(InvalidInput),
-            errno::ETIMEDOUT => Error::from(TimedOut),
-            errno::EINTR => Error::from(Interrupted),
-            ecode => Error::new(Other, errno::strerror(ecode)),
-        }
+        super::errno::io_error(self.ecode)
     }
 }
 
 impl From<std::io::Error> for Rlerror {
     fn from(err: std::io::Error) -> Self {
-        use super::errno;
-        use std::io::ErrorKind::*;
-
-        let ecode = match err.kind() {
-            NotFound => errno::ENOENT,
-            PermissionDenied => errno::EPERM,
-            ConnectionRefused => errno::ECONNREFUSED,
-            ConnectionReset => errno::ECONNRESET,
-            ConnectionAborted => errno::ECONNABORTED,
-            NotConnected => errno::ENOTCONN,
-            AddrInUse => errno::EADDRINUSE,
-            AddrNotAvailable => errno::EADDRNOTAVAIL,
-            BrokenPipe => errno::EPIPE,
-            AlreadyExists => errno::EALREADY,
-            WouldBlock => errno::EAGAIN,
-            InvalidInput => errno::EINVAL,
-            InvalidData => errno::EINVAL,
-            TimedOut => errno::ETIMEDOUT,
-            WriteZero => errno::EAGAIN,
-            Interrupted => errno::EINTR,
-            _ => errno::EIO,
-        };
-
-        Rlerror { ecode }
+        Rlerror {
+            ecode: super::errno::errno_from_io_error(&err),
+        }
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tattach<'a> {
     pub fid: u32,
     pub afid: u32,
@@ -605,34 +681,40 @@ impl<'a> Tattach<'a> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rattach {
     pub qid: Qid,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tstatfs {
     pub fid: u32,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rstatfs {
     pub statfs: Statfs,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tlopen {
     pub fid: u32,
     pub flags: LOpenFlags,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rlopen {
     pub qid: Qid,
     pub iounit: u32,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tlcreate<'a> {
     pub fid: u32,
     pub name: FcallStr<'a>,
@@ -653,13 +735,15 @@ impl<'a> Tlcreate<'a> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rlcreate {
     pub qid: Qid,
     pub iounit: u32,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tsymlink<'a> {
     pub fid: u32,
     pub name: FcallStr<'a>,
@@ -678,12 +762,14 @@ impl<'a> Tsymlink<'a> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rsymlink {
     pub qid: Qid,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tmknod<'a> {
     pub dfid: u32,
     pub name: FcallStr<'a>,
@@ -706,12 +792,14 @@ impl<'a> Tmknod<'a> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rmknod {
     pub qid: Qid,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Trename<'a> {
     pub fid: u32,
     pub dfid: u32,
@@ -728,15 +816,18 @@ impl<'a> Trename<'a> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rrename {}
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Treadlink {
     pub fid: u32,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rreadlink<'a> {
     pub target: FcallStr<'a>,
 }
@@ -749,30 +840,35 @@ impl<'a> Rreadlink<'a> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tgetattr {
     pub fid: u32,
     pub req_mask: GetattrMask,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rgetattr {
     pub valid: GetattrMask,
     pub qid: Qid,
     pub stat: Stat,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tsetattr {
     pub fid: u32,
     pub valid: SetattrMask,
     pub stat: SetAttr,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rsetattr {}
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Txattrwalk<'a> {
     pub fid: u32,
     pub new_fid: u32,
@@ -789,12 +885,14 @@ impl<'a> Txattrwalk<'a> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rxattrwalk {
     pub size: u64,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Txattrcreate<'a> {
     pub fid: u32,
     pub name: FcallStr<'a>,
@@ -813,17 +911,20 @@ impl<'a> Txattrcreate<'a> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rxattrcreate {}
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Treaddir {
     pub fid: u32,
     pub offset: u64,
     pub count: u32,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rreaddir<'a> {
     pub data: DirEntryData<'a>,
 }
@@ -848,15 +949,18 @@ impl<'a> Rreaddir<'a> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tfsync {
     pub fid: u32,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rfsync {}
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tlock<'a> {
     pub fid: u32,
     pub flock: Flock<'a>,
@@ -878,12 +982,14 @@ impl<'a> Tlock<'a> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rlock {
     pub status: LockStatus,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tgetlock<'a> {
     pub fid: u32,
     pub flock: Getlock<'a>,
@@ -904,7 +1010,8 @@ impl<'a> Tgetlock<'a> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rgetlock<'a> {
     pub flock: Getlock<'a>,
 }
@@ -923,7 +1030,8 @@ impl<'a> Rgetlock<'a> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tlink<'a> {
     pub dfid: u32,
     pub fid: u32,
@@ -940,10 +1048,12 @@ impl<'a> Tlink<'a> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rlink {}
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tmkdir<'a> {
     pub dfid: u32,
     pub name: FcallStr<'a>,
@@ -962,12 +1072,14 @@ impl<'a> Tmkdir<'a> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rmkdir {
     pub qid: Qid,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Trenameat<'a> {
     pub olddfid: u32,
     pub oldname: FcallStr<'a>,
@@ -986,10 +1098,12 @@ impl<'a> Trenameat<'a> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rrenameat {}
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tunlinkat<'a> {
     pub dfid: u32,
     pub name: FcallStr<'a>,
@@ -1006,10 +1120,12 @@ impl<'a> Tunlinkat<'a> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Runlinkat {}
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tauth<'a> {
     pub afid: u32,
     pub uname: FcallStr<'a>,
@@ -1028,12 +1144,14 @@ impl<'a> Tauth<'a> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rauth {
     pub aqid: Qid,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tversion<'a> {
     pub msize: u32,
     pub version: FcallStr<'a>,
@@ -1048,7 +1166,8 @@ impl<'a> Tversion<'a> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rversion<'a> {
     pub msize: u32,
     pub version: FcallStr<'a>,
@@ -1063,15 +1182,18 @@ impl<'a> Rversion<'a> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tflush {
     pub oldtag: u16,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rflush {}
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Twalk<'a> {
     pub fid: u32,
     pub new_fid: u32,
@@ -1088,19 +1210,22 @@ impl<'a> Twalk<'a> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rwalk {
     pub wqids: Vec<Qid>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tread {
     pub fid: u32,
     pub offset: u64,
     pub count: u32,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rread<'a> {
     pub data: Cow<'a, [u8]>,
 }
@@ -1113,7 +1238,8 @@ impl<'a> Rread<'a> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Twrite<'a> {
     pub fid: u32,
     pub offset: u64,
@@ -1130,23 +1256,28 @@ impl<'a> Twrite<'a> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rwrite {
     pub count: u32,
 }
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tclunk {
     pub fid: u32,
 }
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rclunk {}
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tremove {
     pub fid: u32,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rremove {}
 
 impl<'a> From<Rlerror> for Fcall<'a> {
@@ -1436,7 +1567,8 @@ impl<'a> From<Rremove> for Fcall<'a> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Fcall<'a> {
     Rlerror(Rlerror),
     Tattach(Tattach<'a>),
@@ -1634,7 +1766,8 @@ impl<'a, A: Into<Fcall<'a>>, B: Into<Fcall<'a>>> From<Result<A, B>> for Fcall<'a
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct TaggedFcall<'a> {
     pub tag: u16,
     pub fcall: Fcall<'a>,
@@ -1716,7 +1849,7 @@ impl<'a> TaggedFcall<'a> {
         };
         let buf = w.into_inner();
         let sz_bytes = &(buf.len() as u32).to_le_bytes()[..];
-        (&mut buf[..4]).copy_from_slice(sz_bytes);
+        buf[..4].copy_from_slice(sz_bytes);
         Ok(())
     }
 
@@ -1727,6 +1860,414 @@ impl<'a> TaggedFcall<'a> {
     }
 }
 
+// Compact single line formatting of messages for protocol traces, in the
+// style printed by diod and the kernel 9p tracepoints, e.g.
+// `Twalk tag=3 fid=1 newfid=2 wname=[usr,lib]`.
+
+// Maximum number of payload bytes and directory entries shown in a trace line.
+const TRACE_DATA_MAX: usize = 16;
+const TRACE_DIRENTS_MAX: usize = 8;
+
+fn fmt_flags(f: &mut std::fmt::Formatter, bits: u64, names: &[(&str, u64)]) -> std::fmt::Result {
+    let mut rest = bits;
+    let mut first = true;
+    for (name, bit) in names.iter() {
+        if *bit != 0 && rest & bit == *bit {
+            if !first {
+                write!(f, "|")?;
+            }
+            write!(f, "{}", name)?;
+            rest &= !bit;
+            first = false;
+        }
+    }
+    if rest != 0 || first {
+        if !first {
+            write!(f, "|")?;
+        }
+        write!(f, "{:#x}", rest)?;
+    }
+    Ok(())
+}
+
+impl std::fmt::Display for LOpenFlags {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let bits = self.bits();
+        match bits & 3 {
+            0 => write!(f, "O_RDONLY")?,
+            1 => write!(f, "O_WRONLY")?,
+            2 => write!(f, "O_RDWR")?,
+            _ => write!(f, "O_ACCMODE")?,
+        }
+        if bits & !3 != 0 {
+            write!(f, "|")?;
+            fmt_flags(
+                f,
+                (bits & !3) as u64,
+                &[
+                    ("O_EXCL", LOpenFlags::O_EXCL.bits() as u64),
+                    ("O_TRUNC", LOpenFlags::O_TRUNC.bits() as u64),
+                ],
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for GetattrMask {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if *self == GetattrMask::ALL {
+            return write!(f, "ALL");
+        }
+        fmt_flags(
+            f,
+            self.bits(),
+            &[
+                ("BASIC", GetattrMask::BASIC.bits()),
+                ("MODE", GetattrMask::MODE.bits()),
+                ("NLINK", GetattrMask::NLINK.bits()),
+                ("UID", GetattrMask::UID.bits()),
+                ("GID", GetattrMask::GID.bits()),
+                ("RDEV", GetattrMask::RDEV.bits()),
+                ("ATIME", GetattrMask::ATIME.bits()),
+                ("MTIME", GetattrMask::MTIME.bits()),
+                ("CTIME", GetattrMask::CTIME.bits()),
+                ("INO", GetattrMask::INO.bits()),
+                ("SIZE", GetattrMask::SIZE.bits()),
+                ("BLOCKS", GetattrMask::BLOCKS.bits()),
+                ("BTIME", GetattrMask::BTIME.bits()),
+                ("GEN", GetattrMask::GEN.bits()),
+                ("DATA_VERSION", GetattrMask::DATA_VERSION.bits()),
+            ],
+        )
+    }
+}
+
+impl std::fmt::Display for SetattrMask {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt_flags(
+            f,
+            self.bits() as u64,
+            &[
+                ("MODE", SetattrMask::MODE.bits() as u64),
+                ("UID", SetattrMask::UID.bits() as u64),
+                ("GID", SetattrMask::GID.bits() as u64),
+                ("SIZE", SetattrMask::SIZE.bits() as u64),
+                ("ATIME", SetattrMask::ATIME.bits() as u64),
+                ("MTIME", SetattrMask::MTIME.bits() as u64),
+                ("CTIME", SetattrMask::CTIME.bits() as u64),
+                ("ATIME_SET", SetattrMask::ATIME_SET.bits() as u64),
+                ("MTIME_SET", SetattrMask::MTIME_SET.bits() as u64),
+            ],
+        )
+    }
+}
+
+impl std::fmt::Display for LockType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            LockType::RDLOCK => write!(f, "RDLOCK"),
+            LockType::WRLOCK => write!(f, "WRLOCK"),
+            LockType::UNLOCK => write!(f, "UNLOCK"),
+            _ => write!(f, "{:#x}", self.bits()),
+        }
+    }
+}
+
+impl std::fmt::Display for LockStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            LockStatus::SUCCESS => write!(f, "SUCCESS"),
+            LockStatus::BLOCKED => write!(f, "BLOCKED"),
+            LockStatus::ERROR => write!(f, "ERROR"),
+            LockStatus::GRACE => write!(f, "GRACE"),
+            _ => write!(f, "{:#x}", self.bits()),
+        }
+    }
+}
+
+// Qids are printed as (path version type) with diod's type letters.
+impl std::fmt::Display for Qid {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "({:016x} {:x} '", self.path, self.version)?;
+        for (c, t) in [
+            ('d', QidType::DIR),
+            ('a', QidType::APPEND),
+            ('l', QidType::EXCL),
+            ('m', QidType::MOUNT),
+            ('A', QidType::AUTH),
+            ('t', QidType::TMP),
+            ('L', QidType::SYMLINK),
+        ] {
+            if self.typ.contains(t) {
+                write!(f, "{}", c)?;
+            }
+        }
+        write!(f, "')")
+    }
+}
+
+impl std::fmt::Display for Time {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}.{:09}", self.sec, self.nsec)
+    }
+}
+
+impl std::fmt::Display for FcallType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+struct TraceData<'a>(&'a [u8]);
+
+impl<'a> std::fmt::Display for TraceData<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "count={}", self.0.len())?;
+        if !self.0.is_empty() {
+            write!(f, " data=")?;
+            for b in self.0.iter().take(TRACE_DATA_MAX) {
+                write!(f, "{:02x}", b)?;
+            }
+            if self.0.len() > TRACE_DATA_MAX {
+                write!(f, "...")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn fmt_stat(f: &mut std::fmt::Formatter, stat: &Stat) -> std::fmt::Result {
+    write!(
+        f,
+        "mode={:o} uid={} gid={} nlink={} rdev={} size={} blksize={} blocks={} \
+         atime={} mtime={} ctime={} btime={} gen={} data_version={}",
+        stat.mode,
+        stat.uid,
+        stat.gid,
+        stat.nlink,
+        stat.rdev,
+        stat.size,
+        stat.blksize,
+        stat.blocks,
+        stat.atime,
+        stat.mtime,
+        stat.ctime,
+        stat.btime,
+        stat.gen,
+        stat.data_version
+    )
+}
+
+impl<'a> Fcall<'a> {
+    fn fmt_fields(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Fcall::Rlerror(v) => write!(f, " ecode={}", super::errno::strerror(v.ecode)),
+            Fcall::Tattach(v) => write!(
+                f,
+                " fid={} afid={} uname={} aname={} n_uname={}",
+                v.fid, v.afid, v.uname, v.aname, v.n_uname
+            ),
+            Fcall::Rattach(v) => write!(f, " qid={}", v.qid),
+            Fcall::Tstatfs(v) => write!(f, " fid={}", v.fid),
+            Fcall::Rstatfs(v) => write!(
+                f,
+                " type={:#x} bsize={} blocks={} bfree={} bavail={} files={} ffree={} fsid={:#x} namelen={}",
+                v.statfs.typ,
+                v.statfs.bsize,
+                v.statfs.blocks,
+                v.statfs.bfree,
+                v.statfs.bavail,
+                v.statfs.files,
+                v.statfs.ffree,
+                v.statfs.fsid,
+                v.statfs.namelen
+            ),
+            Fcall::Tlopen(v) => write!(f, " fid={} flags={}", v.fid, v.flags),
+            Fcall::Rlopen(v) => write!(f, " qid={} iounit={}", v.qid, v.iounit),
+            Fcall::Tlcreate(v) => write!(
+                f,
+                " fid={} name={} flags={} mode={:o} gid={}",
+                v.fid, v.name, v.flags, v.mode, v.gid
+            ),
+            Fcall::Rlcreate(v) => write!(f, " qid={} iounit={}", v.qid, v.iounit),
+            Fcall::Tsymlink(v) => write!(
+                f,
+                " fid={} name={} symtgt={} gid={}",
+                v.fid, v.name, v.symtgt, v.gid
+            ),
+            Fcall::Rsymlink(v) => write!(f, " qid={}", v.qid),
+            Fcall::Tmknod(v) => write!(
+                f,
+                " dfid={} name={} mode={:o} major={} minor={} gid={}",
+                v.dfid, v.name, v.mode, v.major, v.minor, v.gid
+            ),
+            Fcall::Rmknod(v) => write!(f, " qid={}", v.qid),
+            Fcall::Trename(v) => write!(f, " fid={} dfid={} name={}", v.fid, v.dfid, v.name),
+            Fcall::Rrename(_) => Ok(()),
+            Fcall::Treadlink(v) => write!(f, " fid={}", v.fid),
+            Fcall::Rreadlink(v) => write!(f, " target={}", v.target),
+            Fcall::Tgetattr(v) => write!(f, " fid={} request_mask={}", v.fid, v.req_mask),
+            Fcall::Rgetattr(v) => {
+                write!(f, " valid={} qid={} ", v.valid, v.qid)?;
+                fmt_stat(f, &v.stat)
+            }
+            Fcall::Tsetattr(v) => write!(
+                f,
+                " fid={} valid={} mode={:o} uid={} gid={} size={} atime={} mtime={}",
+                v.fid,
+                v.valid,
+                v.stat.mode,
+                v.stat.uid,
+                v.stat.gid,
+                v.stat.size,
+                v.stat.atime,
+                v.stat.mtime
+            ),
+            Fcall::Rsetattr(_) => Ok(()),
+            Fcall::Txattrwalk(v) => {
+                write!(f, " fid={} newfid={} name={}", v.fid, v.new_fid, v.name)
+            }
+            Fcall::Rxattrwalk(v) => write!(f, " size={}", v.size),
+            Fcall::Txattrcreate(v) => write!(
+                f,
+                " fid={} name={} size={} flags={:#x}",
+                v.fid, v.name, v.attr_size, v.flags
+            ),
+            Fcall::Rxattrcreate(_) => Ok(()),
+            Fcall::Treaddir(v) => write!(
+                f,
+                " fid={} offset={} count={}",
+                v.fid, v.offset, v.count
+            ),
+            Fcall::Rreaddir(v) => {
+                write!(f, " count={} entries=[", v.data.size())?;
+                for (i, e) in v.data.data.iter().take(TRACE_DIRENTS_MAX).enumerate() {
+                    if i != 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", e.name)?;
+                }
+                if v.data.data.len() > TRACE_DIRENTS_MAX {
+                    write!(f, ",...")?;
+                }
+                write!(f, "]")
+            }
+            Fcall::Tfsync(v) => write!(f, " fid={}", v.fid),
+            Fcall::Rfsync(_) => Ok(()),
+            Fcall::Tlock(v) => write!(
+                f,
+                " fid={} type={} flags={:#x} start={} length={} proc_id={} client_id={}",
+                v.fid,
+                v.flock.typ,
+                v.flock.flags.bits(),
+                v.flock.start,
+                v.flock.length,
+                v.flock.proc_id,
+                v.flock.client_id
+            ),
+            Fcall::Rlock(v) => write!(f, " status={}", v.status),
+            Fcall::Tgetlock(v) => write!(
+                f,
+                " fid={} type={} start={} length={} proc_id={} client_id={}",
+                v.fid,
+                v.flock.typ,
+                v.flock.start,
+                v.flock.length,
+                v.flock.proc_id,
+                v.flock.client_id
+            ),
+            Fcall::Rgetlock(v) => write!(
+                f,
+                " type={} start={} length={} proc_id={} client_id={}",
+                v.flock.typ, v.flock.start, v.flock.length, v.flock.proc_id, v.flock.client_id
+            ),
+            Fcall::Tlink(v) => write!(f, " dfid={} fid={} name={}", v.dfid, v.fid, v.name),
+            Fcall::Rlink(_) => Ok(()),
+            Fcall::Tmkdir(v) => write!(
+                f,
+                " dfid={} name={} mode={:o} gid={}",
+                v.dfid, v.name, v.mode, v.gid
+            ),
+            Fcall::Rmkdir(v) => write!(f, " qid={}", v.qid),
+            Fcall::Trenameat(v) => write!(
+                f,
+                " olddfid={} oldname={} newdfid={} newname={}",
+                v.olddfid, v.oldname, v.newdfid, v.newname
+            ),
+            Fcall::Rrenameat(_) => Ok(()),
+            Fcall::Tunlinkat(v) => write!(
+                f,
+                " dfid={} name={} flags={:#x}",
+                v.dfid, v.name, v.flags
+            ),
+            Fcall::Runlinkat(_) => Ok(()),
+            Fcall::Tauth(v) => write!(
+                f,
+                " afid={} uname={} aname={} n_uname={}",
+                v.afid, v.uname, v.aname, v.n_uname
+            ),
+            Fcall::Rauth(v) => write!(f, " aqid={}", v.aqid),
+            Fcall::Tversion(v) => write!(f, " msize={} version={}", v.msize, v.version),
+            Fcall::Rversion(v) => write!(f, " msize={} version={}", v.msize, v.version),
+            Fcall::Tflush(v) => write!(f, " oldtag={}", v.oldtag),
+            Fcall::Rflush(_) => Ok(()),
+            Fcall::Twalk(v) => {
+                write!(f, " fid={} newfid={} wname=[", v.fid, v.new_fid)?;
+                for (i, name) in v.wnames.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", name)?;
+                }
+                write!(f, "]")
+            }
+            Fcall::Rwalk(v) => {
+                write!(f, " wqid=[")?;
+                for (i, qid) in v.wqids.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", qid)?;
+                }
+                write!(f, "]")
+            }
+            Fcall::Tread(v) => write!(
+                f,
+                " fid={} offset={} count={}",
+                v.fid, v.offset, v.count
+            ),
+            Fcall::Rread(v) => write!(f, " {}", TraceData(&v.data)),
+            Fcall::Twrite(v) => write!(
+                f,
+                " fid={} offset={} {}",
+                v.fid,
+                v.offset,
+                TraceData(&v.data)
+            ),
+            Fcall::Rwrite(v) => write!(f, " count={}", v.count),
+            Fcall::Tclunk(v) => write!(f, " fid={}", v.fid),
+            Fcall::Rclunk(_) => Ok(()),
+            Fcall::Tremove(v) => write!(f, " fid={}", v.fid),
+            Fcall::Rremove(_) => Ok(()),
+        }
+    }
+}
+
+impl<'a> std::fmt::Display for Fcall<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", FcallType::from(self))?;
+        self.fmt_fields(f)
+    }
+}
+
+impl<'a> std::fmt::Display for TaggedFcall<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} tag={}", FcallType::from(&self.fcall), self.tag)?;
+        self.fcall.fmt_fields(f)
+    }
+}
+
 fn encode_u8<W: Write>(w: &mut W, v: u8) -> std::io::Result<()> {
     w.write_all(&[v])?;
     Ok(())
@@ -2321,7 +2862,11 @@ impl<'a, 'b: 'a> FcallDecoder<'b> {
     }
 
     fn decode_direntrydata(&mut self) -> std::io::Result<DirEntryData<'b>> {
-        let end_len = self.buf.len() - self.decode_u32()? as usize;
+        let n = self.decode_u32()? as usize;
+        if self.buf.len() < n {
+            return Err(invalid_9p_msg());
+        }
+        let end_len = self.buf.len() - n;
         let mut v = Vec::new();
         while self.buf.len() > end_len {
             v.push(self.decode_direntry()?);
@@ -0,0 +1,175 @@
+use super::fcall::{Fcall, FcallType};
+use std::time::Duration;
+
+/// Where in a conversation an event happened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HookPoint {
+    /// A Client wrote a request.
+    ClientRequest,
+    /// A Client got the response to a request.
+    ClientResponse,
+    /// A server read a request.
+    ServerRequest,
+    /// A server wrote a response.
+    ServerResponse,
+}
+
+impl std::fmt::Display for HookPoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let s = match self {
+            HookPoint::ClientRequest => "client request",
+            HookPoint::ClientResponse => "client response",
+            HookPoint::ServerRequest => "server request",
+            HookPoint::ServerResponse => "server response",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// A message seen by a Client or server.
+#[derive(Debug)]
+pub struct FcallEvent<'a, 'b> {
+    pub point: HookPoint,
+    pub tag: u16,
+    /// The fid the request operates on, responses carry their request's fid.
+    pub fid: Option<u32>,
+    pub typ: FcallType,
+    pub fcall: &'a Fcall<'b>,
+    /// For responses, how long since the request was written or read.
+    pub elapsed: Option<Duration>,
+}
+
+/// Called for every message a Client or server sends or receives, see
+/// Client::set_hook and ServeOptions::hook.
+///
+/// Hooks run on the thread handling the message and should be quick.
+pub trait FcallHook: Send + Sync {
+    fn event(&self, event: &FcallEvent);
+}
+
+impl<F: Fn(&FcallEvent) + Send + Sync> FcallHook for F {
+    fn event(&self, event: &FcallEvent) {
+        self(event)
+    }
+}
+
+/// The fid a request operates on.
+pub fn request_fid(fcall: &Fcall) -> Option<u32> {
+    match fcall {
+        Fcall::Tattach(v) => Some(v.fid),
+        Fcall::Tauth(v) => Some(v.afid),
+        Fcall::Tstatfs(v) => Some(v.fid),
+        Fcall::Tlopen(v) => Some(v.fid),
+        Fcall::Tlcreate(v) => Some(v.fid),
+        Fcall::Tsymlink(v) => Some(v.fid),
+        Fcall::Tmknod(v) => Some(v.dfid),
+        Fcall::Trename(v) => Some(v.fid),
+        Fcall::Treadlink(v) => Some(v.fid),
+        Fcall::Tgetattr(v) => Some(v.fid),
+        Fcall::Tsetattr(v) => Some(v.fid),
+        Fcall::Txattrwalk(v) => Some(v.fid),
+        Fcall::Txattrcreate(v) => Some(v.fid),
+        Fcall::Treaddir(v) => Some(v.fid),
+        Fcall::Tfsync(v) => Some(v.fid),
+        Fcall::Tlock(v) => Some(v.fid),
+        Fcall::Tgetlock(v) => Some(v.fid),
+        Fcall::Tlink(v) => Some(v.fid),
+        Fcall::Tmkdir(v) => Some(v.dfid),
+        Fcall::Trenameat(v) => Some(v.olddfid),
+        Fcall::Tunlinkat(v) => Some(v.dfid),
+        Fcall::Twalk(v) => Some(v.fid),
+        Fcall::Tread(v) => Some(v.fid),
+        Fcall::Twrite(v) => Some(v.fid),
+        Fcall::Tclunk(v) => Some(v.fid),
+        Fcall::Tremove(v) => Some(v.fid),
+        _ => None,
+    }
+}
+
+/// The existing fids a request operates on, unlike request_fid this
+/// includes every fid of a two fid request and leaves out Tattach.
+pub fn request_fids(fcall: &Fcall) -> Vec<u32> {
+    match fcall {
+        Fcall::Tstatfs(v) => vec![v.fid],
+        Fcall::Tlopen(v) => vec![v.fid],
+        Fcall::Tlcreate(v) => vec![v.fid],
+        Fcall::Tsymlink(v) => vec![v.fid],
+        Fcall::Tmknod(v) => vec![v.dfid],
+        Fcall::Trename(v) => vec![v.fid, v.dfid],
+        Fcall::Treadlink(v) => vec![v.fid],
+        Fcall::Tgetattr(v) => vec![v.fid],
+        Fcall::Tsetattr(v) => vec![v.fid],
+        Fcall::Txattrwalk(v) => vec![v.fid],
+        Fcall::Txattrcreate(v) => vec![v.fid],
+        Fcall::Treaddir(v) => vec![v.fid],
+        Fcall::Tfsync(v) => vec![v.fid],
+        Fcall::Tlock(v) => vec![v.fid],
+        Fcall::Tgetlock(v) => vec![v.fid],
+        Fcall::Tlink(v) => vec![v.dfid, v.fid],
+        Fcall::Tmkdir(v) => vec![v.dfid],
+        Fcall::Trenameat(v) => vec![v.olddfid, v.newdfid],
+        Fcall::Tunlinkat(v) => vec![v.dfid],
+        Fcall::Twalk(v) => vec![v.fid],
+        Fcall::Tread(v) => vec![v.fid],
+        Fcall::Twrite(v) => vec![v.fid],
+        Fcall::Tclunk(v) => vec![v.fid],
+        Fcall::Tremove(v) => vec![v.fid],
+        _ => Vec::new(),
+    }
+}
+
+// Call a hook for a message, elapsed is only given for responses.
+pub(crate) fn emit(
+    hook: &dyn FcallHook,
+    point: HookPoint,
+    tag: u16,
+    fid: Option<u32>,
+    fcall: &Fcall,
+    elapsed: Option<Duration>,
+) {
+    hook.event(&FcallEvent {
+        point,
+        tag,
+        fid,
+        typ: FcallType::from(fcall),
+        fcall,
+        elapsed,
+    })
+}
+
+/// A hook logging every message at debug level with the log crate.
+#[cfg(feature = "log")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LogHook;
+
+#[cfg(feature = "log")]
+impl FcallHook for LogHook {
+    fn event(&self, event: &FcallEvent) {
+        if !log::log_enabled!(log::Level::Debug) {
+            return;
+        }
+        let fid = match event.fid {
+            Some(fid) => format!(" fid={}", fid),
+            None => String::new(),
+        };
+        match event.elapsed {
+            Some(elapsed) => log::debug!(
+                "9p {} tag={}{} {} took={:?}: {}",
+                event.point,
+                event.tag,
+                fid,
+                event.typ,
+                elapsed,
+                event.fcall
+            ),
+            None => log::debug!(
+                "9p {} tag={}{} {}: {}",
+                event.point,
+                event.tag,
+                fid,
+                event.typ,
+                event.fcall
+            ),
+        }
+    }
+}
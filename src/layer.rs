@@ -0,0 +1,1523 @@
+use super::errno;
+use super::fcall::*;
+use super::server::{call_filesystem, FcallResponse, Filesystem, ThreadedFilesystem};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+// The Tunlinkat flag for removing a directory, as on Linux.
+const AT_REMOVEDIR: u32 = 0x200;
+
+// An overlay hides a name in the lower layer with a file named WHITEOUT
+// followed by the name in the upper layer, and a whole lower directory
+// with an OPAQUE file in the upper one, as container image layers do.
+const WHITEOUT: &[u8] = b".wh.";
+const OPAQUE: &[u8] = b".wh..wh..opq";
+
+// How much to ask a layer for at once when listing or copying.
+const CHUNK: u32 = 65536;
+
+/// A filesystem refusing every request that would change it with EROFS.
+pub struct ReadOnlyFs<F> {
+    fs: F,
+}
+
+impl<F> ReadOnlyFs<F> {
+    pub fn new(fs: F) -> ReadOnlyFs<F> {
+        ReadOnlyFs { fs }
+    }
+
+    pub fn inner(&self) -> &F {
+        &self.fs
+    }
+
+    pub fn into_inner(self) -> F {
+        self.fs
+    }
+}
+
+fn opens_for_write(flags: LOpenFlags) -> bool {
+    flags.intersects(LOpenFlags::O_WRONLY | LOpenFlags::O_RDWR | LOpenFlags::O_TRUNC)
+}
+
+macro_rules! read_only {
+    ($trait:ident, $recv:ty) => {
+        impl<F: $trait> $trait for ReadOnlyFs<F> {
+            fn lopen(self: $recv, req: &Tlopen, resp: FcallResponse) {
+                if opens_for_write(req.flags) {
+                    resp.send(Rlerror {
+                        ecode: errno::EROFS,
+                    })
+                } else {
+                    self.fs.lopen(req, resp)
+                }
+            }
+
+            fn remove(self: $recv, req: &Tremove, resp: FcallResponse) {
+                // Tremove clunks the fid even when it fails.
+                let (clunk, clunked) = FcallResponse::capture(resp.tag);
+                self.fs.clunk(&Tclunk { fid: req.fid }, clunk);
+                clunked.wait();
+                resp.send(Rlerror {
+                    ecode: errno::EROFS,
+                })
+            }
+
+            read_only!(@reject $recv,
+                lcreate(Tlcreate), symlink(Tsymlink), mknod(Tmknod), rename(Trename),
+                setattr(Tsetattr), xattrcreate(Txattrcreate), link(Tlink), mkdir(Tmkdir),
+                renameat(Trenameat), unlinkat(Tunlinkat), write(Twrite));
+            read_only!(@forward $recv,
+                statfs(Tstatfs), readlink(Treadlink), getattr(Tgetattr), xattrwalk(Txattrwalk),
+                readdir(Treaddir), fsync(Tfsync), lock(Tlock), getlock(Tgetlock), auth(Tauth),
+                attach(Tattach), flush(Tflush), walk(Twalk), read(Tread), clunk(Tclunk));
+        }
+    };
+    (@reject $recv:ty, $($method:ident($req:ty)),*) => {
+        $(
+            fn $method(self: $recv, _req: &$req, resp: FcallResponse) {
+                resp.send(Rlerror {
+                    ecode: errno::EROFS,
+                })
+            }
+        )*
+    };
+    (@forward $recv:ty, $($method:ident($req:ty)),*) => {
+        $(
+            fn $method(self: $recv, req: &$req, resp: FcallResponse) {
+                self.fs.$method(req, resp)
+            }
+        )*
+    };
+}
+
+read_only!(Filesystem, &mut Self);
+read_only!(ThreadedFilesystem, &Self);
+
+// The layers of an OverlayFs or UnionFs, topmost first.
+type Stack<'a> = [&'a mut dyn Filesystem];
+
+// One attach of the layers, kept for copying up and walking "..".
+struct Root {
+    uname: Vec<u8>,
+    aname: Vec<u8>,
+    n_uname: u32,
+    fids: Vec<Option<u32>>,
+    qid: Qid,
+}
+
+// A client fid.
+struct Node {
+    attach: usize,
+    // Names from the root.
+    path: Vec<Vec<u8>>,
+    // The file in each layer that has it, never opened so it can be walked.
+    fids: Vec<Option<u32>>,
+    qid: Qid,
+    // The layer and fid opened for I/O.
+    open: Option<(usize, u32)>,
+    // Merged directory entries, read when a listing starts.
+    listing: Option<Vec<DirEntry<'static>>>,
+}
+
+fn call<'a>(fs: &mut dyn Filesystem, fcall: impl Into<Fcall<'a>>) -> Fcall<'static> {
+    call_filesystem(fs, &fcall.into())
+}
+
+fn ecode(fcall: Fcall) -> u32 {
+    match fcall {
+        Fcall::Rlerror(Rlerror { ecode }) => ecode,
+        _ => errno::EIO,
+    }
+}
+
+fn check(fcall: Fcall<'static>) -> Result<Fcall<'static>, u32> {
+    match fcall {
+        Fcall::Rlerror(Rlerror { ecode }) => Err(ecode),
+        fcall => Ok(fcall),
+    }
+}
+
+fn respond(resp: FcallResponse, result: Result<Fcall<'static>, u32>) {
+    match result {
+        Ok(fcall) => resp.send(fcall),
+        Err(ecode) => resp.send(Rlerror { ecode }),
+    }
+}
+
+fn clunk(fs: &mut dyn Filesystem, fid: u32) -> Fcall<'static> {
+    call(fs, Tclunk { fid })
+}
+
+fn clunk_all(fs: &mut Stack, fids: &[Option<u32>]) {
+    for (layer, fid) in fs.iter_mut().zip(fids) {
+        if let Some(fid) = fid {
+            clunk(&mut **layer, *fid);
+        }
+    }
+}
+
+// Clunk the fids of a node, giving the response to clunking its open fid.
+fn release(fs: &mut Stack, node: Node) -> Fcall<'static> {
+    clunk_all(fs, &node.fids);
+    match node.open {
+        Some((layer, fid)) => clunk(&mut *fs[layer], fid),
+        None => Rclunk {}.into(),
+    }
+}
+
+fn top(fids: &[Option<u32>]) -> Option<(usize, u32)> {
+    fids.iter()
+        .enumerate()
+        .find_map(|(layer, fid)| fid.map(|fid| (layer, fid)))
+}
+
+fn is_dir(qid: &Qid) -> bool {
+    qid.typ.contains(QidType::DIR)
+}
+
+// Lower layers' qids get distinct paths.
+fn layer_qid(layer: usize, mut qid: Qid) -> Qid {
+    qid.path ^= (layer as u64) << 56;
+    qid
+}
+
+fn whiteout(name: &[u8]) -> Vec<u8> {
+    [WHITEOUT, name].concat()
+}
+
+// Fid state shared by OverlayFs and UnionFs, which differ in how they
+// change files.
+struct Layers {
+    overlay: bool,
+    roots: Vec<Root>,
+    fids: HashMap<u32, Node>,
+    next_fid: u32,
+}
+
+impl Layers {
+    fn new(overlay: bool) -> Layers {
+        Layers {
+            overlay,
+            roots: Vec::new(),
+            fids: HashMap::new(),
+            next_fid: 0,
+        }
+    }
+
+    fn node(&self, fid: u32) -> Result<&Node, u32> {
+        self.fids.get(&fid).ok_or(errno::EBADF)
+    }
+
+    fn opened(&self, fid: u32) -> Result<(usize, u32), u32> {
+        self.node(fid)?.open.ok_or(errno::EBADF)
+    }
+
+    fn top(&self, fid: u32) -> Result<(usize, u32), u32> {
+        top(&self.node(fid)?.fids).ok_or(errno::EBADF)
+    }
+
+    // A fid for the layers, they are only used by us.
+    fn alloc(&mut self) -> u32 {
+        let fid = self.next_fid;
+        self.next_fid = (self.next_fid + 1) % NOFID;
+        fid
+    }
+
+    fn clone_fid(&mut self, fs: &mut dyn Filesystem, fid: u32) -> Result<u32, u32> {
+        let new_fid = self.alloc();
+        let twalk = Twalk {
+            fid,
+            new_fid,
+            wnames: Vec::new(),
+        };
+        check(call(fs, twalk)).map(|_| new_fid)
+    }
+
+    fn clone_fids(
+        &mut self,
+        fs: &mut Stack,
+        fids: &[Option<u32>],
+    ) -> Result<Vec<Option<u32>>, u32> {
+        let mut cloned = vec![None; fids.len()];
+        for (layer, fid) in fids.iter().enumerate() {
+            if let Some(fid) = fid {
+                match self.clone_fid(&mut *fs[layer], *fid) {
+                    Ok(fid) => cloned[layer] = Some(fid),
+                    Err(ecode) => {
+                        clunk_all(fs, &cloned);
+                        return Err(ecode);
+                    }
+                }
+            }
+        }
+        Ok(cloned)
+    }
+
+    fn walk_one(&mut self, fs: &mut dyn Filesystem, fid: u32, name: &[u8]) -> Option<(u32, Qid)> {
+        let new_fid = self.alloc();
+        let twalk = Twalk {
+            fid,
+            new_fid,
+            wnames: vec![name.into()],
+        };
+        match call(fs, twalk) {
+            Fcall::Rwalk(Rwalk { wqids }) if wqids.len() == 1 => Some((new_fid, wqids[0])),
+            _ => None,
+        }
+    }
+
+    fn exists(&mut self, fs: &mut dyn Filesystem, fid: u32, name: &[u8]) -> bool {
+        match self.walk_one(fs, fid, name) {
+            Some((fid, _)) => {
+                clunk(fs, fid);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Look name up in a directory given its fids in each layer.
+    fn step(
+        &mut self,
+        fs: &mut Stack,
+        dir: &[Option<u32>],
+        name: &[u8],
+    ) -> Result<(Vec<Option<u32>>, Qid), u32> {
+        if self.overlay && name.starts_with(WHITEOUT) {
+            return Err(errno::ENOENT);
+        }
+        let mut fids = vec![None; fs.len()];
+        let mut qid = None;
+        for layer in 0..fs.len() {
+            let Some(dfid) = dir[layer] else {
+                continue;
+            };
+            let fs = &mut *fs[layer];
+            match self.walk_one(fs, dfid, name) {
+                // Only directories merge with directories above them.
+                Some((fid, found)) if qid.is_some() && !is_dir(&found) => {
+                    clunk(fs, fid);
+                }
+                Some((fid, found)) => {
+                    qid.get_or_insert(layer_qid(layer, found));
+                    fids[layer] = Some(fid);
+                    let opaque = self.overlay && layer == 0 && self.exists(fs, fid, OPAQUE);
+                    if !is_dir(&found) || opaque {
+                        break;
+                    }
+                }
+                None => {
+                    if self.overlay && layer == 0 && self.exists(fs, dfid, &whiteout(name)) {
+                        break;
+                    }
+                }
+            }
+        }
+        qid.map(|qid| (fids, qid)).ok_or(errno::ENOENT)
+    }
+
+    // The fids of a path from the root of an attach.
+    fn resolve(
+        &mut self,
+        fs: &mut Stack,
+        attach: usize,
+        path: &[Vec<u8>],
+    ) -> Result<(Vec<Option<u32>>, Qid), u32> {
+        let root = self.roots[attach].fids.clone();
+        let mut fids = self.clone_fids(fs, &root)?;
+        let mut qid = self.roots[attach].qid;
+        for name in path {
+            let next = self.step(fs, &fids, name);
+            clunk_all(fs, &fids);
+            (fids, qid) = next?;
+        }
+        Ok((fids, qid))
+    }
+
+    fn resolve_node(
+        &mut self,
+        fs: &mut Stack,
+        attach: usize,
+        path: &[Vec<u8>],
+    ) -> Result<Node, u32> {
+        let (fids, qid) = self.resolve(fs, attach, path)?;
+        Ok(Node {
+            attach,
+            path: path.to_vec(),
+            fids,
+            qid,
+            open: None,
+            listing: None,
+        })
+    }
+
+    // Every entry of a directory in one layer.
+    fn read_layer_dir(
+        &mut self,
+        fs: &mut dyn Filesystem,
+        fid: u32,
+    ) -> Result<Vec<DirEntry<'static>>, u32> {
+        let dir = self.clone_fid(fs, fid)?;
+        let tlopen = Tlopen {
+            fid: dir,
+            flags: LOpenFlags::O_RDONLY,
+        };
+        let mut result = check(call(fs, tlopen)).map(|_| Vec::new());
+        let mut offset = 0;
+        while let Ok(entries) = &mut result {
+            let treaddir = Treaddir {
+                fid: dir,
+                offset,
+                count: CHUNK,
+            };
+            match call(fs, treaddir) {
+                Fcall::Rreaddir(rreaddir) => match rreaddir.data.data.last() {
+                    Some(last) => {
+                        offset = last.offset;
+                        entries.extend(rreaddir.data.data);
+                    }
+                    None => break,
+                },
+                resp => result = Err(ecode(resp)),
+            }
+        }
+        clunk(fs, dir);
+        result
+    }
+
+    // The merged entries of a directory, renumbered.
+    fn list(
+        &mut self,
+        fs: &mut Stack,
+        fids: &[Option<u32>],
+    ) -> Result<Vec<DirEntry<'static>>, u32> {
+        let mut seen = HashSet::new();
+        let mut entries = Vec::new();
+        for layer in 0..fs.len() {
+            let Some(fid) = fids[layer] else {
+                continue;
+            };
+            for entry in self.read_layer_dir(&mut *fs[layer], fid)? {
+                let name = entry.name.as_bytes().to_vec();
+                if self.overlay && name.starts_with(WHITEOUT) {
+                    // Whiteouts hide the name below, and themselves.
+                    seen.insert(name[WHITEOUT.len()..].to_vec());
+                    continue;
+                }
+                if !seen.insert(name) {
+                    continue;
+                }
+                entries.push(DirEntry {
+                    qid: layer_qid(layer, entry.qid),
+                    offset: entries.len() as u64 + 1,
+                    typ: entry.typ,
+                    name: entry.name,
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    // Make sure a node is in the upper layer of an overlay, copying it and
+    // the directories above it up from the lower layer.
+    fn copy_up(&mut self, fs: &mut Stack, node: &mut Node) -> Result<(), u32> {
+        if node.fids[0].is_some() {
+            return Ok(());
+        }
+        let root = &self.roots[node.attach];
+        let (upper_root, lower_root, root_qid) = (root.fids[0], root.fids[1], root.qid);
+        let mut upper = self.clone_fid(&mut *fs[0], upper_root.ok_or(errno::EIO)?)?;
+        let mut lower = lower_root.and_then(|fid| self.clone_fid(&mut *fs[1], fid).ok());
+        let result = self.copy_up_path(fs, &node.path, root_qid, &mut upper, &mut lower);
+        if let Some(lower) = lower {
+            clunk(&mut *fs[1], lower);
+        }
+        let qid = match result {
+            Ok(qid) => qid,
+            Err(ecode) => {
+                clunk(&mut *fs[0], upper);
+                return Err(ecode);
+            }
+        };
+
+        // Other fids for the file see the copy too.
+        let others: Vec<u32> = self
+            .fids
+            .iter()
+            .filter(|(_, other)| {
+                other.attach == node.attach && other.path == node.path && other.fids[0].is_none()
+            })
+            .map(|(fid, _)| *fid)
+            .collect();
+        let mut nodes = vec![&mut *node];
+        let mut taken = Vec::new();
+        for fid in others {
+            taken.push((fid, self.fids.remove(&fid).unwrap()));
+        }
+        nodes.extend(taken.iter_mut().map(|(_, other)| other));
+        for (i, node) in nodes.into_iter().enumerate() {
+            node.fids[0] = if i == 0 {
+                Some(upper)
+            } else {
+                self.clone_fid(&mut *fs[0], upper).ok()
+            };
+            if !is_dir(&qid) {
+                for (fs, fid) in fs.iter_mut().zip(&mut node.fids).skip(1) {
+                    if let Some(fid) = fid.take() {
+                        clunk(&mut **fs, fid);
+                    }
+                }
+            }
+            node.qid = qid;
+        }
+        self.fids.extend(taken);
+        Ok(())
+    }
+
+    fn copy_up_path(
+        &mut self,
+        fs: &mut Stack,
+        path: &[Vec<u8>],
+        mut qid: Qid,
+        upper: &mut u32,
+        lower: &mut Option<u32>,
+    ) -> Result<Qid, u32> {
+        for name in path {
+            let below = lower.take().and_then(|dir| {
+                let found = self.walk_one(&mut *fs[1], dir, name);
+                clunk(&mut *fs[1], dir);
+                found
+            });
+            *lower = below.map(|(fid, _)| fid);
+            let found = match self.walk_one(&mut *fs[0], *upper, name) {
+                Some(found) => found,
+                None => {
+                    let (from, _) = below.ok_or(errno::ENOENT)?;
+                    self.copy_file(fs, from, *upper, name)?;
+                    self.walk_one(&mut *fs[0], *upper, name).ok_or(errno::EIO)?
+                }
+            };
+            clunk(&mut *fs[0], *upper);
+            (*upper, qid) = found;
+        }
+        Ok(qid)
+    }
+
+    // Copy one file from the lower layer into a directory of the upper.
+    fn copy_file(&mut self, fs: &mut Stack, from: u32, dir: u32, name: &[u8]) -> Result<(), u32> {
+        let tgetattr = Tgetattr {
+            fid: from,
+            req_mask: GetattrMask::BASIC,
+        };
+        let stat = match check(call(&mut *fs[1], tgetattr))? {
+            Fcall::Rgetattr(rgetattr) => rgetattr.stat,
+            _ => return Err(errno::EIO),
+        };
+        let (mode, gid) = (stat.mode & 0o7777, stat.gid);
+        let name = FcallStr::from(name);
+        let created = match stat.mode & S_IFMT {
+            S_IFREG => return self.copy_data(fs, from, dir, name, &stat),
+            S_IFDIR => call(
+                &mut *fs[0],
+                Tmkdir {
+                    dfid: dir,
+                    name,
+                    mode,
+                    gid,
+                },
+            ),
+            S_IFLNK => match check(call(&mut *fs[1], Treadlink { fid: from }))? {
+                Fcall::Rreadlink(Rreadlink { target }) => call(
+                    &mut *fs[0],
+                    Tsymlink {
+                        fid: dir,
+                        name,
+                        symtgt: target,
+                        gid,
+                    },
+                ),
+                _ => return Err(errno::EIO),
+            },
+            _ => call(
+                &mut *fs[0],
+                Tmknod {
+                    dfid: dir,
+                    name,
+                    mode: stat.mode,
+                    major: nix::sys::stat::major(stat.rdev) as u32,
+                    minor: nix::sys::stat::minor(stat.rdev) as u32,
+                    gid,
+                },
+            ),
+        };
+        check(created).map(|_| ())
+    }
+
+    fn copy_data(
+        &mut self,
+        fs: &mut Stack,
+        from: u32,
+        dir: u32,
+        name: FcallStr,
+        stat: &Stat,
+    ) -> Result<(), u32> {
+        let src = self.clone_fid(&mut *fs[1], from)?;
+        let dst = match self.clone_fid(&mut *fs[0], dir) {
+            Ok(dst) => dst,
+            Err(ecode) => {
+                clunk(&mut *fs[1], src);
+                return Err(ecode);
+            }
+        };
+        let result = copy_open(fs, src, dst, name, stat);
+        clunk(&mut *fs[1], src);
+        clunk(&mut *fs[0], dst);
+        result
+    }
+
+    // Prepare to create name in the directory dfid refers to, giving the
+    // layer to create it in, the directory's fid there and whether a
+    // whiteout was removed to make room.
+    fn prepare_create(
+        &mut self,
+        fs: &mut Stack,
+        dfid: u32,
+        name: &[u8],
+    ) -> Result<(usize, u32, bool), u32> {
+        if name.is_empty()
+            || name == b"."
+            || name == b".."
+            || name.contains(&b'/')
+            || (self.overlay && name.starts_with(WHITEOUT))
+        {
+            return Err(errno::EINVAL);
+        }
+        let node = self.node(dfid)?;
+        if !is_dir(&node.qid) {
+            return Err(errno::ENOTDIR);
+        }
+        let dir = node.fids.clone();
+        if let Ok((found, _)) = self.step(fs, &dir, name) {
+            clunk_all(fs, &found);
+            return Err(errno::EEXIST);
+        }
+        if self.overlay {
+            self.copy_up_fid(fs, dfid)?;
+        }
+        let (layer, dir) = self.top(dfid)?;
+        let removed = self.overlay && self.remove_whiteout(&mut *fs[0], dir, name);
+        Ok((layer, dir, removed))
+    }
+
+    fn copy_up_fid(&mut self, fs: &mut Stack, fid: u32) -> Result<(), u32> {
+        let mut node = self.fids.remove(&fid).ok_or(errno::EBADF)?;
+        let result = self.copy_up(fs, &mut node);
+        self.fids.insert(fid, node);
+        result
+    }
+
+    fn remove_whiteout(&mut self, fs: &mut dyn Filesystem, dir: u32, name: &[u8]) -> bool {
+        let whiteout = whiteout(name);
+        let tunlinkat = Tunlinkat {
+            dfid: dir,
+            name: (&whiteout).into(),
+            flags: 0,
+        };
+        matches!(call(fs, tunlinkat), Fcall::Runlinkat(_))
+    }
+
+    fn create_marker(&mut self, fs: &mut dyn Filesystem, dir: u32, name: &[u8]) -> Result<(), u32> {
+        let gid = match call(
+            fs,
+            Tgetattr {
+                fid: dir,
+                req_mask: GetattrMask::GID,
+            },
+        ) {
+            Fcall::Rgetattr(rgetattr) => rgetattr.stat.gid,
+            _ => 0,
+        };
+        let fid = self.clone_fid(fs, dir)?;
+        let tlcreate = Tlcreate {
+            fid,
+            name: name.into(),
+            flags: LOpenFlags::O_WRONLY,
+            mode: 0o600,
+            gid,
+        };
+        let created = call(fs, tlcreate);
+        clunk(fs, fid);
+        check(created).map(|_| ())
+    }
+
+    // Hide the lower directory under an upper one.
+    fn make_opaque(&mut self, fs: &mut dyn Filesystem, dir: u32, name: &[u8]) -> Result<(), u32> {
+        let (fid, _) = self.walk_one(fs, dir, name).ok_or(errno::EIO)?;
+        let result = self.create_marker(fs, fid, OPAQUE);
+        clunk(fs, fid);
+        result
+    }
+
+    // Remove name from a directory in every layer that has it, leaving a
+    // whiteout in an overlay.
+    fn unlink(
+        &mut self,
+        fs: &mut Stack,
+        dir: &mut Node,
+        name: &[u8],
+        flags: u32,
+    ) -> Result<(), u32> {
+        let (found, qid) = self.step(fs, &dir.fids, name)?;
+        let empty = if flags & AT_REMOVEDIR != 0 {
+            self.check_empty(fs, &found, qid)
+        } else {
+            Ok(())
+        };
+        if let Err(ecode) = empty {
+            clunk_all(fs, &found);
+            return Err(ecode);
+        }
+        // Names shadowed by a file above go too, as long as they are the
+        // same kind of file.
+        let mut all = found.clone();
+        for layer in 0..fs.len() {
+            let Some(dfid) = dir.fids[layer].filter(|_| all[layer].is_none()) else {
+                continue;
+            };
+            if let Some((fid, shadowed)) = self.walk_one(&mut *fs[layer], dfid, name) {
+                if is_dir(&shadowed) == is_dir(&qid) {
+                    all[layer] = Some(fid);
+                } else {
+                    clunk(&mut *fs[layer], fid);
+                }
+            }
+        }
+        let result = self.unlink_found(fs, dir, name, flags, &all, qid);
+        clunk_all(fs, &all);
+        result
+    }
+
+    fn check_empty(&mut self, fs: &mut Stack, found: &[Option<u32>], qid: Qid) -> Result<(), u32> {
+        if !is_dir(&qid) {
+            return Err(errno::ENOTDIR);
+        }
+        let entries = self.list(fs, found)?;
+        if entries
+            .iter()
+            .any(|entry| entry.name.as_bytes() != b"." && entry.name.as_bytes() != b"..")
+        {
+            return Err(errno::ENOTEMPTY);
+        }
+        Ok(())
+    }
+
+    fn unlink_found(
+        &mut self,
+        fs: &mut Stack,
+        dir: &mut Node,
+        name: &[u8],
+        flags: u32,
+        all: &[Option<u32>],
+        qid: Qid,
+    ) -> Result<(), u32> {
+        // Only the upper layer of an overlay changes.
+        let writable = if self.overlay { 1 } else { fs.len() };
+        for layer in 0..writable {
+            let (Some(fid), Some(dfid)) = (all[layer], dir.fids[layer]) else {
+                continue;
+            };
+            if self.overlay && is_dir(&qid) {
+                // Whiteouts are all that can be left in the directory.
+                for entry in self.read_layer_dir(&mut *fs[layer], fid)? {
+                    if entry.name.as_bytes().starts_with(WHITEOUT) {
+                        let tunlinkat = Tunlinkat {
+                            dfid: fid,
+                            name: entry.name,
+                            flags: 0,
+                        };
+                        check(call(&mut *fs[layer], tunlinkat))?;
+                    }
+                }
+            }
+            let tunlinkat = Tunlinkat {
+                dfid,
+                name: name.into(),
+                flags,
+            };
+            check(call(&mut *fs[layer], tunlinkat))?;
+        }
+        if self.overlay && all[1..].iter().any(Option::is_some) {
+            self.copy_up(fs, dir)?;
+            let dfid = dir.fids[0].ok_or(errno::EIO)?;
+            self.create_marker(&mut *fs[0], dfid, &whiteout(name))?;
+        }
+        Ok(())
+    }
+
+    // Rename a path within an attach, keeping fids on it and below.
+    fn rename_path(
+        &mut self,
+        fs: &mut Stack,
+        attach: usize,
+        from: &[Vec<u8>],
+        to: &[Vec<u8>],
+    ) -> Result<(), u32> {
+        let (oldname, oldparent) = from.split_last().ok_or(errno::EBUSY)?;
+        let (newname, newparent) = to.split_last().ok_or(errno::EBUSY)?;
+        if newname.is_empty()
+            || newname == b"."
+            || newname == b".."
+            || newname.contains(&b'/')
+            || (self.overlay && newname.starts_with(WHITEOUT))
+        {
+            return Err(errno::EINVAL);
+        }
+        let mut olddir = self.resolve_node(fs, attach, oldparent)?;
+        let mut newdir = match self.resolve_node(fs, attach, newparent) {
+            Ok(newdir) => newdir,
+            Err(ecode) => {
+                release(fs, olddir);
+                return Err(ecode);
+            }
+        };
+        let result = self.rename_in(fs, &mut olddir, oldname, &mut newdir, newname);
+        release(fs, olddir);
+        release(fs, newdir);
+        result?;
+
+        for node in self.fids.values_mut() {
+            if node.attach == attach && node.path.starts_with(from) {
+                node.path.splice(..from.len(), to.iter().cloned());
+            }
+        }
+        Ok(())
+    }
+
+    fn rename_in(
+        &mut self,
+        fs: &mut Stack,
+        olddir: &mut Node,
+        oldname: &[u8],
+        newdir: &mut Node,
+        newname: &[u8],
+    ) -> Result<(), u32> {
+        let (found, qid) = self.step(fs, &olddir.fids, oldname)?;
+        let target = match self.step(fs, &newdir.fids, newname) {
+            Ok((target, _)) => {
+                clunk_all(fs, &target);
+                target.iter().map(Option::is_some).collect()
+            }
+            Err(_) => vec![false; fs.len()],
+        };
+        let (layer, _) = top(&found).ok_or(errno::EIO)?;
+        if !self.overlay {
+            clunk_all(fs, &found);
+            // Anything left behind below, or in front of the new name, would
+            // show instead.
+            if found[layer + 1..].iter().any(Option::is_some) || target[..layer].contains(&true) {
+                return Err(errno::EXDEV);
+            }
+            let (Some(olddfid), Some(newdfid)) = (olddir.fids[layer], newdir.fids[layer]) else {
+                return Err(errno::EXDEV);
+            };
+            return rename_at(&mut *fs[layer], olddfid, oldname, newdfid, newname);
+        }
+
+        let lower = found[1..].iter().any(Option::is_some);
+        let mut source = Node {
+            attach: olddir.attach,
+            path: [&olddir.path[..], &[oldname.to_vec()]].concat(),
+            fids: found,
+            qid,
+            open: None,
+            listing: None,
+        };
+        // Moving a lower directory would mean copying its whole tree.
+        let result = if is_dir(&qid) && lower {
+            Err(errno::EXDEV)
+        } else {
+            self.copy_up(fs, &mut source)
+        };
+        release(fs, source);
+        result?;
+        self.copy_up(fs, olddir)?;
+        self.copy_up(fs, newdir)?;
+        let olddfid = olddir.fids[0].ok_or(errno::EIO)?;
+        let newdfid = newdir.fids[0].ok_or(errno::EIO)?;
+        let removed = self.remove_whiteout(&mut *fs[0], newdfid, newname);
+        rename_at(&mut *fs[0], olddfid, oldname, newdfid, newname)?;
+        if lower {
+            self.create_marker(&mut *fs[0], olddfid, &whiteout(oldname))?;
+        }
+        if is_dir(&qid) && (removed || target[1..].contains(&true)) {
+            self.make_opaque(&mut *fs[0], newdfid, newname)?;
+        }
+        Ok(())
+    }
+
+    fn attach(&mut self, fs: &mut Stack, req: &Tattach) -> Result<Fcall<'static>, u32> {
+        if self.fids.contains_key(&req.fid) {
+            return Err(errno::EBADF);
+        }
+        let known = self.roots.iter().position(|root| {
+            root.uname == req.uname.as_bytes()
+                && root.aname == req.aname.as_bytes()
+                && root.n_uname == req.n_uname
+        });
+        let attach = match known {
+            Some(attach) => attach,
+            None => {
+                let mut fids = Vec::new();
+                let mut qid = None;
+                for layer in 0..fs.len() {
+                    let fid = self.alloc();
+                    let tattach = Tattach {
+                        fid,
+                        afid: NOFID,
+                        uname: req.uname.clone(),
+                        aname: req.aname.clone(),
+                        n_uname: req.n_uname,
+                    };
+                    match call(&mut *fs[layer], tattach) {
+                        Fcall::Rattach(rattach) => {
+                            qid.get_or_insert(layer_qid(layer, rattach.qid));
+                            fids.push(Some(fid));
+                        }
+                        resp => {
+                            clunk_all(fs, &fids);
+                            return Err(ecode(resp));
+                        }
+                    }
+                }
+                self.roots.push(Root {
+                    uname: req.uname.as_bytes().to_vec(),
+                    aname: req.aname.as_bytes().to_vec(),
+                    n_uname: req.n_uname,
+                    fids,
+                    qid: qid.ok_or(errno::ENOENT)?,
+                });
+                self.roots.len() - 1
+            }
+        };
+        let node = self.resolve_node(fs, attach, &[])?;
+        let qid = node.qid;
+        self.fids.insert(req.fid, node);
+        Ok(Rattach { qid }.into())
+    }
+
+    fn walk(&mut self, fs: &mut Stack, req: &Twalk) -> Result<Fcall<'static>, u32> {
+        let node = self.node(req.fid)?;
+        if node.open.is_some() || (req.new_fid != req.fid && self.fids.contains_key(&req.new_fid)) {
+            return Err(errno::EBADF);
+        }
+        let (attach, mut path, mut qid) = (node.attach, node.path.clone(), node.qid);
+        let start = node.fids.clone();
+        let mut fids = self.clone_fids(fs, &start)?;
+        let mut wqids = Vec::new();
+        for name in &req.wnames {
+            let name = name.as_bytes();
+            let mut next_path = path.clone();
+            let next = if !is_dir(&qid) {
+                Err(errno::ENOTDIR)
+            } else if name == b"." || name == b".." {
+                if name == b".." {
+                    next_path.pop();
+                }
+                self.resolve(fs, attach, &next_path)
+            } else {
+                next_path.push(name.to_vec());
+                self.step(fs, &fids, name)
+            };
+            clunk_all(fs, &fids);
+            match next {
+                Ok(next) => {
+                    (fids, qid) = next;
+                    path = next_path;
+                    wqids.push(qid);
+                }
+                Err(_) if !wqids.is_empty() => return Ok(Rwalk { wqids }.into()),
+                Err(ecode) => return Err(ecode),
+            }
+        }
+        if let Some(old) = self.fids.remove(&req.new_fid) {
+            release(fs, old);
+        }
+        let node = Node {
+            attach,
+            path,
+            fids,
+            qid,
+            open: None,
+            listing: None,
+        };
+        self.fids.insert(req.new_fid, node);
+        Ok(Rwalk { wqids }.into())
+    }
+
+    fn clunk(&mut self, fs: &mut Stack, req: &Tclunk) -> Result<Fcall<'static>, u32> {
+        let node = self.fids.remove(&req.fid).ok_or(errno::EBADF)?;
+        check(release(fs, node)).map(|_| Rclunk {}.into())
+    }
+
+    fn flush(&mut self, _fs: &mut Stack, _req: &Tflush) -> Result<Fcall<'static>, u32> {
+        // Requests are answered before the next is read.
+        Ok(Rflush {}.into())
+    }
+
+    fn statfs(&mut self, fs: &mut Stack, req: &Tstatfs) -> Result<Fcall<'static>, u32> {
+        let (layer, fid) = self.top(req.fid)?;
+        Ok(call(&mut *fs[layer], Tstatfs { fid }))
+    }
+
+    fn readlink(&mut self, fs: &mut Stack, req: &Treadlink) -> Result<Fcall<'static>, u32> {
+        let (layer, fid) = self.top(req.fid)?;
+        Ok(call(&mut *fs[layer], Treadlink { fid }))
+    }
+
+    fn getattr(&mut self, fs: &mut Stack, req: &Tgetattr) -> Result<Fcall<'static>, u32> {
+        let (layer, fid) = self.top(req.fid)?;
+        let tgetattr = Tgetattr {
+            fid,
+            req_mask: req.req_mask,
+        };
+        match check(call(&mut *fs[layer], tgetattr))? {
+            Fcall::Rgetattr(mut rgetattr) => {
+                rgetattr.qid = layer_qid(layer, rgetattr.qid);
+                Ok(rgetattr.into())
+            }
+            _ => Err(errno::EIO),
+        }
+    }
+
+    fn setattr(&mut self, fs: &mut Stack, req: &Tsetattr) -> Result<Fcall<'static>, u32> {
+        if self.overlay {
+            self.copy_up_fid(fs, req.fid)?;
+        }
+        let (layer, fid) = self.top(req.fid)?;
+        let tsetattr = Tsetattr {
+            fid,
+            valid: req.valid,
+            stat: req.stat,
+        };
+        Ok(call(&mut *fs[layer], tsetattr))
+    }
+
+    fn lopen(&mut self, fs: &mut Stack, req: &Tlopen) -> Result<Fcall<'static>, u32> {
+        let node = self.node(req.fid)?;
+        if node.open.is_some() {
+            return Err(errno::EBADF);
+        }
+        if self.overlay && opens_for_write(req.flags) && !is_dir(&node.qid) {
+            self.copy_up_fid(fs, req.fid)?;
+        }
+        let (layer, fid) = self.top(req.fid)?;
+        let fs = &mut *fs[layer];
+        let open = self.clone_fid(fs, fid)?;
+        let tlopen = Tlopen {
+            fid: open,
+            flags: req.flags,
+        };
+        match call(fs, tlopen) {
+            Fcall::Rlopen(rlopen) => {
+                self.fids.get_mut(&req.fid).unwrap().open = Some((layer, open));
+                Ok(Rlopen {
+                    qid: layer_qid(layer, rlopen.qid),
+                    iounit: rlopen.iounit,
+                }
+                .into())
+            }
+            resp => {
+                clunk(fs, open);
+                Err(ecode(resp))
+            }
+        }
+    }
+
+    fn lcreate(&mut self, fs: &mut Stack, req: &Tlcreate) -> Result<Fcall<'static>, u32> {
+        if self.node(req.fid)?.open.is_some() {
+            return Err(errno::EBADF);
+        }
+        let name = req.name.as_bytes();
+        let (layer, dir, _) = self.prepare_create(fs, req.fid, name)?;
+        let open = self.clone_fid(&mut *fs[layer], dir)?;
+        let tlcreate = Tlcreate {
+            fid: open,
+            name: req.name.clone(),
+            flags: req.flags,
+            mode: req.mode,
+            gid: req.gid,
+        };
+        let rlcreate = match call(&mut *fs[layer], tlcreate) {
+            Fcall::Rlcreate(rlcreate) => rlcreate,
+            resp => {
+                clunk(&mut *fs[layer], open);
+                return Err(ecode(resp));
+            }
+        };
+        // The fid now refers to the new file.
+        let file = self.walk_one(&mut *fs[layer], dir, name);
+        let node = self.fids.get_mut(&req.fid).unwrap();
+        let dir_fids = std::mem::replace(&mut node.fids, vec![None; fs.len()]);
+        node.fids[layer] = file.map(|(fid, _)| fid);
+        node.path.push(name.to_vec());
+        node.qid = layer_qid(layer, rlcreate.qid);
+        node.open = Some((layer, open));
+        let qid = node.qid;
+        clunk_all(fs, &dir_fids);
+        Ok(Rlcreate {
+            qid,
+            iounit: rlcreate.iounit,
+        }
+        .into())
+    }
+
+    fn mkdir(&mut self, fs: &mut Stack, req: &Tmkdir) -> Result<Fcall<'static>, u32> {
+        let name = req.name.as_bytes();
+        let (layer, dir, removed) = self.prepare_create(fs, req.dfid, name)?;
+        let tmkdir = Tmkdir {
+            dfid: dir,
+            name: req.name.clone(),
+            mode: req.mode,
+            gid: req.gid,
+        };
+        let qid = match check(call(&mut *fs[layer], tmkdir))? {
+            Fcall::Rmkdir(rmkdir) => rmkdir.qid,
+            _ => return Err(errno::EIO),
+        };
+        if removed {
+            // Nothing of the directory deleted before may show through.
+            self.make_opaque(&mut *fs[layer], dir, name)?;
+        }
+        Ok(Rmkdir {
+            qid: layer_qid(layer, qid),
+        }
+        .into())
+    }
+
+    fn symlink(&mut self, fs: &mut Stack, req: &Tsymlink) -> Result<Fcall<'static>, u32> {
+        let (layer, dir, _) = self.prepare_create(fs, req.fid, req.name.as_bytes())?;
+        let tsymlink = Tsymlink {
+            fid: dir,
+            name: req.name.clone(),
+            symtgt: req.symtgt.clone(),
+            gid: req.gid,
+        };
+        match check(call(&mut *fs[layer], tsymlink))? {
+            Fcall::Rsymlink(rsymlink) => Ok(Rsymlink {
+                qid: layer_qid(layer, rsymlink.qid),
+            }
+            .into()),
+            _ => Err(errno::EIO),
+        }
+    }
+
+    fn mknod(&mut self, fs: &mut Stack, req: &Tmknod) -> Result<Fcall<'static>, u32> {
+        let (layer, dir, _) = self.prepare_create(fs, req.dfid, req.name.as_bytes())?;
+        let tmknod = Tmknod {
+            dfid: dir,
+            name: req.name.clone(),
+            mode: req.mode,
+            major: req.major,
+            minor: req.minor,
+            gid: req.gid,
+        };
+        match check(call(&mut *fs[layer], tmknod))? {
+            Fcall::Rmknod(rmknod) => Ok(Rmknod {
+                qid: layer_qid(layer, rmknod.qid),
+            }
+            .into()),
+            _ => Err(errno::EIO),
+        }
+    }
+
+    fn link(&mut self, fs: &mut Stack, req: &Tlink) -> Result<Fcall<'static>, u32> {
+        if self.overlay {
+            self.copy_up_fid(fs, req.fid)?;
+        }
+        let (layer, dir, _) = self.prepare_create(fs, req.dfid, req.name.as_bytes())?;
+        let fid = self.node(req.fid)?.fids[layer].ok_or(errno::EXDEV)?;
+        let tlink = Tlink {
+            dfid: dir,
+            fid,
+            name: req.name.clone(),
+        };
+        Ok(call(&mut *fs[layer], tlink))
+    }
+
+    fn unlinkat(&mut self, fs: &mut Stack, req: &Tunlinkat) -> Result<Fcall<'static>, u32> {
+        let mut dir = self.fids.remove(&req.dfid).ok_or(errno::EBADF)?;
+        let result = self.unlink(fs, &mut dir, req.name.as_bytes(), req.flags);
+        self.fids.insert(req.dfid, dir);
+        result.map(|()| Runlinkat {}.into())
+    }
+
+    fn remove(&mut self, fs: &mut Stack, req: &Tremove) -> Result<Fcall<'static>, u32> {
+        let node = self.fids.remove(&req.fid).ok_or(errno::EBADF)?;
+        let result = match node.path.split_last() {
+            Some((name, parent)) => match self.resolve_node(fs, node.attach, parent) {
+                Ok(mut dir) => {
+                    let flags = if is_dir(&node.qid) { AT_REMOVEDIR } else { 0 };
+                    let result = self.unlink(fs, &mut dir, name, flags);
+                    release(fs, dir);
+                    result
+                }
+                Err(ecode) => Err(ecode),
+            },
+            None => Err(errno::EBUSY),
+        };
+        release(fs, node);
+        result.map(|()| Rremove {}.into())
+    }
+
+    fn rename(&mut self, fs: &mut Stack, req: &Trename) -> Result<Fcall<'static>, u32> {
+        let node = self.node(req.fid)?;
+        let dir = self.node(req.dfid)?;
+        if node.attach != dir.attach {
+            return Err(errno::EXDEV);
+        }
+        let attach = node.attach;
+        let from = node.path.clone();
+        let to = [&dir.path[..], &[req.name.as_bytes().to_vec()]].concat();
+        self.rename_path(fs, attach, &from, &to)
+            .map(|()| Rrename {}.into())
+    }
+
+    fn renameat(&mut self, fs: &mut Stack, req: &Trenameat) -> Result<Fcall<'static>, u32> {
+        let olddir = self.node(req.olddfid)?;
+        let newdir = self.node(req.newdfid)?;
+        if olddir.attach != newdir.attach {
+            return Err(errno::EXDEV);
+        }
+        let attach = olddir.attach;
+        let from = [&olddir.path[..], &[req.oldname.as_bytes().to_vec()]].concat();
+        let to = [&newdir.path[..], &[req.newname.as_bytes().to_vec()]].concat();
+        self.rename_path(fs, attach, &from, &to)
+            .map(|()| Rrenameat {}.into())
+    }
+
+    fn readdir(&mut self, fs: &mut Stack, req: &Treaddir) -> Result<Fcall<'static>, u32> {
+        let node = self.node(req.fid)?;
+        if node.open.is_none() {
+            return Err(errno::EBADF);
+        }
+        if req.offset == 0 || node.listing.is_none() {
+            let fids = node.fids.clone();
+            let listing = self.list(fs, &fids)?;
+            self.fids.get_mut(&req.fid).unwrap().listing = Some(listing);
+        }
+        let listing = self.node(req.fid)?.listing.as_deref().unwrap_or_default();
+        let mut data = DirEntryData::new();
+        for entry in listing.iter().skip(req.offset as usize) {
+            if data.size() + entry.size() > req.count as u64 {
+                break;
+            }
+            data.push(entry.clone());
+        }
+        Ok(Rreaddir { data }.into())
+    }
+
+    fn read(&mut self, fs: &mut Stack, req: &Tread) -> Result<Fcall<'static>, u32> {
+        let (layer, fid) = self.opened(req.fid)?;
+        let tread = Tread {
+            fid,
+            offset: req.offset,
+            count: req.count,
+        };
+        Ok(call(&mut *fs[layer], tread))
+    }
+
+    fn write(&mut self, fs: &mut Stack, req: &Twrite) -> Result<Fcall<'static>, u32> {
+        let (layer, fid) = self.opened(req.fid)?;
+        let twrite = Twrite {
+            fid,
+            offset: req.offset,
+            data: Cow::from(&req.data[..]),
+        };
+        Ok(call(&mut *fs[layer], twrite))
+    }
+
+    fn fsync(&mut self, fs: &mut Stack, req: &Tfsync) -> Result<Fcall<'static>, u32> {
+        let (layer, fid) = self.opened(req.fid)?;
+        Ok(call(&mut *fs[layer], Tfsync { fid }))
+    }
+
+    fn lock(&mut self, fs: &mut Stack, req: &Tlock) -> Result<Fcall<'static>, u32> {
+        let (layer, fid) = self.opened(req.fid)?;
+        let tlock = Tlock {
+            fid,
+            flock: req.flock.clone(),
+        };
+        Ok(call(&mut *fs[layer], tlock))
+    }
+
+    fn getlock(&mut self, fs: &mut Stack, req: &Tgetlock) -> Result<Fcall<'static>, u32> {
+        let (layer, fid) = self.opened(req.fid)?;
+        let tgetlock = Tgetlock {
+            fid,
+            flock: req.flock.clone(),
+        };
+        Ok(call(&mut *fs[layer], tgetlock))
+    }
+
+    fn xattrwalk(&mut self, fs: &mut Stack, req: &Txattrwalk) -> Result<Fcall<'static>, u32> {
+        if req.new_fid != req.fid && self.fids.contains_key(&req.new_fid) {
+            return Err(errno::EBADF);
+        }
+        let node = self.node(req.fid)?;
+        let (attach, path, qid) = (node.attach, node.path.clone(), node.qid);
+        let (layer, fid) = self.top(req.fid)?;
+        let new_fid = self.alloc();
+        let txattrwalk = Txattrwalk {
+            fid,
+            new_fid,
+            name: req.name.clone(),
+        };
+        let rxattrwalk = check(call(&mut *fs[layer], txattrwalk))?;
+        if let Some(old) = self.fids.remove(&req.new_fid) {
+            release(fs, old);
+        }
+        let node = Node {
+            attach,
+            path,
+            fids: vec![None; fs.len()],
+            qid,
+            open: Some((layer, new_fid)),
+            listing: None,
+        };
+        self.fids.insert(req.new_fid, node);
+        Ok(rxattrwalk)
+    }
+
+    fn xattrcreate(&mut self, fs: &mut Stack, req: &Txattrcreate) -> Result<Fcall<'static>, u32> {
+        if self.overlay {
+            self.copy_up_fid(fs, req.fid)?;
+        }
+        if self.node(req.fid)?.open.is_some() {
+            return Err(errno::EBADF);
+        }
+        let (layer, fid) = self.top(req.fid)?;
+        let fs = &mut *fs[layer];
+        let xattr = self.clone_fid(fs, fid)?;
+        let txattrcreate = Txattrcreate {
+            fid: xattr,
+            name: req.name.clone(),
+            attr_size: req.attr_size,
+            flags: req.flags,
+        };
+        match call(fs, txattrcreate) {
+            Fcall::Rxattrcreate(rxattrcreate) => {
+                self.fids.get_mut(&req.fid).unwrap().open = Some((layer, xattr));
+                Ok(rxattrcreate.into())
+            }
+            resp => {
+                clunk(fs, xattr);
+                Err(ecode(resp))
+            }
+        }
+    }
+}
+
+// Copy a lower file's data into a new upper file, src and dst being fids
+// for the file and its new directory.
+fn copy_open(fs: &mut Stack, src: u32, dst: u32, name: FcallStr, stat: &Stat) -> Result<(), u32> {
+    let tlopen = Tlopen {
+        fid: src,
+        flags: LOpenFlags::O_RDONLY,
+    };
+    check(call(&mut *fs[1], tlopen))?;
+    let tlcreate = Tlcreate {
+        fid: dst,
+        name,
+        flags: LOpenFlags::O_WRONLY,
+        mode: stat.mode & 0o7777,
+        gid: stat.gid,
+    };
+    check(call(&mut *fs[0], tlcreate))?;
+    let mut offset = 0;
+    loop {
+        let tread = Tread {
+            fid: src,
+            offset,
+            count: CHUNK,
+        };
+        let data = match check(call(&mut *fs[1], tread))? {
+            Fcall::Rread(rread) => rread.data,
+            _ => return Err(errno::EIO),
+        };
+        if data.is_empty() {
+            break;
+        }
+        let mut written = 0;
+        while written < data.len() {
+            let twrite = Twrite {
+                fid: dst,
+                offset: offset + written as u64,
+                data: Cow::from(&data[written..]),
+            };
+            match check(call(&mut *fs[0], twrite))? {
+                Fcall::Rwrite(Rwrite { count }) if count > 0 => written += count as usize,
+                _ => return Err(errno::EIO),
+            }
+        }
+        offset += data.len() as u64;
+    }
+    let tsetattr = Tsetattr {
+        fid: dst,
+        valid: SetattrMask::ATIME
+            | SetattrMask::MTIME
+            | SetattrMask::ATIME_SET
+            | SetattrMask::MTIME_SET,
+        stat: SetAttr {
+            mode: 0,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            atime: stat.atime,
+            mtime: stat.mtime,
+        },
+    };
+    let _ = call(&mut *fs[0], tsetattr);
+    Ok(())
+}
+
+fn rename_at(
+    fs: &mut dyn Filesystem,
+    olddfid: u32,
+    oldname: &[u8],
+    newdfid: u32,
+    newname: &[u8],
+) -> Result<(), u32> {
+    let trenameat = Trenameat {
+        olddfid,
+        oldname: oldname.into(),
+        newdfid,
+        newname: newname.into(),
+    };
+    check(call(fs, trenameat)).map(|_| ())
+}
+
+macro_rules! layered {
+    ($($method:ident($req:ty);)*) => {
+        $(
+            fn $method(&mut self, req: &$req, resp: FcallResponse) {
+                let (layers, mut fs) = self.split();
+                respond(resp, layers.$method(&mut fs, req))
+            }
+        )*
+    };
+}
+
+macro_rules! layered_filesystem {
+    () => {
+        layered! {
+            statfs(Tstatfs);
+            lopen(Tlopen);
+            lcreate(Tlcreate);
+            symlink(Tsymlink);
+            mknod(Tmknod);
+            rename(Trename);
+            readlink(Treadlink);
+            getattr(Tgetattr);
+            setattr(Tsetattr);
+            xattrwalk(Txattrwalk);
+            xattrcreate(Txattrcreate);
+            readdir(Treaddir);
+            fsync(Tfsync);
+            lock(Tlock);
+            getlock(Tgetlock);
+            link(Tlink);
+            mkdir(Tmkdir);
+            renameat(Trenameat);
+            unlinkat(Tunlinkat);
+            attach(Tattach);
+            flush(Tflush);
+            walk(Twalk);
+            read(Tread);
+            write(Twrite);
+            clunk(Tclunk);
+            remove(Tremove);
+        }
+    };
+}
+
+/// A writable filesystem layered over a read-only one, as container images
+/// are. Names are looked up in the upper layer first and directories in
+/// both are merged. Files are copied up before they change, and names
+/// removed from the lower layer are hidden by whiteouts, files named
+/// ".wh.<name>" in the upper layer that are hidden themselves.
+///
+/// Moving a directory that is in the lower layer fails with EXDEV.
+pub struct OverlayFs<U: Filesystem, L: Filesystem> {
+    upper: U,
+    lower: L,
+    layers: Layers,
+}
+
+impl<U: Filesystem, L: Filesystem> OverlayFs<U, L> {
+    pub fn new(upper: U, lower: L) -> OverlayFs<U, L> {
+        OverlayFs {
+            upper,
+            lower,
+            layers: Layers::new(true),
+        }
+    }
+
+    pub fn upper(&self) -> &U {
+        &self.upper
+    }
+
+    pub fn lower(&self) -> &L {
+        &self.lower
+    }
+
+    fn split(&mut self) -> (&mut Layers, Vec<&mut dyn Filesystem>) {
+        let fs: Vec<&mut dyn Filesystem> = vec![&mut self.upper, &mut self.lower];
+        (&mut self.layers, fs)
+    }
+}
+
+impl<U: Filesystem, L: Filesystem> Filesystem for OverlayFs<U, L> {
+    layered_filesystem!();
+}
+
+/// Several filesystems merged into one, the first taking priority. Names
+/// are looked up in each in turn and directories in more than one are
+/// merged. Changes go to the first filesystem holding a file, new files to
+/// the first holding their directory, and removing a name removes it from
+/// every filesystem.
+///
+/// A ThreadedFilesystem can be merged by serving it with ThreadPoolServer.
+pub struct UnionFs<F: Filesystem> {
+    branches: Vec<F>,
+    layers: Layers,
+}
+
+impl<F: Filesystem> UnionFs<F> {
+    pub fn new(branches: Vec<F>) -> UnionFs<F> {
+        UnionFs {
+            branches,
+            layers: Layers::new(false),
+        }
+    }
+
+    pub fn branches(&self) -> &[F] {
+        &self.branches
+    }
+
+    fn split(&mut self) -> (&mut Layers, Vec<&mut dyn Filesystem>) {
+        let fs = self
+            .branches
+            .iter_mut()
+            .map(|fs| fs as &mut dyn Filesystem)
+            .collect();
+        (&mut self.layers, fs)
+    }
+}
+
+impl<F: Filesystem> Filesystem for UnionFs<F> {
+    layered_filesystem!();
+}
@@ -1,11 +1,35 @@
+pub mod cache;
 pub mod client;
 pub mod errno;
+pub mod error;
+pub mod fault;
 pub mod fcall;
+pub mod hook;
+pub mod layer;
+pub mod loopback;
+pub mod metrics;
+pub mod proxy;
+pub mod replay;
 pub mod server;
+pub mod synth;
+pub mod trace;
 pub mod transport;
 
+mod rng;
+
+pub use cache::*;
 pub use client::*;
 pub use errno::*;
+pub use error::*;
+pub use fault::*;
 pub use fcall::*;
+pub use hook::*;
+pub use layer::*;
+pub use loopback::*;
+pub use metrics::*;
+pub use proxy::*;
+pub use replay::*;
 pub use server::*;
+pub use synth::*;
+pub use trace::*;
 pub use transport::*;
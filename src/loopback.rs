@@ -0,0 +1,307 @@
+use super::rng::Rng;
+use super::transport::{ReadTransport, WriteTransport};
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Conditions for a loopback connection to simulate, in both directions.
+#[derive(Clone, Debug)]
+pub struct LoopbackBuilder {
+    latency: Duration,
+    bandwidth: Option<u64>,
+    drop_rate: f64,
+    reset_rate: f64,
+    seed: u64,
+}
+
+impl Default for LoopbackBuilder {
+    fn default() -> LoopbackBuilder {
+        LoopbackBuilder {
+            latency: Duration::ZERO,
+            bandwidth: None,
+            drop_rate: 0.0,
+            reset_rate: 0.0,
+            seed: 1,
+        }
+    }
+}
+
+impl LoopbackBuilder {
+    pub fn new() -> LoopbackBuilder {
+        LoopbackBuilder::default()
+    }
+
+    /// Delay each message by this long before the other end can read it.
+    pub fn latency(mut self, latency: Duration) -> LoopbackBuilder {
+        self.latency = latency;
+        self
+    }
+
+    /// Deliver at most this many bytes per second, messages queue behind
+    /// each other on the link.
+    pub fn bandwidth(mut self, bytes_per_sec: u64) -> LoopbackBuilder {
+        self.bandwidth = Some(bytes_per_sec.max(1));
+        self
+    }
+
+    /// The chance, from 0 to 1, that each message is silently lost.
+    pub fn drop_rate(mut self, rate: f64) -> LoopbackBuilder {
+        self.drop_rate = rate;
+        self
+    }
+
+    /// The chance, from 0 to 1, that writing a message resets the
+    /// connection instead.
+    pub fn reset_rate(mut self, rate: f64) -> LoopbackBuilder {
+        self.reset_rate = rate;
+        self
+    }
+
+    /// Seed the random choice of dropped messages and resets, the same seed
+    /// and messages give the same faults.
+    pub fn seed(mut self, seed: u64) -> LoopbackBuilder {
+        self.seed = seed;
+        self
+    }
+
+    /// Both ends of a connection.
+    pub fn pair(self) -> (Loopback, Loopback) {
+        let ab = Arc::new(Pipe::new(&self, self.seed));
+        let ba = Arc::new(Pipe::new(&self, !self.seed));
+        let a = End {
+            rx: ba.clone(),
+            tx: ab.clone(),
+            read_timeout: Mutex::new(None),
+        };
+        let b = End {
+            rx: ab,
+            tx: ba,
+            read_timeout: Mutex::new(None),
+        };
+        (Loopback { end: Arc::new(a) }, Loopback { end: Arc::new(b) })
+    }
+}
+
+/// Both ends of a loopback connection with no simulated faults.
+pub fn loopback_pair() -> (Loopback, Loopback) {
+    LoopbackBuilder::new().pair()
+}
+
+struct PipeState {
+    // Messages and when they may be read, the first maybe partly read.
+    queue: VecDeque<(Instant, Vec<u8>)>,
+    read_off: usize,
+    // Written bytes not yet making up a whole message.
+    pending: Vec<u8>,
+    // When the link finishes sending what is queued, for bandwidth limits.
+    link_free: Instant,
+    drop_next: u64,
+    rng: Rng,
+    closed: bool,
+    reset: bool,
+}
+
+// One direction of a connection.
+struct Pipe {
+    state: Mutex<PipeState>,
+    changed: Condvar,
+    latency: Duration,
+    bandwidth: Option<u64>,
+    drop_rate: f64,
+    reset_rate: f64,
+}
+
+impl Pipe {
+    fn new(opts: &LoopbackBuilder, seed: u64) -> Pipe {
+        Pipe {
+            state: Mutex::new(PipeState {
+                queue: VecDeque::new(),
+                read_off: 0,
+                pending: Vec::new(),
+                link_free: Instant::now(),
+                drop_next: 0,
+                rng: Rng::new(seed),
+                closed: false,
+                reset: false,
+            }),
+            changed: Condvar::new(),
+            latency: opts.latency,
+            bandwidth: opts.bandwidth,
+            drop_rate: opts.drop_rate,
+            reset_rate: opts.reset_rate,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PipeState> {
+        self.state.lock().unwrap()
+    }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.changed.notify_all();
+    }
+
+    fn reset(&self) {
+        self.lock().reset = true;
+        self.changed.notify_all();
+    }
+
+    // Queue whole messages from pending, false if one reset the connection.
+    fn deliver(&self, state: &mut PipeState) -> bool {
+        while state.pending.len() >= 4 {
+            let size = u32::from_le_bytes(state.pending[..4].try_into().unwrap()) as usize;
+            if state.pending.len() < size.max(4) {
+                break;
+            }
+            let msg: Vec<u8> = state.pending.drain(..size.max(4)).collect();
+            if state.drop_next > 0 {
+                state.drop_next -= 1;
+                continue;
+            }
+            if self.reset_rate > 0.0 && state.rng.next_f64() < self.reset_rate {
+                return false;
+            }
+            if self.drop_rate > 0.0 && state.rng.next_f64() < self.drop_rate {
+                continue;
+            }
+            let now = Instant::now();
+            let mut sent = now;
+            if let Some(bandwidth) = self.bandwidth {
+                let start = state.link_free.max(now);
+                let nanos = msg.len() as u128 * 1_000_000_000 / bandwidth as u128;
+                state.link_free = start + Duration::from_nanos(nanos as u64);
+                sent = state.link_free;
+            }
+            state.queue.push_back((sent + self.latency, msg));
+            self.changed.notify_all();
+        }
+        true
+    }
+}
+
+struct End {
+    rx: Arc<Pipe>,
+    tx: Arc<Pipe>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl Drop for End {
+    fn drop(&mut self) {
+        self.tx.close();
+        self.rx.close();
+    }
+}
+
+/// One end of an in-process connection carrying 9p messages, for running
+/// clients and servers together without sockets. Clones share the end, as
+/// a socket's clones do, and it closes when the last is dropped.
+///
+/// Bytes are delivered a whole message at a time, so faults act on
+/// messages.
+#[derive(Clone)]
+pub struct Loopback {
+    end: Arc<End>,
+}
+
+impl Loopback {
+    /// Reset the connection, failing reads and writes at both ends with
+    /// ConnectionReset.
+    pub fn reset(&self) {
+        self.end.tx.reset();
+        self.end.rx.reset();
+    }
+
+    /// Silently lose the next n messages written to this end.
+    pub fn drop_next(&self, n: u64) {
+        self.end.tx.lock().drop_next += n;
+    }
+}
+
+impl Read for Loopback {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let timeout = *self.end.read_timeout.lock().unwrap();
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let pipe = &self.end.rx;
+        let mut state = pipe.lock();
+        loop {
+            if state.reset {
+                return Err(std::io::ErrorKind::ConnectionReset.into());
+            }
+            let now = Instant::now();
+            let mut wake = deadline;
+            match state.queue.front() {
+                Some((at, msg)) if *at <= now => {
+                    let off = state.read_off;
+                    let n = buf.len().min(msg.len() - off);
+                    buf[..n].copy_from_slice(&msg[off..off + n]);
+                    if off + n == msg.len() {
+                        state.queue.pop_front();
+                        state.read_off = 0;
+                    } else {
+                        state.read_off += n;
+                    }
+                    return Ok(n);
+                }
+                Some((at, _)) => wake = Some(wake.map_or(*at, |wake| wake.min(*at))),
+                None if state.closed => return Ok(0),
+                None => (),
+            }
+            if deadline.is_some_and(|deadline| deadline <= now) {
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            }
+            state = match wake {
+                Some(wake) => pipe.changed.wait_timeout(state, wake - now).unwrap().0,
+                None => pipe.changed.wait(state).unwrap(),
+            };
+        }
+    }
+}
+
+impl Write for Loopback {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let pipe = &self.end.tx;
+        let mut state = pipe.lock();
+        if state.reset {
+            return Err(std::io::ErrorKind::ConnectionReset.into());
+        }
+        if state.closed {
+            return Err(std::io::ErrorKind::BrokenPipe.into());
+        }
+        state.pending.extend_from_slice(buf);
+        if !pipe.deliver(&mut state) {
+            drop(state);
+            self.reset();
+            return Err(std::io::ErrorKind::ConnectionReset.into());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ReadTransport for Loopback {
+    fn set_read_timeout(&mut self, dur: Option<Duration>) -> Result<(), std::io::Error> {
+        *self.end.read_timeout.lock().unwrap() = dur;
+        Ok(())
+    }
+
+    fn read_timeout(&self) -> Result<Option<Duration>, std::io::Error> {
+        Ok(*self.end.read_timeout.lock().unwrap())
+    }
+}
+
+impl WriteTransport for Loopback {
+    /// Close both directions, as shutting down a socket does. Messages
+    /// already sent can still be read.
+    fn shutdown(&self) -> Result<(), std::io::Error> {
+        self.end.tx.close();
+        self.end.rx.close();
+        Ok(())
+    }
+}
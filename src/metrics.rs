@@ -0,0 +1,327 @@
+use super::errno;
+use super::fcall::{Fcall, FcallType};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the latency histogram buckets in microseconds, a final
+/// bucket counts anything slower.
+pub const LATENCY_BUCKETS: [u64; 12] = [
+    50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 100_000, 1_000_000, 10_000_000,
+];
+
+/// Statistics for one request type.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpStats {
+    /// Requests answered.
+    pub count: u64,
+    /// Requests answered with Rlerror.
+    pub errors: u64,
+    /// Bytes of requests and responses, including headers.
+    pub request_bytes: u64,
+    pub response_bytes: u64,
+    pub latency_sum: Duration,
+    /// Requests in each LATENCY_BUCKETS bucket plus the final slower bucket,
+    /// not cumulative.
+    pub latency: Vec<u64>,
+}
+
+impl Default for OpStats {
+    fn default() -> OpStats {
+        OpStats {
+            count: 0,
+            errors: 0,
+            request_bytes: 0,
+            response_bytes: 0,
+            latency_sum: Duration::ZERO,
+            latency: vec![0; LATENCY_BUCKETS.len() + 1],
+        }
+    }
+}
+
+/// A point in time copy of Metrics.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Statistics by request type, in type order.
+    pub ops: Vec<(FcallType, OpStats)>,
+    /// Error responses by request type and errno.
+    pub errnos: Vec<(FcallType, u32, u64)>,
+    /// Requests waiting for a response.
+    pub inflight: i64,
+    /// Fids currently attached or walked.
+    pub fids: i64,
+}
+
+// The fids a successful request adds or removes.
+#[derive(Clone, Copy, Debug)]
+enum FidChange {
+    None,
+    New,
+    Walk(usize),
+    Gone,
+}
+
+/// A request being timed, see Metrics::start.
+#[derive(Clone, Debug)]
+pub struct Started {
+    typ: FcallType,
+    since: Instant,
+    bytes: usize,
+    fids: FidChange,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    ops: BTreeMap<u8, OpStats>,
+    errnos: BTreeMap<(u8, u32), u64>,
+}
+
+/// Request counts, errors, latencies and sizes for a client, server or
+/// proxy, along with in flight request and fid gauges.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    counters: Mutex<Counters>,
+    inflight: AtomicI64,
+    fids: AtomicI64,
+    // Requests passing through a proxy, by tag.
+    proxied: Mutex<HashMap<u16, Started>>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Note a request of the given encoded size was sent or received.
+    pub fn start(&self, req: &Fcall, bytes: usize) -> Started {
+        self.inflight.fetch_add(1, Ordering::SeqCst);
+        let fids = match req {
+            Fcall::Tattach(_) | Fcall::Tauth(_) | Fcall::Txattrwalk(_) => FidChange::New,
+            Fcall::Twalk(twalk) if twalk.fid != twalk.new_fid => {
+                FidChange::Walk(twalk.wnames.len())
+            }
+            Fcall::Tclunk(_) | Fcall::Tremove(_) => FidChange::Gone,
+            _ => FidChange::None,
+        };
+        Started {
+            typ: FcallType::from(req),
+            since: Instant::now(),
+            bytes,
+            fids,
+        }
+    }
+
+    /// Record the response to a started request.
+    pub fn finish(&self, started: Started, resp: &Fcall, bytes: usize) {
+        let latency = started.since.elapsed();
+        self.inflight.fetch_sub(1, Ordering::SeqCst);
+
+        let ecode = match resp {
+            Fcall::Rlerror(rlerror) => Some(rlerror.ecode),
+            _ => None,
+        };
+        let fids = match (started.fids, resp) {
+            // Clunk and remove forget the fid even on error.
+            (FidChange::Gone, _) => -1,
+            (_, Fcall::Rlerror(_)) => 0,
+            (FidChange::New, _) => 1,
+            (FidChange::Walk(n), Fcall::Rwalk(rwalk)) if rwalk.wqids.len() == n => 1,
+            _ => 0,
+        };
+        self.fids.fetch_add(fids, Ordering::SeqCst);
+
+        let micros = latency.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|le| micros <= *le)
+            .unwrap_or(LATENCY_BUCKETS.len());
+
+        let mut counters = self.counters.lock().unwrap();
+        let op = counters.ops.entry(started.typ as u8).or_default();
+        op.count += 1;
+        op.request_bytes += started.bytes as u64;
+        op.response_bytes += bytes as u64;
+        op.latency_sum += latency;
+        op.latency[bucket] += 1;
+        if let Some(ecode) = ecode {
+            op.errors += 1;
+            *counters
+                .errnos
+                .entry((started.typ as u8, ecode))
+                .or_insert(0) += 1;
+        }
+    }
+
+    /// Forget a request that will never be answered, for example because it
+    /// was flushed or the connection was lost.
+    pub fn cancel(&self, _started: Started) {
+        self.inflight.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let counters = self.counters.lock().unwrap();
+        let typ = |code: u8| FcallType::from_u8(code).unwrap();
+        MetricsSnapshot {
+            ops: counters
+                .ops
+                .iter()
+                .map(|(code, op)| (typ(*code), op.clone()))
+                .collect(),
+            errnos: counters
+                .errnos
+                .iter()
+                .map(|((code, ecode), n)| (typ(*code), *ecode, *n))
+                .collect(),
+            inflight: self.inflight.load(Ordering::SeqCst),
+            fids: self.fids.load(Ordering::SeqCst),
+        }
+    }
+}
+
+impl MetricsSnapshot {
+    pub fn op(&self, typ: FcallType) -> Option<&OpStats> {
+        self.ops.iter().find(|(t, _)| *t == typ).map(|(_, op)| op)
+    }
+
+    /// Format the snapshot in the Prometheus text exposition format, every
+    /// metric name starts with prefix.
+    pub fn to_prometheus(&self, prefix: &str) -> String {
+        let mut out = String::new();
+        let header = |out: &mut String, name: &str, typ: &str, help: &str| {
+            let _ = writeln!(out, "# HELP {}_{} {}", prefix, name, help);
+            let _ = writeln!(out, "# TYPE {}_{} {}", prefix, name, typ);
+        };
+
+        header(
+            &mut out,
+            "requests_total",
+            "counter",
+            "9P requests answered.",
+        );
+        for (typ, op) in self.ops.iter() {
+            let _ = writeln!(
+                out,
+                "{}_requests_total{{op=\"{}\"}} {}",
+                prefix, typ, op.count
+            );
+        }
+
+        header(
+            &mut out,
+            "errors_total",
+            "counter",
+            "9P requests answered with Rlerror.",
+        );
+        for (typ, ecode, n) in self.errnos.iter() {
+            let _ = writeln!(
+                out,
+                "{}_errors_total{{op=\"{}\",errno=\"{}\"}} {}",
+                prefix,
+                typ,
+                errno::strerror(*ecode),
+                n
+            );
+        }
+
+        header(
+            &mut out,
+            "request_bytes_total",
+            "counter",
+            "Bytes of 9P requests.",
+        );
+        for (typ, op) in self.ops.iter() {
+            let _ = writeln!(
+                out,
+                "{}_request_bytes_total{{op=\"{}\"}} {}",
+                prefix, typ, op.request_bytes
+            );
+        }
+
+        header(
+            &mut out,
+            "response_bytes_total",
+            "counter",
+            "Bytes of 9P responses.",
+        );
+        for (typ, op) in self.ops.iter() {
+            let _ = writeln!(
+                out,
+                "{}_response_bytes_total{{op=\"{}\"}} {}",
+                prefix, typ, op.response_bytes
+            );
+        }
+
+        header(
+            &mut out,
+            "request_duration_seconds",
+            "histogram",
+            "Time from a 9P request to its response.",
+        );
+        for (typ, op) in self.ops.iter() {
+            let mut cumulative = 0;
+            for (le, n) in LATENCY_BUCKETS.iter().zip(op.latency.iter()) {
+                cumulative += n;
+                let _ = writeln!(
+                    out,
+                    "{}_request_duration_seconds_bucket{{op=\"{}\",le=\"{}\"}} {}",
+                    prefix,
+                    typ,
+                    *le as f64 / 1e6,
+                    cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_request_duration_seconds_bucket{{op=\"{}\",le=\"+Inf\"}} {}",
+                prefix, typ, op.count
+            );
+            let _ = writeln!(
+                out,
+                "{}_request_duration_seconds_sum{{op=\"{}\"}} {}",
+                prefix,
+                typ,
+                op.latency_sum.as_secs_f64()
+            );
+            let _ = writeln!(
+                out,
+                "{}_request_duration_seconds_count{{op=\"{}\"}} {}",
+                prefix, typ, op.count
+            );
+        }
+
+        header(
+            &mut out,
+            "inflight_requests",
+            "gauge",
+            "9P requests waiting for a response.",
+        );
+        let _ = writeln!(out, "{}_inflight_requests {}", prefix, self.inflight);
+        header(&mut out, "fids", "gauge", "9P fids in use.");
+        let _ = writeln!(out, "{}_fids {}", prefix, self.fids);
+        out
+    }
+}
+
+// Measures the requests passing through a proxy, see Proxy::set_metrics.
+impl Metrics {
+    pub(crate) fn proxy_request(&self, tag: u16, req: &Fcall, bytes: usize) {
+        let started = self.start(req, bytes);
+        if let Some(old) = self.proxied.lock().unwrap().insert(tag, started) {
+            self.cancel(old);
+        }
+    }
+
+    pub(crate) fn proxy_response(&self, req: &Fcall, tag: u16, resp: &Fcall, bytes: usize) {
+        let mut proxied = self.proxied.lock().unwrap();
+        if let Fcall::Tflush(tflush) = req {
+            if let Some(started) = proxied.remove(&tflush.oldtag) {
+                self.cancel(started);
+            }
+        }
+        if let Some(started) = proxied.remove(&tag) {
+            self.finish(started, resp, bytes);
+        }
+    }
+}
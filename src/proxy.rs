@@ -0,0 +1,786 @@
+use super::errno;
+use super::fcall;
+use super::fcall::{Fcall, TaggedFcall};
+use super::metrics::Metrics;
+use super::transport;
+use super::transport::{frame_len, ReadTransport, WriteTransport};
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::ThreadId;
+use std::time::{Duration, Instant};
+
+// How often the client reader checks whether the server connection has died.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What the proxy should do with an intercepted message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Pass the message on unchanged.
+    Forward,
+    /// Pass on a replacement message with the same tag.
+    Rewrite(Fcall<'static>),
+    /// Answer the request directly without contacting the server. For
+    /// responses this is the same as Rewrite.
+    Reply(Fcall<'static>),
+    /// Answer with Rlerror.
+    Reject(u32),
+}
+
+/// Hooks called for every message passing through a Proxy.
+///
+/// Both methods may be called concurrently, requests from the client
+/// reader and responses from the server reader.
+pub trait Interceptor: Send + Sync {
+    /// Called with each client request before it is sent to the server.
+    fn request(&self, _ctx: &ProxyContext, _req: &TaggedFcall) -> Action {
+        Action::Forward
+    }
+
+    /// Called with each response before it is sent to the client, along with
+    /// the original client request. Responses generated by the proxy itself
+    /// pass through here too.
+    fn response(&self, _ctx: &ProxyContext, _req: &Fcall, _resp: &TaggedFcall) -> Action {
+        Action::Forward
+    }
+}
+
+impl Interceptor for () {}
+
+/// Requests that are safe to send again when the server may or may not
+/// have seen them.
+pub fn is_idempotent(fcall: &Fcall) -> bool {
+    match fcall {
+        Fcall::Tlopen(tlopen) => !tlopen.flags.contains(fcall::LOpenFlags::O_TRUNC),
+        Fcall::Tstatfs(_)
+        | Fcall::Treadlink(_)
+        | Fcall::Tgetattr(_)
+        | Fcall::Txattrwalk(_)
+        | Fcall::Treaddir(_)
+        | Fcall::Tgetlock(_)
+        | Fcall::Twalk(_)
+        | Fcall::Tread(_) => true,
+        _ => false,
+    }
+}
+
+/// Controls holding requests while the server is unavailable, see
+/// Proxy::set_retry.
+#[derive(Clone, Debug)]
+pub struct RetryOptions {
+    /// The most requests to hold at once, further requests fail immediately.
+    pub max_held: usize,
+    /// How long a request may wait for a server before failing with ETIMEDOUT.
+    pub max_hold: Duration,
+    /// Which requests may be held and sent again.
+    pub retryable: fn(&Fcall) -> bool,
+}
+
+impl Default for RetryOptions {
+    fn default() -> RetryOptions {
+        RetryOptions {
+            max_held: 256,
+            max_hold: Duration::from_secs(30),
+            retryable: is_idempotent,
+        }
+    }
+}
+
+impl<I: Interceptor + ?Sized> Interceptor for std::sync::Arc<I> {
+    fn request(&self, ctx: &ProxyContext, req: &TaggedFcall) -> Action {
+        (**self).request(ctx, req)
+    }
+
+    fn response(&self, ctx: &ProxyContext, req: &Fcall, resp: &TaggedFcall) -> Action {
+        (**self).response(ctx, req, resp)
+    }
+}
+
+// Chains two interceptors, the first action other than Forward wins. The
+// second never sees requests the first answers, but sees every response.
+impl<A: Interceptor, B: Interceptor> Interceptor for (A, B) {
+    fn request(&self, ctx: &ProxyContext, req: &TaggedFcall) -> Action {
+        match self.0.request(ctx, req) {
+            Action::Forward => self.1.request(ctx, req),
+            action => action,
+        }
+    }
+
+    fn response(&self, ctx: &ProxyContext, req: &Fcall, resp: &TaggedFcall) -> Action {
+        match (
+            self.0.response(ctx, req, resp),
+            self.1.response(ctx, req, resp),
+        ) {
+            (Action::Forward, action) => action,
+            (action, _) => action,
+        }
+    }
+}
+
+struct WriteState {
+    buf: Vec<u8>,
+    conn: Box<dyn WriteTransport>,
+}
+
+struct ServerConn {
+    // Taken by the server reader while the proxy is running.
+    r: Option<Box<dyn ReadTransport>>,
+    w: WriteState,
+}
+
+struct Inflight {
+    // The request as the client sent it.
+    req: Fcall<'static>,
+    // What was sent to the server instead, if an interceptor rewrote it.
+    sent: Option<Fcall<'static>>,
+    since: Instant,
+}
+
+enum Failure {
+    Client(std::io::Error),
+    Server,
+}
+
+/// Proxy state shared with interceptors.
+pub struct ProxyContext {
+    rversion: Option<fcall::Rversion<'static>>,
+    client: Mutex<WriteState>,
+    server: Mutex<Option<ServerConn>>,
+    // Client requests waiting for a server response.
+    inflight: Mutex<HashMap<u16, Inflight>>,
+    // Requests sent by ProxyContext::call.
+    injected: Mutex<HashMap<u16, crossbeam_channel::Sender<Fcall<'static>>>>,
+    response_thread: Mutex<Option<ThreadId>>,
+    relay_stopped: AtomicBool,
+    metrics: Option<Arc<Metrics>>,
+}
+
+fn protocol_error(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+fn not_connected() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::NotConnected,
+        "proxy is not connected to a server",
+    )
+}
+
+// The error response for a request, Tflush must always be answered with Rflush.
+fn error_response(req: &Fcall, ecode: u32) -> Fcall<'static> {
+    match req {
+        Fcall::Tflush(_) => Fcall::Rflush(fcall::Rflush {}),
+        _ => Fcall::Rlerror(fcall::Rlerror { ecode }),
+    }
+}
+
+impl ProxyContext {
+    /// The msize negotiated with the client, zero before the first connect.
+    pub fn msize(&self) -> u32 {
+        self.rversion.as_ref().map(|v| v.msize).unwrap_or(0)
+    }
+
+    /// Tags of the client requests currently waiting for the server.
+    pub fn inflight_tags(&self) -> Vec<u16> {
+        self.inflight.lock().unwrap().keys().copied().collect()
+    }
+
+    /// Send an extra request to the server and wait for its response, the
+    /// response is not seen by the client or the interceptor.
+    ///
+    /// While the proxy is running this may only be called from
+    /// Interceptor::request, otherwise the tag it picks may collide with a
+    /// new client request.
+    pub fn call(&self, fcall: Fcall) -> Result<Fcall<'static>, std::io::Error> {
+        if *self.response_thread.lock().unwrap() == Some(std::thread::current().id()) {
+            return Err(std::io::Error::other(
+                "proxy call made while handling a response",
+            ));
+        }
+
+        let mut guard = self.server.lock().unwrap();
+        let server = guard.as_mut().ok_or_else(not_connected)?;
+        let tag = self.free_tag()?;
+
+        if let Some(r) = server.r.as_mut() {
+            // Not running, nothing else is reading from the server.
+            transport::write(
+                &mut server.w.conn,
+                &mut server.w.buf,
+                &TaggedFcall { tag, fcall },
+            )?;
+            let mut rbuf = Vec::with_capacity(self.msize() as usize);
+            return match transport::read(r, &mut rbuf)? {
+                resp if resp.tag == tag => Ok(resp.fcall.clone_static()),
+                _ => Err(protocol_error("9p protocol error, unexpected tag")),
+            };
+        }
+
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        self.injected.lock().unwrap().insert(tag, tx);
+        // The server reader drops all senders when it stops.
+        if self.relay_stopped.load(Ordering::SeqCst) {
+            self.injected.lock().unwrap().remove(&tag);
+            return Err(not_connected());
+        }
+        if let Err(err) = transport::write(
+            &mut server.w.conn,
+            &mut server.w.buf,
+            &TaggedFcall { tag, fcall },
+        ) {
+            self.injected.lock().unwrap().remove(&tag);
+            return Err(err);
+        }
+        drop(guard);
+
+        rx.recv().map_err(|_| not_connected())
+    }
+
+    fn free_tag(&self) -> Result<u16, std::io::Error> {
+        let inflight = self.inflight.lock().unwrap();
+        let injected = self.injected.lock().unwrap();
+        (0..fcall::NOTAG)
+            .rev()
+            .find(|tag| !inflight.contains_key(tag) && !injected.contains_key(tag))
+            .ok_or_else(|| std::io::Error::other("no free 9p tags"))
+    }
+
+    fn respond<I: Interceptor>(
+        &self,
+        interceptor: &I,
+        req: &Fcall,
+        resp: &TaggedFcall,
+        raw: Option<&[u8]>,
+    ) -> Result<(), std::io::Error> {
+        let replacement = match interceptor.response(self, req, resp) {
+            Action::Forward => None,
+            Action::Rewrite(fcall) | Action::Reply(fcall) => Some(fcall),
+            Action::Reject(ecode) => Some(error_response(req, ecode)),
+        };
+        let replacement = replacement.map(|fcall| TaggedFcall {
+            tag: resp.tag,
+            fcall,
+        });
+        let mut client = self.client.lock().unwrap();
+        let client = &mut *client;
+        let (sent, bytes) = match (&replacement, raw) {
+            (None, Some(raw)) => {
+                client.conn.write_all(raw)?;
+                (resp, raw.len())
+            }
+            (replacement, _) => {
+                let sent = replacement.as_ref().unwrap_or(resp);
+                transport::write(&mut client.conn, &mut client.buf, sent)?;
+                (sent, frame_len(&client.buf))
+            }
+        };
+        if let Some(metrics) = &self.metrics {
+            metrics.proxy_response(req, sent.tag, &sent.fcall, bytes);
+        }
+        Ok(())
+    }
+
+    fn reply<I: Interceptor>(
+        &self,
+        interceptor: &I,
+        req: &TaggedFcall,
+        fcall: Fcall,
+    ) -> Result<(), std::io::Error> {
+        let resp = TaggedFcall {
+            tag: req.tag,
+            fcall,
+        };
+        self.respond(interceptor, &req.fcall, &resp, None)
+    }
+
+    fn forward(&self, tag: u16, entry: Inflight, raw: Option<&[u8]>) -> Result<(), Failure> {
+        let encoded;
+        let buf = match (&entry.sent, raw) {
+            (None, Some(raw)) => raw,
+            (sent, _) => {
+                let mut buf = Vec::new();
+                TaggedFcall {
+                    tag,
+                    fcall: sent.as_ref().unwrap_or(&entry.req).clone(),
+                }
+                .encode_to_buf(&mut buf)
+                .map_err(Failure::Client)?;
+                encoded = buf;
+                &encoded[..]
+            }
+        };
+        // Track the request first, the response can arrive before write returns.
+        self.inflight.lock().unwrap().insert(tag, entry);
+        let mut server = self.server.lock().unwrap();
+        let server = server.as_mut().ok_or(Failure::Server)?;
+        server.w.conn.write_all(buf).map_err(|_| Failure::Server)
+    }
+
+    fn handle_request<I: Interceptor>(&self, interceptor: &I, buf: &[u8]) -> Result<(), Failure> {
+        let req = TaggedFcall::decode(buf).map_err(Failure::Client)?;
+        if let Some(metrics) = &self.metrics {
+            metrics.proxy_request(req.tag, &req.fcall, buf.len());
+        }
+        let entry = |sent| Inflight {
+            req: req.fcall.clone_static(),
+            sent,
+            since: Instant::now(),
+        };
+        match interceptor.request(self, &req) {
+            Action::Forward => self.forward(req.tag, entry(None), Some(buf)),
+            Action::Rewrite(fcall) => self.forward(req.tag, entry(Some(fcall)), None),
+            Action::Reply(fcall) => self
+                .reply(interceptor, &req, fcall)
+                .map_err(Failure::Client),
+            Action::Reject(ecode) => self
+                .reply(interceptor, &req, error_response(&req.fcall, ecode))
+                .map_err(Failure::Client),
+        }
+    }
+
+    // Relay server responses to the client until either side fails.
+    fn relay_responses<I: Interceptor>(
+        &self,
+        interceptor: &I,
+        r: &mut Box<dyn ReadTransport>,
+    ) -> Result<(), std::io::Error> {
+        let mut buf = Vec::with_capacity(self.msize() as usize);
+        loop {
+            if transport::read_to_buf(r, &mut buf).is_err() {
+                return Ok(());
+            }
+            let resp = match TaggedFcall::decode(&buf) {
+                Ok(resp) => resp,
+                Err(_) => return Ok(()),
+            };
+
+            if let Some(tx) = self.injected.lock().unwrap().remove(&resp.tag) {
+                let _ = tx.send(resp.fcall.clone_static());
+                continue;
+            }
+
+            let entry = self.inflight.lock().unwrap().remove(&resp.tag);
+            let result = match entry {
+                Some(Inflight { req, .. }) => {
+                    if let (Fcall::Tflush(tflush), Fcall::Rflush(_)) = (&req, &resp.fcall) {
+                        self.inflight.lock().unwrap().remove(&tflush.oldtag);
+                    }
+                    self.respond(interceptor, &req, &resp, Some(&buf))
+                }
+                // Not something we know about, pass it on and let the client decide.
+                None => self.client.lock().unwrap().conn.write_all(&buf),
+            };
+            if let Err(err) = result {
+                // Wake the client reader.
+                let _ = self.client.lock().unwrap().conn.shutdown();
+                return Err(err);
+            }
+        }
+    }
+}
+
+/// A 9P proxy between one client connection and a server connection that may
+/// be replaced, every message passes through an Interceptor.
+///
+/// A typical proxy calls accept_version and connect once, then alternates
+/// run with connect until run returns an error.
+pub struct Proxy<I: Interceptor> {
+    interceptor: I,
+    client_r: Box<dyn ReadTransport>,
+    rbuf: Vec<u8>,
+    tversion: Option<fcall::Tversion<'static>>,
+    retry: Option<RetryOptions>,
+    // Requests waiting for a server to come back, oldest first.
+    held: Vec<(u16, Inflight)>,
+    ctx: ProxyContext,
+}
+
+impl<I: Interceptor> Proxy<I> {
+    pub fn new<R: ReadTransport + 'static, W: WriteTransport + 'static>(
+        client_r: R,
+        client_w: W,
+        interceptor: I,
+    ) -> Proxy<I> {
+        Proxy {
+            interceptor,
+            client_r: Box::new(client_r),
+            rbuf: Vec::new(),
+            tversion: None,
+            retry: None,
+            held: Vec::new(),
+            ctx: ProxyContext {
+                rversion: None,
+                client: Mutex::new(WriteState {
+                    buf: Vec::new(),
+                    conn: Box::new(client_w),
+                }),
+                server: Mutex::new(None),
+                inflight: Mutex::new(HashMap::new()),
+                injected: Mutex::new(HashMap::new()),
+                response_thread: Mutex::new(None),
+                relay_stopped: AtomicBool::new(true),
+                metrics: None,
+            },
+        }
+    }
+
+    /// Hold retryable requests while the server is unavailable instead of
+    /// failing them, they are sent again when run is next called.
+    pub fn set_retry(&mut self, retry: Option<RetryOptions>) {
+        self.retry = retry;
+    }
+
+    /// Record every client request and the response sent for it in metrics,
+    /// sized as they were on the wire.
+    pub fn set_metrics(&mut self, metrics: Option<Arc<Metrics>>) {
+        self.ctx.metrics = metrics;
+    }
+
+    pub fn interceptor(&self) -> &I {
+        &self.interceptor
+    }
+
+    pub fn context(&self) -> &ProxyContext {
+        &self.ctx
+    }
+
+    /// The version negotiated by the first connect.
+    pub fn rversion(&self) -> Option<&fcall::Rversion<'static>> {
+        self.ctx.rversion.as_ref()
+    }
+
+    /// Read the Tversion the client starts with, clients asking for anything
+    /// other than 9P2000.L are refused.
+    pub fn accept_version(&mut self) -> Result<fcall::Tversion<'static>, std::io::Error> {
+        let mut buf = Vec::with_capacity(8192);
+        let tversion = match transport::read(&mut self.client_r, &mut buf)? {
+            TaggedFcall {
+                tag: fcall::NOTAG,
+                fcall: Fcall::Tversion(tversion),
+            } => tversion.clone_static(),
+            _ => return Err(protocol_error("9p protocol error, expected Tversion")),
+        };
+
+        if tversion.version.as_bytes() != b"9P2000.L" {
+            let mut client = self.ctx.client.lock().unwrap();
+            transport::write(
+                &mut client.conn,
+                &mut buf,
+                &TaggedFcall {
+                    tag: fcall::NOTAG,
+                    fcall: Fcall::Rversion(fcall::Rversion {
+                        msize: tversion.msize,
+                        version: "unknown".into(),
+                    }),
+                },
+            )?;
+            return Err(protocol_error(
+                "9p protocol error, expected version 9P2000.L",
+            ));
+        }
+
+        self.tversion = Some(tversion.clone());
+        Ok(tversion)
+    }
+
+    /// Perform the version handshake with a server connection and start
+    /// proxying to it.
+    ///
+    /// The first connect relays the server's Rversion to the client. Later
+    /// connects must agree with that version, a server that has changed its
+    /// parameters is an error of kind InvalidData.
+    pub fn connect<R: ReadTransport + 'static, W: WriteTransport + 'static>(
+        &mut self,
+        mut r: R,
+        mut w: W,
+    ) -> Result<fcall::Rversion<'static>, std::io::Error> {
+        let tversion = match (&self.ctx.rversion, &self.tversion) {
+            (Some(rversion), _) => fcall::Tversion {
+                msize: rversion.msize,
+                version: rversion.version.clone(),
+            },
+            (None, Some(tversion)) => tversion.clone(),
+            (None, None) => {
+                return Err(std::io::Error::other(
+                    "client version has not been accepted",
+                ))
+            }
+        };
+
+        let mut buf = Vec::with_capacity(tversion.msize as usize);
+        transport::write(
+            &mut w,
+            &mut buf,
+            &TaggedFcall {
+                tag: fcall::NOTAG,
+                fcall: Fcall::Tversion(tversion),
+            },
+        )?;
+        let rversion = match transport::read(&mut r, &mut buf)? {
+            TaggedFcall {
+                tag: fcall::NOTAG,
+                fcall: Fcall::Rversion(rversion),
+            } => rversion.clone_static(),
+            _ => return Err(protocol_error("9p protocol error, expected Rversion")),
+        };
+
+        match &self.ctx.rversion {
+            Some(established) => {
+                if rversion.msize < established.msize
+                    || rversion.version.as_bytes() != established.version.as_bytes()
+                {
+                    return Err(protocol_error("server changed its version on reconnect"));
+                }
+            }
+            None => {
+                let mut client = self.ctx.client.lock().unwrap();
+                let client = &mut *client;
+                client.buf = Vec::with_capacity(rversion.msize as usize);
+                transport::write(
+                    &mut client.conn,
+                    &mut client.buf,
+                    &TaggedFcall {
+                        tag: fcall::NOTAG,
+                        fcall: Fcall::Rversion(rversion.clone()),
+                    },
+                )?;
+                self.rbuf = Vec::with_capacity(rversion.msize as usize);
+                self.ctx.rversion = Some(rversion.clone());
+            }
+        }
+
+        *self.ctx.server.lock().unwrap() = Some(ServerConn {
+            r: Some(Box::new(r)),
+            w: WriteState {
+                buf: Vec::with_capacity(self.ctx.msize() as usize),
+                conn: Box::new(w),
+            },
+        });
+        Ok(rversion)
+    }
+
+    /// Proxy messages until one side fails. Returns Ok when the server
+    /// connection was lost and an error when the client connection was.
+    ///
+    /// Requests still waiting for the lost server are left inflight, see
+    /// fail_inflight.
+    pub fn run(&mut self) -> Result<(), std::io::Error> {
+        self.expire_held()?;
+        let mut server_r = self
+            .ctx
+            .server
+            .lock()
+            .unwrap()
+            .as_mut()
+            .and_then(|server| server.r.take())
+            .ok_or_else(not_connected)?;
+
+        let Proxy {
+            interceptor,
+            client_r,
+            rbuf,
+            held,
+            ctx,
+            ..
+        } = self;
+        let interceptor = &*interceptor;
+        let ctx = &*ctx;
+        ctx.relay_stopped.store(false, Ordering::SeqCst);
+
+        let result = std::thread::scope(|s| {
+            let relay = s.spawn(move || {
+                *ctx.response_thread.lock().unwrap() = Some(std::thread::current().id());
+                let result = ctx.relay_responses(interceptor, &mut server_r);
+                ctx.relay_stopped.store(true, Ordering::SeqCst);
+                ctx.injected.lock().unwrap().clear();
+                result
+            });
+
+            let mut result = Ok(());
+            let mut held = held.drain(..);
+            for (tag, entry) in held.by_ref() {
+                match ctx.forward(tag, entry, None) {
+                    Ok(()) => (),
+                    Err(Failure::Client(err)) => {
+                        result = Err(err);
+                        break;
+                    }
+                    Err(Failure::Server) => break,
+                }
+            }
+            // Anything not resent is still waiting for a server.
+            ctx.inflight.lock().unwrap().extend(held);
+
+            while result.is_ok() && !ctx.relay_stopped.load(Ordering::SeqCst) {
+                result = match transport::read_to_buf_timeout(client_r, rbuf, POLL_INTERVAL) {
+                    Ok(()) => match ctx.handle_request(interceptor, rbuf) {
+                        Ok(()) => Ok(()),
+                        Err(Failure::Client(err)) => Err(err),
+                        Err(Failure::Server) => break,
+                    },
+                    Err(err) if err.kind() == std::io::ErrorKind::TimedOut => Ok(()),
+                    Err(err) => Err(err),
+                };
+            }
+
+            // Stop the server reader.
+            if let Some(server) = ctx.server.lock().unwrap().as_ref() {
+                let _ = server.w.conn.shutdown();
+            }
+            let relay_result = relay.join().unwrap();
+            result.and(relay_result)
+        });
+
+        *self.ctx.response_thread.lock().unwrap() = None;
+        *self.ctx.server.lock().unwrap() = None;
+        result
+    }
+
+    fn fail(&self, tag: u16, req: &Fcall, ecode: u32) -> Result<(), std::io::Error> {
+        let resp = TaggedFcall {
+            tag,
+            fcall: error_response(req, ecode),
+        };
+        self.ctx.respond(&self.interceptor, req, &resp, None)
+    }
+
+    // Hold a request for retrying, giving it back if it can't be held.
+    fn hold(&mut self, tag: u16, entry: Inflight) -> Option<Inflight> {
+        match &self.retry {
+            Some(retry)
+                if self.held.len() < retry.max_held
+                    && (retry.retryable)(&entry.req)
+                    && entry.since.elapsed() < retry.max_hold =>
+            {
+                self.held.push((tag, entry));
+                None
+            }
+            _ => Some(entry),
+        }
+    }
+
+    fn expire_held(&mut self) -> Result<(), std::io::Error> {
+        let max_hold = match &self.retry {
+            Some(retry) => retry.max_hold,
+            None => Duration::ZERO,
+        };
+        let (expired, held) = std::mem::take(&mut self.held)
+            .into_iter()
+            .partition(|(_, entry)| entry.since.elapsed() >= max_hold);
+        self.held = held;
+        for (tag, entry) in expired {
+            self.fail(tag, &entry.req, errno::ETIMEDOUT)?;
+        }
+        Ok(())
+    }
+
+    /// Answer every request still waiting for the server with an error, or
+    /// hold it for retrying if set_retry allows.
+    pub fn fail_inflight(&mut self, ecode: u32) -> Result<(), std::io::Error> {
+        let mut inflight: Vec<(u16, Inflight)> =
+            self.ctx.inflight.lock().unwrap().drain().collect();
+        inflight.sort_by_key(|(_, entry)| entry.since);
+        // The client no longer wants an answer to flushed requests, so
+        // they are failed rather than held.
+        let flushed: Vec<u16> = inflight
+            .iter()
+            .filter_map(|(_, entry)| match &entry.req {
+                Fcall::Tflush(tflush) => Some(tflush.oldtag),
+                _ => None,
+            })
+            .collect();
+        self.held.retain(|(tag, _)| !flushed.contains(tag));
+        for (tag, entry) in inflight {
+            let entry = if flushed.contains(&tag) {
+                Some(entry)
+            } else {
+                self.hold(tag, entry)
+            };
+            if let Some(entry) = entry {
+                self.fail(tag, &entry.req, ecode)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Answer client requests with an error until the deadline, for use while
+    /// there is no server. Requests still pass through the interceptor, which
+    /// may reply to them itself, and retryable requests are held if
+    /// set_retry allows.
+    pub fn reject_until(&mut self, deadline: Instant, ecode: u32) -> Result<(), std::io::Error> {
+        loop {
+            self.expire_held()?;
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(());
+            }
+            // Wake up in time to expire the oldest held request.
+            let wake = match (&self.retry, self.held.first()) {
+                (Some(retry), Some((_, entry))) => deadline.min(entry.since + retry.max_hold),
+                _ => deadline,
+            };
+            match transport::read_to_buf_timeout(
+                &mut self.client_r,
+                &mut self.rbuf,
+                wake.saturating_duration_since(now),
+            ) {
+                Ok(()) => (),
+                Err(err) if err.kind() == std::io::ErrorKind::TimedOut => continue,
+                Err(err) => return Err(err),
+            }
+            let req = TaggedFcall::decode(&self.rbuf)?.clone_static();
+            if let Some(metrics) = &self.ctx.metrics {
+                metrics.proxy_request(req.tag, &req.fcall, self.rbuf.len());
+            }
+            if let Fcall::Tflush(tflush) = &req.fcall {
+                // The client no longer wants an answer.
+                self.held.retain(|(tag, _)| *tag != tflush.oldtag);
+            }
+            let sent = match self.interceptor.request(&self.ctx, &req) {
+                Action::Reply(fcall) => {
+                    self.ctx.reply(&self.interceptor, &req, fcall)?;
+                    continue;
+                }
+                Action::Reject(ecode) => {
+                    self.fail(req.tag, &req.fcall, ecode)?;
+                    continue;
+                }
+                Action::Forward => None,
+                Action::Rewrite(fcall) => Some(fcall),
+            };
+            let entry = Inflight {
+                req: req.fcall,
+                sent,
+                since: now,
+            };
+            if let Some(entry) = self.hold(req.tag, entry) {
+                self.fail(req.tag, &entry.req, ecode)?;
+            }
+        }
+    }
+}
+
+/// Proxy a single connection until either side closes it.
+pub fn proxy_transport<I, CR, CW, SR, SW>(
+    client_r: CR,
+    client_w: CW,
+    server_r: SR,
+    server_w: SW,
+    interceptor: I,
+) -> Result<(), std::io::Error>
+where
+    I: Interceptor,
+    CR: ReadTransport + 'static,
+    CW: WriteTransport + 'static,
+    SR: ReadTransport + 'static,
+    SW: WriteTransport + 'static,
+{
+    let mut proxy = Proxy::new(client_r, client_w, interceptor);
+    proxy.accept_version()?;
+    proxy.connect(server_r, server_w)?;
+    let result = proxy.run();
+    let _ = proxy.fail_inflight(errno::EIO);
+    let _ = proxy.ctx.client.lock().unwrap().conn.shutdown();
+    result
+}
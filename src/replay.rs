@@ -0,0 +1,435 @@
+use super::fcall;
+use super::fcall::{Fcall, FcallType, Qid, TaggedFcall};
+use super::server::{serve_transport, Filesystem};
+use super::trace::TraceFrame;
+use super::transport;
+use std::collections::{BTreeMap, HashMap};
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// Controls which differences between recorded and replayed
+/// responses are reported.
+#[derive(Clone, Debug)]
+pub struct ReplayOptions {
+    /// Don't compare qids, useful when replaying against a
+    /// different filesystem than the one that was recorded.
+    pub ignore_qids: bool,
+    /// Don't compare timestamps in Rgetattr.
+    pub ignore_times: bool,
+    /// How long to wait for each response before reporting
+    /// [`DivergenceKind::NoResponse`], ten seconds by default.
+    pub timeout: Duration,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> ReplayOptions {
+        ReplayOptions {
+            ignore_qids: false,
+            ignore_times: false,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DivergenceKind {
+    /// The replayed response has a different message type.
+    Type { expected: FcallType, got: FcallType },
+    /// Both responses were errors, with different errno values.
+    Errno { expected: u32, got: u32 },
+    /// A qid in the response differs.
+    Qid { expected: Qid, got: Qid },
+    /// Rread data differs, offset is the first differing byte.
+    Data {
+        offset: usize,
+        expected_len: usize,
+        got_len: usize,
+    },
+    /// Some other field differs.
+    Other { expected: String, got: String },
+    /// The filesystem did not answer the request within
+    /// [`ReplayOptions::timeout`].
+    NoResponse,
+}
+
+impl std::fmt::Display for DivergenceKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        use super::errno::strerror;
+        match self {
+            DivergenceKind::Type { expected, got } => {
+                write!(f, "expected {} got {}", expected, got)
+            }
+            DivergenceKind::Errno { expected, got } => {
+                write!(f, "expected {} got {}", strerror(*expected), strerror(*got))
+            }
+            DivergenceKind::Qid { expected, got } => {
+                write!(f, "expected qid {} got {}", expected, got)
+            }
+            DivergenceKind::Data {
+                offset,
+                expected_len,
+                got_len,
+            } => write!(
+                f,
+                "data differs at offset {} (expected {} bytes got {})",
+                offset, expected_len, got_len
+            ),
+            DivergenceKind::Other { expected, got } => {
+                write!(f, "expected {} got {}", expected, got)
+            }
+            DivergenceKind::NoResponse => write!(f, "no response"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    pub conn_id: u32,
+    /// Position of the request within its connection.
+    pub index: usize,
+    /// The recorded request, formatted for display.
+    pub request: String,
+    pub kind: DivergenceKind,
+}
+
+impl std::fmt::Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "conn {} #{} {}: {}",
+            self.conn_id, self.index, self.request, self.kind
+        )
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ReplayReport {
+    /// Number of requests replayed.
+    pub requests: usize,
+    /// Number of replayed requests that had a recorded response to compare against.
+    pub compared: usize,
+    pub divergences: Vec<Divergence>,
+}
+
+impl ReplayReport {
+    pub fn is_clean(&self) -> bool {
+        self.divergences.is_empty()
+    }
+}
+
+impl std::fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(
+            f,
+            "replayed {} requests, compared {}, {} divergences",
+            self.requests,
+            self.compared,
+            self.divergences.len()
+        )?;
+        for d in self.divergences.iter() {
+            writeln!(f, "{}", d)?;
+        }
+        Ok(())
+    }
+}
+
+struct Exchange {
+    request: TaggedFcall<'static>,
+    response: Option<Fcall<'static>>,
+}
+
+struct Session {
+    msize: u32,
+    exchanges: Vec<Exchange>,
+}
+
+// Split a trace into per connection request streams, pairing each
+// request with its recorded response by tag.
+fn sessions<I: IntoIterator<Item = TraceFrame>>(
+    frames: I,
+) -> Result<BTreeMap<u32, Session>, std::io::Error> {
+    let mut sessions: BTreeMap<u32, Session> = BTreeMap::new();
+    let mut pending: HashMap<(u32, u16), usize> = HashMap::new();
+    let mut early: HashMap<(u32, u16), Fcall<'static>> = HashMap::new();
+
+    for frame in frames {
+        let fcall = frame.fcall()?.clone_static();
+        let session = sessions.entry(frame.conn_id).or_insert(Session {
+            msize: 8192,
+            exchanges: Vec::new(),
+        });
+        let typ = FcallType::from(&fcall.fcall);
+        if let Fcall::Tversion(ref tversion) = fcall.fcall {
+            session.msize = tversion.msize;
+            continue;
+        }
+        let key = (frame.conn_id, fcall.tag);
+        if typ.is_request() {
+            // The read side of a tee can record a response before the
+            // write side records its request.
+            let response = early.remove(&key);
+            if response.is_none() {
+                pending.insert(key, session.exchanges.len());
+            }
+            session.exchanges.push(Exchange {
+                request: fcall,
+                response,
+            });
+        } else if let Some(idx) = pending.remove(&key) {
+            session.exchanges[idx].response = Some(fcall.fcall);
+        } else {
+            early.insert(key, fcall.fcall);
+        }
+    }
+
+    Ok(sessions)
+}
+
+fn response_qids(fcall: &Fcall) -> Vec<Qid> {
+    match fcall {
+        Fcall::Rattach(v) => vec![v.qid],
+        Fcall::Rauth(v) => vec![v.aqid],
+        Fcall::Rlopen(v) => vec![v.qid],
+        Fcall::Rlcreate(v) => vec![v.qid],
+        Fcall::Rsymlink(v) => vec![v.qid],
+        Fcall::Rmknod(v) => vec![v.qid],
+        Fcall::Rmkdir(v) => vec![v.qid],
+        Fcall::Rgetattr(v) => vec![v.qid],
+        Fcall::Rwalk(v) => v.wqids.clone(),
+        Fcall::Rreaddir(v) => v.data.data.iter().map(|e| e.qid).collect(),
+        _ => Vec::new(),
+    }
+}
+
+// Blank out the fields the options ask us to ignore.
+fn normalize(fcall: &mut Fcall, options: &ReplayOptions) {
+    let zero_qid = Qid {
+        typ: fcall::QidType::FILE,
+        version: 0,
+        path: 0,
+    };
+    let zero_time = fcall::Time { sec: 0, nsec: 0 };
+    if options.ignore_qids {
+        match fcall {
+            Fcall::Rattach(v) => v.qid = zero_qid,
+            Fcall::Rauth(v) => v.aqid = zero_qid,
+            Fcall::Rlopen(v) => v.qid = zero_qid,
+            Fcall::Rlcreate(v) => v.qid = zero_qid,
+            Fcall::Rsymlink(v) => v.qid = zero_qid,
+            Fcall::Rmknod(v) => v.qid = zero_qid,
+            Fcall::Rmkdir(v) => v.qid = zero_qid,
+            Fcall::Rgetattr(v) => v.qid = zero_qid,
+            Fcall::Rwalk(v) => v.wqids.iter_mut().for_each(|q| *q = zero_qid),
+            Fcall::Rreaddir(v) => v.data.data.iter_mut().for_each(|e| e.qid = zero_qid),
+            _ => (),
+        }
+    }
+    if options.ignore_times {
+        if let Fcall::Rgetattr(v) = fcall {
+            v.stat.atime = zero_time;
+            v.stat.mtime = zero_time;
+            v.stat.ctime = zero_time;
+            v.stat.btime = zero_time;
+        }
+    }
+}
+
+fn compare(
+    expected: &Fcall<'static>,
+    got: &Fcall<'static>,
+    options: &ReplayOptions,
+) -> Option<DivergenceKind> {
+    let expected_type = FcallType::from(expected);
+    let got_type = FcallType::from(got);
+    if expected_type != got_type {
+        return Some(DivergenceKind::Type {
+            expected: expected_type,
+            got: got_type,
+        });
+    }
+
+    match (expected, got) {
+        (Fcall::Rlerror(e), Fcall::Rlerror(g)) if e.ecode != g.ecode => {
+            return Some(DivergenceKind::Errno {
+                expected: e.ecode,
+                got: g.ecode,
+            })
+        }
+        (Fcall::Rread(e), Fcall::Rread(g)) if e.data != g.data => {
+            let offset = e
+                .data
+                .iter()
+                .zip(g.data.iter())
+                .position(|(a, b)| a != b)
+                .unwrap_or_else(|| e.data.len().min(g.data.len()));
+            return Some(DivergenceKind::Data {
+                offset,
+                expected_len: e.data.len(),
+                got_len: g.data.len(),
+            });
+        }
+        _ => (),
+    }
+
+    if !options.ignore_qids {
+        let expected_qids = response_qids(expected);
+        let got_qids = response_qids(got);
+        if let Some((e, g)) = expected_qids
+            .iter()
+            .zip(got_qids.iter())
+            .find(|(e, g)| e != g)
+        {
+            return Some(DivergenceKind::Qid {
+                expected: *e,
+                got: *g,
+            });
+        }
+    }
+
+    let mut expected = expected.clone();
+    let mut got = got.clone();
+    normalize(&mut expected, options);
+    normalize(&mut got, options);
+    if expected != got {
+        return Some(DivergenceKind::Other {
+            expected: expected.to_string(),
+            got: got.to_string(),
+        });
+    }
+
+    None
+}
+
+fn replay_session(
+    conn_id: u32,
+    session: &Session,
+    mut conn: UnixStream,
+    options: &ReplayOptions,
+    report: &mut ReplayReport,
+) -> Result<(), std::io::Error> {
+    let msize = session.msize as usize;
+    let mut rbuf = Vec::with_capacity(msize);
+    let mut wbuf = Vec::with_capacity(msize);
+
+    transport::write(
+        &mut conn,
+        &mut wbuf,
+        &TaggedFcall {
+            tag: fcall::NOTAG,
+            fcall: Fcall::Tversion(fcall::Tversion {
+                msize: session.msize,
+                version: "9P2000.L".into(),
+            }),
+        },
+    )?;
+    transport::read_to_buf_timeout(&mut conn, &mut rbuf, options.timeout)?;
+    match TaggedFcall::decode(&rbuf)?.fcall {
+        Fcall::Rversion(_) => (),
+        _ => {
+            return Err(std::io::Error::other(
+                "replayed filesystem rejected Tversion",
+            ))
+        }
+    }
+
+    // Requests are replayed one at a time so the results do not
+    // depend on scheduling, which means a single tag is enough.
+    // Recorded Tflush messages can't be reproduced this way and are skipped.
+    const REPLAY_TAG: u16 = 0;
+
+    for (index, exchange) in session.exchanges.iter().enumerate() {
+        if let Fcall::Tflush(_) = exchange.request.fcall {
+            continue;
+        }
+        report.requests += 1;
+
+        transport::write(
+            &mut conn,
+            &mut wbuf,
+            &TaggedFcall {
+                tag: REPLAY_TAG,
+                fcall: exchange.request.fcall.clone(),
+            },
+        )?;
+
+        // A timeout, a closed connection or a stray tag all mean the
+        // request went unanswered.
+        let got = transport::read_to_buf_timeout(&mut conn, &mut rbuf, options.timeout)
+            .and_then(|_| TaggedFcall::decode(&rbuf));
+        let got = match got {
+            Ok(resp) if resp.tag == REPLAY_TAG => Some(resp.fcall.clone_static()),
+            _ => None,
+        };
+
+        let kind = match (&exchange.response, &got) {
+            (Some(expected), Some(got)) => {
+                report.compared += 1;
+                compare(expected, got, options)
+            }
+            (_, None) => Some(DivergenceKind::NoResponse),
+            (None, Some(_)) => None,
+        };
+
+        if let Some(kind) = kind {
+            report.divergences.push(Divergence {
+                conn_id,
+                index,
+                request: exchange.request.to_string(),
+                kind,
+            });
+        }
+
+        if got.is_none() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Replay the client requests of a recorded trace against a filesystem,
+/// reporting every response that differs from the recording.
+///
+/// Each recorded connection is replayed over its own fresh connection, so
+/// fids are used exactly as recorded. Requests are sent one at a time with
+/// a single tag, so a connection that had several requests in flight at
+/// once is replayed serially and any response that depended on the
+/// overlap, such as a lock released by a concurrent request, may differ.
+///
+/// The filesystem is served on its own thread. If it is still busy
+/// [`ReplayOptions::timeout`] after a connection is closed, that thread is
+/// abandoned and the remaining connections are not replayed.
+pub fn replay<F, I>(
+    frames: I,
+    fs: F,
+    options: &ReplayOptions,
+) -> Result<ReplayReport, std::io::Error>
+where
+    F: Filesystem + Send + 'static,
+    I: IntoIterator<Item = TraceFrame>,
+{
+    let mut report = ReplayReport::default();
+    let mut fs = Some(fs);
+    for (conn_id, session) in sessions(frames)?.iter() {
+        let Some(mut served) = fs.take() else {
+            break;
+        };
+        let (conn, server_conn) = UnixStream::pair()?;
+        let server_wconn = server_conn.try_clone()?;
+        let msize = session.msize as usize;
+        let (done_tx, done_rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            serve_transport(server_conn, server_wconn, &mut served, msize);
+            let _ = done_tx.send(served);
+        });
+
+        let result = replay_session(*conn_id, session, conn.try_clone()?, options, &mut report);
+        let _ = conn.shutdown(std::net::Shutdown::Both);
+        result?;
+
+        // A handler that never returns keeps the filesystem; give up on it
+        // rather than joining.
+        fs = done_rx.recv_timeout(options.timeout).ok();
+    }
+    Ok(report)
+}
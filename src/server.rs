@@ -1,40 +1,321 @@
 use super::errno;
 use super::fcall;
 use super::fcall::*;
+use super::hook;
+use super::hook::{FcallHook, HookPoint};
+use super::metrics::{Metrics, Started};
 use super::transport;
+use super::transport::{ReadTransport, WriteTransport};
+use std::borrow::Cow;
 use std::boxed::Box;
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
 use std::ops::DerefMut;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::Instant;
 
 struct WriteState {
     buf: Vec<u8>,
-    conn: std::net::TcpStream,
+    conn: Box<dyn WriteTransport>,
 }
 
+// The size of an Rread before its data.
+const RREAD_HDRSZ: usize = 4 + 1 + 2 + 4;
+// How many free buffers a connection keeps.
+const POOLED_BUFS: usize = 16;
+
+// Buffers of a connection's msize, reused for Rread data.
+struct BufPool {
+    msize: usize,
+    free: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BufPool {
+    fn new(msize: usize) -> Arc<BufPool> {
+        Arc::new(BufPool {
+            msize,
+            free: Mutex::new(Vec::new()),
+        })
+    }
+}
+
+// A buffer that goes back to its pool when dropped.
+struct Lease {
+    buf: Vec<u8>,
+    pool: Option<Arc<BufPool>>,
+}
+
+impl Lease {
+    fn new(pool: Option<&Arc<BufPool>>) -> Lease {
+        let buf = pool.and_then(|pool| pool.free.lock().unwrap().pop());
+        Lease {
+            buf: buf.unwrap_or_else(|| Vec::with_capacity(pool.map_or(0, |pool| pool.msize))),
+            pool: pool.cloned(),
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if let Some(pool) = &self.pool {
+            let mut free = pool.free.lock().unwrap();
+            if free.len() < POOLED_BUFS {
+                free.push(std::mem::take(&mut self.buf));
+            }
+        }
+    }
+}
+
+/// Space for the data of an Rread, leased from the connection so a server
+/// can read straight into it and send it without a copy. The buffer goes
+/// back to the connection once it has been written.
+///
+/// See FcallResponse::read_buf and FcallResponse::send_read.
+pub struct ReadBuf {
+    lease: Lease,
+    len: usize,
+}
+
+impl ReadBuf {
+    /// Room for as much data as the Rread may hold.
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.lease.buf[RREAD_HDRSZ..]
+    }
+
+    /// Send only the first len bytes of the data, all of it by default.
+    pub fn set_len(&mut self, len: usize) {
+        self.len = len.min(self.lease.buf.len() - RREAD_HDRSZ);
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.lease.buf[RREAD_HDRSZ..RREAD_HDRSZ + self.len]
+    }
+
+    /// Fill the buffer with pread from file at offset, setting the length
+    /// to what was read.
+    #[cfg(unix)]
+    pub fn read_at<F>(&mut self, file: &F, offset: u64) -> std::io::Result<usize>
+    where
+        F: std::os::unix::fs::FileExt + ?Sized,
+    {
+        let n = file.read_at(self.data_mut(), offset)?;
+        self.set_len(n);
+        Ok(n)
+    }
+}
+
+// What a connection's writer thread is sent.
+enum Outgoing {
+    Message(Lease),
+    Shutdown,
+}
+
+// The responses of a connection served by a ShardedServer.
+struct ResponseQueue {
+    tx: crossbeam_channel::Sender<Outgoing>,
+    // Holds a request's place in the connection's in-flight limit until
+    // the response is queued and every copy of it dropped.
+    _slot: Arc<Slot>,
+}
+
+impl ResponseQueue {
+    // Queue an encoded response, copies times.
+    fn send(&self, lease: Lease, copies: usize) {
+        for _ in 1..copies {
+            let copy = Lease {
+                buf: lease.buf.clone(),
+                pool: None,
+            };
+            let _ = self.tx.send(Outgoing::Message(copy));
+        }
+        if copies > 0 {
+            let _ = self.tx.send(Outgoing::Message(lease));
+        }
+    }
+}
+
+// Where a response goes.
+#[derive(Clone)]
+enum Sink {
+    Conn(Arc<Mutex<WriteState>>),
+    Queue(Arc<ResponseQueue>),
+    Capture(crossbeam_channel::Sender<Fcall<'static>>),
+}
+
+// The metrics recording a request.
+type Metered = (Arc<Metrics>, Started);
+// The hook, when the request was read and its fid.
+type Hooked = (Arc<dyn FcallHook>, Instant, Option<u32>);
+
 #[derive(Clone)]
 pub struct FcallResponse {
     pub tag: u16,
-    wstate: Arc<Mutex<WriteState>>,
+    sink: Sink,
+    metrics: Option<Metered>,
+    hook: Option<Hooked>,
+    // How many times to write the response, changed by fault injection.
+    copies: usize,
+    // The connection's buffers for Rread, none for captured responses.
+    pool: Option<Arc<BufPool>>,
 }
 
 impl<'a> FcallResponse {
     fn _send(&mut self, resp: Fcall<'_>) {
-        let mut wstate = self.wstate.lock().unwrap();
-        let wstate = wstate.deref_mut();
-        let _ = transport::write(
-            &mut wstate.conn,
-            &mut wstate.buf,
-            &fcall::TaggedFcall {
-                tag: self.tag,
-                fcall: resp,
-            },
-        );
+        let resp = fcall::TaggedFcall {
+            tag: self.tag,
+            fcall: resp,
+        };
+        let bytes = match &self.sink {
+            Sink::Conn(wstate) => {
+                let mut wstate = wstate.lock().unwrap();
+                let wstate = wstate.deref_mut();
+                wstate.buf.truncate(0);
+                for _ in 0..self.copies {
+                    let _ = transport::write(&mut wstate.conn, &mut wstate.buf, &resp);
+                }
+                wstate
+                    .buf
+                    .get(..4)
+                    .map(|sz| u32::from_le_bytes(sz.try_into().unwrap()) as usize)
+                    .unwrap_or(0)
+            }
+            Sink::Queue(queue) => {
+                // Encoded on the handler's thread, the writer only writes.
+                let mut lease = Lease::new(None);
+                let msize = self.pool.as_ref().map_or(usize::MAX, |pool| pool.msize);
+                if resp.encode_to_buf(&mut lease.buf).is_err() || lease.buf.len() > msize {
+                    // The tag still needs an answer.
+                    let eio = fcall::TaggedFcall {
+                        tag: self.tag,
+                        fcall: Rlerror { ecode: errno::EIO }.into(),
+                    };
+                    let _ = eio.encode_to_buf(&mut lease.buf);
+                    let bytes = lease.buf.len();
+                    queue.send(lease, self.copies);
+                    self.finish(&eio.fcall, bytes);
+                    return;
+                }
+                let bytes = lease.buf.len();
+                queue.send(lease, self.copies);
+                bytes
+            }
+            Sink::Capture(tx) => {
+                if self.copies > 0 {
+                    let _ = tx.send(resp.fcall.clone_static());
+                }
+                self.tag = fcall::NOTAG;
+                return;
+            }
+        };
+        self.finish(&resp.fcall, bytes);
+    }
+
+    // Record a sent response.
+    fn finish(&mut self, resp: &Fcall, bytes: usize) {
+        if let Some((metrics, started)) = self.metrics.take() {
+            metrics.finish(started, resp, bytes);
+        }
+        if let Some((hook, since, fid)) = self.hook.take() {
+            let elapsed = Some(since.elapsed());
+            hook::emit(
+                &*hook,
+                HookPoint::ServerResponse,
+                self.tag,
+                fid,
+                resp,
+                elapsed,
+            );
+        }
         self.tag = fcall::NOTAG;
     }
 
+    /// A buffer for the data answering a Tread of count bytes, holding no
+    /// more than fits in the connection's msize.
+    pub fn read_buf(&self, count: u32) -> ReadBuf {
+        let mut lease = Lease::new(self.pool.as_ref());
+        let max = self.pool.as_ref().map_or(usize::MAX, |pool| pool.msize);
+        let size = (RREAD_HDRSZ + count as usize).min(max.max(RREAD_HDRSZ));
+        // Pooled buffers keep their contents, so only growing them costs.
+        lease.buf.resize(size, 0);
+        ReadBuf {
+            lease,
+            len: size - RREAD_HDRSZ,
+        }
+    }
+
+    /// Send an Rread of the data in buf, writing the buffer as it is.
+    pub fn send_read(mut self, buf: ReadBuf) {
+        let ReadBuf { mut lease, len } = buf;
+        let size = RREAD_HDRSZ + len;
+        lease.buf.truncate(size);
+        lease.buf[..4].copy_from_slice(&(size as u32).to_le_bytes());
+        lease.buf[4] = FcallType::Rread as u8;
+        lease.buf[5..7].copy_from_slice(&self.tag.to_le_bytes());
+        lease.buf[7..11].copy_from_slice(&(len as u32).to_le_bytes());
+        let queued = match &self.sink {
+            Sink::Conn(wstate) => {
+                let mut wstate = wstate.lock().unwrap();
+                for _ in 0..self.copies {
+                    let _ = wstate.conn.write_all(&lease.buf);
+                }
+                None
+            }
+            Sink::Queue(queue) => Some(queue.clone()),
+            Sink::Capture(tx) => {
+                if self.copies > 0 {
+                    let data = Cow::from(lease.buf[RREAD_HDRSZ..].to_vec());
+                    let _ = tx.send(Fcall::Rread(Rread { data }));
+                }
+                self.tag = fcall::NOTAG;
+                return;
+            }
+        };
+        let data = Cow::from(&lease.buf[RREAD_HDRSZ..]);
+        self.finish(&Fcall::Rread(Rread { data }), size);
+        if let Some(queue) = queued {
+            // The writer thread drops the lease, returning the buffer.
+            queue.send(lease, self.copies);
+        }
+    }
+
     pub fn send<R: Into<Fcall<'a>>>(mut self, r: R) {
         self._send(r.into())
     }
+
+    /// A response that is kept instead of sent, for filesystems passing
+    /// requests on to other filesystems and looking at the answers.
+    pub fn capture(tag: u16) -> (FcallResponse, CapturedResponse) {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let resp = FcallResponse {
+            tag,
+            sink: Sink::Capture(tx),
+            metrics: None,
+            hook: None,
+            copies: 1,
+            pool: None,
+        };
+        (resp, CapturedResponse { rx })
+    }
+
+    // Write the response this many times when it is sent, 0 to lose it.
+    pub(crate) fn set_copies(&mut self, copies: usize) {
+        self.copies = copies;
+    }
+
+    // Never answer the request, and shut the connection.
+    pub(crate) fn kill(mut self) {
+        match &self.sink {
+            Sink::Conn(wstate) => {
+                let _ = wstate.lock().unwrap().conn.shutdown();
+            }
+            Sink::Queue(queue) => {
+                let _ = queue.tx.send(Outgoing::Shutdown);
+            }
+            Sink::Capture(_) => (),
+        }
+        self.tag = fcall::NOTAG;
+    }
 }
 
 impl Drop for FcallResponse {
@@ -45,6 +326,85 @@ impl Drop for FcallResponse {
     }
 }
 
+/// The answer to a request made with a captured FcallResponse.
+pub struct CapturedResponse {
+    rx: crossbeam_channel::Receiver<Fcall<'static>>,
+}
+
+impl CapturedResponse {
+    /// Wait for the response, which may be sent from another thread.
+    pub fn wait(self) -> Fcall<'static> {
+        self.rx
+            .recv()
+            .unwrap_or(Fcall::Rlerror(Rlerror { ecode: errno::EIO }))
+    }
+}
+
+macro_rules! dispatch_request {
+    ($fs:expr, $fcall:expr, $resp:expr) => {
+        match $fcall {
+            Fcall::Tstatfs(req) => $fs.statfs(req, $resp),
+            Fcall::Tlopen(req) => $fs.lopen(req, $resp),
+            Fcall::Tlcreate(req) => $fs.lcreate(req, $resp),
+            Fcall::Tsymlink(req) => $fs.symlink(req, $resp),
+            Fcall::Tmknod(req) => $fs.mknod(req, $resp),
+            Fcall::Treadlink(req) => $fs.readlink(req, $resp),
+            Fcall::Tgetattr(req) => $fs.getattr(req, $resp),
+            Fcall::Tsetattr(req) => $fs.setattr(req, $resp),
+            Fcall::Treaddir(req) => $fs.readdir(req, $resp),
+            Fcall::Tfsync(req) => $fs.fsync(req, $resp),
+            Fcall::Tmkdir(req) => $fs.mkdir(req, $resp),
+            Fcall::Tflush(req) => $fs.flush(req, $resp),
+            Fcall::Tread(req) => $fs.read(req, $resp),
+            Fcall::Twrite(req) => $fs.write(req, $resp),
+            Fcall::Tclunk(req) => $fs.clunk(req, $resp),
+            Fcall::Tremove(req) => $fs.remove(req, $resp),
+            Fcall::Trename(req) => $fs.rename(req, $resp),
+            Fcall::Tlink(req) => $fs.link(req, $resp),
+            Fcall::Trenameat(req) => $fs.renameat(req, $resp),
+            Fcall::Tunlinkat(req) => $fs.unlinkat(req, $resp),
+            Fcall::Tlock(req) => $fs.lock(req, $resp),
+            Fcall::Tgetlock(req) => $fs.getlock(req, $resp),
+            Fcall::Tauth(req) => $fs.auth(req, $resp),
+            Fcall::Tattach(req) => $fs.attach(req, $resp),
+            Fcall::Twalk(req) => $fs.walk(req, $resp),
+            Fcall::Txattrwalk(req) => $fs.xattrwalk(req, $resp),
+            Fcall::Txattrcreate(req) => $fs.xattrcreate(req, $resp),
+            _ => return Some($resp),
+        }
+    };
+}
+
+/// Pass a request to the method of fs handling it, giving back resp if
+/// fcall is not a request a Filesystem handles.
+pub fn dispatch<F>(fs: &mut F, fcall: &Fcall, resp: FcallResponse) -> Option<FcallResponse>
+where
+    F: Filesystem + ?Sized,
+{
+    dispatch_request!(fs, fcall, resp);
+    None
+}
+
+/// Like dispatch, for a ThreadedFilesystem.
+pub fn dispatch_threaded<F>(fs: &F, fcall: &Fcall, resp: FcallResponse) -> Option<FcallResponse>
+where
+    F: ThreadedFilesystem + ?Sized,
+{
+    dispatch_request!(fs, fcall, resp);
+    None
+}
+
+/// Make a request of fs and wait for the response.
+pub fn call_filesystem<F>(fs: &mut F, fcall: &Fcall) -> Fcall<'static>
+where
+    F: Filesystem + ?Sized,
+{
+    let (resp, captured) = FcallResponse::capture(0);
+    // Anything else is answered with EIO as resp drops.
+    let _ = dispatch(fs, fcall, resp);
+    captured.wait()
+}
+
 pub trait Filesystem {
     fn statfs(&mut self, _req: &Tstatfs, resp: FcallResponse) {
         resp.send(Rlerror {
@@ -585,27 +945,413 @@ impl<Fs: 'static + ThreadedFilesystem + Send + Sync> Filesystem for ThreadPoolSe
     }
 }
 
-pub fn serve<F>(mut conn: std::net::TcpStream, fs: &mut F, mut bufsize: usize)
+// Holds a request's place among those of its connection being handled,
+// once the gate has started it. Requests still kept back hold no place.
+struct Slot {
+    gate: Arc<Gate>,
+    started: AtomicBool,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if *self.started.get_mut() {
+            self.gate.release()
+        }
+    }
+}
+
+type Job = Box<dyn Send + FnOnce()>;
+
+// Hands a connection's requests to the workers, at most limit at once and
+// the rest kept back in the order they were read.
+struct Gate {
+    conn: u64,
+    limit: usize,
+    scheduler: Weak<Scheduler>,
+    state: Mutex<GateState>,
+}
+
+#[derive(Default)]
+struct GateState {
+    running: usize,
+    backlog: VecDeque<(Job, Arc<Slot>)>,
+}
+
+impl Gate {
+    // Hand over a job, or keep it back until a running one finishes.
+    // Flushes are never kept back, as they may be what ends a request.
+    fn submit(&self, job: Job, slot: Arc<Slot>, flush: bool) {
+        let mut state = self.state.lock().unwrap();
+        if flush || state.running < self.limit {
+            state.running += 1;
+            slot.started.store(true, Ordering::SeqCst);
+            drop(state);
+            self.push(job);
+        } else {
+            state.backlog.push_back((job, slot));
+        }
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.running -= 1;
+        let mut jobs = Vec::new();
+        while state.running < self.limit {
+            match state.backlog.pop_front() {
+                Some((job, slot)) => {
+                    slot.started.store(true, Ordering::SeqCst);
+                    jobs.push(job);
+                }
+                None => break,
+            }
+            state.running += 1;
+        }
+        drop(state);
+        for job in jobs {
+            self.push(job);
+        }
+    }
+
+    fn push(&self, job: Job) {
+        match self.scheduler.upgrade() {
+            Some(scheduler) => scheduler.push(self.conn, job),
+            // The server is gone, dropping the job answers it with EIO and
+            // lets go of the rest of the backlog.
+            None => {
+                let backlog = std::mem::take(&mut self.state.lock().unwrap().backlog);
+                drop(job);
+                drop(backlog);
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Shards {
+    // The waiting requests of each connection that has any.
+    queues: HashMap<u64, VecDeque<Job>>,
+    // Connections with waiting requests, in the order they are served.
+    ready: VecDeque<u64>,
+    shutdown: bool,
+}
+
+#[derive(Default)]
+struct Scheduler {
+    shards: Mutex<Shards>,
+    work: Condvar,
+    next_conn: AtomicU64,
+}
+
+impl Scheduler {
+    fn push(&self, conn: u64, job: Job) {
+        let mut shards = self.shards.lock().unwrap();
+        let shards = shards.deref_mut();
+        let queue = shards.queues.entry(conn).or_default();
+        queue.push_back(job);
+        if queue.len() == 1 {
+            shards.ready.push_back(conn);
+        }
+        self.work.notify_one();
+    }
+
+    // The next request to handle, taking one from each connection in turn.
+    fn pop(&self) -> Option<Job> {
+        let mut shards = self.shards.lock().unwrap();
+        loop {
+            let state = shards.deref_mut();
+            if let Some(conn) = state.ready.pop_front() {
+                let queue = state.queues.get_mut(&conn).unwrap();
+                let job = queue.pop_front().unwrap();
+                if queue.is_empty() {
+                    state.queues.remove(&conn);
+                } else {
+                    state.ready.push_back(conn);
+                }
+                return Some(job);
+            }
+            if state.shutdown {
+                return None;
+            }
+            shards = self.work.wait(shards).unwrap();
+        }
+    }
+}
+
+// Write a connection's responses as they are queued, until every sender
+// is gone, a write fails or a shutdown is asked for.
+fn write_responses(conn: Box<dyn WriteTransport>, rx: crossbeam_channel::Receiver<Outgoing>) {
+    let mut conn = std::io::BufWriter::new(conn);
+    for outgoing in rx.iter() {
+        let written = match outgoing {
+            Outgoing::Message(lease) => conn.write_all(&lease.buf).and_then(|_| {
+                // Responses completing together go out in one write.
+                if rx.is_empty() {
+                    conn.flush()
+                } else {
+                    Ok(())
+                }
+            }),
+            Outgoing::Shutdown => Err(std::io::ErrorKind::ConnectionAborted.into()),
+        };
+        if written.is_err() {
+            let _ = conn.get_ref().shutdown();
+            return;
+        }
+    }
+    let _ = conn.flush();
+}
+
+/// Serves a ThreadedFilesystem to many connections from one pool of
+/// worker threads. Each connection has its own queue of requests and the
+/// workers take one from each in turn, so a busy connection cannot starve
+/// a quiet one. Responses are written by a thread per connection as they
+/// complete, in any order.
+///
+/// At most max_in_flight requests of a connection are handled at once.
+/// Past that requests are still read but wait their turn, so clients see
+/// the limit as tags waiting for responses. A Tflush never waits, as it
+/// may be what ends a request; filesystems must still not answer a request
+/// once they have answered its flush.
+pub struct ShardedServer<Fs: 'static + ThreadedFilesystem + Send + Sync> {
+    fs: Arc<Fs>,
+    scheduler: Arc<Scheduler>,
+    workers: Vec<std::thread::JoinHandle<()>>,
+    max_in_flight: usize,
+    options: ServeOptions,
+}
+
+impl<Fs: 'static + ThreadedFilesystem + Send + Sync> ShardedServer<Fs> {
+    /// Serve fs with this many worker threads.
+    pub fn new(fs: Fs, workers: usize) -> ShardedServer<Fs> {
+        let scheduler = Arc::new(Scheduler::default());
+        let workers = (0..workers.max(1))
+            .map(|_| {
+                let scheduler = scheduler.clone();
+                std::thread::spawn(move || {
+                    while let Some(job) = scheduler.pop() {
+                        job()
+                    }
+                })
+            })
+            .collect();
+        ShardedServer {
+            fs: Arc::new(fs),
+            scheduler,
+            workers,
+            max_in_flight: 64,
+            options: ServeOptions::default(),
+        }
+    }
+
+    /// Handle at most this many requests of a connection at once, 64 by
+    /// default.
+    pub fn max_in_flight(mut self, max_in_flight: usize) -> ShardedServer<Fs> {
+        self.max_in_flight = max_in_flight.max(1);
+        self
+    }
+
+    /// Serve every connection with the extras in options.
+    pub fn options(mut self, options: ServeOptions) -> ShardedServer<Fs> {
+        self.options = options;
+        self
+    }
+
+    pub fn serve(&self, conn: std::net::TcpStream, bufsize: usize) {
+        if let Ok(wconn) = conn.try_clone() {
+            self.serve_transport(conn, wconn, bufsize)
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn serve_unix(&self, conn: std::os::unix::net::UnixStream, bufsize: usize) {
+        if let Ok(wconn) = conn.try_clone() {
+            self.serve_transport(conn, wconn, bufsize)
+        }
+    }
+
+    /// Serve one connection until it closes, call from a thread per
+    /// connection.
+    pub fn serve_transport<R, W>(&self, mut rconn: R, wconn: W, bufsize: usize)
+    where
+        R: ReadTransport,
+        W: WriteTransport + 'static,
+    {
+        let mut wconn: Box<dyn WriteTransport> = Box::new(wconn);
+        let hook = self.options.hook.as_ref();
+        let msize = match negotiate_version(&mut rconn, &mut wconn, bufsize, hook) {
+            Some(msize) => msize,
+            None => return,
+        };
+        let (tx, rx) = crossbeam_channel::unbounded();
+        std::thread::spawn(move || write_responses(wconn, rx));
+
+        let gate = Arc::new(Gate {
+            conn: self.scheduler.next_conn.fetch_add(1, Ordering::Relaxed),
+            limit: self.max_in_flight,
+            scheduler: Arc::downgrade(&self.scheduler),
+            state: Mutex::new(GateState::default()),
+        });
+        let pool = BufPool::new(msize);
+        let mut rbuf: Vec<u8> = vec![0; msize];
+        loop {
+            if transport::read_to_buf(&mut rconn, &mut rbuf).is_err() {
+                return;
+            }
+            let (tag, fcall) = match fcall::TaggedFcall::decode(&rbuf[..]) {
+                Ok(fcall::TaggedFcall { tag, fcall }) => (tag, fcall.clone_static()),
+                _ => return,
+            };
+            let (metrics, hook) = self.options.start(tag, &fcall, rbuf.len());
+            let slot = Arc::new(Slot {
+                gate: gate.clone(),
+                started: AtomicBool::new(false),
+            });
+            let queue = ResponseQueue {
+                tx: tx.clone(),
+                _slot: slot.clone(),
+            };
+            let resp = FcallResponse {
+                tag,
+                sink: Sink::Queue(Arc::new(queue)),
+                metrics,
+                hook,
+                copies: 1,
+                pool: Some(pool.clone()),
+            };
+            let flush = matches!(fcall, Fcall::Tflush(_));
+            let fs = self.fs.clone();
+            gate.submit(
+                Box::new(move || {
+                    if let Some(resp) = dispatch_threaded(&*fs, &fcall, resp) {
+                        resp.kill()
+                    }
+                }),
+                slot,
+                flush,
+            );
+        }
+    }
+}
+
+impl<Fs: 'static + ThreadedFilesystem + Send + Sync> Drop for ShardedServer<Fs> {
+    fn drop(&mut self) {
+        self.scheduler.shards.lock().unwrap().shutdown = true;
+        self.scheduler.work.notify_all();
+        for w in self.workers.drain(..) {
+            w.join().unwrap()
+        }
+    }
+}
+
+pub fn serve<F>(conn: std::net::TcpStream, fs: &mut F, bufsize: usize)
+where
+    F: Filesystem,
+{
+    let (r, w) = if let Ok(wconn) = conn.try_clone() {
+        (conn, wconn)
+    } else {
+        return;
+    };
+    serve_transport(r, w, fs, bufsize)
+}
+
+#[cfg(unix)]
+pub fn serve_unix<F>(conn: std::os::unix::net::UnixStream, fs: &mut F, bufsize: usize)
+where
+    F: Filesystem,
+{
+    let (r, w) = if let Ok(wconn) = conn.try_clone() {
+        (conn, wconn)
+    } else {
+        return;
+    };
+    serve_transport(r, w, fs, bufsize)
+}
+
+pub fn serve_transport<F, R, W>(rconn: R, wconn: W, fs: &mut F, bufsize: usize)
 where
     F: Filesystem,
+    R: ReadTransport,
+    W: WriteTransport + 'static,
 {
-    bufsize = bufsize
+    serve_transport_with(rconn, wconn, fs, bufsize, &ServeOptions::default())
+}
+
+/// What else to do while serving a connection, besides answering requests.
+#[derive(Clone, Default)]
+pub struct ServeOptions {
+    metrics: Option<Arc<Metrics>>,
+    hook: Option<Arc<dyn FcallHook>>,
+}
+
+impl ServeOptions {
+    pub fn new() -> ServeOptions {
+        ServeOptions::default()
+    }
+
+    /// Record every request in metrics.
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> ServeOptions {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Call hook for every request and response, the version negotiation
+    /// included.
+    pub fn hook(mut self, hook: Arc<dyn FcallHook>) -> ServeOptions {
+        self.hook = Some(hook);
+        self
+    }
+
+    // Note a request read from the connection, giving what its response
+    // records when sent.
+    fn start(&self, tag: u16, fcall: &Fcall, bytes: usize) -> (Option<Metered>, Option<Hooked>) {
+        let metrics = self
+            .metrics
+            .as_ref()
+            .map(|metrics| (metrics.clone(), metrics.start(fcall, bytes)));
+        let hook = self.hook.as_ref().map(|hook| {
+            let fid = hook::request_fid(fcall);
+            hook::emit(&**hook, HookPoint::ServerRequest, tag, fid, fcall, None);
+            (hook.clone(), Instant::now(), fid)
+        });
+        (metrics, hook)
+    }
+}
+
+// Answer the Tversion starting a connection, giving the msize agreed.
+fn negotiate_version<R: ReadTransport>(
+    rconn: &mut R,
+    wconn: &mut Box<dyn WriteTransport>,
+    bufsize: usize,
+    hook: Option<&Arc<dyn FcallHook>>,
+) -> Option<usize> {
+    let bufsize = bufsize
         .min(u32::MAX as usize)
         .max(4096 + fcall::READDIRHDRSZ as usize);
 
     let mut rbuf: Vec<u8> = Vec::with_capacity(bufsize);
     let mut wbuf: Vec<u8> = Vec::with_capacity(bufsize);
 
-    // Handle version and size buffer.
-    match transport::read(&mut conn, &mut rbuf) {
+    match transport::read(rconn, &mut rbuf) {
         Ok(fcall::TaggedFcall {
             tag: fcall::NOTAG,
             fcall:
-                Fcall::Tversion(Tversion {
+                ref tversion @ Fcall::Tversion(Tversion {
                     ref msize,
                     ref version,
                 }),
         }) => {
+            let since = Instant::now();
+            if let Some(hook) = hook {
+                hook::emit(
+                    &**hook,
+                    HookPoint::ServerRequest,
+                    fcall::NOTAG,
+                    None,
+                    tversion,
+                    None,
+                );
+            }
             let msize = (*msize).min(bufsize as u32);
 
             let rversion = if version.as_bytes() == "9P2000.L".as_bytes() {
@@ -620,79 +1366,75 @@ where
                 }
             };
 
-            if transport::write(
-                &mut conn,
-                &mut wbuf,
-                &fcall::TaggedFcall {
-                    tag: fcall::NOTAG,
-                    fcall: rversion.into(),
-                },
-            )
-            .is_err()
-            {
-                return;
+            let rversion = fcall::TaggedFcall {
+                tag: fcall::NOTAG,
+                fcall: rversion.into(),
+            };
+            transport::write(wconn, &mut wbuf, &rversion).ok()?;
+            if let Some(hook) = hook {
+                let elapsed = Some(since.elapsed());
+                let point = HookPoint::ServerResponse;
+                hook::emit(&**hook, point, fcall::NOTAG, None, &rversion.fcall, elapsed);
             }
 
-            bufsize = msize as usize;
+            Some(msize as usize)
         }
-        _ => return,
+        _ => None,
     }
+}
 
-    rbuf.resize(bufsize, 0);
-    wbuf.resize(bufsize, 0);
-
-    let (wconn, mut rconn) = if let Ok(wconn) = conn.try_clone() {
-        (wconn, conn)
-    } else {
-        return;
+/// Like serve_transport, with the extras in options.
+pub fn serve_transport_with<F, R, W>(
+    mut rconn: R,
+    wconn: W,
+    fs: &mut F,
+    bufsize: usize,
+    options: &ServeOptions,
+) where
+    F: Filesystem,
+    R: ReadTransport,
+    W: WriteTransport + 'static,
+{
+    let mut wconn: Box<dyn WriteTransport> = Box::new(wconn);
+    let hook = options.hook.as_ref();
+    let msize = match negotiate_version(&mut rconn, &mut wconn, bufsize, hook) {
+        Some(msize) => msize,
+        None => return,
     };
 
+    let mut rbuf: Vec<u8> = vec![0; msize];
+    let wbuf: Vec<u8> = vec![0; msize];
+
     let wstate = Arc::new(Mutex::new(WriteState {
         conn: wconn,
         buf: wbuf,
     }));
+    let pool = BufPool::new(msize);
 
     loop {
-        let (fcall, resp) = match transport::read(&mut rconn, &mut rbuf) {
-            Ok(fcall::TaggedFcall { tag, fcall }) => (
-                fcall,
-                FcallResponse {
-                    tag,
-                    wstate: wstate.clone(),
-                },
-            ),
+        if transport::read_to_buf(&mut rconn, &mut rbuf).is_err() {
+            return;
+        }
+        let (fcall, resp) = match fcall::TaggedFcall::decode(&rbuf[..]) {
+            Ok(fcall::TaggedFcall { tag, fcall }) => {
+                let (metrics, hook) = options.start(tag, &fcall, rbuf.len());
+                (
+                    fcall,
+                    FcallResponse {
+                        tag,
+                        sink: Sink::Conn(wstate.clone()),
+                        metrics,
+                        hook,
+                        copies: 1,
+                        pool: Some(pool.clone()),
+                    },
+                )
+            }
             _ => return,
         };
 
-        match fcall {
-            Fcall::Tstatfs(req) => fs.statfs(&req, resp),
-            Fcall::Tlopen(req) => fs.lopen(&req, resp),
-            Fcall::Tlcreate(req) => fs.lcreate(&req, resp),
-            Fcall::Tsymlink(req) => fs.symlink(&req, resp),
-            Fcall::Tmknod(req) => fs.mknod(&req, resp),
-            Fcall::Treadlink(req) => fs.readlink(&req, resp),
-            Fcall::Tgetattr(req) => fs.getattr(&req, resp),
-            Fcall::Tsetattr(req) => fs.setattr(&req, resp),
-            Fcall::Treaddir(req) => fs.readdir(&req, resp),
-            Fcall::Tfsync(req) => fs.fsync(&req, resp),
-            Fcall::Tmkdir(req) => fs.mkdir(&req, resp),
-            Fcall::Tflush(req) => fs.flush(&req, resp),
-            Fcall::Tread(req) => fs.read(&req, resp),
-            Fcall::Twrite(req) => fs.write(&req, resp),
-            Fcall::Tclunk(req) => fs.clunk(&req, resp),
-            Fcall::Tremove(req) => fs.remove(&req, resp),
-            Fcall::Trename(req) => fs.rename(&req, resp),
-            Fcall::Tlink(req) => fs.link(&req, resp),
-            Fcall::Trenameat(req) => fs.renameat(&req, resp),
-            Fcall::Tunlinkat(req) => fs.unlinkat(&req, resp),
-            Fcall::Tlock(req) => fs.lock(&req, resp),
-            Fcall::Tgetlock(req) => fs.getlock(&req, resp),
-            Fcall::Tauth(req) => fs.auth(&req, resp),
-            Fcall::Tattach(req) => fs.attach(&req, resp),
-            Fcall::Twalk(req) => fs.walk(&req, resp),
-            Fcall::Txattrwalk(req) => fs.xattrwalk(&req, resp),
-            Fcall::Txattrcreate(req) => fs.xattrcreate(&req, resp),
-            _ => return,
-        };
+        if dispatch(fs, &fcall, resp).is_some() {
+            return;
+        }
     }
 }
@@ -0,0 +1,662 @@
+use super::errno;
+use super::fcall::*;
+use super::server::{FcallResponse, Filesystem};
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::io::Read;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+// Directory entry types, as in struct dirent.
+const DT_DIR: u8 = 4;
+const DT_REG: u8 = 8;
+
+/// What reading a synthetic file gives.
+pub enum SynthData {
+    /// The whole file, which can be read at any offset.
+    Bytes(Vec<u8>),
+    /// A file read once from start to end, such as a long log.
+    Stream(Box<dyn Read + Send>),
+}
+
+impl From<Vec<u8>> for SynthData {
+    fn from(data: Vec<u8>) -> SynthData {
+        SynthData::Bytes(data)
+    }
+}
+
+impl From<String> for SynthData {
+    fn from(data: String) -> SynthData {
+        SynthData::Bytes(data.into_bytes())
+    }
+}
+
+impl From<&str> for SynthData {
+    fn from(data: &str) -> SynthData {
+        SynthData::Bytes(data.as_bytes().to_vec())
+    }
+}
+
+type ReadFn = Arc<dyn Fn() -> std::io::Result<SynthData> + Send + Sync>;
+type WriteFn = Arc<dyn Fn(&[u8]) -> std::io::Result<()> + Send + Sync>;
+type GenerateFn = Arc<dyn Fn() -> Vec<(String, SynthNode)> + Send + Sync>;
+
+#[derive(Clone)]
+enum Kind {
+    File {
+        read: Option<ReadFn>,
+        write: Option<WriteFn>,
+    },
+    Events(Events),
+    Dir(Vec<(Vec<u8>, SynthNode)>),
+    Generated(GenerateFn),
+}
+
+/// A file or directory of a SynthFs. Clones are the same file.
+#[derive(Clone)]
+pub struct SynthNode {
+    kind: Kind,
+    perm: Option<u32>,
+    version: Arc<AtomicU32>,
+}
+
+impl SynthNode {
+    fn new(kind: Kind) -> SynthNode {
+        SynthNode {
+            kind,
+            perm: None,
+            version: Arc::new(AtomicU32::new(0)),
+        }
+    }
+
+    /// A file that can be neither read nor written, until given callbacks
+    /// with read and write.
+    pub fn file() -> SynthNode {
+        SynthNode::new(Kind::File {
+            read: None,
+            write: None,
+        })
+    }
+
+    /// An empty directory, filled with child.
+    pub fn dir() -> SynthNode {
+        SynthNode::new(Kind::Dir(Vec::new()))
+    }
+
+    /// A directory listing what generate returns each time it is walked
+    /// or read, for trees such as one directory per connection. Entries
+    /// keep their qid path for as long as their name is the same.
+    pub fn generated<F>(generate: F) -> SynthNode
+    where
+        F: Fn() -> Vec<(String, SynthNode)> + Send + Sync + 'static,
+    {
+        SynthNode::new(Kind::Generated(Arc::new(generate)))
+    }
+
+    /// A file whose reads wait for events posted to events, each read
+    /// giving one event. Readers only see events posted after they opened
+    /// the file.
+    pub fn events(events: &Events) -> SynthNode {
+        SynthNode {
+            kind: Kind::Events(events.clone()),
+            perm: None,
+            version: events.version.clone(),
+        }
+    }
+
+    /// Call read when the file is opened for reading, reads are answered
+    /// from what it returned until the file is closed.
+    pub fn read<F, D>(mut self, read: F) -> SynthNode
+    where
+        F: Fn() -> std::io::Result<D> + Send + Sync + 'static,
+        D: Into<SynthData>,
+    {
+        if let Kind::File { read: r, .. } = &mut self.kind {
+            *r = Some(Arc::new(move || read().map(Into::into)));
+        }
+        self
+    }
+
+    /// Call write with the data of each write to the file, such as a
+    /// command to a control file.
+    pub fn write<F>(mut self, write: F) -> SynthNode
+    where
+        F: Fn(&[u8]) -> std::io::Result<()> + Send + Sync + 'static,
+    {
+        if let Kind::File { write: w, .. } = &mut self.kind {
+            *w = Some(Arc::new(write));
+        }
+        self
+    }
+
+    /// Add name to a directory made with SynthNode::dir.
+    pub fn child(mut self, name: &str, node: SynthNode) -> SynthNode {
+        if let Kind::Dir(children) = &mut self.kind {
+            children.push((name.as_bytes().to_vec(), node));
+        }
+        self
+    }
+
+    /// The permission bits getattr reports, by default read for readable
+    /// files and directories and write for writable files.
+    pub fn perm(mut self, perm: u32) -> SynthNode {
+        self.perm = Some(perm & 0o7777);
+        self
+    }
+
+    /// Change the qid version, telling clients caching the file that it
+    /// has changed. Writes and events change it too.
+    pub fn changed(&self) {
+        self.version.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn is_dir(&self) -> bool {
+        matches!(self.kind, Kind::Dir(_) | Kind::Generated(_))
+    }
+
+    fn mode(&self) -> u32 {
+        let perm = match &self.kind {
+            Kind::File { read, write } => {
+                let read = if read.is_some() { 0o444 } else { 0 };
+                let write = if write.is_some() { 0o200 } else { 0 };
+                read | write
+            }
+            Kind::Events(_) => 0o444,
+            Kind::Dir(_) | Kind::Generated(_) => 0o555,
+        };
+        let typ = if self.is_dir() { S_IFDIR } else { S_IFREG };
+        typ | self.perm.unwrap_or(perm)
+    }
+
+    fn children(&self) -> Vec<(Vec<u8>, SynthNode)> {
+        match &self.kind {
+            Kind::Dir(children) => children.clone(),
+            Kind::Generated(generate) => generate()
+                .into_iter()
+                .map(|(name, node)| (name.into_bytes(), node))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn lookup(&self, name: &[u8]) -> Option<SynthNode> {
+        match &self.kind {
+            Kind::Dir(children) => children
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, node)| node.clone()),
+            Kind::Generated(_) => self
+                .children()
+                .into_iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, node)| node),
+            _ => None,
+        }
+    }
+}
+
+// An open event file's unread events, and its read waiting for one.
+#[derive(Default)]
+struct Reader {
+    queue: VecDeque<Vec<u8>>,
+    waiting: Option<(FcallResponse, u32)>,
+}
+
+impl Reader {
+    fn answer(&mut self) {
+        if self.queue.is_empty() {
+            return;
+        }
+        if let Some((resp, count)) = self.waiting.take() {
+            let mut event = self.queue.pop_front().unwrap();
+            if event.len() > count as usize {
+                self.queue.push_front(event.split_off(count as usize));
+            }
+            resp.send(Rread {
+                data: Cow::from(event),
+            })
+        }
+    }
+}
+
+/// The events of an event file, see SynthNode::events. Clones post to the
+/// same file.
+#[derive(Clone, Default)]
+pub struct Events {
+    readers: Arc<Mutex<Vec<Weak<Mutex<Reader>>>>>,
+    version: Arc<AtomicU32>,
+}
+
+impl Events {
+    pub fn new() -> Events {
+        Events::default()
+    }
+
+    /// Give an event to everyone with the file open, answering a waiting
+    /// read or the next one. An event longer than a read is split.
+    pub fn post(&self, event: impl AsRef<[u8]>) {
+        self.version.fetch_add(1, Ordering::Relaxed);
+        let mut readers = self.readers.lock().unwrap();
+        readers.retain(|reader| match reader.upgrade() {
+            Some(reader) => {
+                let mut reader = reader.lock().unwrap();
+                reader.queue.push_back(event.as_ref().to_vec());
+                reader.answer();
+                true
+            }
+            None => false,
+        });
+    }
+
+    /// How many have the file open.
+    pub fn readers(&self) -> usize {
+        let readers = self.readers.lock().unwrap();
+        readers.iter().filter(|r| r.strong_count() > 0).count()
+    }
+
+    fn open(&self) -> Arc<Mutex<Reader>> {
+        let reader = Arc::new(Mutex::new(Reader::default()));
+        self.readers.lock().unwrap().push(Arc::downgrade(&reader));
+        reader
+    }
+}
+
+enum Open {
+    Listing(Vec<DirEntry<'static>>),
+    Bytes(Vec<u8>),
+    Stream(Box<dyn Read + Send>, u64),
+    Events(Arc<Mutex<Reader>>),
+    WriteOnly,
+}
+
+struct Fid {
+    path: Vec<Vec<u8>>,
+    node: SynthNode,
+    open: Option<Open>,
+    writable: bool,
+}
+
+/// A filesystem of synthetic files, such as status and control files,
+/// described by a tree of SynthNodes.
+///
+/// Callbacks run on the thread serving the filesystem, apart from reads of
+/// event files, which are answered by the thread posting the event.
+pub struct SynthFs {
+    root: SynthNode,
+    fids: HashMap<u32, Fid>,
+    paths: HashMap<Vec<Vec<u8>>, u64>,
+    started: Time,
+}
+
+fn respond(resp: FcallResponse, result: Result<Fcall<'static>, u32>) {
+    match result {
+        Ok(fcall) => resp.send(fcall),
+        Err(ecode) => resp.send(Rlerror { ecode }),
+    }
+}
+
+impl SynthFs {
+    pub fn new(root: SynthNode) -> SynthFs {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        SynthFs {
+            root,
+            fids: HashMap::new(),
+            paths: HashMap::new(),
+            started: Time {
+                sec: started.as_secs(),
+                nsec: started.subsec_nanos() as u64,
+            },
+        }
+    }
+
+    fn fid(&self, fid: u32) -> Result<&Fid, u32> {
+        self.fids.get(&fid).ok_or(errno::EBADF)
+    }
+
+    // Paths get a number the first time they are seen and keep it.
+    fn qid(&mut self, path: &[Vec<u8>], node: &SynthNode) -> Qid {
+        let next = self.paths.len() as u64;
+        let qid_path = *self.paths.entry(path.to_vec()).or_insert(next);
+        Qid {
+            typ: if node.is_dir() {
+                QidType::DIR
+            } else {
+                QidType::FILE
+            },
+            version: node.version.load(Ordering::Relaxed),
+            path: qid_path,
+        }
+    }
+
+    fn resolve(&self, path: &[Vec<u8>]) -> Option<SynthNode> {
+        let mut node = self.root.clone();
+        for name in path {
+            node = node.lookup(name)?;
+        }
+        Some(node)
+    }
+
+    fn list(&mut self, path: &[Vec<u8>], node: &SynthNode) -> Vec<DirEntry<'static>> {
+        let mut path = path.to_vec();
+        let mut entries = Vec::new();
+        for (offset, (name, child)) in node.children().into_iter().enumerate() {
+            path.push(name);
+            entries.push(DirEntry {
+                qid: self.qid(&path, &child),
+                offset: offset as u64 + 1,
+                typ: if child.is_dir() { DT_DIR } else { DT_REG },
+                name: FcallStr::Owned(path.pop().unwrap()),
+            });
+        }
+        entries
+    }
+
+    fn do_attach(&mut self, req: &Tattach) -> Result<Fcall<'static>, u32> {
+        let root = self.root.clone();
+        let fid = Fid {
+            path: Vec::new(),
+            node: root.clone(),
+            open: None,
+            writable: false,
+        };
+        self.fids.insert(req.fid, fid);
+        Ok(Rattach {
+            qid: self.qid(&[], &root),
+        }
+        .into())
+    }
+
+    fn do_walk(&mut self, req: &Twalk) -> Result<Fcall<'static>, u32> {
+        let fid = self.fid(req.fid)?;
+        if req.new_fid != req.fid && self.fids.contains_key(&req.new_fid) {
+            return Err(errno::EBADF);
+        }
+        let mut path = fid.path.clone();
+        let mut node = fid.node.clone();
+        let mut wqids = Vec::new();
+        for name in &req.wnames {
+            let next = match name.as_bytes() {
+                b"." => Some(node.clone()),
+                b".." => {
+                    path.pop();
+                    self.resolve(&path)
+                }
+                name => node.lookup(name).inspect(|_| path.push(name.to_vec())),
+            };
+            match next {
+                Some(next) => node = next,
+                None if wqids.is_empty() => return Err(errno::ENOENT),
+                None => return Ok(Rwalk { wqids }.into()),
+            }
+            wqids.push(self.qid(&path, &node));
+        }
+        let fid = Fid {
+            path,
+            node,
+            open: None,
+            writable: false,
+        };
+        self.fids.insert(req.new_fid, fid);
+        Ok(Rwalk { wqids }.into())
+    }
+
+    fn do_lopen(&mut self, req: &Tlopen) -> Result<Fcall<'static>, u32> {
+        let fid = self.fid(req.fid)?;
+        if fid.open.is_some() {
+            return Err(errno::EBADF);
+        }
+        let (path, node) = (fid.path.clone(), fid.node.clone());
+        let access = req.flags & (LOpenFlags::O_WRONLY | LOpenFlags::O_RDWR);
+        let reading = access != LOpenFlags::O_WRONLY;
+        let writing = !access.is_empty();
+        let open = match &node.kind {
+            _ if node.is_dir() && writing => return Err(errno::EISDIR),
+            Kind::Dir(_) | Kind::Generated(_) => Open::Listing(self.list(&path, &node)),
+            Kind::Events(_) if writing => return Err(errno::EACCES),
+            Kind::Events(events) => Open::Events(events.open()),
+            Kind::File { read, write } => {
+                if (reading && read.is_none()) || (writing && write.is_none()) {
+                    return Err(errno::EACCES);
+                }
+                match read {
+                    Some(read) if reading => match read() {
+                        Ok(SynthData::Bytes(data)) => Open::Bytes(data),
+                        Ok(SynthData::Stream(stream)) => Open::Stream(stream, 0),
+                        Err(err) => return Err(errno::errno_from_io_error(&err)),
+                    },
+                    _ => Open::WriteOnly,
+                }
+            }
+        };
+        let qid = self.qid(&path, &node);
+        let fid = self.fids.get_mut(&req.fid).unwrap();
+        fid.open = Some(open);
+        fid.writable = writing;
+        Ok(Rlopen { qid, iounit: 0 }.into())
+    }
+
+    fn do_readdir(&mut self, req: &Treaddir) -> Result<Fcall<'static>, u32> {
+        let fid = self.fid(req.fid)?;
+        if !matches!(fid.open, Some(Open::Listing(_))) {
+            return Err(errno::EBADF);
+        }
+        // Starting again lists the directory again.
+        if req.offset == 0 {
+            let (path, node) = (fid.path.clone(), fid.node.clone());
+            let listing = self.list(&path, &node);
+            self.fids.get_mut(&req.fid).unwrap().open = Some(Open::Listing(listing));
+        }
+        let Some(Open::Listing(listing)) = &self.fid(req.fid)?.open else {
+            unreachable!()
+        };
+        let mut data = DirEntryData::new();
+        for entry in listing.iter().skip(req.offset as usize) {
+            if data.size() + entry.size() > req.count as u64 {
+                break;
+            }
+            data.push(entry.clone());
+        }
+        Ok(Rreaddir { data }.into())
+    }
+
+    fn do_read(&mut self, req: &Tread) -> Result<Fcall<'static>, u32> {
+        let fid = self.fids.get_mut(&req.fid).ok_or(errno::EBADF)?;
+        let data = match &mut fid.open {
+            Some(Open::Bytes(data)) => {
+                let start = (req.offset as usize).min(data.len());
+                let end = (start + req.count as usize).min(data.len());
+                data[start..end].to_vec()
+            }
+            Some(Open::Stream(stream, offset)) => {
+                if req.offset != *offset {
+                    return Err(errno::ESPIPE);
+                }
+                let mut data = vec![0; req.count as usize];
+                let n = loop {
+                    match stream.read(&mut data) {
+                        Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                        result => break result.map_err(|err| errno::errno_from_io_error(&err))?,
+                    }
+                };
+                data.truncate(n);
+                *offset += n as u64;
+                data
+            }
+            Some(Open::Listing(_)) => return Err(errno::EISDIR),
+            _ => return Err(errno::EBADF),
+        };
+        Ok(Rread {
+            data: Cow::from(data),
+        }
+        .into())
+    }
+
+    fn do_write(&mut self, req: &Twrite) -> Result<Fcall<'static>, u32> {
+        let fid = self.fid(req.fid)?;
+        let write = match &fid.node.kind {
+            Kind::File {
+                write: Some(write), ..
+            } if fid.open.is_some() && fid.writable => write,
+            _ => return Err(errno::EBADF),
+        };
+        write(&req.data).map_err(|err| errno::errno_from_io_error(&err))?;
+        fid.node.changed();
+        Ok(Rwrite {
+            count: req.data.len() as u32,
+        }
+        .into())
+    }
+
+    fn do_getattr(&mut self, req: &Tgetattr) -> Result<Fcall<'static>, u32> {
+        let fid = self.fid(req.fid)?;
+        let (path, node) = (fid.path.clone(), fid.node.clone());
+        let qid = self.qid(&path, &node);
+        // Synthetic files have no size, their contents are made as they
+        // are read.
+        let stat = Stat {
+            mode: node.mode(),
+            uid: 0,
+            gid: 0,
+            nlink: if node.is_dir() { 2 } else { 1 },
+            rdev: 0,
+            size: 0,
+            blksize: 4096,
+            blocks: 0,
+            atime: self.started,
+            mtime: self.started,
+            ctime: self.started,
+            btime: self.started,
+            gen: 0,
+            data_version: qid.version as u64,
+        };
+        Ok(Rgetattr {
+            valid: GetattrMask::BASIC,
+            qid,
+            stat,
+        }
+        .into())
+    }
+
+    fn do_setattr(&mut self, req: &Tsetattr) -> Result<Fcall<'static>, u32> {
+        self.fid(req.fid)?;
+        // Truncating and touching are ignored, so shells can write
+        // commands with "echo cmd > ctl".
+        if req
+            .valid
+            .intersects(SetattrMask::MODE | SetattrMask::UID | SetattrMask::GID)
+        {
+            return Err(errno::EPERM);
+        }
+        Ok(Rsetattr {}.into())
+    }
+
+    fn do_statfs(&mut self, req: &Tstatfs) -> Result<Fcall<'static>, u32> {
+        self.fid(req.fid)?;
+        Ok(Rstatfs {
+            statfs: Statfs {
+                typ: 0,
+                bsize: 4096,
+                blocks: 0,
+                bfree: 0,
+                bavail: 0,
+                files: self.paths.len() as u64,
+                ffree: 0,
+                fsid: 0,
+                namelen: 255,
+            },
+        }
+        .into())
+    }
+}
+
+impl Filesystem for SynthFs {
+    fn attach(&mut self, req: &Tattach, resp: FcallResponse) {
+        respond(resp, self.do_attach(req))
+    }
+
+    fn walk(&mut self, req: &Twalk, resp: FcallResponse) {
+        respond(resp, self.do_walk(req))
+    }
+
+    fn lopen(&mut self, req: &Tlopen, resp: FcallResponse) {
+        respond(resp, self.do_lopen(req))
+    }
+
+    fn readdir(&mut self, req: &Treaddir, resp: FcallResponse) {
+        respond(resp, self.do_readdir(req))
+    }
+
+    fn read(&mut self, req: &Tread, resp: FcallResponse) {
+        if let Some(Fid {
+            open: Some(Open::Events(reader)),
+            ..
+        }) = self.fids.get(&req.fid)
+        {
+            let mut reader = reader.lock().unwrap();
+            if reader.waiting.is_some() {
+                return resp.send(Rlerror {
+                    ecode: errno::EBUSY,
+                });
+            }
+            reader.waiting = Some((resp, req.count));
+            return reader.answer();
+        }
+        respond(resp, self.do_read(req))
+    }
+
+    fn write(&mut self, req: &Twrite, resp: FcallResponse) {
+        respond(resp, self.do_write(req))
+    }
+
+    fn getattr(&mut self, req: &Tgetattr, resp: FcallResponse) {
+        respond(resp, self.do_getattr(req))
+    }
+
+    fn setattr(&mut self, req: &Tsetattr, resp: FcallResponse) {
+        respond(resp, self.do_setattr(req))
+    }
+
+    fn statfs(&mut self, req: &Tstatfs, resp: FcallResponse) {
+        respond(resp, self.do_statfs(req))
+    }
+
+    fn flush(&mut self, req: &Tflush, resp: FcallResponse) {
+        // A flushed read waiting for an event is never answered.
+        for fid in self.fids.values() {
+            if let Some(Open::Events(reader)) = &fid.open {
+                let mut reader = reader.lock().unwrap();
+                if matches!(&reader.waiting, Some((waiting, _)) if waiting.tag == req.oldtag) {
+                    let (mut waiting, _) = reader.waiting.take().unwrap();
+                    waiting.tag = NOTAG;
+                }
+            }
+        }
+        resp.send(Rflush {})
+    }
+
+    fn clunk(&mut self, req: &Tclunk, resp: FcallResponse) {
+        // Let go of the fid, and any event reader, before answering.
+        let known = self.fids.remove(&req.fid).is_some();
+        if known {
+            resp.send(Rclunk {})
+        } else {
+            resp.send(Rlerror {
+                ecode: errno::EBADF,
+            })
+        }
+    }
+
+    fn remove(&mut self, req: &Tremove, resp: FcallResponse) {
+        // Tremove clunks the fid even when it fails.
+        let ecode = match self.fids.remove(&req.fid) {
+            Some(_) => errno::EPERM,
+            None => errno::EBADF,
+        };
+        resp.send(Rlerror { ecode })
+    }
+}
@@ -0,0 +1,310 @@
+use super::fcall;
+use super::transport::{ReadTransport, WriteTransport};
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Trace file layout:
+//
+//   magic[8] = "9PTRACE1"
+//   record*
+//
+// Each record is:
+//
+//   timestamp[8] (nanoseconds since the unix epoch)
+//   conn_id[4]
+//   direction[1]
+//   frame (a complete 9p message, including its size[4] prefix)
+//
+// All integers are little endian like the 9p protocol itself. Writes are
+// recorded once they succeed, so a response may be stored just ahead of
+// its request, ordering by timestamp puts them back.
+
+const TRACE_MAGIC: &[u8; 8] = b"9PTRACE1";
+
+const RECORD_HDRSZ: usize = 8 + 4 + 1;
+
+// The largest frame kept or read back, far beyond any msize in use. A
+// larger size means the stream or file is corrupt.
+const MAX_FRAME: usize = 16 << 20;
+
+/// Direction of a traced frame relative to the traced endpoint.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Read = 0,
+    Write = 1,
+}
+
+impl Direction {
+    pub fn from_u8(v: u8) -> Option<Direction> {
+        match v {
+            0 => Some(Direction::Read),
+            1 => Some(Direction::Write),
+            _ => None,
+        }
+    }
+}
+
+struct TraceWriterInner {
+    w: Mutex<Box<dyn Write + Send>>,
+    next_conn_id: AtomicU32,
+}
+
+/// Records 9p frames to a trace file.
+///
+/// A TraceWriter is cheap to clone and may be shared by many
+/// connections, each one is given its own connection id by `tee`.
+#[derive(Clone)]
+pub struct TraceWriter {
+    inner: Arc<TraceWriterInner>,
+}
+
+impl TraceWriter {
+    pub fn new<W: Write + Send + 'static>(mut w: W) -> Result<TraceWriter, std::io::Error> {
+        w.write_all(TRACE_MAGIC)?;
+        Ok(TraceWriter {
+            inner: Arc::new(TraceWriterInner {
+                w: Mutex::new(Box::new(w)),
+                next_conn_id: AtomicU32::new(0),
+            }),
+        })
+    }
+
+    pub fn create<P: AsRef<std::path::Path>>(path: P) -> Result<TraceWriter, std::io::Error> {
+        let f = std::fs::File::create(path)?;
+        TraceWriter::new(std::io::BufWriter::new(f))
+    }
+
+    pub fn record(
+        &self,
+        conn_id: u32,
+        direction: Direction,
+        frame: &[u8],
+    ) -> Result<(), std::io::Error> {
+        self.record_at(SystemTime::now(), conn_id, direction, frame)
+    }
+
+    fn record_at(
+        &self,
+        time: SystemTime,
+        conn_id: u32,
+        direction: Direction,
+        frame: &[u8],
+    ) -> Result<(), std::io::Error> {
+        let timestamp = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        let mut hdr = [0; RECORD_HDRSZ];
+        hdr[0..8].copy_from_slice(&timestamp.to_le_bytes());
+        hdr[8..12].copy_from_slice(&conn_id.to_le_bytes());
+        hdr[12] = direction as u8;
+        let mut w = self.inner.w.lock().unwrap();
+        w.write_all(&hdr)?;
+        w.write_all(frame)?;
+        Ok(())
+    }
+
+    pub fn flush(&self) -> Result<(), std::io::Error> {
+        self.inner.w.lock().unwrap().flush()
+    }
+
+    /// Allocate a new connection id and wrap both halves of a connection
+    /// so every frame passing through them is recorded.
+    pub fn tee<R: ReadTransport, W: WriteTransport>(
+        &self,
+        r: R,
+        w: W,
+    ) -> (TraceReadTransport<R>, TraceWriteTransport<W>) {
+        let conn_id = self.inner.next_conn_id.fetch_add(1, Ordering::Relaxed);
+        (
+            TraceReadTransport {
+                r,
+                frames: FrameRecorder::new(self.clone(), conn_id, Direction::Read),
+            },
+            TraceWriteTransport {
+                w,
+                frames: Mutex::new(FrameRecorder::new(self.clone(), conn_id, Direction::Write)),
+            },
+        )
+    }
+}
+
+// Reassembles frames from a byte stream that may be read or
+// written in arbitrary chunks, recording each complete frame with the
+// time its first chunk was seen.
+struct FrameRecorder {
+    trace: TraceWriter,
+    conn_id: u32,
+    direction: Direction,
+    buf: Vec<u8>,
+    since: SystemTime,
+    // Set once a frame size is out of bounds, nothing after it can be
+    // split into frames.
+    lost: bool,
+}
+
+impl FrameRecorder {
+    fn new(trace: TraceWriter, conn_id: u32, direction: Direction) -> FrameRecorder {
+        FrameRecorder {
+            trace,
+            conn_id,
+            direction,
+            buf: Vec::new(),
+            since: UNIX_EPOCH,
+            lost: false,
+        }
+    }
+
+    fn push(&mut self, mut data: &[u8], time: SystemTime) {
+        while !data.is_empty() && !self.lost {
+            if self.buf.is_empty() {
+                self.since = time;
+            }
+            if self.buf.len() < 4 {
+                let n = (4 - self.buf.len()).min(data.len());
+                self.buf.extend_from_slice(&data[..n]);
+                data = &data[n..];
+                continue;
+            }
+            let sz = u32::from_le_bytes(self.buf[..4].try_into().unwrap()) as usize;
+            if sz > MAX_FRAME {
+                self.lost = true;
+                self.buf = Vec::new();
+                return;
+            }
+            let n = sz.saturating_sub(self.buf.len()).min(data.len());
+            self.buf.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.buf.len() >= sz {
+                // Tracing is best effort, it must never break the connection.
+                if sz >= 4 + 1 + 2 {
+                    let _ =
+                        self.trace
+                            .record_at(self.since, self.conn_id, self.direction, &self.buf);
+                }
+                self.buf.clear();
+            }
+        }
+    }
+}
+
+pub struct TraceReadTransport<R: ReadTransport> {
+    r: R,
+    frames: FrameRecorder,
+}
+
+impl<R: ReadTransport> Read for TraceReadTransport<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.r.read(buf)?;
+        self.frames.push(&buf[..n], SystemTime::now());
+        Ok(n)
+    }
+}
+
+impl<R: ReadTransport> ReadTransport for TraceReadTransport<R> {
+    fn set_read_timeout(&mut self, dur: Option<Duration>) -> Result<(), std::io::Error> {
+        self.r.set_read_timeout(dur)
+    }
+
+    fn read_timeout(&self) -> Result<Option<Duration>, std::io::Error> {
+        self.r.read_timeout()
+    }
+}
+
+pub struct TraceWriteTransport<W: WriteTransport> {
+    w: W,
+    // WriteTransport must be Sync, so the recorder needs its own lock.
+    frames: Mutex<FrameRecorder>,
+}
+
+impl<W: WriteTransport> Write for TraceWriteTransport<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // Only what reached the connection is recorded, stamped with when
+        // the write began. The read side may record a response before its
+        // request, but never with an earlier time.
+        let time = SystemTime::now();
+        self.w.write_all(buf)?;
+        self.frames.get_mut().unwrap().push(buf, time);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.w.flush()
+    }
+}
+
+impl<W: WriteTransport> WriteTransport for TraceWriteTransport<W> {
+    fn shutdown(&self) -> Result<(), std::io::Error> {
+        self.w.shutdown()
+    }
+}
+
+/// A single recorded frame.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceFrame {
+    pub timestamp: SystemTime,
+    pub conn_id: u32,
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+impl TraceFrame {
+    pub fn fcall(&self) -> Result<fcall::TaggedFcall<'_>, std::io::Error> {
+        fcall::TaggedFcall::decode(&self.data)
+    }
+}
+
+fn invalid_trace() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid 9p trace file")
+}
+
+/// Iterates over the frames of a trace file.
+pub struct TraceReader<R: Read> {
+    r: R,
+}
+
+impl<R: Read> TraceReader<R> {
+    pub fn new(mut r: R) -> Result<TraceReader<R>, std::io::Error> {
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != TRACE_MAGIC {
+            return Err(invalid_trace());
+        }
+        Ok(TraceReader { r })
+    }
+
+    pub fn read_frame(&mut self) -> Result<Option<TraceFrame>, std::io::Error> {
+        let mut hdr = [0; RECORD_HDRSZ + 4];
+        // A clean end of file is only allowed between records.
+        match self.r.read(&mut hdr[..1])? {
+            0 => return Ok(None),
+            _ => self.r.read_exact(&mut hdr[1..])?,
+        }
+        let timestamp = u64::from_le_bytes(hdr[0..8].try_into().unwrap());
+        let conn_id = u32::from_le_bytes(hdr[8..12].try_into().unwrap());
+        let direction = Direction::from_u8(hdr[12]).ok_or_else(invalid_trace)?;
+        let sz = u32::from_le_bytes(hdr[13..17].try_into().unwrap()) as usize;
+        if !(4..=MAX_FRAME).contains(&sz) {
+            return Err(invalid_trace());
+        }
+        let mut data = vec![0; sz];
+        data[..4].copy_from_slice(&hdr[13..17]);
+        self.r.read_exact(&mut data[4..])?;
+        Ok(Some(TraceFrame {
+            timestamp: UNIX_EPOCH + Duration::from_nanos(timestamp),
+            conn_id,
+            direction,
+            data,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = Result<TraceFrame, std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_frame().transpose()
+    }
+}
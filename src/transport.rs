@@ -15,6 +15,22 @@ pub trait WriteTransport: Write + Send + Sync {
     fn shutdown(&self) -> Result<(), std::io::Error>;
 }
 
+impl<T: ReadTransport + ?Sized> ReadTransport for Box<T> {
+    fn set_read_timeout(&mut self, d: Option<Duration>) -> Result<(), std::io::Error> {
+        (**self).set_read_timeout(d)
+    }
+
+    fn read_timeout(&self) -> Result<Option<Duration>, std::io::Error> {
+        (**self).read_timeout()
+    }
+}
+
+impl<T: WriteTransport + ?Sized> WriteTransport for Box<T> {
+    fn shutdown(&self) -> Result<(), std::io::Error> {
+        (**self).shutdown()
+    }
+}
+
 impl ReadTransport for TcpStream {
     fn set_read_timeout(&mut self, d: Option<Duration>) -> Result<(), std::io::Error> {
         TcpStream::set_read_timeout(self, d)
@@ -49,13 +65,19 @@ impl WriteTransport for UnixStream {
     }
 }
 
+// The size of the message last encoded into a write buffer.
+pub(crate) fn frame_len(buf: &[u8]) -> usize {
+    buf.get(..4)
+        .map(|sz| u32::from_le_bytes(sz.try_into().unwrap()) as usize)
+        .unwrap_or(0)
+}
+
 pub fn read_to_buf<R: Read>(r: &mut R, buf: &mut Vec<u8>) -> std::io::Result<()> {
     buf.resize(4, 0);
     r.read_exact(&mut buf[..])?;
     let sz = u32::from_le_bytes(buf[..4].try_into().unwrap()) as usize;
     if sz > buf.capacity() {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
+        return Err(std::io::Error::other(
             "9p remote violated protocol size limit",
         ));
     }
@@ -94,8 +116,7 @@ pub fn read_to_buf_timeout<T: ReadTransport>(
 
     let sz = u32::from_le_bytes(fcall_buf[..4].try_into().unwrap()) as usize;
     if sz > fcall_buf.capacity() {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
+        return Err(std::io::Error::other(
             "9p remote violated protocol size limit",
         ));
     }
@@ -148,10 +169,7 @@ pub fn write<W: Write>(
             // Zero copy Rread path.
             let sz = 4 + 1 + 2 + 4 + data.len();
             if sz > buf.capacity() {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "9p message overflows msize",
-                ));
+                return Err(std::io::Error::other("9p message overflows msize"));
             }
             let mut cursor = std::io::Cursor::new(buf);
             write_u32(&mut cursor, sz as u32)?;
@@ -171,10 +189,7 @@ pub fn write<W: Write>(
             // Zero copy Twrite path.
             let sz = 4 + 1 + 2 + 4 + 8 + 4 + data.len();
             if sz > buf.capacity() {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "9p message overflows msize",
-                ));
+                return Err(std::io::Error::other("9p message overflows msize"));
             }
             let mut cursor = std::io::Cursor::new(buf);
             write_u32(&mut cursor, sz as u32)?;
@@ -0,0 +1,318 @@
+use p92000l::*;
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Default)]
+struct Shared {
+    data: Vec<u8>,
+    version: u32,
+    // The most a read returns, like a server with a small buffer.
+    max_read: Option<usize>,
+    seen: Vec<FcallType>,
+}
+
+// A root directory containing the file "f", every request is recorded.
+#[derive(Clone, Default)]
+struct FileFs(Arc<Mutex<Shared>>);
+
+impl FileFs {
+    fn file_qid(&self) -> Qid {
+        Qid {
+            typ: QidType::FILE,
+            version: self.0.lock().unwrap().version,
+            path: 2,
+        }
+    }
+
+    fn seen(&self) -> Vec<FcallType> {
+        std::mem::take(&mut self.0.lock().unwrap().seen)
+    }
+
+    fn saw(&self, typ: FcallType) {
+        self.0.lock().unwrap().seen.push(typ);
+    }
+}
+
+impl Filesystem for FileFs {
+    fn attach(&mut self, _req: &Tattach, resp: FcallResponse) {
+        resp.send(Rattach {
+            qid: Qid {
+                typ: QidType::DIR,
+                version: 0,
+                path: 1,
+            },
+        })
+    }
+
+    fn walk(&mut self, req: &Twalk, resp: FcallResponse) {
+        self.saw(FcallType::Twalk);
+        match req.wnames.first() {
+            Some(name) if name.as_bytes() != b"f" => resp.send(Rlerror {
+                ecode: errno::ENOENT,
+            }),
+            _ => resp.send(Rwalk {
+                wqids: req.wnames.iter().map(|_| self.file_qid()).collect(),
+            }),
+        }
+    }
+
+    fn lopen(&mut self, _req: &Tlopen, resp: FcallResponse) {
+        self.saw(FcallType::Tlopen);
+        resp.send(Rlopen {
+            qid: self.file_qid(),
+            iounit: 0,
+        })
+    }
+
+    fn read(&mut self, req: &Tread, resp: FcallResponse) {
+        self.saw(FcallType::Tread);
+        let shared = self.0.lock().unwrap();
+        let off = (req.offset as usize).min(shared.data.len());
+        let count = shared
+            .max_read
+            .unwrap_or(usize::MAX)
+            .min(req.count as usize);
+        let end = (off + count).min(shared.data.len());
+        resp.send(Rread {
+            data: std::borrow::Cow::from(&shared.data[off..end]),
+        })
+    }
+
+    fn write(&mut self, req: &Twrite, resp: FcallResponse) {
+        self.saw(FcallType::Twrite);
+        let mut shared = self.0.lock().unwrap();
+        let off = req.offset as usize;
+        shared.data.truncate(off);
+        shared.data.extend_from_slice(&req.data);
+        shared.version += 1;
+        resp.send(Rwrite {
+            count: req.data.len() as u32,
+        })
+    }
+
+    fn getattr(&mut self, _req: &Tgetattr, resp: FcallResponse) {
+        self.saw(FcallType::Tgetattr);
+        let time = Time { sec: 0, nsec: 0 };
+        let size = self.0.lock().unwrap().data.len() as u64;
+        resp.send(Rgetattr {
+            valid: GetattrMask::BASIC,
+            qid: self.file_qid(),
+            stat: Stat {
+                mode: 0o100644,
+                uid: 0,
+                gid: 0,
+                nlink: 1,
+                rdev: 0,
+                size,
+                blksize: 4096,
+                blocks: 0,
+                atime: time,
+                mtime: time,
+                ctime: time,
+                btime: time,
+                gen: 0,
+                data_version: 0,
+            },
+        })
+    }
+
+    fn clunk(&mut self, _req: &Tclunk, resp: FcallResponse) {
+        self.saw(FcallType::Tclunk);
+        resp.send(Rclunk {})
+    }
+}
+
+fn spawn_cached(fs: FileFs, cache: Arc<Cache>) -> (Client, std::thread::JoinHandle<()>) {
+    let (client_conn, proxy_conn) = UnixStream::pair().unwrap();
+    let (server_conn, fs_conn) = UnixStream::pair().unwrap();
+    let server = std::thread::spawn(move || {
+        let mut fs = fs;
+        serve_transport(fs_conn.try_clone().unwrap(), fs_conn, &mut fs, 8192);
+    });
+    let handle = std::thread::spawn(move || {
+        let _ = proxy_transport(
+            proxy_conn.try_clone().unwrap(),
+            proxy_conn,
+            server_conn.try_clone().unwrap(),
+            server_conn,
+            cache,
+        );
+        server.join().unwrap();
+    });
+    let client =
+        Client::over_transport(client_conn.try_clone().unwrap(), client_conn, 8192).unwrap();
+    (client, handle)
+}
+
+fn read_all(f: &ClientFid) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut buf = vec![0; 64];
+    loop {
+        match f.read(data.len() as u64, &mut buf).unwrap() {
+            0 => return data,
+            n => data.extend_from_slice(&buf[..n]),
+        }
+    }
+}
+
+#[test]
+fn cache_reads_and_writes_through() {
+    let fs = FileFs::default();
+    fs.0.lock().unwrap().data = b"hello".to_vec();
+    let cache = Arc::new(Cache::default());
+    let (client, proxy) = spawn_cached(fs.clone(), cache.clone());
+    let (_, root) = client.attach(0, "root", "/").unwrap();
+    let (_, f) = root.walk(&["f"]).unwrap();
+    f.open(LOpenFlags::O_RDWR).unwrap();
+    assert_eq!(read_all(&f), b"hello");
+    assert_eq!(read_all(&f), b"hello");
+    assert_eq!(read_all(&f), b"hello");
+    assert_eq!(
+        fs.seen(),
+        vec![
+            FcallType::Twalk,
+            FcallType::Tlopen,
+            FcallType::Tread,
+            FcallType::Tread
+        ]
+    );
+
+    f.write(0, b"bye").unwrap();
+    assert_eq!(read_all(&f), b"bye");
+    assert_eq!(
+        fs.seen(),
+        vec![FcallType::Twrite, FcallType::Tread, FcallType::Tread]
+    );
+
+    let stats = cache.stats();
+    assert_eq!(stats.hits, 4);
+    assert_eq!(stats.misses, 5);
+    assert!(stats.bytes > 0);
+    drop(f);
+    drop(root);
+    drop(client);
+    proxy.join().unwrap();
+}
+
+#[test]
+fn cache_lazy_walks() {
+    let fs = FileFs::default();
+    let cache = Arc::new(Cache::default());
+    let (client, proxy) = spawn_cached(fs.clone(), cache.clone());
+    let (_, root) = client.attach(0, "root", "/").unwrap();
+    let (_, f) = root.walk(&["f"]).unwrap();
+    f.getattr(GetattrMask::BASIC).unwrap();
+    f.clunk().unwrap();
+    fs.seen();
+
+    // Stat of a known file never reaches the server.
+    let (wqids, f) = root.walk(&["f"]).unwrap();
+    assert_eq!(wqids.len(), 1);
+    let attr = f.getattr(GetattrMask::BASIC).unwrap();
+    assert_eq!(attr.qid.path, 2);
+    f.clunk().unwrap();
+    assert_eq!(fs.seen(), vec![]);
+
+    // Opening makes the server walk the fid first.
+    let (_, f) = root.walk(&["f"]).unwrap();
+    f.open(LOpenFlags::O_RDONLY).unwrap();
+    assert_eq!(fs.seen(), vec![FcallType::Twalk, FcallType::Tlopen]);
+
+    // Unknown names still get the server's error.
+    let err = root.walk(&["g"]).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    drop(f);
+    drop(root);
+    drop(client);
+    proxy.join().unwrap();
+}
+
+#[test]
+fn cache_version_invalidates() {
+    let fs = FileFs::default();
+    fs.0.lock().unwrap().data = b"one".to_vec();
+    let cache = Arc::new(Cache::new(CacheOptions {
+        max_age: Duration::from_secs(3600),
+        ..CacheOptions::default()
+    }));
+    let (client, proxy) = spawn_cached(fs.clone(), cache.clone());
+    let (_, root) = client.attach(0, "root", "/").unwrap();
+    let (_, f) = root.walk(&["f"]).unwrap();
+    f.open(LOpenFlags::O_RDONLY).unwrap();
+    assert_eq!(read_all(&f), b"one");
+
+    // Changed behind the proxy's back, noticed when the file is reopened.
+    {
+        let mut shared = fs.0.lock().unwrap();
+        shared.data = b"two".to_vec();
+        shared.version += 1;
+    }
+    assert_eq!(read_all(&f), b"one");
+    let (_, f2) = root.walk(&["f"]).unwrap();
+    f2.open(LOpenFlags::O_RDONLY).unwrap();
+    assert_eq!(read_all(&f2), b"two");
+    drop(f);
+    drop(f2);
+    drop(root);
+    drop(client);
+    proxy.join().unwrap();
+}
+
+#[test]
+fn cache_budget() {
+    let fs = FileFs::default();
+    fs.0.lock().unwrap().data = vec![7; 4000];
+    let cache = Arc::new(Cache::new(CacheOptions {
+        budget: 1024,
+        ..CacheOptions::default()
+    }));
+    let (client, proxy) = spawn_cached(fs.clone(), cache.clone());
+    let (_, root) = client.attach(0, "root", "/").unwrap();
+    let (_, f) = root.walk(&["f"]).unwrap();
+    f.open(LOpenFlags::O_RDONLY).unwrap();
+    let mut buf = vec![0; 4000];
+    assert_eq!(f.read(0, &mut buf).unwrap(), 4000);
+    assert_eq!(f.read(0, &mut buf).unwrap(), 4000);
+    assert!(cache.stats().bytes <= 1024);
+    assert_eq!(cache.stats().hits, 0);
+    drop(f);
+    drop(root);
+    drop(client);
+    proxy.join().unwrap();
+}
+
+#[test]
+fn cache_short_reads() {
+    let fs = FileFs::default();
+    {
+        let mut shared = fs.0.lock().unwrap();
+        shared.data = b"hello world".to_vec();
+        shared.max_read = Some(4);
+    }
+    let cache = Arc::new(Cache::default());
+    let (client, proxy) = spawn_cached(fs.clone(), cache.clone());
+    let (_, root) = client.attach(0, "root", "/").unwrap();
+    let (_, f) = root.walk(&["f"]).unwrap();
+    f.open(LOpenFlags::O_RDONLY).unwrap();
+    fs.seen();
+    let mut buf = vec![0; 64];
+    assert_eq!(f.read(0, &mut buf).unwrap(), 4);
+
+    // A short read isn't the end of the file.
+    assert_eq!(f.read(4, &mut buf).unwrap(), 4);
+    assert_eq!(&buf[..4], b"o wo");
+    assert_eq!(fs.seen(), vec![FcallType::Tread, FcallType::Tread]);
+
+    // Once the size is known a short read up to it is.
+    f.getattr(GetattrMask::BASIC).unwrap();
+    fs.seen();
+    assert_eq!(f.read(8, &mut buf).unwrap(), 3);
+    assert_eq!(f.read(11, &mut buf).unwrap(), 0);
+    assert_eq!(read_all(&f), b"hello world");
+    assert_eq!(fs.seen(), vec![FcallType::Tread]);
+    drop(f);
+    drop(root);
+    drop(client);
+    proxy.join().unwrap();
+}
@@ -0,0 +1,308 @@
+mod common;
+
+use common::*;
+use p92000l::*;
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+// Answers Tversion with each of the given versions in turn, then hangs up.
+fn spawn_versions(versions: &'static [&'static str]) -> UnixStream {
+    let (conn, server_conn) = UnixStream::pair().unwrap();
+    std::thread::spawn(move || {
+        let mut r = server_conn.try_clone().unwrap();
+        let mut w = server_conn;
+        let mut rbuf = Vec::with_capacity(8192);
+        let mut wbuf = Vec::with_capacity(8192);
+        for version in versions {
+            let msize = match transport::read(&mut r, &mut rbuf) {
+                Ok(TaggedFcall {
+                    fcall: Fcall::Tversion(tversion),
+                    ..
+                }) => tversion.msize,
+                _ => return,
+            };
+            let rversion = TaggedFcall {
+                tag: NOTAG,
+                fcall: Rversion {
+                    msize,
+                    version: (*version).into(),
+                }
+                .into(),
+            };
+            transport::write(&mut w, &mut wbuf, &rversion).unwrap();
+        }
+    });
+    conn
+}
+
+#[test]
+fn builder_negotiates_msize() {
+    let (conn, server) = spawn_server(RootFs, 16384, ServeOptions::new());
+    let client = ClientBuilder::new()
+        .msize(65536)
+        .thread_name("p9 reader")
+        .over_unix_stream(conn)
+        .unwrap();
+    assert_eq!(client.msize(), 16384);
+    assert_eq!(client.version(), "9P2000.L");
+    assert_eq!(client.iounit(), 16384 - IOHDRSZ);
+    assert_eq!(client.readdir_size(), 16384 - READDIRHDRSZ);
+    client.attach(0, "root", "/").unwrap();
+    drop(client);
+    server.join().unwrap();
+
+    // The old constructor still raises small sizes to the minimum.
+    let (conn, server) = spawn_server(RootFs, 65536, ServeOptions::new());
+    let client = Client::over_unix_stream(conn, 1).unwrap();
+    assert_eq!(client.msize(), MIN_MSIZE);
+    drop(client);
+    server.join().unwrap();
+}
+
+#[test]
+fn builder_rejects_small_msize() {
+    let (conn, server) = spawn_server(RootFs, 8192, ServeOptions::new());
+    let err = ClientBuilder::new()
+        .min_msize(16384)
+        .over_unix_stream(conn)
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    server.join().unwrap();
+
+    let (conn, _) = UnixStream::pair().unwrap();
+    let err = ClientBuilder::new()
+        .msize(1024)
+        .over_unix_stream(conn)
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn builder_handshake_timeout() {
+    let (conn, _silent) = UnixStream::pair().unwrap();
+    let err = ClientBuilder::new()
+        .handshake_timeout(Duration::from_millis(50))
+        .over_unix_stream(conn)
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    let err = P9Error::downcast(&err).unwrap();
+    assert_eq!(err.kind, P9ErrorKind::Timeout);
+    assert_eq!(err.op, Some(FcallType::Tversion));
+}
+
+#[test]
+fn builder_dialects() {
+    let conn = spawn_versions(&["unknown", "9P2000.L"]);
+    let client = ClientBuilder::new()
+        .dialects(["9P2000.L.x", "9P2000.L"])
+        .over_unix_stream(conn)
+        .unwrap();
+    assert_eq!(client.version(), "9P2000.L");
+    drop(client);
+
+    let conn = spawn_versions(&["unknown"]);
+    assert!(ClientBuilder::new().over_unix_stream(conn).is_err());
+
+    let conn = spawn_versions(&["9P2000.u"]);
+    let err = ClientBuilder::new().over_unix_stream(conn).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn builder_dial() {
+    let path = std::env::temp_dir().join(format!("p9-dial-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+    let server = std::thread::spawn(move || {
+        let (conn, _) = listener.accept().unwrap();
+        let wconn = conn.try_clone().unwrap();
+        serve_transport(conn, wconn, &mut RootFs, 8192);
+    });
+    let client = ClientBuilder::new()
+        .dial(&format!("unix:{}", path.display()))
+        .unwrap();
+    client.attach(0, "", "").unwrap();
+    drop(client);
+    server.join().unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let err = ClientBuilder::new()
+        .dial("udp:localhost:564")
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn raw_fcall() {
+    let (conn, _server) = spawn_server(RootFs, 8192, ServeOptions::new());
+    let client = Client::over_unix_stream(conn, 8192).unwrap();
+    assert!(client.reserve_fid(100));
+    assert!(!client.reserve_fid(100));
+    assert!(!client.reserve_fid(NOFID));
+
+    let resp = client
+        .raw_fcall(
+            Tattach {
+                fid: 100,
+                afid: NOFID,
+                uname: "".into(),
+                aname: "".into(),
+                n_uname: 0,
+            }
+            .into(),
+        )
+        .unwrap();
+    assert!(matches!(resp, Fcall::Rattach(_)));
+    // The client never hands out a reserved fid.
+    let (_, root) = client.attach(0, "", "").unwrap();
+    assert_ne!(root.id(), 100);
+
+    // Errors are responses like any other.
+    let resp = client.raw_fcall(Tfsync { fid: 100 }.into()).unwrap();
+    assert_eq!(
+        resp,
+        Fcall::Rlerror(Rlerror {
+            ecode: errno::EOPNOTSUPP
+        })
+    );
+    let resp = client.raw_fcall(Tclunk { fid: 100 }.into()).unwrap();
+    assert!(matches!(resp, Fcall::Rclunk(_)));
+    client.release_fid(100);
+    assert!(client.reserve_fid(100));
+}
+
+#[test]
+fn fid_requests() {
+    let fs = CannedFs::default();
+    let (conn, server) = spawn_server(fs.clone(), 8192, ServeOptions::new());
+    let client = Client::over_unix_stream(conn, 8192).unwrap();
+    let (_, root) = client.attach(0, "root", "/").unwrap();
+    let (_, f) = root.walk(&[] as &[&str]).unwrap();
+    fs.seen();
+
+    assert_eq!(root.statfs().unwrap().namelen, 255);
+    assert_eq!(root.symlink("s", "target", 5).unwrap().path, 2);
+    assert_eq!(root.mknod("n", 0o20644, 1, 3, 5).unwrap().path, 2);
+    assert_eq!(f.readlink().unwrap().as_bytes(), b"target");
+    f.link(&root, "l").unwrap();
+    let held = f
+        .getlock(Getlock {
+            typ: LockType::RDLOCK,
+            start: 0,
+            length: 100,
+            proc_id: 1,
+            client_id: "me".into(),
+        })
+        .unwrap();
+    assert_eq!(held.typ, LockType::WRLOCK);
+    assert_eq!((held.start, held.length, held.proc_id), (10, 0, 9));
+    assert_eq!(held.client_id.as_bytes(), b"holder");
+
+    let (rootid, fid) = (root.id(), f.id());
+    assert_eq!(
+        fs.seen(),
+        vec![
+            Tstatfs { fid: rootid }.into(),
+            Tsymlink {
+                fid: rootid,
+                name: "s".into(),
+                symtgt: "target".into(),
+                gid: 5,
+            }
+            .into(),
+            Tmknod {
+                dfid: rootid,
+                name: "n".into(),
+                mode: 0o20644,
+                major: 1,
+                minor: 3,
+                gid: 5,
+            }
+            .into(),
+            Treadlink { fid }.into(),
+            Tlink {
+                dfid: rootid,
+                fid,
+                name: "l".into(),
+            }
+            .into(),
+            Tgetlock {
+                fid,
+                flock: Getlock {
+                    typ: LockType::RDLOCK,
+                    start: 0,
+                    length: 100,
+                    proc_id: 1,
+                    client_id: "me".into(),
+                },
+            }
+            .into(),
+        ]
+    );
+    drop(f);
+    drop(root);
+    drop(client);
+    server.join().unwrap();
+}
+
+#[test]
+fn xattrs() {
+    let fs = CannedFs::default();
+    let (conn, server) = spawn_server(fs.clone(), 8192, ServeOptions::new());
+    let client = Client::over_unix_stream(conn, 8192).unwrap();
+    let (_, root) = client.attach(0, "root", "/").unwrap();
+    let (_, w) = root.walk(&[] as &[&str]).unwrap();
+    fs.seen();
+
+    let (size, x) = root.xattrwalk("user.a").unwrap();
+    assert_eq!(size, CannedFs::XATTR.len() as u64);
+    let mut buf = vec![0; 64];
+    let n = x.read(0, &mut buf).unwrap();
+    assert_eq!(&buf[..n], CannedFs::XATTR);
+    let xid = x.id();
+    x.clunk().unwrap();
+
+    // The attribute is written through the fid it was created on.
+    w.xattrcreate("user.b", 3, 1).unwrap();
+    assert_eq!(w.write(0, b"new").unwrap(), 3);
+    let wid = w.id();
+    w.clunk().unwrap();
+
+    assert_eq!(
+        fs.seen(),
+        vec![
+            Txattrwalk {
+                fid: root.id(),
+                new_fid: xid,
+                name: "user.a".into(),
+            }
+            .into(),
+            Tread {
+                fid: xid,
+                offset: 0,
+                count: 64,
+            }
+            .into(),
+            Txattrcreate {
+                fid: wid,
+                name: "user.b".into(),
+                attr_size: 3,
+                flags: 1,
+            }
+            .into(),
+            Twrite {
+                fid: wid,
+                offset: 0,
+                data: b"new"[..].into(),
+            }
+            .into(),
+        ]
+    );
+    drop(root);
+    drop(client);
+    server.join().unwrap();
+}
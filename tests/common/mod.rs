@@ -0,0 +1,183 @@
+// Fixtures shared by the integration tests, not every test uses all of them.
+#![allow(dead_code)]
+
+use p92000l::*;
+use std::borrow::Cow;
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+
+// An empty root directory.
+pub struct RootFs;
+
+impl Filesystem for RootFs {
+    fn attach(&mut self, _req: &Tattach, resp: FcallResponse) {
+        resp.send(Rattach {
+            qid: Qid {
+                typ: QidType::DIR,
+                version: 0,
+                path: 1,
+            },
+        })
+    }
+
+    fn walk(&mut self, req: &Twalk, resp: FcallResponse) {
+        if req.wnames.is_empty() {
+            resp.send(Rwalk { wqids: Vec::new() })
+        } else {
+            resp.send(Rlerror {
+                ecode: errno::ENOENT,
+            })
+        }
+    }
+
+    fn statfs(&mut self, _req: &Tstatfs, resp: FcallResponse) {
+        resp.send(Rstatfs {
+            statfs: Statfs {
+                typ: 0,
+                bsize: 4096,
+                blocks: 0,
+                bfree: 0,
+                bavail: 0,
+                files: 0,
+                ffree: 0,
+                fsid: 0,
+                namelen: 255,
+            },
+        })
+    }
+
+    fn clunk(&mut self, _req: &Tclunk, resp: FcallResponse) {
+        resp.send(Rclunk {})
+    }
+}
+
+fn file_qid() -> Qid {
+    Qid {
+        typ: QidType::FILE,
+        version: 0,
+        path: 2,
+    }
+}
+
+// A root directory answering the rest of the requests with made up data,
+// keeping every request to check what the client sent.
+#[derive(Clone, Default)]
+pub struct CannedFs {
+    seen: Arc<Mutex<Vec<Fcall<'static>>>>,
+}
+
+impl CannedFs {
+    // The XATTR value read from any xattr fid.
+    pub const XATTR: &'static [u8] = b"value";
+
+    // The requests seen since the last call, besides attach and clunk.
+    pub fn seen(&self) -> Vec<Fcall<'static>> {
+        std::mem::take(&mut self.seen.lock().unwrap())
+    }
+
+    fn saw<'a>(&self, req: impl Into<Fcall<'a>>) {
+        self.seen.lock().unwrap().push(req.into().clone_static());
+    }
+}
+
+impl Filesystem for CannedFs {
+    fn attach(&mut self, req: &Tattach, resp: FcallResponse) {
+        RootFs.attach(req, resp)
+    }
+
+    fn walk(&mut self, req: &Twalk, resp: FcallResponse) {
+        self.saw(req.clone());
+        RootFs.walk(req, resp)
+    }
+
+    fn statfs(&mut self, req: &Tstatfs, resp: FcallResponse) {
+        self.saw(req.clone());
+        RootFs.statfs(req, resp)
+    }
+
+    fn symlink(&mut self, req: &Tsymlink, resp: FcallResponse) {
+        self.saw(req.clone());
+        resp.send(Rsymlink { qid: file_qid() })
+    }
+
+    fn mknod(&mut self, req: &Tmknod, resp: FcallResponse) {
+        self.saw(req.clone());
+        resp.send(Rmknod { qid: file_qid() })
+    }
+
+    fn readlink(&mut self, req: &Treadlink, resp: FcallResponse) {
+        self.saw(req.clone());
+        resp.send(Rreadlink {
+            target: "target".into(),
+        })
+    }
+
+    fn link(&mut self, req: &Tlink, resp: FcallResponse) {
+        self.saw(req.clone());
+        resp.send(Rlink {})
+    }
+
+    fn xattrwalk(&mut self, req: &Txattrwalk, resp: FcallResponse) {
+        self.saw(req.clone());
+        resp.send(Rxattrwalk {
+            size: CannedFs::XATTR.len() as u64,
+        })
+    }
+
+    fn xattrcreate(&mut self, req: &Txattrcreate, resp: FcallResponse) {
+        self.saw(req.clone());
+        resp.send(Rxattrcreate {})
+    }
+
+    fn read(&mut self, req: &Tread, resp: FcallResponse) {
+        self.saw(req.clone());
+        let data = CannedFs::XATTR;
+        let start = (req.offset as usize).min(data.len());
+        let end = (start + req.count as usize).min(data.len());
+        resp.send(Rread {
+            data: Cow::from(&data[start..end]),
+        })
+    }
+
+    fn write(&mut self, req: &Twrite, resp: FcallResponse) {
+        self.saw(req.clone());
+        resp.send(Rwrite {
+            count: req.data.len() as u32,
+        })
+    }
+
+    fn getlock(&mut self, req: &Tgetlock, resp: FcallResponse) {
+        self.saw(req.clone());
+        resp.send(Rgetlock {
+            flock: Getlock {
+                typ: LockType::WRLOCK,
+                start: 10,
+                length: 0,
+                proc_id: 9,
+                client_id: "holder".into(),
+            },
+        })
+    }
+
+    fn clunk(&mut self, req: &Tclunk, resp: FcallResponse) {
+        RootFs.clunk(req, resp)
+    }
+}
+
+// Serve fs on one end of a socket pair, returning the other.
+pub fn spawn_server<F>(
+    fs: F,
+    bufsize: usize,
+    options: ServeOptions,
+) -> (UnixStream, std::thread::JoinHandle<()>)
+where
+    F: Filesystem + Send + 'static,
+{
+    let (conn, server_conn) = UnixStream::pair().unwrap();
+    let handle = std::thread::spawn(move || {
+        let mut fs = fs;
+        let wconn = server_conn.try_clone().unwrap();
+        serve_transport_with(server_conn, wconn, &mut fs, bufsize, &options);
+    });
+    (conn, handle)
+}
@@ -0,0 +1,51 @@
+use p92000l::*;
+use std::io::{Error, ErrorKind};
+
+#[test]
+fn errno_round_trip() {
+    for (ecode, kind) in [
+        (errno::ENOENT, ErrorKind::NotFound),
+        (errno::EEXIST, ErrorKind::AlreadyExists),
+        (errno::ENOTEMPTY, ErrorKind::DirectoryNotEmpty),
+        (errno::EXDEV, ErrorKind::CrossesDevices),
+        (errno::ENOTDIR, ErrorKind::NotADirectory),
+        (errno::ESTALE, ErrorKind::StaleNetworkFileHandle),
+        (errno::EROFS, ErrorKind::ReadOnlyFilesystem),
+        (errno::EACCES, ErrorKind::PermissionDenied),
+        (errno::EPERM, ErrorKind::PermissionDenied),
+    ] {
+        let err = Rlerror { ecode }.into_io_error();
+        assert_eq!(err.kind(), kind, "{}", errno::strerror(ecode));
+        assert_eq!(errno::errno_from_io_error(&err), ecode);
+        assert_eq!(Rlerror::from(err).ecode, ecode);
+        assert_eq!(errno::io_error_kind(ecode), kind);
+    }
+    // EALREADY is an operation in progress, not an existing file.
+    assert_ne!(
+        Rlerror {
+            ecode: errno::EALREADY
+        }
+        .into_io_error()
+        .kind(),
+        ErrorKind::AlreadyExists
+    );
+}
+
+#[test]
+fn errno_from_kind_and_payload() {
+    for (kind, ecode) in [
+        (ErrorKind::AlreadyExists, errno::EEXIST),
+        (ErrorKind::DirectoryNotEmpty, errno::ENOTEMPTY),
+        (ErrorKind::CrossesDevices, errno::EXDEV),
+        (ErrorKind::NotADirectory, errno::ENOTDIR),
+        (ErrorKind::StaleNetworkFileHandle, errno::ESTALE),
+        (ErrorKind::StorageFull, errno::ENOSPC),
+        (ErrorKind::Other, errno::EIO),
+    ] {
+        assert_eq!(Rlerror::from(Error::from(kind)).ecode, ecode);
+    }
+    let err = Error::other(errno::Errno(errno::EHWPOISON));
+    assert_eq!(Rlerror::from(err).ecode, errno::EHWPOISON);
+    let err = Error::from_raw_os_error(errno::host_errno(errno::ENOTEMPTY).unwrap());
+    assert_eq!(Rlerror::from(err).ecode, errno::ENOTEMPTY);
+}
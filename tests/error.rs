@@ -0,0 +1,84 @@
+mod common;
+
+use common::*;
+use p92000l::*;
+use std::os::unix::net::UnixStream;
+
+#[test]
+fn remote_errno_error() {
+    let fs = FaultFs::new(RootFs, 0)
+        .rule(FaultRule::new(Fault::Errno(errno::ENOTDIR)).op(FcallType::Twalk));
+    let (conn, server) = spawn_server(fs, 8192, ServeOptions::new());
+    let client = Client::over_unix_stream(conn, 8192).unwrap();
+    let (_, root) = client.attach(0, "root", "/").unwrap();
+
+    let err = root.walk(&["a", "b"]).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotADirectory);
+    assert_eq!(errno::errno_from_io_error(&err), errno::ENOTDIR);
+    let p9err = P9Error::downcast(&err).unwrap();
+    assert_eq!(p9err.kind, P9ErrorKind::RemoteErrno(errno::ENOTDIR));
+    assert_eq!(p9err.op, Some(FcallType::Twalk));
+    assert!(p9err.fid.is_some());
+    assert_eq!(p9err.path, vec!["a", "b"]);
+    assert!(err.to_string().starts_with("Twalk fid="), "{}", err);
+    assert!(
+        err.to_string().ends_with("\"a/b\": ENOTDIR (errno 20)"),
+        "{}",
+        err
+    );
+
+    // The original is recoverable by value too.
+    let p9err = err.into_inner().unwrap().downcast::<P9Error>().unwrap();
+    assert_eq!(p9err.kind, P9ErrorKind::RemoteErrno(errno::ENOTDIR));
+    drop(root);
+    drop(client);
+    server.join().unwrap();
+}
+
+#[test]
+fn protocol_and_disconnected_errors() {
+    let (conn, server_conn) = UnixStream::pair().unwrap();
+    // Answers every request with Rclunk, then hangs up.
+    let server = std::thread::spawn(move || {
+        let mut r = server_conn.try_clone().unwrap();
+        let mut w = server_conn;
+        let mut rbuf = Vec::with_capacity(8192);
+        let mut wbuf = Vec::with_capacity(8192);
+        let rversion = match transport::read(&mut r, &mut rbuf).unwrap() {
+            TaggedFcall {
+                tag,
+                fcall: Fcall::Tversion(Tversion { msize, version }),
+            } => TaggedFcall {
+                tag,
+                fcall: Rversion {
+                    msize,
+                    version: version.clone_static(),
+                }
+                .into(),
+            },
+            _ => panic!("expected Tversion"),
+        };
+        transport::write(&mut w, &mut wbuf, &rversion).unwrap();
+        let tag = transport::read(&mut r, &mut rbuf).unwrap().tag;
+        let rclunk = TaggedFcall {
+            tag,
+            fcall: Rclunk {}.into(),
+        };
+        transport::write(&mut w, &mut wbuf, &rclunk).unwrap();
+    });
+    let client = Client::over_unix_stream(conn, 8192).unwrap();
+
+    let err = client.attach(0, "root", "/").err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    let p9err = P9Error::downcast(&err).unwrap();
+    assert_eq!(p9err.kind, P9ErrorKind::Protocol(FcallType::Rclunk));
+    assert_eq!(p9err.op, Some(FcallType::Tattach));
+    assert_eq!(errno::errno_from_io_error(&err), errno::EPROTO);
+
+    server.join().unwrap();
+    let err = client.attach(0, "root", "/").err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotConnected);
+    let p9err = P9Error::downcast(&err).unwrap();
+    assert_eq!(p9err.kind, P9ErrorKind::Disconnected);
+    assert_eq!(p9err.to_string(), "Tattach fid=1 \"/\": disconnected");
+}
@@ -0,0 +1,177 @@
+mod common;
+
+use common::RootFs;
+use p92000l::*;
+use std::time::{Duration, Instant};
+
+fn spawn_server(fs: FaultFs<RootFs>) -> (Loopback, std::thread::JoinHandle<()>) {
+    let (conn, server_conn) = loopback_pair();
+    let server = std::thread::spawn(move || {
+        let mut fs = fs;
+        serve_transport(server_conn.clone(), server_conn, &mut fs, 8192)
+    });
+    (conn, server)
+}
+
+fn client(fs: FaultFs<RootFs>) -> (Client, std::thread::JoinHandle<()>) {
+    let (conn, server) = spawn_server(fs);
+    let client = ClientBuilder::new()
+        .over_transport(conn.clone(), conn)
+        .unwrap();
+    (client, server)
+}
+
+// Talks to the server a message at a time, to see exactly what it sends.
+struct Raw {
+    conn: Loopback,
+    buf: Vec<u8>,
+}
+
+impl Raw {
+    fn new(conn: Loopback) -> Raw {
+        let mut raw = Raw {
+            conn,
+            buf: Vec::with_capacity(8192),
+        };
+        raw.send(
+            NOTAG,
+            Tversion {
+                msize: 8192,
+                version: "9P2000.L".into(),
+            },
+        );
+        assert!(matches!(raw.recv().unwrap().fcall, Fcall::Rversion(_)));
+        raw
+    }
+
+    fn send<'a>(&mut self, tag: u16, fcall: impl Into<Fcall<'a>>) {
+        let fcall = TaggedFcall {
+            tag,
+            fcall: fcall.into(),
+        };
+        write(&mut self.conn, &mut self.buf, &fcall).unwrap();
+    }
+
+    fn recv(&mut self) -> Option<TaggedFcall<'_>> {
+        match read_to_buf_timeout(&mut self.conn, &mut self.buf, Duration::from_millis(50)) {
+            Ok(()) => Some(TaggedFcall::decode(&self.buf).unwrap()),
+            Err(err) if err.kind() == std::io::ErrorKind::TimedOut => None,
+            Err(err) => panic!("{}", err),
+        }
+    }
+}
+
+fn tattach(fid: u32) -> Tattach<'static> {
+    Tattach {
+        fid,
+        afid: NOFID,
+        uname: "root".into(),
+        aname: "".into(),
+        n_uname: 0,
+    }
+}
+
+#[test]
+fn errno_by_op_and_fid() {
+    let fs = FaultFs::new(RootFs, 1)
+        .rule(FaultRule::new(Fault::Errno(errno::EIO)).op(FcallType::Tstatfs))
+        .rule(FaultRule::new(Fault::Errno(errno::EACCES)).fid(7));
+    let control = fs.control();
+    let (client, server) = client(fs);
+    let (_, root) = client.attach(0, "root", "").unwrap();
+    let err = root.statfs().unwrap_err();
+    assert_eq!(errno::errno_from_io_error(&err), errno::EIO);
+    // Only Tattach on fid 7 fails.
+    let resp = client.raw_fcall(tattach(7).into()).unwrap();
+    assert_eq!(
+        resp,
+        Fcall::Rlerror(Rlerror {
+            ecode: errno::EACCES
+        })
+    );
+    assert_eq!(control.injected(), 2);
+
+    control.clear();
+    root.statfs().unwrap();
+    drop(root);
+    drop(client);
+    server.join().unwrap();
+}
+
+#[test]
+fn times() {
+    let fs = FaultFs::new(RootFs, 1).rule(
+        FaultRule::new(Fault::Errno(errno::EAGAIN))
+            .op(FcallType::Tstatfs)
+            .times(2),
+    );
+    let (client, _server) = client(fs);
+    let (_, root) = client.attach(0, "root", "").unwrap();
+    assert!(root.statfs().is_err());
+    assert!(root.statfs().is_err());
+    root.statfs().unwrap();
+}
+
+#[test]
+fn probability_is_reproducible() {
+    let failures = |seed| {
+        let fs = FaultFs::new(RootFs, seed).rule(
+            FaultRule::new(Fault::Errno(errno::EIO))
+                .op(FcallType::Tstatfs)
+                .probability(0.5),
+        );
+        let (client, _server) = client(fs);
+        let (_, root) = client.attach(0, "root", "").unwrap();
+        (0..64)
+            .map(|_| root.statfs().is_err())
+            .collect::<Vec<bool>>()
+    };
+    let got = failures(3);
+    let n = got.iter().filter(|failed| **failed).count();
+    assert!(n > 8 && n < 56, "{}", n);
+    assert_eq!(got, failures(3));
+    assert_ne!(got, failures(4));
+}
+
+#[test]
+fn delay() {
+    let fs = FaultFs::new(RootFs, 1)
+        .rule(FaultRule::new(Fault::Delay(Duration::from_millis(30))).op(FcallType::Tstatfs))
+        .rule(FaultRule::new(Fault::Errno(errno::EIO)).op(FcallType::Tstatfs));
+    let (client, _server) = client(fs);
+    let (_, root) = client.attach(0, "root", "").unwrap();
+    let start = Instant::now();
+    // Delays combine with the fault after them.
+    assert!(root.statfs().is_err());
+    assert!(start.elapsed() >= Duration::from_millis(30));
+}
+
+#[test]
+fn drop_and_duplicate() {
+    let fs = FaultFs::new(RootFs, 1)
+        .rule(FaultRule::new(Fault::Drop).op(FcallType::Tattach).times(1))
+        .rule(FaultRule::new(Fault::Duplicate).op(FcallType::Tclunk));
+    let (conn, _server) = spawn_server(fs);
+    let mut raw = Raw::new(conn);
+
+    raw.send(1, tattach(0));
+    assert!(raw.recv().is_none());
+    // The filesystem still handled the dropped request.
+    raw.send(2, Tclunk { fid: 0 });
+    for _ in 0..2 {
+        let resp = raw.recv().unwrap();
+        assert_eq!(resp.tag, 2);
+        assert!(matches!(resp.fcall, Fcall::Rclunk(_)));
+    }
+    assert!(raw.recv().is_none());
+}
+
+#[test]
+fn kill() {
+    let fs = FaultFs::new(RootFs, 1).rule(FaultRule::new(Fault::Kill).op(FcallType::Tstatfs));
+    let (client, server) = client(fs);
+    let (_, root) = client.attach(0, "root", "").unwrap();
+    let err = root.statfs().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotConnected);
+    server.join().unwrap();
+}
@@ -0,0 +1,1004 @@
+use p92000l::fcall::*;
+use p92000l::transport;
+use proptest::prelude::*;
+use proptest::strategy::Union;
+use std::borrow::Cow;
+
+fn encode(fcall: &TaggedFcall) -> Vec<u8> {
+    let mut buf = Vec::new();
+    fcall.encode_to_buf(&mut buf).unwrap();
+    buf
+}
+
+const HDRSZ: usize = 4 + 1 + 2;
+const QIDSZ: usize = 1 + 4 + 8;
+const TIMESZ: usize = 8 + 8;
+const STATSZ: usize = 4 * 3 + 8 * 5 + TIMESZ * 4 + 8 * 2;
+const STATFSSZ: usize = 4 * 2 + 8 * 6 + 4;
+const SETATTRSZ: usize = 4 * 3 + 8 + TIMESZ * 2;
+
+fn strsz(s: &FcallStr) -> usize {
+    2 + s.len()
+}
+
+// Message sizes as laid out in the 9P2000.L specification, computed
+// independently of the encoder.
+fn spec_size(fcall: &Fcall) -> usize {
+    HDRSZ
+        + match fcall {
+            Fcall::Rlerror(_) => 4,
+            Fcall::Tattach(v) => 4 + 4 + strsz(&v.uname) + strsz(&v.aname) + 4,
+            Fcall::Rattach(_) => QIDSZ,
+            Fcall::Tstatfs(_) => 4,
+            Fcall::Rstatfs(_) => STATFSSZ,
+            Fcall::Tlopen(_) => 4 + 4,
+            Fcall::Rlopen(_) => QIDSZ + 4,
+            Fcall::Tlcreate(v) => 4 + strsz(&v.name) + 4 + 4 + 4,
+            Fcall::Rlcreate(_) => QIDSZ + 4,
+            Fcall::Tsymlink(v) => 4 + strsz(&v.name) + strsz(&v.symtgt) + 4,
+            Fcall::Rsymlink(_) => QIDSZ,
+            Fcall::Tmknod(v) => 4 + strsz(&v.name) + 4 * 4,
+            Fcall::Rmknod(_) => QIDSZ,
+            Fcall::Trename(v) => 4 + 4 + strsz(&v.name),
+            Fcall::Rrename(_) => 0,
+            Fcall::Treadlink(_) => 4,
+            Fcall::Rreadlink(v) => strsz(&v.target),
+            Fcall::Tgetattr(_) => 4 + 8,
+            Fcall::Rgetattr(_) => 8 + QIDSZ + STATSZ,
+            Fcall::Tsetattr(_) => 4 + 4 + SETATTRSZ,
+            Fcall::Rsetattr(_) => 0,
+            Fcall::Txattrwalk(v) => 4 + 4 + strsz(&v.name),
+            Fcall::Rxattrwalk(_) => 8,
+            Fcall::Txattrcreate(v) => 4 + strsz(&v.name) + 8 + 4,
+            Fcall::Rxattrcreate(_) => 0,
+            Fcall::Treaddir(_) => 4 + 8 + 4,
+            Fcall::Rreaddir(v) => {
+                4 + v
+                    .data
+                    .data
+                    .iter()
+                    .map(|e| QIDSZ + 8 + 1 + strsz(&e.name))
+                    .sum::<usize>()
+            }
+            Fcall::Tfsync(_) => 4,
+            Fcall::Rfsync(_) => 0,
+            Fcall::Tlock(v) => 4 + 1 + 4 + 8 + 8 + 4 + strsz(&v.flock.client_id),
+            Fcall::Rlock(_) => 1,
+            Fcall::Tgetlock(v) => 4 + 1 + 8 + 8 + 4 + strsz(&v.flock.client_id),
+            Fcall::Rgetlock(v) => 1 + 8 + 8 + 4 + strsz(&v.flock.client_id),
+            Fcall::Tlink(v) => 4 + 4 + strsz(&v.name),
+            Fcall::Rlink(_) => 0,
+            Fcall::Tmkdir(v) => 4 + strsz(&v.name) + 4 + 4,
+            Fcall::Rmkdir(_) => QIDSZ,
+            Fcall::Trenameat(v) => 4 + strsz(&v.oldname) + 4 + strsz(&v.newname),
+            Fcall::Rrenameat(_) => 0,
+            Fcall::Tunlinkat(v) => 4 + strsz(&v.name) + 4,
+            Fcall::Runlinkat(_) => 0,
+            Fcall::Tauth(v) => 4 + strsz(&v.uname) + strsz(&v.aname) + 4,
+            Fcall::Rauth(_) => QIDSZ,
+            Fcall::Tversion(v) => 4 + strsz(&v.version),
+            Fcall::Rversion(v) => 4 + strsz(&v.version),
+            Fcall::Tflush(_) => 2,
+            Fcall::Rflush(_) => 0,
+            Fcall::Twalk(v) => 4 + 4 + 2 + v.wnames.iter().map(strsz).sum::<usize>(),
+            Fcall::Rwalk(v) => 2 + QIDSZ * v.wqids.len(),
+            Fcall::Tread(_) => 4 + 8 + 4,
+            Fcall::Rread(v) => 4 + v.data.len(),
+            Fcall::Twrite(v) => 4 + 8 + 4 + v.data.len(),
+            Fcall::Rwrite(_) => 4,
+            Fcall::Tclunk(_) => 4,
+            Fcall::Rclunk(_) => 0,
+            Fcall::Tremove(_) => 4,
+            Fcall::Rremove(_) => 0,
+        }
+}
+
+fn fcall_str() -> impl Strategy<Value = FcallStr<'static>> {
+    proptest::collection::vec(any::<u8>(), 0..32).prop_map(FcallStr::Owned)
+}
+
+fn data() -> impl Strategy<Value = Cow<'static, [u8]>> {
+    proptest::collection::vec(any::<u8>(), 0..512).prop_map(Cow::Owned)
+}
+
+fn qid() -> impl Strategy<Value = Qid> {
+    (any::<u8>(), any::<u32>(), any::<u64>()).prop_map(|(typ, version, path)| Qid {
+        typ: QidType::from_bits_truncate(typ),
+        version,
+        path,
+    })
+}
+
+fn time() -> impl Strategy<Value = Time> {
+    (any::<u64>(), any::<u64>()).prop_map(|(sec, nsec)| Time { sec, nsec })
+}
+
+fn stat() -> impl Strategy<Value = Stat> {
+    (
+        (any::<u32>(), any::<u32>(), any::<u32>()),
+        (any::<u64>(), any::<u64>(), any::<u64>()),
+        (any::<u64>(), any::<u64>()),
+        (time(), time(), time(), time()),
+        (any::<u64>(), any::<u64>()),
+    )
+        .prop_map(
+            |(
+                (mode, uid, gid),
+                (nlink, rdev, size),
+                (blksize, blocks),
+                (atime, mtime, ctime, btime),
+                (gen, data_version),
+            )| Stat {
+                mode,
+                uid,
+                gid,
+                nlink,
+                rdev,
+                size,
+                blksize,
+                blocks,
+                atime,
+                mtime,
+                ctime,
+                btime,
+                gen,
+                data_version,
+            },
+        )
+}
+
+fn statfs() -> impl Strategy<Value = Statfs> {
+    (
+        (any::<u32>(), any::<u32>()),
+        (any::<u64>(), any::<u64>(), any::<u64>()),
+        (any::<u64>(), any::<u64>(), any::<u64>()),
+        any::<u32>(),
+    )
+        .prop_map(
+            |((typ, bsize), (blocks, bfree, bavail), (files, ffree, fsid), namelen)| Statfs {
+                typ,
+                bsize,
+                blocks,
+                bfree,
+                bavail,
+                files,
+                ffree,
+                fsid,
+                namelen,
+            },
+        )
+}
+
+fn setattr() -> impl Strategy<Value = SetAttr> {
+    (
+        any::<u32>(),
+        any::<u32>(),
+        any::<u32>(),
+        any::<u64>(),
+        time(),
+        time(),
+    )
+        .prop_map(|(mode, uid, gid, size, atime, mtime)| SetAttr {
+            mode,
+            uid,
+            gid,
+            size,
+            atime,
+            mtime,
+        })
+}
+
+fn lopen_flags() -> impl Strategy<Value = LOpenFlags> {
+    any::<u32>().prop_map(LOpenFlags::from_bits_truncate)
+}
+
+fn lock_type() -> impl Strategy<Value = LockType> {
+    any::<u8>().prop_map(LockType::from_bits_truncate)
+}
+
+fn flock() -> impl Strategy<Value = Flock<'static>> {
+    (
+        lock_type(),
+        any::<u32>(),
+        any::<u64>(),
+        any::<u64>(),
+        any::<u32>(),
+        fcall_str(),
+    )
+        .prop_map(|(typ, flags, start, length, proc_id, client_id)| Flock {
+            typ,
+            flags: LockFlag::from_bits_truncate(flags),
+            start,
+            length,
+            proc_id,
+            client_id,
+        })
+}
+
+fn getlock() -> impl Strategy<Value = Getlock<'static>> {
+    (
+        lock_type(),
+        any::<u64>(),
+        any::<u64>(),
+        any::<u32>(),
+        fcall_str(),
+    )
+        .prop_map(|(typ, start, length, proc_id, client_id)| Getlock {
+            typ,
+            start,
+            length,
+            proc_id,
+            client_id,
+        })
+}
+
+fn direntry() -> impl Strategy<Value = DirEntry<'static>> {
+    (qid(), any::<u64>(), any::<u8>(), fcall_str()).prop_map(|(qid, offset, typ, name)| DirEntry {
+        qid,
+        offset,
+        typ,
+        name,
+    })
+}
+
+fn fcall() -> impl Strategy<Value = Fcall<'static>> {
+    let strategies: Vec<BoxedStrategy<Fcall<'static>>> = vec![
+        any::<u32>()
+            .prop_map(|ecode| Rlerror { ecode }.into())
+            .boxed(),
+        (
+            any::<u32>(),
+            any::<u32>(),
+            fcall_str(),
+            fcall_str(),
+            any::<u32>(),
+        )
+            .prop_map(|(fid, afid, uname, aname, n_uname)| {
+                Tattach {
+                    fid,
+                    afid,
+                    uname,
+                    aname,
+                    n_uname,
+                }
+                .into()
+            })
+            .boxed(),
+        qid().prop_map(|qid| Rattach { qid }.into()).boxed(),
+        any::<u32>().prop_map(|fid| Tstatfs { fid }.into()).boxed(),
+        statfs()
+            .prop_map(|statfs| Rstatfs { statfs }.into())
+            .boxed(),
+        (any::<u32>(), lopen_flags())
+            .prop_map(|(fid, flags)| Tlopen { fid, flags }.into())
+            .boxed(),
+        (qid(), any::<u32>())
+            .prop_map(|(qid, iounit)| Rlopen { qid, iounit }.into())
+            .boxed(),
+        (
+            any::<u32>(),
+            fcall_str(),
+            lopen_flags(),
+            any::<u32>(),
+            any::<u32>(),
+        )
+            .prop_map(|(fid, name, flags, mode, gid)| {
+                Tlcreate {
+                    fid,
+                    name,
+                    flags,
+                    mode,
+                    gid,
+                }
+                .into()
+            })
+            .boxed(),
+        (qid(), any::<u32>())
+            .prop_map(|(qid, iounit)| Rlcreate { qid, iounit }.into())
+            .boxed(),
+        (any::<u32>(), fcall_str(), fcall_str(), any::<u32>())
+            .prop_map(|(fid, name, symtgt, gid)| {
+                Tsymlink {
+                    fid,
+                    name,
+                    symtgt,
+                    gid,
+                }
+                .into()
+            })
+            .boxed(),
+        qid().prop_map(|qid| Rsymlink { qid }.into()).boxed(),
+        (
+            any::<u32>(),
+            fcall_str(),
+            any::<u32>(),
+            any::<u32>(),
+            any::<u32>(),
+            any::<u32>(),
+        )
+            .prop_map(|(dfid, name, mode, major, minor, gid)| {
+                Tmknod {
+                    dfid,
+                    name,
+                    mode,
+                    major,
+                    minor,
+                    gid,
+                }
+                .into()
+            })
+            .boxed(),
+        qid().prop_map(|qid| Rmknod { qid }.into()).boxed(),
+        (any::<u32>(), any::<u32>(), fcall_str())
+            .prop_map(|(fid, dfid, name)| Trename { fid, dfid, name }.into())
+            .boxed(),
+        Just(Rrename {}.into()).boxed(),
+        any::<u32>()
+            .prop_map(|fid| Treadlink { fid }.into())
+            .boxed(),
+        fcall_str()
+            .prop_map(|target| Rreadlink { target }.into())
+            .boxed(),
+        (any::<u32>(), any::<u64>())
+            .prop_map(|(fid, mask)| {
+                Tgetattr {
+                    fid,
+                    req_mask: GetattrMask::from_bits_truncate(mask),
+                }
+                .into()
+            })
+            .boxed(),
+        (any::<u64>(), qid(), stat())
+            .prop_map(|(valid, qid, stat)| {
+                Rgetattr {
+                    valid: GetattrMask::from_bits_truncate(valid),
+                    qid,
+                    stat,
+                }
+                .into()
+            })
+            .boxed(),
+        (any::<u32>(), any::<u32>(), setattr())
+            .prop_map(|(fid, valid, stat)| {
+                Tsetattr {
+                    fid,
+                    valid: SetattrMask::from_bits_truncate(valid),
+                    stat,
+                }
+                .into()
+            })
+            .boxed(),
+        Just(Rsetattr {}.into()).boxed(),
+        (any::<u32>(), any::<u32>(), fcall_str())
+            .prop_map(|(fid, new_fid, name)| Txattrwalk { fid, new_fid, name }.into())
+            .boxed(),
+        any::<u64>()
+            .prop_map(|size| Rxattrwalk { size }.into())
+            .boxed(),
+        (any::<u32>(), fcall_str(), any::<u64>(), any::<u32>())
+            .prop_map(|(fid, name, attr_size, flags)| {
+                Txattrcreate {
+                    fid,
+                    name,
+                    attr_size,
+                    flags,
+                }
+                .into()
+            })
+            .boxed(),
+        Just(Rxattrcreate {}.into()).boxed(),
+        (any::<u32>(), any::<u64>(), any::<u32>())
+            .prop_map(|(fid, offset, count)| Treaddir { fid, offset, count }.into())
+            .boxed(),
+        proptest::collection::vec(direntry(), 0..16)
+            .prop_map(|data| {
+                Rreaddir {
+                    data: DirEntryData::with(data),
+                }
+                .into()
+            })
+            .boxed(),
+        any::<u32>().prop_map(|fid| Tfsync { fid }.into()).boxed(),
+        Just(Rfsync {}.into()).boxed(),
+        (any::<u32>(), flock())
+            .prop_map(|(fid, flock)| Tlock { fid, flock }.into())
+            .boxed(),
+        any::<u8>()
+            .prop_map(|status| {
+                Rlock {
+                    status: LockStatus::from_bits_truncate(status),
+                }
+                .into()
+            })
+            .boxed(),
+        (any::<u32>(), getlock())
+            .prop_map(|(fid, flock)| Tgetlock { fid, flock }.into())
+            .boxed(),
+        getlock()
+            .prop_map(|flock| Rgetlock { flock }.into())
+            .boxed(),
+        (any::<u32>(), any::<u32>(), fcall_str())
+            .prop_map(|(dfid, fid, name)| Tlink { dfid, fid, name }.into())
+            .boxed(),
+        Just(Rlink {}.into()).boxed(),
+        (any::<u32>(), fcall_str(), any::<u32>(), any::<u32>())
+            .prop_map(|(dfid, name, mode, gid)| {
+                Tmkdir {
+                    dfid,
+                    name,
+                    mode,
+                    gid,
+                }
+                .into()
+            })
+            .boxed(),
+        qid().prop_map(|qid| Rmkdir { qid }.into()).boxed(),
+        (any::<u32>(), fcall_str(), any::<u32>(), fcall_str())
+            .prop_map(|(olddfid, oldname, newdfid, newname)| {
+                Trenameat {
+                    olddfid,
+                    oldname,
+                    newdfid,
+                    newname,
+                }
+                .into()
+            })
+            .boxed(),
+        Just(Rrenameat {}.into()).boxed(),
+        (any::<u32>(), fcall_str(), any::<u32>())
+            .prop_map(|(dfid, name, flags)| Tunlinkat { dfid, name, flags }.into())
+            .boxed(),
+        Just(Runlinkat {}.into()).boxed(),
+        (any::<u32>(), fcall_str(), fcall_str(), any::<u32>())
+            .prop_map(|(afid, uname, aname, n_uname)| {
+                Tauth {
+                    afid,
+                    uname,
+                    aname,
+                    n_uname,
+                }
+                .into()
+            })
+            .boxed(),
+        qid().prop_map(|aqid| Rauth { aqid }.into()).boxed(),
+        (any::<u32>(), fcall_str())
+            .prop_map(|(msize, version)| Tversion { msize, version }.into())
+            .boxed(),
+        (any::<u32>(), fcall_str())
+            .prop_map(|(msize, version)| Rversion { msize, version }.into())
+            .boxed(),
+        any::<u16>()
+            .prop_map(|oldtag| Tflush { oldtag }.into())
+            .boxed(),
+        Just(Rflush {}.into()).boxed(),
+        (
+            any::<u32>(),
+            any::<u32>(),
+            proptest::collection::vec(fcall_str(), 0..=MAXWELEM),
+        )
+            .prop_map(|(fid, new_fid, wnames)| {
+                Twalk {
+                    fid,
+                    new_fid,
+                    wnames,
+                }
+                .into()
+            })
+            .boxed(),
+        proptest::collection::vec(qid(), 0..=MAXWELEM)
+            .prop_map(|wqids| Rwalk { wqids }.into())
+            .boxed(),
+        (any::<u32>(), any::<u64>(), any::<u32>())
+            .prop_map(|(fid, offset, count)| Tread { fid, offset, count }.into())
+            .boxed(),
+        data().prop_map(|data| Rread { data }.into()).boxed(),
+        (any::<u32>(), any::<u64>(), data())
+            .prop_map(|(fid, offset, data)| Twrite { fid, offset, data }.into())
+            .boxed(),
+        any::<u32>()
+            .prop_map(|count| Rwrite { count }.into())
+            .boxed(),
+        any::<u32>().prop_map(|fid| Tclunk { fid }.into()).boxed(),
+        Just(Rclunk {}.into()).boxed(),
+        any::<u32>().prop_map(|fid| Tremove { fid }.into()).boxed(),
+        Just(Rremove {}.into()).boxed(),
+    ];
+    Union::new(strategies)
+}
+
+fn tagged_fcall() -> impl Strategy<Value = TaggedFcall<'static>> {
+    (any::<u16>(), fcall()).prop_map(|(tag, fcall)| TaggedFcall { tag, fcall })
+}
+
+proptest! {
+    #[test]
+    fn roundtrip(fcall in tagged_fcall()) {
+        let buf = encode(&fcall);
+        let decoded = TaggedFcall::decode(&buf).unwrap();
+        prop_assert_eq!(&decoded, &fcall);
+        prop_assert_eq!(encode(&decoded), buf);
+    }
+
+    #[test]
+    fn encoded_size_matches_spec(fcall in tagged_fcall()) {
+        let buf = encode(&fcall);
+        prop_assert_eq!(buf.len(), spec_size(&fcall.fcall));
+        prop_assert_eq!(u32::from_le_bytes(buf[..4].try_into().unwrap()) as usize, buf.len());
+        prop_assert_eq!(buf[4], FcallType::from(&fcall.fcall) as u8);
+        prop_assert_eq!(u16::from_le_bytes(buf[5..7].try_into().unwrap()), fcall.tag);
+    }
+
+    #[test]
+    fn transport_write_matches_encode(fcall in tagged_fcall()) {
+        // Rread and Twrite take the zero copy fast path in transport::write,
+        // everything else must still produce identical bytes.
+        let mut wbuf = Vec::with_capacity(64 * 1024);
+        let mut out = Vec::new();
+        transport::write(&mut out, &mut wbuf, &fcall).unwrap();
+        prop_assert_eq!(out, encode(&fcall));
+    }
+
+    #[test]
+    fn transport_read_matches_decode(fcall in tagged_fcall()) {
+        let buf = encode(&fcall);
+        let mut rbuf = Vec::with_capacity(64 * 1024);
+        let read = transport::read(&mut &buf[..], &mut rbuf).unwrap();
+        prop_assert_eq!(read, fcall);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_roundtrip(fcall in tagged_fcall()) {
+        let json = serde_json::to_string(&fcall).unwrap();
+        let decoded: TaggedFcall = serde_json::from_str(&json).unwrap();
+        prop_assert_eq!(decoded, fcall);
+    }
+
+    #[test]
+    fn truncated_messages_are_rejected(fcall in tagged_fcall(), cut in any::<prop::sample::Index>()) {
+        let buf = encode(&fcall);
+        if buf.len() > HDRSZ {
+            let n = HDRSZ + cut.index(buf.len() - HDRSZ);
+            let mut truncated = buf[..n].to_vec();
+            truncated[..4].copy_from_slice(&(n as u32).to_le_bytes());
+            prop_assert!(TaggedFcall::decode(&truncated).is_err());
+        }
+    }
+}
+
+#[test]
+fn transport_write_overflow() {
+    let fcall = TaggedFcall {
+        tag: 1,
+        fcall: Rread {
+            data: Cow::from(&[0u8; 128][..]),
+        }
+        .into(),
+    };
+    let mut wbuf = Vec::with_capacity(64);
+    let mut out = Vec::new();
+    assert!(transport::write(&mut out, &mut wbuf, &fcall).is_err());
+    assert!(out.is_empty());
+}
+
+#[test]
+fn transport_read_rejects_oversized() {
+    let buf = encode(&TaggedFcall {
+        tag: 1,
+        fcall: Rread {
+            data: Cow::from(&[0u8; 128][..]),
+        }
+        .into(),
+    });
+    let mut rbuf = Vec::with_capacity(64);
+    assert!(transport::read(&mut &buf[..], &mut rbuf).is_err());
+}
+
+#[test]
+fn decode_rejects_unknown_type() {
+    let buf = [7, 0, 0, 0, 106, 1, 0];
+    assert!(TaggedFcall::decode(&buf).is_err());
+}
+
+#[test]
+fn decode_rejects_bad_readdir_count() {
+    let mut buf = encode(&TaggedFcall {
+        tag: 1,
+        fcall: Rreaddir {
+            data: DirEntryData::new(),
+        }
+        .into(),
+    });
+    buf[7..11].copy_from_slice(&1000u32.to_le_bytes());
+    assert!(TaggedFcall::decode(&buf).is_err());
+}
+
+// Reference encodings of a typical session, assembled by hand field by
+// field from the 9P2000.L wire format rather than produced by this crate.
+// They are not captures: no frames recorded from the Linux v9fs client or
+// diod have been checked in yet. Add such captures here with a note of the
+// client, server and versions they came from.
+mod golden {
+    use super::*;
+
+    const TVERSION: &[u8] = &[
+        0x15, 0x00, 0x00, 0x00, 0x64, 0xff, 0xff, 0x00, 0x20, 0x00, 0x00, 0x08, 0x00, 0x39, 0x50,
+        0x32, 0x30, 0x30, 0x30, 0x2e, 0x4c,
+    ];
+
+    const RVERSION: &[u8] = &[
+        0x15, 0x00, 0x00, 0x00, 0x65, 0xff, 0xff, 0x00, 0x20, 0x00, 0x00, 0x08, 0x00, 0x39, 0x50,
+        0x32, 0x30, 0x30, 0x30, 0x2e, 0x4c,
+    ];
+
+    const TATTACH: &[u8] = &[
+        0x1f, 0x00, 0x00, 0x00, 0x68, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff,
+        0x04, 0x00, 0x72, 0x6f, 0x6f, 0x74, 0x04, 0x00, 0x2f, 0x74, 0x6d, 0x70, 0x00, 0x00, 0x00,
+        0x00,
+    ];
+
+    const RATTACH: &[u8] = &[
+        0x14, 0x00, 0x00, 0x00, 0x69, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    const TWALK: &[u8] = &[
+        0x1b, 0x00, 0x00, 0x00, 0x6e, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+        0x02, 0x00, 0x03, 0x00, 0x75, 0x73, 0x72, 0x03, 0x00, 0x6c, 0x69, 0x62,
+    ];
+
+    const RWALK: &[u8] = &[
+        0x23, 0x00, 0x00, 0x00, 0x6f, 0x02, 0x00, 0x02, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x10,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x11, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    const TLOPEN: &[u8] = &[
+        0x0f, 0x00, 0x00, 0x00, 0x0c, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02, 0x02, 0x00, 0x00,
+    ];
+
+    const RLOPEN: &[u8] = &[
+        0x18, 0x00, 0x00, 0x00, 0x0d, 0x03, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, 0x12, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    const TGETATTR: &[u8] = &[
+        0x13, 0x00, 0x00, 0x00, 0x18, 0x04, 0x00, 0x01, 0x00, 0x00, 0x00, 0xff, 0x07, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+    ];
+
+    const RLERROR: &[u8] = &[
+        0x0b, 0x00, 0x00, 0x00, 0x07, 0x04, 0x00, 0x02, 0x00, 0x00, 0x00,
+    ];
+
+    const TREAD: &[u8] = &[
+        0x17, 0x00, 0x00, 0x00, 0x74, 0x05, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00,
+    ];
+
+    const RREAD: &[u8] = &[
+        0x11, 0x00, 0x00, 0x00, 0x75, 0x05, 0x00, 0x06, 0x00, 0x00, 0x00, 0x68, 0x65, 0x6c, 0x6c,
+        0x6f, 0x0a,
+    ];
+
+    const TWRITE: &[u8] = &[
+        0x1d, 0x00, 0x00, 0x00, 0x76, 0x06, 0x00, 0x01, 0x00, 0x00, 0x00, 0x06, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x77, 0x6f, 0x72, 0x6c, 0x64, 0x0a,
+    ];
+
+    const RWRITE: &[u8] = &[
+        0x0b, 0x00, 0x00, 0x00, 0x77, 0x06, 0x00, 0x06, 0x00, 0x00, 0x00,
+    ];
+
+    const TREADDIR: &[u8] = &[
+        0x17, 0x00, 0x00, 0x00, 0x28, 0x07, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0xe8, 0x1f, 0x00, 0x00,
+    ];
+
+    const RREADDIR: &[u8] = &[
+        0x3e, 0x00, 0x00, 0x00, 0x29, 0x07, 0x00, 0x33, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00,
+        0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x04, 0x01, 0x00, 0x2e, 0x80, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x02, 0x00,
+        0x2e, 0x2e,
+    ];
+
+    const TCLUNK: &[u8] = &[
+        0x0b, 0x00, 0x00, 0x00, 0x78, 0x08, 0x00, 0x01, 0x00, 0x00, 0x00,
+    ];
+
+    const RCLUNK: &[u8] = &[0x07, 0x00, 0x00, 0x00, 0x79, 0x08, 0x00];
+
+    fn qid(typ: QidType, version: u32, path: u64) -> Qid {
+        Qid { typ, version, path }
+    }
+
+    fn s(v: &str) -> FcallStr<'static> {
+        FcallStr::Owned(v.as_bytes().to_vec())
+    }
+
+    fn tagged<'a, F: Into<Fcall<'a>>>(tag: u16, fcall: F) -> TaggedFcall<'a> {
+        TaggedFcall {
+            tag,
+            fcall: fcall.into(),
+        }
+    }
+
+    fn messages() -> Vec<(&'static [u8], TaggedFcall<'static>)> {
+        vec![
+            (
+                TVERSION,
+                tagged(
+                    NOTAG,
+                    Tversion {
+                        msize: 8192,
+                        version: s("9P2000.L"),
+                    },
+                ),
+            ),
+            (
+                RVERSION,
+                tagged(
+                    NOTAG,
+                    Rversion {
+                        msize: 8192,
+                        version: s("9P2000.L"),
+                    },
+                ),
+            ),
+            (
+                TATTACH,
+                tagged(
+                    1,
+                    Tattach {
+                        fid: 0,
+                        afid: NOFID,
+                        uname: s("root"),
+                        aname: s("/tmp"),
+                        n_uname: 0,
+                    },
+                ),
+            ),
+            (
+                RATTACH,
+                tagged(
+                    1,
+                    Rattach {
+                        qid: qid(QidType::DIR, 0, 0x1234),
+                    },
+                ),
+            ),
+            (
+                TWALK,
+                tagged(
+                    2,
+                    Twalk {
+                        fid: 0,
+                        new_fid: 1,
+                        wnames: vec![s("usr"), s("lib")],
+                    },
+                ),
+            ),
+            (
+                RWALK,
+                tagged(
+                    2,
+                    Rwalk {
+                        wqids: vec![qid(QidType::DIR, 0, 0x10), qid(QidType::DIR, 0, 0x11)],
+                    },
+                ),
+            ),
+            (
+                TLOPEN,
+                tagged(
+                    3,
+                    Tlopen {
+                        fid: 1,
+                        flags: LOpenFlags::O_RDWR | LOpenFlags::O_TRUNC,
+                    },
+                ),
+            ),
+            (
+                RLOPEN,
+                tagged(
+                    3,
+                    Rlopen {
+                        qid: qid(QidType::FILE, 7, 0x12),
+                        iounit: 0,
+                    },
+                ),
+            ),
+            (
+                TGETATTR,
+                tagged(
+                    4,
+                    Tgetattr {
+                        fid: 1,
+                        req_mask: GetattrMask::BASIC,
+                    },
+                ),
+            ),
+            (RLERROR, tagged(4, Rlerror { ecode: 2 })),
+            (
+                TREAD,
+                tagged(
+                    5,
+                    Tread {
+                        fid: 1,
+                        offset: 0,
+                        count: 4096,
+                    },
+                ),
+            ),
+            (
+                RREAD,
+                tagged(
+                    5,
+                    Rread {
+                        data: Cow::from(&b"hello\n"[..]),
+                    },
+                ),
+            ),
+            (
+                TWRITE,
+                tagged(
+                    6,
+                    Twrite {
+                        fid: 1,
+                        offset: 6,
+                        data: Cow::from(&b"world\n"[..]),
+                    },
+                ),
+            ),
+            (RWRITE, tagged(6, Rwrite { count: 6 })),
+            (
+                TREADDIR,
+                tagged(
+                    7,
+                    Treaddir {
+                        fid: 1,
+                        offset: 0,
+                        count: 8168,
+                    },
+                ),
+            ),
+            (
+                RREADDIR,
+                tagged(
+                    7,
+                    Rreaddir {
+                        data: DirEntryData::with(vec![
+                            DirEntry {
+                                qid: qid(QidType::DIR, 0, 0x10),
+                                offset: 1,
+                                typ: 4,
+                                name: s("."),
+                            },
+                            DirEntry {
+                                qid: qid(QidType::DIR, 0, 0x1),
+                                offset: 2,
+                                typ: 4,
+                                name: s(".."),
+                            },
+                        ]),
+                    },
+                ),
+            ),
+            (TCLUNK, tagged(8, Tclunk { fid: 1 })),
+            (RCLUNK, tagged(8, Rclunk {})),
+        ]
+    }
+
+    #[test]
+    fn decode() {
+        for (bytes, expected) in messages() {
+            let decoded = TaggedFcall::decode(bytes).unwrap();
+            assert_eq!(decoded, expected);
+        }
+    }
+
+    #[test]
+    fn encode() {
+        for (bytes, fcall) in messages() {
+            assert_eq!(super::encode(&fcall), bytes, "{:?}", fcall);
+            let mut wbuf = Vec::with_capacity(8192);
+            let mut out = Vec::new();
+            transport::write(&mut out, &mut wbuf, &fcall).unwrap();
+            assert_eq!(out, bytes, "{:?}", fcall);
+        }
+    }
+}
+
+#[test]
+fn display() {
+    let cases: Vec<(TaggedFcall, &str)> = vec![
+        (
+            TaggedFcall {
+                tag: 3,
+                fcall: Twalk {
+                    fid: 1,
+                    new_fid: 2,
+                    wnames: vec!["usr".into(), "lib".into()],
+                }
+                .into(),
+            },
+            "Twalk tag=3 fid=1 newfid=2 wname=[usr,lib]",
+        ),
+        (
+            TaggedFcall {
+                tag: 4,
+                fcall: Rlerror { ecode: 2 }.into(),
+            },
+            "Rlerror tag=4 ecode=ENOENT",
+        ),
+        (
+            TaggedFcall {
+                tag: 5,
+                fcall: Tlopen {
+                    fid: 1,
+                    flags: LOpenFlags::O_WRONLY | LOpenFlags::O_TRUNC,
+                }
+                .into(),
+            },
+            "Tlopen tag=5 fid=1 flags=O_WRONLY|O_TRUNC",
+        ),
+        (
+            TaggedFcall {
+                tag: 6,
+                fcall: Tgetattr {
+                    fid: 1,
+                    req_mask: GetattrMask::BASIC | GetattrMask::GEN,
+                }
+                .into(),
+            },
+            "Tgetattr tag=6 fid=1 request_mask=BASIC|GEN",
+        ),
+        (
+            TaggedFcall {
+                tag: 7,
+                fcall: Tsetattr {
+                    fid: 1,
+                    valid: SetattrMask::MODE | SetattrMask::MTIME | SetattrMask::MTIME_SET,
+                    stat: SetAttr {
+                        mode: 0o644,
+                        uid: 0,
+                        gid: 0,
+                        size: 0,
+                        atime: Time { sec: 0, nsec: 0 },
+                        mtime: Time { sec: 5, nsec: 1 },
+                    },
+                }
+                .into(),
+            },
+            "Tsetattr tag=7 fid=1 valid=MODE|MTIME|MTIME_SET mode=644 uid=0 gid=0 size=0 \
+             atime=0.000000000 mtime=5.000000001",
+        ),
+        (
+            TaggedFcall {
+                tag: 8,
+                fcall: Rread {
+                    data: Cow::from(&[0xabu8; 100][..]),
+                }
+                .into(),
+            },
+            "Rread tag=8 count=100 data=abababababababababababababababab...",
+        ),
+        (
+            TaggedFcall {
+                tag: 9,
+                fcall: Rattach {
+                    qid: Qid {
+                        typ: QidType::DIR,
+                        version: 0,
+                        path: 0x1234,
+                    },
+                }
+                .into(),
+            },
+            "Rattach tag=9 qid=(0000000000001234 0 'd')",
+        ),
+    ];
+    for (fcall, expected) in cases {
+        assert_eq!(fcall.to_string(), expected);
+    }
+}
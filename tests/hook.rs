@@ -0,0 +1,68 @@
+mod common;
+
+use common::*;
+use p92000l::*;
+use std::sync::{Arc, Mutex};
+
+type Seen = Arc<Mutex<Vec<(HookPoint, Option<u32>, FcallType, bool)>>>;
+
+fn recorder(seen: Seen) -> Arc<dyn FcallHook> {
+    Arc::new(move |event: &FcallEvent| {
+        seen.lock()
+            .unwrap()
+            .push((event.point, event.fid, event.typ, event.elapsed.is_some()))
+    })
+}
+
+fn expected(
+    request: HookPoint,
+    response: HookPoint,
+    fid: u32,
+) -> Vec<(HookPoint, Option<u32>, FcallType, bool)> {
+    vec![
+        (request, Some(fid), FcallType::Tattach, false),
+        (response, Some(fid), FcallType::Rattach, true),
+        (request, Some(fid), FcallType::Twalk, false),
+        (response, Some(fid), FcallType::Rlerror, true),
+        (request, Some(fid), FcallType::Tclunk, false),
+        (response, Some(fid), FcallType::Rclunk, true),
+    ]
+}
+
+#[test]
+fn client_and_server_hooks() {
+    let server_seen = Seen::default();
+    let server_hook = recorder(server_seen.clone());
+    let (conn, server) = spawn_server(RootFs, 8192, ServeOptions::new().hook(server_hook));
+    let client = Client::over_unix_stream(conn, 8192).unwrap();
+    let client_seen = Seen::default();
+    client.set_hook(Some(recorder(client_seen.clone())));
+
+    let (_, root) = client.attach(0, "root", "/").unwrap();
+    assert!(root.walk(&["missing"]).is_err());
+    root.clunk().unwrap();
+
+    client.set_hook(None);
+    drop(client);
+    server.join().unwrap();
+
+    let client_seen = client_seen.lock().unwrap().clone();
+    let fid = client_seen[0].1.unwrap();
+    assert_eq!(
+        client_seen,
+        expected(HookPoint::ClientRequest, HookPoint::ClientResponse, fid)
+    );
+    // The server also saw the version handshake.
+    let server_seen = server_seen.lock().unwrap().clone();
+    assert_eq!(
+        server_seen[..2],
+        [
+            (HookPoint::ServerRequest, None, FcallType::Tversion, false),
+            (HookPoint::ServerResponse, None, FcallType::Rversion, true),
+        ]
+    );
+    assert_eq!(
+        server_seen[2..],
+        expected(HookPoint::ServerRequest, HookPoint::ServerResponse, fid)
+    );
+}
@@ -0,0 +1,477 @@
+use p92000l::*;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct Node {
+    dir: bool,
+    data: Vec<u8>,
+    children: BTreeMap<Vec<u8>, u64>,
+}
+
+#[derive(Default)]
+struct Tree {
+    nodes: HashMap<u64, Node>,
+    next: u64,
+}
+
+impl Tree {
+    fn lookup(&self, path: &str) -> Option<u64> {
+        let mut id = 0;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            id = *self.nodes[&id].children.get(name.as_bytes())?;
+        }
+        Some(id)
+    }
+
+    fn add(&mut self, dir: u64, name: &[u8], is_dir: bool) -> u64 {
+        self.next += 1;
+        let id = self.next;
+        let node = Node {
+            dir: is_dir,
+            ..Node::default()
+        };
+        self.nodes.insert(id, node);
+        self.nodes
+            .get_mut(&dir)
+            .unwrap()
+            .children
+            .insert(name.to_vec(), id);
+        id
+    }
+
+    fn qid(&self, id: u64) -> Qid {
+        Qid {
+            typ: if self.nodes[&id].dir {
+                QidType::DIR
+            } else {
+                QidType::FILE
+            },
+            version: 0,
+            path: id,
+        }
+    }
+}
+
+// A filesystem in memory, clones share the tree so tests can look inside.
+#[derive(Clone)]
+struct MemFs {
+    tree: Arc<Mutex<Tree>>,
+    fids: HashMap<u32, u64>,
+}
+
+impl MemFs {
+    fn new(files: &[(&str, Option<&str>)]) -> MemFs {
+        let mut tree = Tree::default();
+        tree.nodes.insert(
+            0,
+            Node {
+                dir: true,
+                ..Node::default()
+            },
+        );
+        for (path, data) in files {
+            let mut dir = 0;
+            let names: Vec<&str> = path.split('/').collect();
+            for (i, name) in names.iter().enumerate() {
+                let last = i + 1 == names.len();
+                dir = match tree.nodes[&dir].children.get(name.as_bytes()) {
+                    Some(id) => *id,
+                    None => tree.add(dir, name.as_bytes(), !last || data.is_none()),
+                };
+            }
+            if let Some(data) = data {
+                tree.nodes.get_mut(&dir).unwrap().data = data.as_bytes().to_vec();
+            }
+        }
+        MemFs {
+            tree: Arc::new(Mutex::new(tree)),
+            fids: HashMap::new(),
+        }
+    }
+
+    fn contents(&self, path: &str) -> Option<String> {
+        let tree = self.tree.lock().unwrap();
+        let node = &tree.nodes[&tree.lookup(path)?];
+        Some(String::from_utf8(node.data.clone()).unwrap())
+    }
+
+    fn names(&self, path: &str) -> Vec<String> {
+        let tree = self.tree.lock().unwrap();
+        let node = &tree.nodes[&tree.lookup(path).unwrap()];
+        node.children
+            .keys()
+            .map(|name| String::from_utf8(name.clone()).unwrap())
+            .collect()
+    }
+
+    fn fid(&self, fid: u32) -> Result<u64, u32> {
+        self.fids.get(&fid).copied().ok_or(errno::EBADF)
+    }
+
+    fn child(&self, fid: u32, name: &FcallStr) -> Result<u64, u32> {
+        let tree = self.tree.lock().unwrap();
+        let node = &tree.nodes[&self.fid(fid)?];
+        node.children
+            .get(name.as_bytes())
+            .copied()
+            .ok_or(errno::ENOENT)
+    }
+
+    fn create(&mut self, dfid: u32, name: &FcallStr, dir: bool) -> Result<Qid, u32> {
+        let parent = self.fid(dfid)?;
+        if self.child(dfid, name).is_ok() {
+            return Err(errno::EEXIST);
+        }
+        let mut tree = self.tree.lock().unwrap();
+        let id = tree.add(parent, name.as_bytes(), dir);
+        Ok(tree.qid(id))
+    }
+}
+
+fn reply(resp: FcallResponse, result: Result<Fcall, u32>) {
+    match result {
+        Ok(fcall) => resp.send(fcall),
+        Err(ecode) => resp.send(Rlerror { ecode }),
+    }
+}
+
+impl Filesystem for MemFs {
+    fn attach(&mut self, req: &Tattach, resp: FcallResponse) {
+        self.fids.insert(req.fid, 0);
+        let qid = self.tree.lock().unwrap().qid(0);
+        resp.send(Rattach { qid })
+    }
+
+    fn walk(&mut self, req: &Twalk, resp: FcallResponse) {
+        let mut id = match self.fid(req.fid) {
+            Ok(id) => id,
+            Err(ecode) => return resp.send(Rlerror { ecode }),
+        };
+        let mut wqids = Vec::new();
+        for name in &req.wnames {
+            let tree = self.tree.lock().unwrap();
+            match tree.nodes[&id].children.get(name.as_bytes()) {
+                Some(child) => id = *child,
+                None if wqids.is_empty() => {
+                    return resp.send(Rlerror {
+                        ecode: errno::ENOENT,
+                    })
+                }
+                None => return resp.send(Rwalk { wqids }),
+            }
+            wqids.push(tree.qid(id));
+        }
+        self.fids.insert(req.new_fid, id);
+        resp.send(Rwalk { wqids })
+    }
+
+    fn getattr(&mut self, req: &Tgetattr, resp: FcallResponse) {
+        reply(
+            resp,
+            self.fid(req.fid).map(|id| {
+                let tree = self.tree.lock().unwrap();
+                let node = &tree.nodes[&id];
+                let mut stat: Stat = unsafe { std::mem::zeroed() };
+                stat.mode = if node.dir { 0o40755 } else { 0o100644 };
+                stat.size = node.data.len() as u64;
+                Rgetattr {
+                    valid: GetattrMask::BASIC,
+                    qid: tree.qid(id),
+                    stat,
+                }
+                .into()
+            }),
+        )
+    }
+
+    fn setattr(&mut self, _req: &Tsetattr, resp: FcallResponse) {
+        resp.send(Rsetattr {})
+    }
+
+    fn lopen(&mut self, req: &Tlopen, resp: FcallResponse) {
+        reply(
+            resp,
+            self.fid(req.fid).map(|id| {
+                let qid = self.tree.lock().unwrap().qid(id);
+                Rlopen { qid, iounit: 0 }.into()
+            }),
+        )
+    }
+
+    fn lcreate(&mut self, req: &Tlcreate, resp: FcallResponse) {
+        let qid = self.create(req.fid, &req.name, false);
+        if let Ok(qid) = qid {
+            self.fids.insert(req.fid, qid.path);
+        }
+        reply(resp, qid.map(|qid| Rlcreate { qid, iounit: 0 }.into()))
+    }
+
+    fn mkdir(&mut self, req: &Tmkdir, resp: FcallResponse) {
+        let qid = self.create(req.dfid, &req.name, true);
+        reply(resp, qid.map(|qid| Rmkdir { qid }.into()))
+    }
+
+    fn readdir(&mut self, req: &Treaddir, resp: FcallResponse) {
+        let id = match self.fid(req.fid) {
+            Ok(id) => id,
+            Err(ecode) => return resp.send(Rlerror { ecode }),
+        };
+        let tree = self.tree.lock().unwrap();
+        let mut data = DirEntryData::new();
+        let children = &tree.nodes[&id].children;
+        for (i, (name, child)) in children.iter().enumerate().skip(req.offset as usize) {
+            let entry = DirEntry {
+                qid: tree.qid(*child),
+                offset: i as u64 + 1,
+                typ: 0,
+                name: name.into(),
+            };
+            if data.size() + entry.size() > req.count as u64 {
+                break;
+            }
+            data.push(entry);
+        }
+        resp.send(Rreaddir { data })
+    }
+
+    fn read(&mut self, req: &Tread, resp: FcallResponse) {
+        let id = match self.fid(req.fid) {
+            Ok(id) => id,
+            Err(ecode) => return resp.send(Rlerror { ecode }),
+        };
+        let tree = self.tree.lock().unwrap();
+        let data = &tree.nodes[&id].data;
+        let start = (req.offset as usize).min(data.len());
+        let end = (start + req.count as usize).min(data.len());
+        resp.send(Rread {
+            data: data[start..end].into(),
+        })
+    }
+
+    fn write(&mut self, req: &Twrite, resp: FcallResponse) {
+        let id = match self.fid(req.fid) {
+            Ok(id) => id,
+            Err(ecode) => return resp.send(Rlerror { ecode }),
+        };
+        let mut tree = self.tree.lock().unwrap();
+        let data = &mut tree.nodes.get_mut(&id).unwrap().data;
+        let end = req.offset as usize + req.data.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[req.offset as usize..end].copy_from_slice(&req.data);
+        resp.send(Rwrite {
+            count: req.data.len() as u32,
+        })
+    }
+
+    fn unlinkat(&mut self, req: &Tunlinkat, resp: FcallResponse) {
+        let result = self.child(req.dfid, &req.name).and_then(|child| {
+            let mut tree = self.tree.lock().unwrap();
+            if !tree.nodes[&child].children.is_empty() {
+                return Err(errno::ENOTEMPTY);
+            }
+            let dir = self.fids[&req.dfid];
+            let dir = tree.nodes.get_mut(&dir).unwrap();
+            dir.children.remove(req.name.as_bytes());
+            Ok(Runlinkat {}.into())
+        });
+        reply(resp, result)
+    }
+
+    fn renameat(&mut self, req: &Trenameat, resp: FcallResponse) {
+        let result = self.child(req.olddfid, &req.oldname).and_then(|child| {
+            let newdir = self.fid(req.newdfid)?;
+            let olddir = self.fids[&req.olddfid];
+            let mut tree = self.tree.lock().unwrap();
+            let olddir = tree.nodes.get_mut(&olddir).unwrap();
+            olddir.children.remove(req.oldname.as_bytes());
+            let newdir = tree.nodes.get_mut(&newdir).unwrap();
+            newdir
+                .children
+                .insert(req.newname.as_bytes().to_vec(), child);
+            Ok(Rrenameat {}.into())
+        });
+        reply(resp, result)
+    }
+
+    fn clunk(&mut self, req: &Tclunk, resp: FcallResponse) {
+        self.fids.remove(&req.fid);
+        resp.send(Rclunk {})
+    }
+}
+
+fn serve<F: Filesystem + Send + 'static>(mut fs: F) -> (Client, ClientFid) {
+    let (conn, server_conn) = loopback_pair();
+    std::thread::spawn(move || serve_transport(server_conn.clone(), server_conn, &mut fs, 65536));
+    let client = ClientBuilder::new()
+        .over_transport(conn.clone(), conn)
+        .unwrap();
+    let (_, root) = client.attach(0, "root", "").unwrap();
+    (client, root)
+}
+
+fn walk(root: &ClientFid, path: &str) -> std::io::Result<ClientFid> {
+    let names: Vec<&str> = path.split('/').filter(|name| !name.is_empty()).collect();
+    let (wqids, fid) = root.walk(&names)?;
+    if wqids.len() != names.len() {
+        return Err(errno::io_error(errno::ENOENT));
+    }
+    Ok(fid)
+}
+
+fn read(root: &ClientFid, path: &str) -> String {
+    let fid = walk(root, path).unwrap();
+    fid.open(LOpenFlags::O_RDONLY).unwrap();
+    let mut buf = vec![0; 4096];
+    let n = fid.read(0, &mut buf).unwrap();
+    String::from_utf8(buf[..n].to_vec()).unwrap()
+}
+
+fn write(root: &ClientFid, path: &str, data: &str) {
+    let fid = walk(root, path).unwrap();
+    fid.open(LOpenFlags::O_WRONLY).unwrap();
+    fid.write(0, data.as_bytes()).unwrap();
+}
+
+fn list(root: &ClientFid, path: &str) -> Vec<String> {
+    let fid = walk(root, path).unwrap();
+    fid.open(LOpenFlags::O_RDONLY).unwrap();
+    let entries = fid.read_dir().unwrap();
+    entries.iter().map(|entry| entry.name.to_string()).collect()
+}
+
+fn errno(result: std::io::Result<impl Sized>) -> u32 {
+    errno::errno_from_io_error(&result.err().unwrap())
+}
+
+#[test]
+fn read_only() {
+    let fs = MemFs::new(&[("a", Some("data"))]);
+    let view = fs.clone();
+    let (_client, root) = serve(ReadOnlyFs::new(fs));
+    assert_eq!(read(&root, "a"), "data");
+    let a = walk(&root, "a").unwrap();
+    assert_eq!(errno(a.open(LOpenFlags::O_RDWR)), errno::EROFS);
+    assert_eq!(errno(a.remove()), errno::EROFS);
+    assert_eq!(errno(root.mkdir("d", 0o755, 0)), errno::EROFS);
+    assert_eq!(errno(root.unlinkat("a", 0)), errno::EROFS);
+    assert_eq!(view.contents("a").unwrap(), "data");
+    // The fid is gone after a failed remove.
+    assert!(view.fids.is_empty());
+}
+
+fn overlay() -> (MemFs, MemFs, Client, ClientFid) {
+    let upper = MemFs::new(&[("a", Some("upper a")), ("b", Some("b")), ("d/y", Some("y"))]);
+    let lower = MemFs::new(&[
+        ("a", Some("lower a")),
+        ("d/x", Some("x")),
+        ("e/f", Some("f")),
+    ]);
+    let (client, root) = serve(OverlayFs::new(upper.clone(), lower.clone()));
+    (upper, lower, client, root)
+}
+
+#[test]
+fn overlay_merges() {
+    let (_upper, _lower, _client, root) = overlay();
+    assert_eq!(list(&root, ""), ["a", "b", "d", "e"]);
+    assert_eq!(list(&root, "d"), ["y", "x"]);
+    assert_eq!(read(&root, "a"), "upper a");
+    assert_eq!(read(&root, "d/x"), "x");
+    assert_eq!(read(&root, "e/f"), "f");
+    // Walking back up finds the merged directory again.
+    assert_eq!(read(&root, "e/../d/y"), "y");
+}
+
+#[test]
+fn overlay_copies_up() {
+    let (upper, lower, _client, root) = overlay();
+    write(&root, "e/f", "F");
+    assert_eq!(read(&root, "e/f"), "F");
+    assert_eq!(upper.contents("e/f").unwrap(), "F");
+    assert_eq!(lower.contents("e/f").unwrap(), "f");
+
+    let e = walk(&root, "e").unwrap();
+    e.create("g", LOpenFlags::O_WRONLY, 0o644, 0).unwrap();
+    e.write(0, b"g").unwrap();
+    assert_eq!(upper.contents("e/g").unwrap(), "g");
+    assert_eq!(lower.names("e"), ["f"]);
+    assert_eq!(
+        errno(walk(&root, "d").unwrap().mkdir("x", 0o755, 0)),
+        errno::EEXIST
+    );
+}
+
+#[test]
+fn overlay_whiteouts() {
+    let (upper, lower, _client, root) = overlay();
+    let d = walk(&root, "d").unwrap();
+    d.unlinkat("x", 0).unwrap();
+    assert_eq!(list(&root, "d"), ["y"]);
+    assert_eq!(errno(walk(&root, "d/x")), errno::ENOENT);
+    assert_eq!(errno(walk(&root, "d/.wh.x")), errno::ENOENT);
+    assert_eq!(upper.names("d"), [".wh.x", "y"]);
+    assert_eq!(lower.contents("d/x").unwrap(), "x");
+
+    // Both copies of a go.
+    walk(&root, "a").unwrap().remove().unwrap();
+    assert_eq!(errno(walk(&root, "a")), errno::ENOENT);
+    assert_eq!(upper.names(""), [".wh.a", "b", "d"]);
+
+    // Creating the name again removes the whiteout.
+    d.create("x", LOpenFlags::O_WRONLY, 0o644, 0).unwrap();
+    d.write(0, b"new x").unwrap();
+    assert_eq!(read(&root, "d/x"), "new x");
+    assert_eq!(upper.names("d"), ["x", "y"]);
+
+    // A directory made where one was removed starts empty.
+    assert_eq!(errno(root.unlinkat("e", 0x200)), errno::ENOTEMPTY);
+    walk(&root, "e").unwrap().unlinkat("f", 0).unwrap();
+    root.unlinkat("e", 0x200).unwrap();
+    root.mkdir("e", 0o755, 0).unwrap();
+    assert_eq!(list(&root, "e"), Vec::<String>::new());
+    assert_eq!(lower.names("e"), ["f"]);
+}
+
+#[test]
+fn overlay_renames() {
+    let (upper, lower, _client, root) = overlay();
+    let d = walk(&root, "d").unwrap();
+    root.renameat("e", &d, "e").err().unwrap();
+    let f = walk(&root, "e/f").unwrap();
+    walk(&root, "e").unwrap().renameat("f", &d, "z").unwrap();
+    assert_eq!(read(&root, "d/z"), "f");
+    assert_eq!(list(&root, "e"), Vec::<String>::new());
+    assert_eq!(upper.names("e"), [".wh.f"]);
+    assert_eq!(lower.names("e"), ["f"]);
+    // Fids follow the file.
+    f.open(LOpenFlags::O_RDONLY).unwrap();
+    let mut buf = [0; 8];
+    assert_eq!(f.read(0, &mut buf).unwrap(), 1);
+}
+
+#[test]
+fn union() {
+    let first = MemFs::new(&[("a", Some("first a")), ("d/x", Some("x"))]);
+    let second = MemFs::new(&[("a", Some("second a")), ("d/y", Some("y")), ("e/z", None)]);
+    let (_client, root) = serve(UnionFs::new(vec![first.clone(), second.clone()]));
+    assert_eq!(list(&root, ""), ["a", "d", "e"]);
+    assert_eq!(list(&root, "d"), ["x", "y"]);
+    assert_eq!(read(&root, "a"), "first a");
+
+    // Changes go where the file is.
+    write(&root, "d/y", "Y");
+    assert_eq!(second.contents("d/y").unwrap(), "Y");
+    walk(&root, "e")
+        .unwrap()
+        .create("new", LOpenFlags::O_WRONLY, 0o644, 0)
+        .unwrap();
+    assert_eq!(second.names("e"), ["new", "z"]);
+
+    root.unlinkat("a", 0).unwrap();
+    assert_eq!(errno(walk(&root, "a")), errno::ENOENT);
+    assert_eq!(first.names(""), ["d"]);
+    assert_eq!(second.names(""), ["d", "e"]);
+}
@@ -0,0 +1,135 @@
+mod common;
+
+use common::RootFs;
+use p92000l::*;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+fn spawn_server(conn: Loopback) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || serve_transport(conn.clone(), conn, &mut RootFs, 8192))
+}
+
+// A message of n bytes, its size first as in 9p.
+fn message(n: usize, fill: u8) -> Vec<u8> {
+    let mut msg = vec![fill; n];
+    msg[..4].copy_from_slice(&(n as u32).to_le_bytes());
+    msg
+}
+
+fn read_message(conn: &mut Loopback) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(65536);
+    read_to_buf(conn, &mut buf).unwrap();
+    buf
+}
+
+#[test]
+fn client_and_server() {
+    let (client_conn, server_conn) = loopback_pair();
+    let server = spawn_server(server_conn);
+    let client = ClientBuilder::new()
+        .over_transport(client_conn.clone(), client_conn)
+        .unwrap();
+    let (qid, root) = client.attach(0, "root", "").unwrap();
+    assert_eq!(qid.path, 1);
+    root.clunk().unwrap();
+    // Dropping the client shuts the connection, ending the server.
+    drop(client);
+    server.join().unwrap();
+}
+
+#[test]
+fn read_timeout_and_shutdown() {
+    let (mut a, mut b) = loopback_pair();
+    let mut buf = Vec::with_capacity(64);
+    let start = Instant::now();
+    let err = read_to_buf_timeout(&mut a, &mut buf, Duration::from_millis(20)).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    assert!(start.elapsed() >= Duration::from_millis(20));
+    assert_eq!(a.read_timeout().unwrap(), None);
+
+    b.write_all(&message(8, 1)).unwrap();
+    b.shutdown().unwrap();
+    // What was sent before the shutdown still arrives.
+    assert_eq!(read_message(&mut a), message(8, 1));
+    assert_eq!(a.read(&mut [0; 8]).unwrap(), 0);
+    assert_eq!(
+        b.write(&[0]).unwrap_err().kind(),
+        std::io::ErrorKind::BrokenPipe
+    );
+
+    let (mut a, b) = loopback_pair();
+    drop(b);
+    assert_eq!(a.read(&mut [0; 8]).unwrap(), 0);
+}
+
+#[test]
+fn latency_and_bandwidth() {
+    let (mut a, mut b) = LoopbackBuilder::new()
+        .latency(Duration::from_millis(30))
+        .pair();
+    let start = Instant::now();
+    b.write_all(&message(16, 2)).unwrap();
+    assert_eq!(read_message(&mut a), message(16, 2));
+    assert!(start.elapsed() >= Duration::from_millis(30));
+
+    // 4 messages of 2500 bytes at 100000 bytes per second take 100ms.
+    let (mut a, mut b) = LoopbackBuilder::new().bandwidth(100_000).pair();
+    let start = Instant::now();
+    for _ in 0..4 {
+        b.write_all(&message(2500, 3)).unwrap();
+    }
+    assert!(start.elapsed() < Duration::from_millis(50));
+    for _ in 0..4 {
+        assert_eq!(read_message(&mut a), message(2500, 3));
+    }
+    assert!(start.elapsed() >= Duration::from_millis(100));
+}
+
+#[test]
+fn drops() {
+    let (mut a, mut b) = loopback_pair();
+    b.drop_next(1);
+    // Split writes still make up whole messages.
+    let first = message(12, 4);
+    b.write_all(&first[..6]).unwrap();
+    b.write_all(&first[6..]).unwrap();
+    b.write_all(&message(12, 5)).unwrap();
+    assert_eq!(read_message(&mut a), message(12, 5));
+
+    // The same seed loses the same messages.
+    let received = |seed| {
+        let (mut a, mut b) = LoopbackBuilder::new().drop_rate(0.5).seed(seed).pair();
+        for i in 0..64 {
+            b.write_all(&message(5, i)).unwrap();
+        }
+        b.shutdown().unwrap();
+        let mut got = Vec::new();
+        a.read_to_end(&mut got).unwrap();
+        got.chunks(5).map(|msg| msg[4]).collect::<Vec<u8>>()
+    };
+    let got = received(7);
+    assert!(got.len() > 8 && got.len() < 56, "{}", got.len());
+    assert_eq!(got, received(7));
+    assert_ne!(got, received(8));
+}
+
+#[test]
+fn resets() {
+    let (client_conn, server_conn) = loopback_pair();
+    let server = spawn_server(server_conn);
+    let client = ClientBuilder::new()
+        .over_transport(client_conn.clone(), client_conn.clone())
+        .unwrap();
+    client.attach(0, "root", "").unwrap();
+    client_conn.reset();
+    let err = client.attach(0, "root", "").err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotConnected);
+    server.join().unwrap();
+
+    // Every message resets the connection.
+    let (mut a, mut b) = LoopbackBuilder::new().reset_rate(1.0).pair();
+    let err = b.write_all(&message(8, 6)).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+    let err = a.read(&mut [0; 8]).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+}
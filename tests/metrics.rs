@@ -0,0 +1,153 @@
+mod common;
+
+use common::*;
+use p92000l::*;
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+
+// Attach, clone the root, fail a walk and clunk both fids.
+fn exercise(client: &Client) {
+    let (_, root) = client.attach(0, "root", "/").unwrap();
+    let (_, clone) = root.walk::<&str>(&[]).unwrap();
+    assert!(root.walk(&["missing"]).is_err());
+    clone.clunk().unwrap();
+    root.clunk().unwrap();
+}
+
+fn check(snapshot: &MetricsSnapshot) {
+    assert_eq!(snapshot.op(FcallType::Tattach).unwrap().count, 1);
+    let walk = snapshot.op(FcallType::Twalk).unwrap();
+    assert_eq!(walk.count, 2);
+    assert_eq!(walk.errors, 1);
+    assert_eq!(walk.latency.iter().sum::<u64>(), 2);
+    assert!(walk.request_bytes > 0 && walk.response_bytes > 0);
+    assert_eq!(snapshot.op(FcallType::Tclunk).unwrap().count, 2);
+    assert_eq!(snapshot.errnos, vec![(FcallType::Twalk, errno::ENOENT, 1)]);
+    assert_eq!(snapshot.inflight, 0);
+    assert_eq!(snapshot.fids, 0);
+}
+
+#[test]
+fn client_and_server_metrics() {
+    let server_metrics = Arc::new(Metrics::new());
+    let (conn, server) = spawn_server(
+        RootFs,
+        8192,
+        ServeOptions::new().metrics(server_metrics.clone()),
+    );
+    let client_metrics = Arc::new(Metrics::new());
+    let client = ClientBuilder::new()
+        .msize(8192)
+        .metrics(client_metrics.clone())
+        .over_unix_stream(conn)
+        .unwrap();
+    exercise(&client);
+
+    check(&client_metrics.snapshot());
+    // The server records responses after writing them, wait for it to finish.
+    drop(client);
+    server.join().unwrap();
+    check(&server_metrics.snapshot());
+}
+
+#[test]
+fn client_metrics_are_opt_in() {
+    let (conn, server) = spawn_server(RootFs, 8192, ServeOptions::new());
+    let client = Client::over_unix_stream(conn, 8192).unwrap();
+    assert!(client.metrics().is_none());
+    drop(client);
+    server.join().unwrap();
+}
+
+#[test]
+fn fid_gauge() {
+    let (conn, server) = spawn_server(
+        RootFs,
+        8192,
+        ServeOptions::new().metrics(Arc::new(Metrics::new())),
+    );
+    let metrics = Arc::new(Metrics::new());
+    let client = ClientBuilder::new()
+        .msize(8192)
+        .metrics(metrics.clone())
+        .over_unix_stream(conn)
+        .unwrap();
+    let (_, root) = client.attach(0, "root", "/").unwrap();
+    let (_, clone) = root.walk::<&str>(&[]).unwrap();
+    assert_eq!(metrics.snapshot().fids, 2);
+    clone.clunk().unwrap();
+    assert_eq!(metrics.snapshot().fids, 1);
+    drop(root);
+    drop(client);
+    server.join().unwrap();
+}
+
+#[test]
+fn proxy_metrics() {
+    let metrics = Arc::new(Metrics::new());
+    let (client_conn, proxy_conn) = UnixStream::pair().unwrap();
+    let (server_conn, server) = spawn_server(
+        RootFs,
+        8192,
+        ServeOptions::new().metrics(Arc::new(Metrics::new())),
+    );
+    let mut proxy = Proxy::new(proxy_conn.try_clone().unwrap(), proxy_conn, ());
+    proxy.set_metrics(Some(metrics.clone()));
+    let proxy = std::thread::spawn(move || {
+        proxy.accept_version().unwrap();
+        proxy
+            .connect(server_conn.try_clone().unwrap(), server_conn)
+            .unwrap();
+        let _ = proxy.run();
+        server.join().unwrap();
+    });
+    let client_metrics = Arc::new(Metrics::new());
+    let client = ClientBuilder::new()
+        .msize(8192)
+        .metrics(client_metrics.clone())
+        .over_unix_stream(client_conn)
+        .unwrap();
+    exercise(&client);
+    drop(client);
+    proxy.join().unwrap();
+    let snapshot = metrics.snapshot();
+    check(&snapshot);
+    // Both ends see the same messages on the wire.
+    for ((typ, proxied), (_, sent)) in snapshot.ops.iter().zip(client_metrics.snapshot().ops) {
+        assert_eq!(proxied.request_bytes, sent.request_bytes, "{:?}", typ);
+        assert_eq!(proxied.response_bytes, sent.response_bytes, "{:?}", typ);
+    }
+}
+
+#[test]
+fn prometheus_text() {
+    let metrics = Metrics::new();
+    let started = metrics.start(&Tclunk { fid: 1 }.into(), 11);
+    metrics.finish(
+        started,
+        &Rlerror {
+            ecode: errno::EBADF,
+        }
+        .into(),
+        11,
+    );
+    let text = metrics.snapshot().to_prometheus("p9");
+    for line in [
+        "# TYPE p9_requests_total counter",
+        "p9_requests_total{op=\"Tclunk\"} 1",
+        "p9_errors_total{op=\"Tclunk\",errno=\"EBADF\"} 1",
+        "p9_request_bytes_total{op=\"Tclunk\"} 11",
+        "# TYPE p9_request_duration_seconds histogram",
+        "p9_request_duration_seconds_bucket{op=\"Tclunk\",le=\"+Inf\"} 1",
+        "p9_request_duration_seconds_count{op=\"Tclunk\"} 1",
+        "p9_inflight_requests 0",
+        "p9_fids -1",
+    ] {
+        assert!(
+            text.lines().any(|l| l == line),
+            "missing {}:\n{}",
+            line,
+            text
+        );
+    }
+}
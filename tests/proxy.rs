@@ -0,0 +1,426 @@
+use p92000l::*;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+struct AttachFs;
+
+impl Filesystem for AttachFs {
+    fn attach(&mut self, req: &Tattach, resp: FcallResponse) {
+        resp.send(Rattach {
+            qid: Qid {
+                typ: QidType::DIR,
+                version: 0,
+                path: req.n_uname as u64,
+            },
+        })
+    }
+
+    fn walk(&mut self, req: &Twalk, resp: FcallResponse) {
+        resp.send(Rwalk {
+            wqids: req
+                .wnames
+                .iter()
+                .map(|_| Qid {
+                    typ: QidType::FILE,
+                    version: 0,
+                    path: 2,
+                })
+                .collect(),
+        })
+    }
+
+    fn clunk(&mut self, _req: &Tclunk, resp: FcallResponse) {
+        resp.send(Rclunk {})
+    }
+}
+
+// Returns the proxy's end of a fresh server connection.
+fn spawn_server() -> (UnixStream, std::thread::JoinHandle<()>) {
+    let (conn, server_conn) = UnixStream::pair().unwrap();
+    let handle = std::thread::spawn(move || {
+        let wconn = server_conn.try_clone().unwrap();
+        serve_transport(server_conn, wconn, &mut AttachFs, 8192);
+    });
+    (conn, handle)
+}
+
+fn spawn_proxy<I: Interceptor + 'static>(interceptor: I) -> (Client, std::thread::JoinHandle<()>) {
+    let (client_conn, proxy_conn) = UnixStream::pair().unwrap();
+    let (server_conn, server) = spawn_server();
+    let handle = std::thread::spawn(move || {
+        let _ = proxy_transport(
+            proxy_conn.try_clone().unwrap(),
+            proxy_conn,
+            server_conn.try_clone().unwrap(),
+            server_conn,
+            interceptor,
+        );
+        server.join().unwrap();
+    });
+    let client =
+        Client::over_transport(client_conn.try_clone().unwrap(), client_conn, 8192).unwrap();
+    (client, handle)
+}
+
+#[test]
+fn proxy_passthrough() {
+    let (client, proxy) = spawn_proxy(());
+    let (qid, root) = client.attach(7, "root", "/").unwrap();
+    assert_eq!(qid.path, 7);
+    let (wqids, f) = root.walk(&["a", "b"]).unwrap();
+    assert_eq!(wqids.len(), 2);
+    f.clunk().unwrap();
+    root.clunk().unwrap();
+    drop(client);
+    proxy.join().unwrap();
+}
+
+#[derive(Default)]
+struct Observer {
+    seen: Mutex<Vec<(FcallType, FcallType)>>,
+}
+
+impl Interceptor for Observer {
+    fn response(&self, _ctx: &ProxyContext, req: &Fcall, resp: &TaggedFcall) -> Action {
+        self.seen
+            .lock()
+            .unwrap()
+            .push((FcallType::from(req), FcallType::from(&resp.fcall)));
+        Action::Forward
+    }
+}
+
+#[test]
+fn proxy_observe() {
+    let observer = Arc::new(Observer::default());
+    let (client, proxy) = spawn_proxy(observer.clone());
+    let (_, root) = client.attach(0, "root", "/").unwrap();
+    root.clunk().unwrap();
+    drop(client);
+    proxy.join().unwrap();
+    assert_eq!(
+        *observer.seen.lock().unwrap(),
+        vec![
+            (FcallType::Tattach, FcallType::Rattach),
+            (FcallType::Tclunk, FcallType::Rclunk),
+        ]
+    );
+}
+
+struct Rules;
+
+impl Interceptor for Rules {
+    fn request(&self, _ctx: &ProxyContext, req: &TaggedFcall) -> Action {
+        match &req.fcall {
+            Fcall::Tattach(tattach) if tattach.uname.as_bytes() == b"mallory" => {
+                Action::Reject(errno::EPERM)
+            }
+            // Answer without the server.
+            Fcall::Twalk(twalk) if twalk.wnames.is_empty() => {
+                Action::Reply(Rwalk { wqids: Vec::new() }.into())
+            }
+            _ => Action::Forward,
+        }
+    }
+
+    fn response(&self, _ctx: &ProxyContext, _req: &Fcall, resp: &TaggedFcall) -> Action {
+        match &resp.fcall {
+            Fcall::Rattach(rattach) => Action::Rewrite(
+                Rattach {
+                    qid: Qid {
+                        path: rattach.qid.path + 100,
+                        ..rattach.qid
+                    },
+                }
+                .into(),
+            ),
+            _ => Action::Forward,
+        }
+    }
+}
+
+#[test]
+fn proxy_rewrite_and_reject() {
+    let (client, proxy) = spawn_proxy(Rules);
+    let err = client.attach(0, "mallory", "/").err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    let (qid, root) = client.attach(1, "root", "/").unwrap();
+    assert_eq!(qid.path, 101);
+    let (wqids, f) = root.walk::<&str>(&[]).unwrap();
+    assert!(wqids.is_empty());
+    // The server never saw the clone, but it answers clunk for any fid.
+    f.clunk().unwrap();
+    root.clunk().unwrap();
+    drop(client);
+    proxy.join().unwrap();
+}
+
+#[derive(Default)]
+struct Injector {
+    attached: Mutex<Vec<Fcall<'static>>>,
+}
+
+impl Interceptor for Injector {
+    fn request(&self, ctx: &ProxyContext, req: &TaggedFcall) -> Action {
+        if let Fcall::Tclunk(_) = req.fcall {
+            let resp = ctx
+                .call(
+                    Tattach {
+                        fid: 99,
+                        afid: NOFID,
+                        uname: "proxy".into(),
+                        aname: "/".into(),
+                        n_uname: 99,
+                    }
+                    .into(),
+                )
+                .unwrap();
+            self.attached.lock().unwrap().push(resp);
+        }
+        Action::Forward
+    }
+}
+
+#[test]
+fn proxy_inject() {
+    let injector = Arc::new(Injector::default());
+    let (client, proxy) = spawn_proxy(injector.clone());
+    let (_, root) = client.attach(0, "root", "/").unwrap();
+    root.clunk().unwrap();
+    drop(client);
+    proxy.join().unwrap();
+    assert_eq!(
+        *injector.attached.lock().unwrap(),
+        vec![Fcall::Rattach(Rattach {
+            qid: Qid {
+                typ: QidType::DIR,
+                version: 0,
+                path: 99,
+            },
+        })]
+    );
+}
+
+#[derive(Default)]
+struct Counter(AtomicUsize);
+
+impl Interceptor for Counter {
+    fn request(&self, _ctx: &ProxyContext, _req: &TaggedFcall) -> Action {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        Action::Forward
+    }
+}
+
+#[test]
+fn proxy_reconnect() {
+    let (client_conn, proxy_conn) = UnixStream::pair().unwrap();
+    let (server_conn, server) = spawn_server();
+    let kill = server_conn.try_clone().unwrap();
+    let counter = Arc::new(Counter::default());
+
+    let mut proxy = Proxy::new(proxy_conn.try_clone().unwrap(), proxy_conn, counter.clone());
+    let proxy = std::thread::spawn(move || {
+        proxy.accept_version().unwrap();
+        proxy
+            .connect(server_conn.try_clone().unwrap(), server_conn)
+            .unwrap();
+        // Lose the server.
+        proxy.run().unwrap();
+        proxy.fail_inflight(errno::EIO).unwrap();
+        let (server_conn, server) = spawn_server();
+        proxy
+            .connect(server_conn.try_clone().unwrap(), server_conn)
+            .unwrap();
+        assert!(proxy.run().is_err());
+        server.join().unwrap();
+    });
+
+    let client =
+        Client::over_transport(client_conn.try_clone().unwrap(), client_conn, 8192).unwrap();
+    let (_, root) = client.attach(0, "root", "/").unwrap();
+    kill.shutdown(std::net::Shutdown::Both).unwrap();
+    server.join().unwrap();
+
+    // Requests keep working once the proxy has reconnected.
+    let (_, root2) = loop {
+        match client.attach(1, "root", "/") {
+            Ok(r) => break r,
+            Err(_) => std::thread::sleep(std::time::Duration::from_millis(10)),
+        }
+    };
+    root2.clunk().unwrap();
+    drop(root);
+    drop(client);
+    proxy.join().unwrap();
+    assert!(counter.0.load(Ordering::SeqCst) >= 3);
+}
+
+#[test]
+fn proxy_retry() {
+    let (client_conn, proxy_conn) = UnixStream::pair().unwrap();
+    let (server_conn, server) = spawn_server();
+    let kill = server_conn.try_clone().unwrap();
+
+    let mut proxy = Proxy::new(proxy_conn.try_clone().unwrap(), proxy_conn, ());
+    proxy.set_retry(Some(RetryOptions {
+        max_hold: Duration::from_millis(250),
+        ..RetryOptions::default()
+    }));
+    let proxy = std::thread::spawn(move || {
+        proxy.accept_version().unwrap();
+        proxy
+            .connect(server_conn.try_clone().unwrap(), server_conn)
+            .unwrap();
+        proxy.run().unwrap();
+        proxy.fail_inflight(errno::EIO).unwrap();
+        // Long enough for the first held request to time out.
+        proxy
+            .reject_until(Instant::now() + Duration::from_millis(400), errno::EIO)
+            .unwrap();
+        proxy.set_retry(Some(RetryOptions::default()));
+        proxy
+            .reject_until(Instant::now() + Duration::from_millis(100), errno::EIO)
+            .unwrap();
+        let (server_conn, server) = spawn_server();
+        proxy
+            .connect(server_conn.try_clone().unwrap(), server_conn)
+            .unwrap();
+        assert!(proxy.run().is_err());
+        server.join().unwrap();
+    });
+
+    let client =
+        Client::over_transport(client_conn.try_clone().unwrap(), client_conn, 8192).unwrap();
+    let (_, root) = client.attach(0, "root", "/").unwrap();
+    kill.shutdown(std::net::Shutdown::Both).unwrap();
+    server.join().unwrap();
+
+    // Not idempotent, fails straight away.
+    let err = root.mkdir("d", 0o755, 0).unwrap_err();
+    assert_eq!(errno::errno_from_io_error(&err), errno::EIO);
+    let err = root.walk(&["a"]).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    // Held until the proxy reconnects.
+    let (wqids, f) = root.walk(&["a"]).unwrap();
+    assert_eq!(wqids.len(), 1);
+    drop(f);
+    drop(root);
+    drop(client);
+    proxy.join().unwrap();
+}
+
+#[test]
+fn proxy_flushed_request_is_not_held() {
+    let (mut client_conn, proxy_conn) = UnixStream::pair().unwrap();
+    let (server_conn, mut backend) = UnixStream::pair().unwrap();
+
+    // A backend that takes a Tread and its Tflush, then goes away.
+    let server = std::thread::spawn(move || {
+        let mut buf = Vec::with_capacity(8192);
+        let msize = match transport::read(&mut backend, &mut buf).unwrap().fcall {
+            Fcall::Tversion(tversion) => tversion.msize,
+            fcall => panic!("unexpected {:?}", fcall),
+        };
+        let rversion = TaggedFcall {
+            tag: NOTAG,
+            fcall: Rversion {
+                msize,
+                version: "9P2000.L".into(),
+            }
+            .into(),
+        };
+        transport::write(&mut backend, &mut buf, &rversion).unwrap();
+        let tread = transport::read(&mut backend, &mut buf)
+            .unwrap()
+            .clone_static();
+        assert!(matches!(tread.fcall, Fcall::Tread(_)));
+        let tflush = transport::read(&mut backend, &mut buf)
+            .unwrap()
+            .clone_static();
+        assert!(matches!(tflush.fcall, Fcall::Tflush(_)));
+    });
+
+    let mut proxy = Proxy::new(proxy_conn.try_clone().unwrap(), proxy_conn, ());
+    proxy.set_retry(Some(RetryOptions::default()));
+    let proxy = std::thread::spawn(move || {
+        proxy.accept_version().unwrap();
+        proxy
+            .connect(server_conn.try_clone().unwrap(), server_conn)
+            .unwrap();
+        proxy.run().unwrap();
+        proxy.fail_inflight(errno::EIO).unwrap();
+    });
+
+    let mut buf = Vec::with_capacity(8192);
+    let tversion = TaggedFcall {
+        tag: NOTAG,
+        fcall: Tversion {
+            msize: 8192,
+            version: "9P2000.L".into(),
+        }
+        .into(),
+    };
+    transport::write(&mut client_conn, &mut buf, &tversion).unwrap();
+    transport::read(&mut client_conn, &mut buf).unwrap();
+    let tread = TaggedFcall {
+        tag: 1,
+        fcall: Tread {
+            fid: 1,
+            offset: 0,
+            count: 10,
+        }
+        .into(),
+    };
+    transport::write(&mut client_conn, &mut buf, &tread).unwrap();
+    let tflush = TaggedFcall {
+        tag: 2,
+        fcall: Tflush { oldtag: 1 }.into(),
+    };
+    transport::write(&mut client_conn, &mut buf, &tflush).unwrap();
+    server.join().unwrap();
+    proxy.join().unwrap();
+
+    // The read is answered rather than held for a later server, then the flush.
+    client_conn
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let resp = transport::read(&mut client_conn, &mut buf).unwrap();
+    assert_eq!(resp.tag, 1);
+    assert!(matches!(
+        resp.fcall,
+        Fcall::Rlerror(Rlerror { ecode: errno::EIO })
+    ));
+    let resp = transport::read(&mut client_conn, &mut buf).unwrap();
+    assert_eq!(resp.tag, 2);
+    assert!(matches!(resp.fcall, Fcall::Rflush(_)));
+}
+
+#[test]
+fn idempotent_requests() {
+    assert!(is_idempotent(
+        &Tread {
+            fid: 1,
+            offset: 0,
+            count: 1
+        }
+        .into()
+    ));
+    assert!(is_idempotent(
+        &Tlopen {
+            fid: 1,
+            flags: LOpenFlags::O_RDWR
+        }
+        .into()
+    ));
+    assert!(!is_idempotent(
+        &Tlopen {
+            fid: 1,
+            flags: LOpenFlags::O_RDWR | LOpenFlags::O_TRUNC
+        }
+        .into()
+    ));
+    assert!(!is_idempotent(&Tclunk { fid: 1 }.into()));
+    assert!(!is_idempotent(&Tflush { oldtag: 1 }.into()));
+}
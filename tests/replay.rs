@@ -0,0 +1,243 @@
+use p92000l::*;
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Clone, Default)]
+struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// A root directory containing a single file called "hello".
+#[derive(Clone)]
+struct HelloFs {
+    data: Vec<u8>,
+    missing_errno: u32,
+    // Reads are held here unanswered when set.
+    stalled: Option<Vec<FcallResponse>>,
+    // Reads never return when set.
+    hang: bool,
+}
+
+impl HelloFs {
+    fn new() -> HelloFs {
+        HelloFs {
+            data: b"hello world".to_vec(),
+            missing_errno: errno::ENOENT,
+            stalled: None,
+            hang: false,
+        }
+    }
+}
+
+impl Filesystem for HelloFs {
+    fn attach(&mut self, _req: &Tattach, resp: FcallResponse) {
+        resp.send(Rattach {
+            qid: Qid {
+                typ: QidType::DIR,
+                version: 0,
+                path: 1,
+            },
+        })
+    }
+
+    fn walk(&mut self, req: &Twalk, resp: FcallResponse) {
+        let mut wqids = Vec::new();
+        for name in req.wnames.iter() {
+            if name.as_bytes() != b"hello" {
+                break;
+            }
+            wqids.push(Qid {
+                typ: QidType::FILE,
+                version: 0,
+                path: 2,
+            });
+        }
+        if wqids.is_empty() && !req.wnames.is_empty() {
+            resp.send(Rlerror {
+                ecode: self.missing_errno,
+            })
+        } else {
+            resp.send(Rwalk { wqids })
+        }
+    }
+
+    fn lopen(&mut self, _req: &Tlopen, resp: FcallResponse) {
+        resp.send(Rlopen {
+            qid: Qid {
+                typ: QidType::FILE,
+                version: 0,
+                path: 2,
+            },
+            iounit: 0,
+        })
+    }
+
+    fn read(&mut self, req: &Tread, resp: FcallResponse) {
+        if self.hang {
+            loop {
+                std::thread::park();
+            }
+        }
+        if let Some(stalled) = &mut self.stalled {
+            return stalled.push(resp);
+        }
+        let off = (req.offset as usize).min(self.data.len());
+        let end = (off + req.count as usize).min(self.data.len());
+        resp.send(Rread {
+            data: self.data[off..end].to_vec().into(),
+        })
+    }
+
+    fn clunk(&mut self, _req: &Tclunk, resp: FcallResponse) {
+        resp.send(Rclunk {})
+    }
+}
+
+fn record_session(fs: HelloFs) -> Vec<TraceFrame> {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || {
+        let mut fs = fs;
+        let (conn, _) = listener.accept().unwrap();
+        serve(conn, &mut fs, 8192);
+    });
+
+    let sink = SharedBuf::default();
+    let trace = TraceWriter::new(sink.clone()).unwrap();
+    let conn = TcpStream::connect(addr).unwrap();
+    let (r, w) = trace.tee(conn.try_clone().unwrap(), conn);
+    let client = Client::over_transport(r, w, 8192).unwrap();
+    let (_, root) = client.attach(0, "root", "/").unwrap();
+    assert!(root.walk(&["missing"]).is_err());
+    let (_, f) = root.walk(&["hello"]).unwrap();
+    f.open(LOpenFlags::O_RDONLY).unwrap();
+    let mut buf = [0; 64];
+    f.read(0, &mut buf).unwrap();
+    f.clunk().unwrap();
+    root.clunk().unwrap();
+    drop(client);
+    server.join().unwrap();
+
+    let data = sink.0.lock().unwrap().clone();
+    TraceReader::new(&data[..])
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap()
+}
+
+#[test]
+fn replay_same_fs_is_clean() {
+    let frames = record_session(HelloFs::new());
+    let report = replay(frames, HelloFs::new(), &ReplayOptions::default()).unwrap();
+    assert!(report.is_clean(), "{}", report);
+    assert_eq!(report.requests, 7);
+    assert_eq!(report.compared, 7);
+}
+
+#[test]
+fn replay_reports_divergences() {
+    let frames = record_session(HelloFs::new());
+    let mut fs = HelloFs::new();
+    fs.missing_errno = errno::EACCES;
+    fs.data = b"hello there".to_vec();
+    let report = replay(frames, fs, &ReplayOptions::default()).unwrap();
+    let kinds: Vec<DivergenceKind> = report.divergences.iter().map(|d| d.kind.clone()).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            DivergenceKind::Errno {
+                expected: errno::ENOENT,
+                got: errno::EACCES,
+            },
+            DivergenceKind::Data {
+                offset: 6,
+                expected_len: 11,
+                got_len: 11,
+            },
+        ]
+    );
+    assert!(report.divergences[0].request.starts_with("Twalk"));
+    assert!(report.divergences[1].request.starts_with("Tread"));
+}
+
+#[test]
+fn replay_ignore_qids() {
+    let frames = record_session(HelloFs::new());
+    // The recorded attach qid differs from the replayed one.
+    let frames: Vec<TraceFrame> = frames
+        .into_iter()
+        .map(|mut frame| {
+            let fcall = frame.fcall().unwrap().clone_static();
+            if let Fcall::Rattach(ref rattach) = fcall.fcall {
+                let mut fcall = fcall.clone();
+                fcall.fcall = Rattach {
+                    qid: Qid {
+                        path: rattach.qid.path + 100,
+                        ..rattach.qid
+                    },
+                }
+                .into();
+                frame.data.clear();
+                fcall.encode_to_buf(&mut frame.data).unwrap();
+            }
+            frame
+        })
+        .collect();
+
+    let report = replay(frames.clone(), HelloFs::new(), &ReplayOptions::default()).unwrap();
+    assert_eq!(report.divergences.len(), 1);
+    assert!(matches!(
+        report.divergences[0].kind,
+        DivergenceKind::Qid { .. }
+    ));
+
+    let options = ReplayOptions {
+        ignore_qids: true,
+        ..ReplayOptions::default()
+    };
+    let report = replay(frames, HelloFs::new(), &options).unwrap();
+    assert!(report.is_clean(), "{}", report);
+}
+
+#[test]
+fn replay_times_out() {
+    let frames = record_session(HelloFs::new());
+    let mut fs = HelloFs::new();
+    fs.stalled = Some(Vec::new());
+    let options = ReplayOptions {
+        timeout: Duration::from_millis(100),
+        ..ReplayOptions::default()
+    };
+    let report = replay(frames, fs, &options).unwrap();
+    assert_eq!(report.divergences.len(), 1, "{}", report);
+    assert_eq!(report.divergences[0].kind, DivergenceKind::NoResponse);
+    assert!(report.divergences[0].request.starts_with("Tread"));
+    // Replay stops at the first unanswered request.
+    assert_eq!(report.requests, 5);
+}
+
+#[test]
+fn replay_gives_up_on_stuck_fs() {
+    let frames = record_session(HelloFs::new());
+    let mut fs = HelloFs::new();
+    fs.hang = true;
+    let options = ReplayOptions {
+        timeout: Duration::from_millis(100),
+        ..ReplayOptions::default()
+    };
+    let report = replay(frames, fs, &options).unwrap();
+    assert_eq!(report.divergences.len(), 1, "{}", report);
+    assert_eq!(report.divergences[0].kind, DivergenceKind::NoResponse);
+    assert!(report.divergences[0].request.starts_with("Tread"));
+}
@@ -0,0 +1,129 @@
+use p92000l::*;
+use std::fs::File;
+use std::sync::Arc;
+
+// Serves one file, attached as fid 0, reading it with ReadBuf.
+struct FileFs {
+    file: File,
+}
+
+impl FileFs {
+    fn new(name: &str, data: &[u8]) -> FileFs {
+        let path = std::env::temp_dir().join(format!("p9-{}-{}", name, std::process::id()));
+        std::fs::write(&path, data).unwrap();
+        let file = File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        FileFs { file }
+    }
+}
+
+impl ThreadedFilesystem for FileFs {
+    fn attach(&self, _req: &Tattach, resp: FcallResponse) {
+        resp.send(Rattach {
+            qid: Qid {
+                typ: QidType::FILE,
+                version: 0,
+                path: 1,
+            },
+        })
+    }
+
+    fn lopen(&self, _req: &Tlopen, resp: FcallResponse) {
+        resp.send(Rlopen {
+            qid: Qid {
+                typ: QidType::FILE,
+                version: 0,
+                path: 1,
+            },
+            iounit: 0,
+        })
+    }
+
+    fn read(&self, req: &Tread, resp: FcallResponse) {
+        let mut buf = resp.read_buf(req.count);
+        match buf.read_at(&self.file, req.offset) {
+            Ok(_) => resp.send_read(buf),
+            Err(err) => resp.send(Rlerror {
+                ecode: errno::errno_from_io_error(&err),
+            }),
+        }
+    }
+
+    fn clunk(&self, _req: &Tclunk, resp: FcallResponse) {
+        resp.send(Rclunk {})
+    }
+}
+
+impl Filesystem for FileFs {
+    fn attach(&mut self, req: &Tattach, resp: FcallResponse) {
+        ThreadedFilesystem::attach(self, req, resp)
+    }
+
+    fn lopen(&mut self, req: &Tlopen, resp: FcallResponse) {
+        ThreadedFilesystem::lopen(self, req, resp)
+    }
+
+    fn read(&mut self, req: &Tread, resp: FcallResponse) {
+        ThreadedFilesystem::read(self, req, resp)
+    }
+
+    fn clunk(&mut self, req: &Tclunk, resp: FcallResponse) {
+        ThreadedFilesystem::clunk(self, req, resp)
+    }
+}
+
+fn contents() -> Vec<u8> {
+    (0..20000u32).map(|i| (i % 251) as u8).collect()
+}
+
+fn read_all(conn: Loopback) -> Vec<u8> {
+    let client = ClientBuilder::new()
+        .msize(8192)
+        .over_transport(conn.clone(), conn)
+        .unwrap();
+    let (_, root) = client.attach(0, "root", "").unwrap();
+    root.open(LOpenFlags::O_RDONLY).unwrap();
+    let mut data = Vec::new();
+    let mut buf = vec![0; 65536];
+    loop {
+        match root.read(data.len() as u64, &mut buf).unwrap() {
+            0 => return data,
+            n => data.extend_from_slice(&buf[..n]),
+        }
+    }
+}
+
+#[test]
+fn read_buf() {
+    let (conn, server_conn) = loopback_pair();
+    std::thread::spawn(move || {
+        let mut fs = FileFs::new("read-buf", &contents());
+        serve_transport(server_conn.clone(), server_conn, &mut fs, 8192)
+    });
+    assert_eq!(read_all(conn), contents());
+}
+
+#[test]
+fn read_buf_sharded() {
+    let server = Arc::new(ShardedServer::new(FileFs::new("sharded", &contents()), 2));
+    let (conn, server_conn) = loopback_pair();
+    std::thread::spawn(move || server.serve_transport(server_conn.clone(), server_conn, 8192));
+    assert_eq!(read_all(conn), contents());
+}
+
+#[test]
+fn read_buf_captured() {
+    let mut fs = FileFs::new("captured", b"captured");
+    let tread = Tread {
+        fid: 0,
+        offset: 2,
+        count: 4,
+    };
+    let resp = call_filesystem(&mut fs, &tread.into());
+    assert_eq!(
+        resp,
+        Fcall::Rread(Rread {
+            data: b"ptur"[..].into()
+        })
+    );
+}
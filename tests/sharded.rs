@@ -0,0 +1,299 @@
+use p92000l::*;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Reads wait for a token on gate, statfs takes delay. Flushed reads are
+// never answered, getattr responses are handed to held instead.
+struct GateFs {
+    entered: Arc<AtomicUsize>,
+    gate: crossbeam_channel::Receiver<()>,
+    delay: Duration,
+    flushed: Mutex<HashSet<u16>>,
+    held: crossbeam_channel::Sender<FcallResponse>,
+}
+
+impl ThreadedFilesystem for GateFs {
+    fn read(&self, _req: &Tread, mut resp: FcallResponse) {
+        self.entered.fetch_add(1, Ordering::SeqCst);
+        let _ = self.gate.recv();
+        if self.flushed.lock().unwrap().contains(&resp.tag) {
+            resp.tag = NOTAG;
+            return;
+        }
+        resp.send(Rread {
+            data: Cow::from(&b"done"[..]),
+        })
+    }
+
+    fn statfs(&self, _req: &Tstatfs, resp: FcallResponse) {
+        std::thread::sleep(self.delay);
+        resp.send(Rstatfs {
+            statfs: Statfs {
+                typ: 0,
+                bsize: 4096,
+                blocks: 0,
+                bfree: 0,
+                bavail: 0,
+                files: 0,
+                ffree: 0,
+                fsid: 0,
+                namelen: 255,
+            },
+        })
+    }
+
+    fn getattr(&self, _req: &Tgetattr, resp: FcallResponse) {
+        let _ = self.held.send(resp);
+    }
+
+    fn readlink(&self, _req: &Treadlink, resp: FcallResponse) {
+        resp.send(Rreadlink {
+            target: FcallStr::Owned(vec![b'x'; 10000]),
+        })
+    }
+
+    fn flush(&self, req: &Tflush, resp: FcallResponse) {
+        self.flushed.lock().unwrap().insert(req.oldtag);
+        resp.send(Rflush {})
+    }
+}
+
+struct Setup {
+    server: Arc<ShardedServer<GateFs>>,
+    entered: Arc<AtomicUsize>,
+    gate: crossbeam_channel::Sender<()>,
+    held: crossbeam_channel::Receiver<FcallResponse>,
+}
+
+fn setup(workers: usize, max_in_flight: usize, delay: Duration) -> Setup {
+    setup_with(workers, max_in_flight, delay, ServeOptions::new())
+}
+
+fn setup_with(
+    workers: usize,
+    max_in_flight: usize,
+    delay: Duration,
+    options: ServeOptions,
+) -> Setup {
+    let entered = Arc::new(AtomicUsize::new(0));
+    let (gate, rx) = crossbeam_channel::unbounded();
+    let (held_tx, held) = crossbeam_channel::unbounded();
+    let fs = GateFs {
+        entered: entered.clone(),
+        gate: rx,
+        delay,
+        flushed: Mutex::new(HashSet::new()),
+        held: held_tx,
+    };
+    let server = ShardedServer::new(fs, workers)
+        .max_in_flight(max_in_flight)
+        .options(options);
+    Setup {
+        server: Arc::new(server),
+        entered,
+        gate,
+        held,
+    }
+}
+
+// Talks to the server a message at a time, to see exactly what it sends.
+struct Raw {
+    conn: Loopback,
+    buf: Vec<u8>,
+}
+
+impl Raw {
+    fn connect(server: &Arc<ShardedServer<GateFs>>) -> Raw {
+        let (conn, server_conn) = loopback_pair();
+        let server = server.clone();
+        std::thread::spawn(move || server.serve_transport(server_conn.clone(), server_conn, 8192));
+        let mut raw = Raw {
+            conn,
+            buf: Vec::with_capacity(8192),
+        };
+        raw.send(
+            NOTAG,
+            Tversion {
+                msize: 8192,
+                version: "9P2000.L".into(),
+            },
+        );
+        assert!(matches!(raw.recv().unwrap().fcall, Fcall::Rversion(_)));
+        raw
+    }
+
+    fn send<'a>(&mut self, tag: u16, fcall: impl Into<Fcall<'a>>) {
+        let fcall = TaggedFcall {
+            tag,
+            fcall: fcall.into(),
+        };
+        write(&mut self.conn, &mut self.buf, &fcall).unwrap();
+    }
+
+    fn recv(&mut self) -> Option<TaggedFcall<'_>> {
+        match read_to_buf_timeout(&mut self.conn, &mut self.buf, Duration::from_millis(50)) {
+            Ok(()) => Some(TaggedFcall::decode(&self.buf).unwrap()),
+            Err(err) if err.kind() == std::io::ErrorKind::TimedOut => None,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    fn recv_tag(&mut self) -> Option<u16> {
+        self.recv().map(|resp| resp.tag)
+    }
+}
+
+fn tread() -> Tread {
+    Tread {
+        fid: 0,
+        offset: 0,
+        count: 64,
+    }
+}
+
+#[test]
+fn out_of_order() {
+    let setup = setup(2, 64, Duration::ZERO);
+    let mut raw = Raw::connect(&setup.server);
+    raw.send(1, tread());
+    raw.send(2, Tstatfs { fid: 0 });
+    assert_eq!(raw.recv_tag(), Some(2));
+    assert_eq!(raw.recv_tag(), None);
+    setup.gate.send(()).unwrap();
+    let resp = raw.recv().unwrap();
+    assert_eq!(resp.tag, 1);
+    assert!(matches!(resp.fcall, Fcall::Rread(Rread { ref data }) if &data[..] == b"done"));
+}
+
+#[test]
+fn in_flight_limit() {
+    let setup = setup(4, 2, Duration::ZERO);
+    let mut raw = Raw::connect(&setup.server);
+    for tag in 1..=3 {
+        raw.send(tag, tread());
+    }
+    assert_eq!(raw.recv_tag(), None);
+    assert_eq!(setup.entered.load(Ordering::SeqCst), 2);
+    // Answering one lets the next in.
+    setup.gate.send(()).unwrap();
+    assert!(raw.recv_tag().is_some());
+    assert_eq!(raw.recv_tag(), None);
+    assert_eq!(setup.entered.load(Ordering::SeqCst), 3);
+    setup.gate.send(()).unwrap();
+    setup.gate.send(()).unwrap();
+    assert!(raw.recv_tag().is_some());
+    assert!(raw.recv_tag().is_some());
+}
+
+#[test]
+fn flush_is_not_held_back() {
+    let setup = setup(2, 1, Duration::ZERO);
+    let mut raw = Raw::connect(&setup.server);
+    raw.send(1, tread());
+    // Waits behind the first read, but is flushed before it runs.
+    raw.send(2, tread());
+    raw.send(3, Tflush { oldtag: 2 });
+    assert_eq!(raw.recv_tag(), Some(3));
+    assert_eq!(setup.entered.load(Ordering::SeqCst), 1);
+    setup.gate.send(()).unwrap();
+    setup.gate.send(()).unwrap();
+    assert_eq!(raw.recv_tag(), Some(1));
+    assert_eq!(raw.recv_tag(), None);
+    assert_eq!(setup.entered.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn drop_with_backlog() {
+    let setup = setup(1, 1, Duration::ZERO);
+    let mut raw = Raw::connect(&setup.server);
+    let tgetattr = Tgetattr {
+        fid: 0,
+        req_mask: GetattrMask::empty(),
+    };
+    // The first stays in flight, the rest wait behind it.
+    for tag in 1..5 {
+        raw.send(tag, tgetattr.clone());
+    }
+    let resp = setup.held.recv().unwrap();
+    assert_eq!(raw.recv_tag(), None);
+    drop(raw);
+    let server = setup.server;
+    while Arc::strong_count(&server) > 1 {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    drop(server);
+    // Finishing the first request once the server is gone lets go of the
+    // requests still waiting, which never took a place of their own.
+    drop(resp);
+    assert!(setup.held.is_empty());
+}
+
+#[test]
+fn oversized_response() {
+    let setup = setup(2, 64, Duration::ZERO);
+    let mut raw = Raw::connect(&setup.server);
+    raw.send(1, Treadlink { fid: 0 });
+    let resp = raw.recv().unwrap();
+    assert_eq!(resp.tag, 1);
+    assert_eq!(resp.fcall, Fcall::Rlerror(Rlerror { ecode: errno::EIO }));
+}
+
+#[test]
+fn metrics_and_hook() {
+    let metrics = Arc::new(Metrics::new());
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let hook_seen = seen.clone();
+    let hook: Arc<dyn FcallHook> = Arc::new(move |event: &FcallEvent| {
+        hook_seen
+            .lock()
+            .unwrap()
+            .push((event.point, event.tag, event.typ));
+    });
+    let options = ServeOptions::new().metrics(metrics.clone()).hook(hook);
+    let setup = setup_with(2, 64, Duration::ZERO, options);
+    let mut raw = Raw::connect(&setup.server);
+    raw.send(1, Tstatfs { fid: 0 });
+    assert_eq!(raw.recv_tag(), Some(1));
+    // Responses are recorded after they are queued.
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while seen.lock().unwrap().len() < 4 && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(1));
+    }
+
+    assert_eq!(
+        *seen.lock().unwrap(),
+        vec![
+            (HookPoint::ServerRequest, NOTAG, FcallType::Tversion),
+            (HookPoint::ServerResponse, NOTAG, FcallType::Rversion),
+            (HookPoint::ServerRequest, 1, FcallType::Tstatfs),
+            (HookPoint::ServerResponse, 1, FcallType::Rstatfs),
+        ]
+    );
+    let snapshot = metrics.snapshot();
+    let (_, statfs) = snapshot
+        .ops
+        .iter()
+        .find(|(typ, _)| *typ == FcallType::Tstatfs)
+        .unwrap();
+    assert_eq!(statfs.count, 1);
+    assert_eq!(snapshot.inflight, 0);
+}
+
+#[test]
+fn fair_across_connections() {
+    let setup = setup(1, 64, Duration::from_millis(10));
+    let mut busy = Raw::connect(&setup.server);
+    let mut quiet = Raw::connect(&setup.server);
+    for tag in 0..20 {
+        busy.send(tag, Tstatfs { fid: 0 });
+    }
+    std::thread::sleep(Duration::from_millis(5));
+    let start = Instant::now();
+    quiet.send(1, Tstatfs { fid: 0 });
+    while quiet.recv_tag().is_none() {}
+    // Waiting behind every busy request would take 200ms.
+    assert!(start.elapsed() < Duration::from_millis(100));
+}
@@ -0,0 +1,172 @@
+use p92000l::*;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+fn serve(root: SynthNode) -> (Client, ClientFid) {
+    let (conn, server_conn) = loopback_pair();
+    std::thread::spawn(move || {
+        let mut fs = SynthFs::new(root);
+        serve_transport(server_conn.clone(), server_conn, &mut fs, 65536)
+    });
+    let client = ClientBuilder::new()
+        .over_transport(conn.clone(), conn)
+        .unwrap();
+    let (_, root) = client.attach(0, "root", "").unwrap();
+    (client, root)
+}
+
+fn open(root: &ClientFid, path: &[&str], flags: LOpenFlags) -> std::io::Result<ClientFid> {
+    let (_, fid) = root.walk(path)?;
+    fid.open(flags)?;
+    Ok(fid)
+}
+
+fn read(root: &ClientFid, path: &[&str]) -> String {
+    let fid = open(root, path, LOpenFlags::O_RDONLY).unwrap();
+    let mut data = Vec::new();
+    let mut buf = [0; 4];
+    loop {
+        match fid.read(data.len() as u64, &mut buf).unwrap() {
+            0 => return String::from_utf8(data).unwrap(),
+            n => data.extend_from_slice(&buf[..n]),
+        }
+    }
+}
+
+fn list(root: &ClientFid, path: &[&str]) -> Vec<String> {
+    let fid = open(root, path, LOpenFlags::O_RDONLY).unwrap();
+    let entries = fid.read_dir().unwrap();
+    entries.iter().map(|entry| entry.name.to_string()).collect()
+}
+
+fn errno(result: std::io::Result<impl Sized>) -> u32 {
+    errno::errno_from_io_error(&result.err().unwrap())
+}
+
+#[test]
+fn files() {
+    let commands = Arc::new(Mutex::new(Vec::new()));
+    let c = commands.clone();
+    let root = SynthNode::dir()
+        .child("status", SynthNode::file().read(|| Ok("running\n")))
+        .child(
+            "ctl",
+            SynthNode::file().write(move |cmd| match cmd {
+                b"stop\n" | b"start\n" => {
+                    c.lock().unwrap().push(cmd.to_vec());
+                    Ok(())
+                }
+                _ => Err(errno::io_error(errno::EINVAL)),
+            }),
+        )
+        .child(
+            "log",
+            SynthNode::file().read(|| {
+                let log = std::io::Cursor::new(b"one\ntwo\n".to_vec());
+                Ok(SynthData::Stream(Box::new(log)))
+            }),
+        );
+    let (_client, root) = serve(root);
+    assert_eq!(list(&root, &[]), ["status", "ctl", "log"]);
+    assert_eq!(read(&root, &["status"]), "running\n");
+    assert_eq!(read(&root, &["log"]), "one\ntwo\n");
+
+    let ctl = open(&root, &["ctl"], LOpenFlags::O_WRONLY).unwrap();
+    ctl.write(0, b"stop\n").unwrap();
+    assert_eq!(errno(ctl.write(0, b"explode\n")), errno::EINVAL);
+    assert_eq!(*commands.lock().unwrap(), [b"stop\n".to_vec()]);
+
+    let (_, ctl) = root.walk(&["ctl"]).unwrap();
+    assert_eq!(errno(ctl.open(LOpenFlags::O_RDONLY)), errno::EACCES);
+    let (_, status) = root.walk(&["status"]).unwrap();
+    assert_eq!(errno(status.open(LOpenFlags::O_RDWR)), errno::EACCES);
+    assert_eq!(errno(root.walk(&["missing"])), errno::ENOENT);
+
+    let attr = ctl.getattr(GetattrMask::BASIC).unwrap();
+    assert_eq!(attr.stat.mode, 0o100200);
+    let attr = root.getattr(GetattrMask::BASIC).unwrap();
+    assert_eq!(attr.stat.mode, 0o40555);
+}
+
+#[test]
+fn qids() {
+    let root = SynthNode::dir()
+        .child("ctl", SynthNode::file().write(|_| Ok(())))
+        .child("sub", SynthNode::dir());
+    let (_client, root) = serve(root);
+    let (first, _) = root.walk(&["sub", "..", "ctl"]).unwrap();
+    let (again, ctl) = root.walk(&["ctl"]).unwrap();
+    assert_eq!(first[2], again[0]);
+    assert_eq!(first[0].typ, QidType::DIR);
+    assert_eq!(first[1], root.getattr(GetattrMask::BASIC).unwrap().qid);
+
+    // Writing changes the version.
+    ctl.open(LOpenFlags::O_WRONLY).unwrap();
+    ctl.write(0, b"x").unwrap();
+    let qid = ctl.getattr(GetattrMask::BASIC).unwrap().qid;
+    assert_eq!(qid.path, again[0].path);
+    assert_eq!(qid.version, again[0].version + 1);
+}
+
+#[test]
+fn generated() {
+    let names = Arc::new(Mutex::new(vec!["a", "b"]));
+    let n = names.clone();
+    let root = SynthNode::dir().child(
+        "conns",
+        SynthNode::generated(move || {
+            n.lock()
+                .unwrap()
+                .iter()
+                .map(|name| {
+                    let name = name.to_string();
+                    let file = SynthNode::file().read({
+                        let name = name.clone();
+                        move || Ok(name.clone())
+                    });
+                    (name, file)
+                })
+                .collect()
+        }),
+    );
+    let (_client, root) = serve(root);
+    assert_eq!(list(&root, &["conns"]), ["a", "b"]);
+    assert_eq!(read(&root, &["conns", "b"]), "b");
+    let (before, _) = root.walk(&["conns", "b"]).unwrap();
+
+    *names.lock().unwrap() = vec!["b", "c"];
+    assert_eq!(list(&root, &["conns"]), ["b", "c"]);
+    let (wqids, _) = root.walk(&["conns", "a"]).unwrap();
+    assert_eq!(wqids.len(), 1);
+    let (after, _) = root.walk(&["conns", "b"]).unwrap();
+    assert_eq!(before, after);
+}
+
+#[test]
+fn events() {
+    let events = Events::new();
+    let root = SynthNode::dir().child("events", SynthNode::events(&events));
+    let (_client, root) = serve(root);
+
+    // Events posted before opening are not seen.
+    events.post("early");
+    let fid = open(&root, &["events"], LOpenFlags::O_RDONLY).unwrap();
+    assert_eq!(events.readers(), 1);
+    let poster = events.clone();
+    std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(20));
+        poster.post("first");
+        poster.post("second");
+    });
+    let mut buf = [0; 64];
+    let n = fid.read(0, &mut buf).unwrap();
+    assert_eq!(&buf[..n], b"first");
+    // Long events are split over reads.
+    let n = fid.read(0, &mut buf[..4]).unwrap();
+    assert_eq!(&buf[..n], b"seco");
+    let n = fid.read(0, &mut buf).unwrap();
+    assert_eq!(&buf[..n], b"nd");
+
+    fid.clunk().unwrap();
+    assert_eq!(events.readers(), 0);
+}
@@ -0,0 +1,142 @@
+use p92000l::*;
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Default)]
+struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+struct AttachFs;
+
+impl Filesystem for AttachFs {
+    fn attach(&mut self, _req: &Tattach, resp: FcallResponse) {
+        resp.send(Rattach {
+            qid: Qid {
+                typ: QidType::DIR,
+                version: 0,
+                path: 1,
+            },
+        })
+    }
+
+    fn clunk(&mut self, _req: &Tclunk, resp: FcallResponse) {
+        resp.send(Rclunk {})
+    }
+}
+
+#[test]
+fn trace_client_session() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || {
+        let (conn, _) = listener.accept().unwrap();
+        serve(conn, &mut AttachFs, 8192);
+    });
+
+    let sink = SharedBuf::default();
+    let trace = TraceWriter::new(sink.clone()).unwrap();
+    let conn = TcpStream::connect(addr).unwrap();
+    let (r, w) = trace.tee(conn.try_clone().unwrap(), conn);
+    let client = Client::over_transport(r, w, 8192).unwrap();
+    let (_, fid) = client.attach(0, "root", "/").unwrap();
+    fid.clunk().unwrap();
+    drop(client);
+    server.join().unwrap();
+
+    let data = sink.0.lock().unwrap().clone();
+    let mut frames: Vec<TraceFrame> = TraceReader::new(&data[..])
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    // A response can be stored ahead of its request, never with an
+    // earlier time.
+    frames.sort_by_key(|f| f.timestamp);
+    let summary: Vec<(Direction, FcallType)> = frames
+        .iter()
+        .map(|f| (f.direction, FcallType::from(&f.fcall().unwrap().fcall)))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (Direction::Write, FcallType::Tversion),
+            (Direction::Read, FcallType::Rversion),
+            (Direction::Write, FcallType::Tattach),
+            (Direction::Read, FcallType::Rattach),
+            (Direction::Write, FcallType::Tclunk),
+            (Direction::Read, FcallType::Rclunk),
+        ]
+    );
+    assert!(frames.iter().all(|f| f.conn_id == 0));
+}
+
+#[test]
+fn trace_reader_rejects_bad_magic() {
+    assert!(TraceReader::new(&b"not a trace"[..]).is_err());
+}
+
+#[test]
+fn trace_reader_truncated() {
+    let sink = SharedBuf::default();
+    let trace = TraceWriter::new(sink.clone()).unwrap();
+    let mut buf = Vec::new();
+    TaggedFcall {
+        tag: 1,
+        fcall: Tclunk { fid: 1 }.into(),
+    }
+    .encode_to_buf(&mut buf)
+    .unwrap();
+    trace.record(0, Direction::Read, &buf).unwrap();
+    let data = sink.0.lock().unwrap().clone();
+    let mut frames = TraceReader::new(&data[..data.len() - 1]).unwrap();
+    assert!(frames.next().unwrap().is_err());
+}
+
+#[test]
+fn trace_reader_rejects_huge_frames() {
+    let mut data = b"9PTRACE1".to_vec();
+    data.extend_from_slice(&[0; 8 + 4 + 1]);
+    data.extend_from_slice(&u32::MAX.to_le_bytes());
+    let mut frames = TraceReader::new(&data[..]).unwrap();
+    let err = frames.next().unwrap().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn trace_only_written_frames() {
+    let mut frame = Vec::new();
+    TaggedFcall {
+        tag: 1,
+        fcall: Tclunk { fid: 1 }.into(),
+    }
+    .encode_to_buf(&mut frame)
+    .unwrap();
+    let sink = SharedBuf::default();
+    let trace = TraceWriter::new(sink.clone()).unwrap();
+
+    // A write that fails never reached the peer.
+    let (conn, peer) = UnixStream::pair().unwrap();
+    let (_, mut w) = trace.tee(conn.try_clone().unwrap(), conn);
+    drop(peer);
+    assert!(w.write_all(&frame).is_err());
+
+    // Nor is anything after a frame size no connection would use.
+    let (conn, _peer) = UnixStream::pair().unwrap();
+    let (_, mut w) = trace.tee(conn.try_clone().unwrap(), conn);
+    w.write_all(&u32::MAX.to_le_bytes()).unwrap();
+    w.write_all(&frame).unwrap();
+
+    let data = sink.0.lock().unwrap().clone();
+    assert_eq!(TraceReader::new(&data[..]).unwrap().count(), 0);
+}